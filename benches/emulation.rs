@@ -0,0 +1,200 @@
+// Benchmarks of the emulation hot paths: the CPU interpreter on flat RAM, a
+// whole frame of the system, PPU scanline rendering and APU sample generation.
+// Run with `cargo bench`; criterion keeps the previous results in
+// target/criterion and reports the change.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gameboy_emulator::apu::Apu;
+use gameboy_emulator::emulator::Emulator;
+use gameboy_emulator::ppu::{Ppu, SCREEN_HEIGHT};
+
+const CYCLES_PER_FRAME: u32 = 70224;
+
+// Loop mixing loads, ALU, memory, CB-prefixed, stack and branch instructions
+#[rustfmt::skip]
+const INSTRUCTION_MIX: &[u8] = &[
+    0x21, 0x00, 0xC0, // 0100: ld hl, $C000
+    0x06, 0x00,       // 0103: ld b, 0
+    0x7E,             // 0105: ld a, [hl]
+    0x80,             //       add a, b
+    0xEE, 0x5A,       //       xor $5A
+    0x22,             //       ld [hl+], a
+    0xCB, 0x37,       //       swap a
+    0xCB, 0x47,       //       bit 0, a
+    0xC5,             //       push bc
+    0xC1,             //       pop bc
+    0x05,             //       dec b
+    0x20, 0xF2,       //       jr nz, $0105
+    0xC3, 0x00, 0x01, //       jp $0100
+];
+
+// Loop calling a subroutine that copies and sums memory
+#[rustfmt::skip]
+const CALL_LOOP: &[u8] = &[
+    0xCD, 0x06, 0x01, // 0100: call $0106
+    0xC3, 0x00, 0x01, // 0103: jp $0100
+    0x11, 0x00, 0xC0, // 0106: ld de, $C000
+    0x21, 0x00, 0xD0, //       ld hl, $D000
+    0x0E, 0x10,       //       ld c, 16
+    0x1A,             // 010E: ld a, [de]
+    0x86,             //       add a, [hl]
+    0x77,             //       ld [hl], a
+    0x13,             //       inc de
+    0x23,             //       inc hl
+    0x0D,             //       dec c
+    0x20, 0xF8,       //       jr nz, $010E
+    0xC9,             //       ret
+];
+
+const STEPS: u64 = 10_000;
+
+// Emulator with the program at $0100 in flat RAM, so only the CPU runs
+fn flat_ram_cpu(program: &[u8]) -> Emulator {
+    let mut emu = Emulator::with_program(&[], None);
+    emu.mmu.use_flat_ram();
+    for (i, &byte) in program.iter().enumerate() {
+        emu.mmu.write_byte(0x0100 + i as u16, byte);
+    }
+    emu.cpu.registers.pc = 0x0100;
+    emu.cpu.registers.sp = 0xFFFE;
+    emu
+}
+
+fn cpu(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(STEPS));
+    for (name, program) in [("instruction_mix", INSTRUCTION_MIX), ("call_loop", CALL_LOOP)] {
+        let mut emu = flat_ram_cpu(program);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut cycles = 0;
+                for _ in 0..STEPS {
+                    cycles += emu.cpu.step(&mut emu.mmu);
+                }
+                black_box(cycles)
+            })
+        });
+    }
+    group.finish();
+}
+
+// A frame of the whole machine (CPU, PPU, APU, timer) running from ROM, with
+// and without the block cache
+fn system(c: &mut Criterion) {
+    let mut rom = vec![0; 0x0100];
+    rom.extend_from_slice(INSTRUCTION_MIX);
+    let mut emu = Emulator::with_program(&rom, None);
+    c.bench_function("system/frame", |b| b.iter(|| black_box(emu.run_frame())));
+    emu.cpu.block_cache = None;
+    c.bench_function("system/frame_no_block_cache", |b| b.iter(|| black_box(emu.run_frame())));
+}
+
+// Screen with every layer on: a patterned background, the window over the
+// bottom half and 40 sprites, 10 per line on some lines
+fn busy_ppu(is_gbc: bool) -> Ppu {
+    let mut ppu = Ppu::new(is_gbc);
+    for tile in 0..256u16 {
+        for row in 0..8u16 {
+            ppu.write_vram(0x8000 + tile * 16 + row * 2, (tile as u8).wrapping_mul(37) ^ row as u8);
+            ppu.write_vram(0x8000 + tile * 16 + row * 2 + 1, (tile as u8).rotate_left(row as u32));
+        }
+    }
+    for i in 0..0x400u16 {
+        ppu.write_vram(0x9800 + i, (i % 256) as u8);
+        ppu.write_vram(0x9C00 + i, (255 - i % 256) as u8);
+    }
+    for sprite in 0..40u16 {
+        let base = 0xFE00 + sprite * 4;
+        ppu.write_oam(base, 16 + (sprite / 10 * 36) as u8);
+        ppu.write_oam(base + 1, 8 + (sprite % 10 * 16) as u8);
+        ppu.write_oam(base + 2, sprite as u8);
+        ppu.write_oam(base + 3, if sprite % 2 == 0 { 0x00 } else { 0x30 });
+    }
+    ppu.lcdc = 0xF3; // LCD, window at $9C00, tiles at $8000, sprites and background on
+    ppu.wy = 72;
+    ppu.wx = 7;
+    ppu.bgp = 0xE4;
+    ppu.obp0 = 0xD2;
+    ppu.obp1 = 0x1B;
+    ppu
+}
+
+fn ppu(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ppu");
+    group.throughput(Throughput::Elements(SCREEN_HEIGHT as u64));
+    // The streaming variant rewrites every tile each frame, so all of them
+    // are decoded again
+    for (name, is_gbc, streaming) in
+        [("frame_dmg", false, false), ("frame_cgb", true, false), ("frame_dmg_tile_streaming", false, true)]
+    {
+        let mut ppu = busy_ppu(is_gbc);
+        let mut scroll = 0u8;
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                // Scroll and change a tile each frame, as games do
+                scroll = scroll.wrapping_add(1);
+                ppu.scx = scroll;
+                ppu.write_vram(0x8000 + scroll as u16 * 16, scroll);
+                if streaming {
+                    for tile in 0..384u16 {
+                        ppu.write_vram(0x8000 + tile * 16, scroll ^ tile as u8);
+                    }
+                }
+                for _ in 0..CYCLES_PER_FRAME / 4 {
+                    ppu.step(4);
+                }
+                black_box(ppu.frame()[0])
+            })
+        });
+    }
+    group.finish();
+}
+
+// APU with all four channels playing
+fn playing_apu() -> Apu {
+    let mut apu = Apu::new(false);
+    apu.write_register(0xFF26, 0x80);
+    for i in 0..16 {
+        apu.write_register(0xFF30 + i, (i as u8).wrapping_mul(0x1F));
+    }
+    for (address, value) in [
+        (0xFF24, 0x77), // Full volume
+        (0xFF25, 0xFF), // Every channel on both sides
+        (0xFF10, 0x27), // Square 1: sweep
+        (0xFF11, 0x80),
+        (0xFF12, 0xF3),
+        (0xFF13, 0x00),
+        (0xFF14, 0x87),
+        (0xFF16, 0x40), // Square 2
+        (0xFF17, 0xA5),
+        (0xFF18, 0x80),
+        (0xFF19, 0x86),
+        (0xFF1A, 0x80), // Wave
+        (0xFF1C, 0x20),
+        (0xFF1D, 0x40),
+        (0xFF1E, 0x85),
+        (0xFF21, 0xF1), // Noise
+        (0xFF22, 0x45),
+        (0xFF23, 0x80),
+    ] {
+        apu.write_register(address, value);
+    }
+    apu
+}
+
+fn apu(c: &mut Criterion) {
+    let mut apu = playing_apu();
+    c.bench_function("apu/frame", |b| {
+        b.iter(|| {
+            for _ in 0..CYCLES_PER_FRAME / 4 {
+                apu.step(4);
+            }
+            black_box(apu.take_samples().len())
+        })
+    });
+}
+
+criterion_group!(benches, cpu, system, ppu, apu);
+criterion_main!(benches);
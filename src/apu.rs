@@ -1,14 +1,58 @@
-// Basic APU (Audio Processing Unit) implementation with audio output
-
-use std::sync::{Arc, Mutex};
-
-const SAMPLE_RATE: u32 = 48000;
-const BUFFER_SIZE: usize = 2048;
+// Basic APU (Audio Processing Unit) implementation. The raw stereo mix is
+// collected once per frame; mixer.rs filters and resamples it for playback.
+
+use std::collections::VecDeque;
+use crate::mixer::HighPass;
+use crate::savestate::{StateReader, StateWriter};
+use crate::vgm::VgmWriter;
+use crate::wav::WavWriter;
+use log::{error, info};
+
+pub const SAMPLE_RATE: u32 = 48000;
+// Target audio latency in samples at SAMPLE_RATE
+pub const BUFFER_SIZE: usize = 2048;
+// Output kept when nobody takes it, in stereo samples
+const MAX_OUTPUT: usize = SAMPLE_RATE as usize;
+// Samples kept for the debug oscilloscope, about one frame
+pub const SCOPE_LEN: usize = 800;
+
+// The frame sequencer (512 Hz) is clocked by the falling edge of DIV bit 4,
+// bit 12 of the internal divider, or bit 5 in double speed mode
+const SEQUENCER_SHIFT: u32 = 13; // One clock every 2^13 divider counts
+
+// Wave RAM at power-on: random-looking on the DMG (this is a common pattern),
+// alternating 00/FF on the CGB
+const DMG_WAVE_RAM: [u8; 16] =
+    [0x84, 0x40, 0x43, 0xAA, 0x2D, 0x78, 0x92, 0x3C, 0x60, 0x59, 0x59, 0xB0, 0x34, 0xB8, 0x2E, 0xDA];
+const CGB_WAVE_RAM: [u8; 16] =
+    [0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF];
+
+// Snapshot of one channel for the debug views
+pub struct ChannelStatus {
+    pub enabled: bool,
+    pub frequency: f32,              // Hz
+    pub duty: Option<u8>,            // Square channels: 0-3 = 12.5%, 25%, 50%, 75%
+    pub volume: u8,                  // 0-15; channel 3: output level 0-3 (mute, 100%, 50%, 25%)
+    pub envelope: Option<(bool, u8)>, // (increasing, period)
+    pub length: u16,                 // Length counter
+    pub length_enabled: bool,
+    pub output: u8, // Current digital output (0-15), as PCM12/PCM34 read it
+}
 
 pub struct Apu {
-    // Audio buffer shared with output thread
-    pub audio_buffer: Arc<Mutex<Vec<f32>>>,
+    // Stereo mix (interleaved, unfiltered) since the last take_samples()
+    output: Vec<f32>,
     sample_counter: f32,
+    // Optional WAV capture of the stereo mix
+    recorder: Option<WavWriter>,
+    // Optional VGM log of the register writes
+    vgm: Option<VgmWriter>,
+    // Stereo samples collected for video recording, drained once per frame
+    capture: Option<Vec<f32>>,
+    // Removes the DAC offset from the recorded samples
+    recording_filter: [HighPass; 2],
+    // Recent output of each channel for the debug oscilloscope
+    scope: Option<VecDeque<[f32; 4]>>,
 
     // Channel state
     ch1_freq_timer: i32,
@@ -18,6 +62,10 @@ pub struct Apu {
     ch1_envelope_timer: u8,
     ch1_enabled: bool,
     ch1_length_counter: u16,
+    ch1_sweep_timer: u8,
+    ch1_sweep_enabled: bool,
+    ch1_shadow_freq: u16,
+    ch1_sweep_negated: bool, // Set once a subtraction has been calculated since the last trigger
 
     ch2_freq_timer: i32,
     ch2_duty_pos: u8,
@@ -31,6 +79,8 @@ pub struct Apu {
     ch3_wave_pos: u8,
     ch3_enabled: bool,
     ch3_length_counter: u16,
+    ch3_sample_buffer: u8,    // Last byte fetched from wave RAM
+    ch3_since_fetch: u32,     // Cycles since the last wave RAM fetch (DMG access window)
 
     ch4_lfsr: u16,
     ch4_freq_timer: i32,
@@ -40,10 +90,6 @@ pub struct Apu {
     ch4_enabled: bool,
     ch4_length_counter: u16,
 
-    // High-pass filter state
-    capacitor: f32,
-    // Low-pass filter state (for smoothing)
-    last_output: f32,
     // Channel control
     pub nr50: u8, // Master volume & VIN panning
     pub nr51: u8, // Sound panning
@@ -78,22 +124,31 @@ pub struct Apu {
 
     // Internal state
     frame_sequencer: u8,
-    cycles: u32,
+    is_gbc: bool,
 }
 
 impl Apu {
-    pub fn new() -> Self {
+    pub fn new(is_gbc: bool) -> Self {
         Apu {
-            audio_buffer: Arc::new(Mutex::new(Vec::new())),
+            output: Vec::new(),
             sample_counter: 0.0,
+            recorder: None,
+            vgm: None,
+            capture: None,
+            recording_filter: [HighPass::new(SAMPLE_RATE), HighPass::new(SAMPLE_RATE)],
+            scope: None,
 
             ch1_freq_timer: 0,
             ch1_duty_pos: 0,
             ch1_volume: 0,
             ch1_volume_initial: 0,
             ch1_envelope_timer: 0,
-            ch1_enabled: false,
+            ch1_enabled: true, // Still on from the startup sound, faded out
             ch1_length_counter: 0,
+            ch1_sweep_timer: 0,
+            ch1_sweep_enabled: false,
+            ch1_shadow_freq: 0,
+            ch1_sweep_negated: false,
 
             ch2_freq_timer: 0,
             ch2_duty_pos: 0,
@@ -107,6 +162,8 @@ impl Apu {
             ch3_wave_pos: 0,
             ch3_enabled: false,
             ch3_length_counter: 0,
+            ch3_sample_buffer: 0,
+            ch3_since_fetch: u32::MAX,
 
             ch4_lfsr: 0x7FFF,
             ch4_freq_timer: 0,
@@ -116,46 +173,207 @@ impl Apu {
             ch4_enabled: false,
             ch4_length_counter: 0,
 
-            capacitor: 0.0,
-            last_output: 0.0,
+            // Registers as the boot ROM leaves them, after the startup sound
+            nr50: 0x77,
+            nr51: 0xF3,
+            nr52: 0x80, // Powered on, the channel bits are read from the channels
 
-            nr50: 0,
-            nr51: 0,
-            nr52: 0xF1, // All channels enabled by default
+            nr10: 0x80,
+            nr11: 0xBF,
+            nr12: 0xF3,
+            nr13: 0xFF,
+            nr14: 0xBF,
 
-            nr10: 0,
-            nr11: 0,
-            nr12: 0,
-            nr13: 0,
-            nr14: 0,
-
-            nr21: 0,
+            nr21: 0x3F,
             nr22: 0,
-            nr23: 0,
-            nr24: 0,
+            nr23: 0xFF,
+            nr24: 0xBF,
 
-            nr30: 0,
-            nr31: 0,
-            nr32: 0,
-            nr33: 0,
-            nr34: 0,
-            wave_ram: [0; 16],
+            nr30: 0x7F,
+            nr31: 0xFF,
+            nr32: 0x9F,
+            nr33: 0xFF,
+            nr34: 0xBF,
+            wave_ram: if is_gbc { CGB_WAVE_RAM } else { DMG_WAVE_RAM },
 
-            nr41: 0,
+            nr41: 0xFF,
             nr42: 0,
             nr43: 0,
-            nr44: 0,
+            nr44: 0xBF,
 
             frame_sequencer: 0,
-            cycles: 0,
+            is_gbc,
+        }
+    }
+
+    // Returns the stereo mix produced since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn start_recording(&mut self, path: &str) -> std::io::Result<()> {
+        self.stop_recording();
+        self.recorder = Some(WavWriter::create(path, SAMPLE_RATE, 2)?);
+        info!("Recording audio to: {}", path);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            match recorder.finish() {
+                Ok(()) => info!("Audio recording stopped"),
+                Err(e) => error!("Failed to finish audio recording: {}", e),
+            }
         }
     }
 
-    pub fn get_audio_buffer(&self) -> Arc<Mutex<Vec<f32>>> {
-        Arc::clone(&self.audio_buffer)
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Starts logging register writes to a VGM file, beginning with the
+    // current register state so the music plays from where it is
+    pub fn start_vgm_log(&mut self, path: &str, game: &str) -> std::io::Result<()> {
+        self.stop_vgm_log();
+        let system = if self.is_gbc { "Nintendo Game Boy Color" } else { "Nintendo Game Boy" };
+        let mut vgm = VgmWriter::create(path, game, system)?;
+        vgm.write_register(0xFF26, self.nr52 & 0x80)?;
+        // Wave RAM can only be written with the channel 3 DAC off
+        vgm.write_register(0xFF1A, 0)?;
+        for (i, &value) in self.wave_ram.iter().enumerate() {
+            vgm.write_register(0xFF30 + i as u16, value)?;
+        }
+        if (self.nr52 & 0x80) != 0 {
+            // Channels that are playing are triggered again
+            let trigger = |enabled: bool, value: u8| (value & 0x7F) | if enabled { 0x80 } else { 0 };
+            let registers = [
+                (0xFF24, self.nr50),
+                (0xFF25, self.nr51),
+                (0xFF10, self.nr10),
+                (0xFF11, self.nr11),
+                (0xFF12, self.nr12),
+                (0xFF13, self.nr13),
+                (0xFF14, trigger(self.ch1_enabled, self.nr14)),
+                (0xFF16, self.nr21),
+                (0xFF17, self.nr22),
+                (0xFF18, self.nr23),
+                (0xFF19, trigger(self.ch2_enabled, self.nr24)),
+                (0xFF1A, self.nr30),
+                (0xFF1B, self.nr31),
+                (0xFF1C, self.nr32),
+                (0xFF1D, self.nr33),
+                (0xFF1E, trigger(self.ch3_enabled, self.nr34)),
+                (0xFF20, self.nr41),
+                (0xFF21, self.nr42),
+                (0xFF22, self.nr43),
+                (0xFF23, trigger(self.ch4_enabled, self.nr44)),
+            ];
+            for (address, value) in registers {
+                vgm.write_register(address, value)?;
+            }
+        }
+        self.vgm = Some(vgm);
+        info!("Logging sound to: {}", path);
+        Ok(())
+    }
+
+    pub fn stop_vgm_log(&mut self) {
+        if let Some(vgm) = self.vgm.take() {
+            match vgm.finish() {
+                Ok(()) => info!("Sound log stopped"),
+                Err(e) => error!("Failed to finish sound log: {}", e),
+            }
+        }
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    // Starts or stops collecting the stereo mix for video recording
+    pub fn set_capture(&mut self, enabled: bool) {
+        self.capture = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // Returns the samples collected since the last call
+    pub fn take_captured(&mut self) -> Vec<f32> {
+        self.capture.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Starts or stops keeping per-channel output for the oscilloscope
+    pub fn set_scope(&mut self, enabled: bool) {
+        self.scope = if enabled { Some(VecDeque::with_capacity(SCOPE_LEN)) } else { None };
+    }
+
+    // Recent per-channel output, oldest first
+    pub fn scope(&self) -> Option<&VecDeque<[f32; 4]>> {
+        self.scope.as_ref()
+    }
+
+    pub fn channel_status(&self) -> [ChannelStatus; 4] {
+        let square_freq = |lo: u8, hi: u8| {
+            let period = (lo as u32) | (((hi & 0x07) as u32) << 8);
+            131072.0 / (2048 - period) as f32
+        };
+        let envelope = |nrx2: u8| Some(((nrx2 & 0x08) != 0, nrx2 & 0x07));
+        let on = (self.nr52 & 0x80) != 0;
+
+        // Noise: 262144 / (divisor * 2^shift), divisor 0 counts as 0.5
+        let divisor = match self.nr43 & 0x07 {
+            0 => 0.5,
+            r => r as f32,
+        };
+        let noise_freq = 262144.0 / (divisor * (1u32 << (self.nr43 >> 4)) as f32);
+        let outputs = self.channel_outputs();
+
+        [
+            ChannelStatus {
+                enabled: self.ch1_enabled && on,
+                frequency: square_freq(self.nr13, self.nr14),
+                duty: Some(self.nr11 >> 6),
+                volume: self.ch1_volume,
+                envelope: envelope(self.nr12),
+                length: self.ch1_length_counter,
+                length_enabled: (self.nr14 & 0x40) != 0,
+                output: outputs[0],
+            },
+            ChannelStatus {
+                enabled: self.ch2_enabled && on,
+                frequency: square_freq(self.nr23, self.nr24),
+                duty: Some(self.nr21 >> 6),
+                volume: self.ch2_volume,
+                envelope: envelope(self.nr22),
+                length: self.ch2_length_counter,
+                length_enabled: (self.nr24 & 0x40) != 0,
+                output: outputs[1],
+            },
+            ChannelStatus {
+                enabled: self.ch3_enabled && on,
+                frequency: square_freq(self.nr33, self.nr34) / 2.0,
+                duty: None,
+                volume: (self.nr32 >> 5) & 0x03,
+                envelope: None,
+                length: self.ch3_length_counter,
+                length_enabled: (self.nr34 & 0x40) != 0,
+                output: outputs[2],
+            },
+            ChannelStatus {
+                enabled: self.ch4_enabled && on,
+                frequency: noise_freq,
+                duty: None,
+                volume: self.ch4_volume,
+                envelope: envelope(self.nr42),
+                length: self.ch4_length_counter,
+                length_enabled: (self.nr44 & 0x40) != 0,
+                output: outputs[3],
+            },
+        ]
     }
 
     pub fn step(&mut self, cycles: u32) {
+        if let Some(ref mut vgm) = self.vgm {
+            vgm.advance(cycles);
+        }
         if (self.nr52 & 0x80) == 0 {
             return; // APU is off
         }
@@ -163,8 +381,6 @@ impl Apu {
         // Update channel timers first
         self.update_channels(cycles);
 
-        self.cycles += cycles;
-
         // Generate audio samples - GB CPU is ~4.19MHz, we need 48kHz samples
         self.sample_counter += cycles as f32;
         let cycles_per_sample = 4194304.0 / SAMPLE_RATE as f32; // ~87 cycles per sample
@@ -173,89 +389,99 @@ impl Apu {
             self.sample_counter -= cycles_per_sample;
             self.generate_sample();
         }
+    }
 
-        // Frame sequencer runs at 512 Hz (every 8192 cycles)
-        while self.cycles >= 8192 {
-            self.cycles -= 8192;
+    // Clocks the frame sequencer on each falling edge of its DIV bit while
+    // the divider advances `cycles` (at CPU speed) from `div`
+    pub fn step_div(&mut self, div: u16, cycles: u32, double_speed: bool) {
+        let shift = if double_speed { SEQUENCER_SHIFT + 1 } else { SEQUENCER_SHIFT };
+        let clocks = ((div as u32 + cycles) >> shift) - (div as u32 >> shift);
+        for _ in 0..clocks {
             self.tick_frame_sequencer();
         }
     }
 
-    fn generate_sample(&mut self) {
-        let mut sample_left = 0.0;
-        let mut sample_right = 0.0;
+    // DIV is reset from `div`: if its bit was set, it falls and clocks the
+    // frame sequencer early
+    pub fn reset_div(&mut self, div: u16, double_speed: bool) {
+        let shift = if double_speed { SEQUENCER_SHIFT + 1 } else { SEQUENCER_SHIFT };
+        if (div >> (shift - 1)) & 1 != 0 {
+            self.tick_frame_sequencer();
+        }
+    }
 
-        // Channel 1 - Square with sweep
-        if self.ch1_enabled && (self.nr52 & 0x01) != 0 && self.ch1_volume > 0 {
-            let duty = (self.nr11 >> 6) & 0x03;
-            let duty_pattern = match duty {
-                0 => [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
-                1 => [1, 0, 0, 0, 0, 0, 0, 1], // 25%
-                2 => [1, 0, 0, 0, 0, 1, 1, 1], // 50%
-                3 => [0, 1, 1, 1, 1, 1, 1, 0], // 75%
-                _ => [0; 8],
-            };
-            // Convert to -1.0 to 1.0 range to remove DC offset
-            let output = if duty_pattern[self.ch1_duty_pos as usize] == 1 {
-                self.ch1_volume as f32 / 15.0
-            } else {
-                -(self.ch1_volume as f32 / 15.0)
-            };
+    // Digital output of each channel (0-15, 0 while stopped), before the DACs
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        const DUTY_PATTERNS: [[u8; 8]; 4] = [
+            [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+            [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+            [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+            [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+        ];
+        let mut digital = [0u8; 4];
 
-            if (self.nr51 & 0x01) != 0 { sample_right += output; }
-            if (self.nr51 & 0x10) != 0 { sample_left += output; }
+        // Channel 1 - Square with sweep
+        if self.ch1_enabled {
+            digital[0] = DUTY_PATTERNS[(self.nr11 >> 6) as usize][self.ch1_duty_pos as usize] * self.ch1_volume;
         }
 
         // Channel 2 - Square
-        if self.ch2_enabled && (self.nr52 & 0x02) != 0 && self.ch2_volume > 0 {
-            let duty = (self.nr21 >> 6) & 0x03;
-            let duty_pattern = match duty {
-                0 => [0, 0, 0, 0, 0, 0, 0, 1],
-                1 => [1, 0, 0, 0, 0, 0, 0, 1],
-                2 => [1, 0, 0, 0, 0, 1, 1, 1],
-                3 => [0, 1, 1, 1, 1, 1, 1, 0],
-                _ => [0; 8],
-            };
-            let output = if duty_pattern[self.ch2_duty_pos as usize] == 1 {
-                self.ch2_volume as f32 / 15.0
-            } else {
-                -(self.ch2_volume as f32 / 15.0)
-            };
-
-            if (self.nr51 & 0x02) != 0 { sample_right += output; }
-            if (self.nr51 & 0x20) != 0 { sample_left += output; }
+        if self.ch2_enabled {
+            digital[1] = DUTY_PATTERNS[(self.nr21 >> 6) as usize][self.ch2_duty_pos as usize] * self.ch2_volume;
         }
 
         // Channel 3 - Wave
-        if self.ch3_enabled && (self.nr52 & 0x04) != 0 && (self.nr30 & 0x80) != 0 {
-            let sample_byte = self.wave_ram[(self.ch3_wave_pos / 2) as usize];
+        if self.ch3_enabled {
+            // Output comes from the sample buffer, not directly from wave RAM
+            let sample_byte = self.ch3_sample_buffer;
             let nibble = if (self.ch3_wave_pos & 1) == 0 {
                 (sample_byte >> 4) & 0x0F
             } else {
                 sample_byte & 0x0F
             };
-
             let volume_shift = (self.nr32 >> 5) & 0x03;
-            let output = if volume_shift > 0 {
-                ((nibble >> (volume_shift - 1)) as f32 / 7.5) - 1.0
-            } else {
-                0.0
-            };
-
-            if (self.nr51 & 0x04) != 0 { sample_right += output; }
-            if (self.nr51 & 0x40) != 0 { sample_left += output; }
+            digital[2] = if volume_shift > 0 { nibble >> (volume_shift - 1) } else { 0 };
         }
 
         // Channel 4 - Noise
-        if self.ch4_enabled && (self.nr52 & 0x08) != 0 && self.ch4_volume > 0 {
-            let output = if (self.ch4_lfsr & 1) == 0 {
-                self.ch4_volume as f32 / 15.0
-            } else {
-                -(self.ch4_volume as f32 / 15.0)
-            };
+        if self.ch4_enabled && (self.ch4_lfsr & 1) == 0 {
+            digital[3] = self.ch4_volume;
+        }
+
+        digital
+    }
+
+    // PCM12 (0xFF76) and PCM34 (0xFF77), CGB only: the digital output of
+    // channels 1 and 3 in the low nibble, 2 and 4 in the high nibble
+    pub fn read_pcm(&self, address: u16) -> u8 {
+        let digital = self.channel_outputs();
+        let first = if address == 0xFF76 { 0 } else { 2 };
+        (digital[first + 1] << 4) | digital[first]
+    }
 
-            if (self.nr51 & 0x08) != 0 { sample_right += output; }
-            if (self.nr51 & 0x80) != 0 { sample_left += output; }
+    fn generate_sample(&mut self) {
+        let mut sample_left = 0.0;
+        let mut sample_right = 0.0;
+        let mut channel_out = [0.0; 4];
+
+        // Each channel's digital output goes through its DAC; a DAC that's
+        // off outputs analog zero
+        let digital = self.channel_outputs();
+
+        let dacs = [Self::dac_on(self.nr12), Self::dac_on(self.nr22), (self.nr30 & 0x80) != 0, Self::dac_on(self.nr42)];
+        let playing = [self.ch1_enabled, self.ch2_enabled, self.ch3_enabled, self.ch4_enabled];
+        for channel in 0..4 {
+            // -1.0 to 1.0 while the DAC is on. Silent channels with their DAC
+            // on sit at one end, which the output capacitor filters out.
+            let output = if dacs[channel] { digital[channel] as f32 / 7.5 - 1.0 } else { 0.0 };
+            if (self.nr51 & (0x01 << channel)) != 0 {
+                sample_right += output;
+            }
+            if (self.nr51 & (0x10 << channel)) != 0 {
+                sample_left += output;
+            }
+            // The oscilloscope shows only what's playing, centered
+            channel_out[channel] = if playing[channel] { output } else { 0.0 };
         }
 
         // Apply master volume
@@ -265,24 +491,31 @@ impl Apu {
         sample_left *= left_vol * 0.15;
         sample_right *= right_vol * 0.15;
 
-        // Mix to mono
-        let mut sample = (sample_left + sample_right) * 0.5;
-
-        // High-pass filter to remove DC offset (capacitor charge/discharge)
-        let filtered = sample - self.capacitor;
-        self.capacitor = sample - filtered * 0.996;
-        sample = filtered;
-
-        // Low-pass filter for smoothing (reduces aliasing and harshness)
-        // Simple one-pole filter
-        let alpha = 0.85; // Higher = more smoothing
-        sample = self.last_output * alpha + sample * (1.0 - alpha);
-        self.last_output = sample;
-
-        if let Ok(mut buffer) = self.audio_buffer.lock() {
-            if buffer.len() < BUFFER_SIZE * 2 {
-                buffer.push(sample);
+        // Recordings are filtered like the playback (mixer.rs) so they start
+        // and stay centered
+        let recorded = if self.recorder.is_some() || self.capture.is_some() {
+            [self.recording_filter[0].apply(sample_left), self.recording_filter[1].apply(sample_right)]
+        } else {
+            [sample_left, sample_right]
+        };
+        if let Some(ref mut recorder) = self.recorder {
+            if let Err(e) = recorder.write_frame(&recorded) {
+                error!("Audio recording failed: {}", e);
+                self.recorder = None;
+            }
+        }
+        if let Some(ref mut scope) = self.scope {
+            if scope.len() >= SCOPE_LEN {
+                scope.pop_front();
             }
+            scope.push_back(channel_out);
+        }
+        if let Some(ref mut capture) = self.capture {
+            capture.extend_from_slice(&recorded);
+        }
+        if self.output.len() < MAX_OUTPUT * 2 {
+            self.output.push(sample_left);
+            self.output.push(sample_right);
         }
     }
 
@@ -312,11 +545,15 @@ impl Apu {
         // Channel 3 frequency
         if self.ch3_enabled {
             self.ch3_freq_timer -= cycles as i32;
+            self.ch3_since_fetch = self.ch3_since_fetch.saturating_add(cycles);
             while self.ch3_freq_timer <= 0 {
                 let freq = ((self.nr34 as u16 & 0x07) << 8) | self.nr33 as u16;
                 let period = ((2048 - freq) * 2) as i32;
                 self.ch3_freq_timer += period;
+                // Position advances first, then the new byte is fetched into the buffer
                 self.ch3_wave_pos = (self.ch3_wave_pos + 1) & 31;
+                self.ch3_sample_buffer = self.wave_ram[(self.ch3_wave_pos / 2) as usize];
+                self.ch3_since_fetch = (period - self.ch3_freq_timer) as u32;
             }
         }
 
@@ -350,39 +587,22 @@ impl Apu {
     }
 
     fn tick_frame_sequencer(&mut self) {
+        if (self.nr52 & 0x80) == 0 {
+            return; // APU is off
+        }
+        // frame_sequencer holds the step about to be executed
+        let step = self.frame_sequencer;
         self.frame_sequencer = (self.frame_sequencer + 1) % 8;
 
-        match self.frame_sequencer {
-            0 | 4 => {
-                // Length counter tick
-                if self.ch1_length_counter > 0 && (self.nr14 & 0x40) != 0 {
-                    self.ch1_length_counter -= 1;
-                    if self.ch1_length_counter == 0 {
-                        self.ch1_enabled = false;
-                    }
-                }
-                if self.ch2_length_counter > 0 && (self.nr24 & 0x40) != 0 {
-                    self.ch2_length_counter -= 1;
-                    if self.ch2_length_counter == 0 {
-                        self.ch2_enabled = false;
-                    }
-                }
-                if self.ch3_length_counter > 0 && (self.nr34 & 0x40) != 0 {
-                    self.ch3_length_counter -= 1;
-                    if self.ch3_length_counter == 0 {
-                        self.ch3_enabled = false;
-                    }
-                }
-                if self.ch4_length_counter > 0 && (self.nr44 & 0x40) != 0 {
-                    self.ch4_length_counter -= 1;
-                    if self.ch4_length_counter == 0 {
-                        self.ch4_enabled = false;
-                    }
-                }
-            }
+        // Length counters are clocked on every even step (256 Hz)
+        if step.is_multiple_of(2) {
+            self.tick_lengths();
+        }
+
+        match step {
             2 | 6 => {
-                // Sweep tick (channel 1 only)
-                // Simplified - full sweep would require more state
+                // Sweep tick (channel 1 only, 128 Hz)
+                self.tick_sweep();
             }
             7 => {
                 // Envelope tick
@@ -394,6 +614,109 @@ impl Apu {
         }
     }
 
+    // True when the next frame sequencer step won't clock the length counters.
+    // Enabling length or triggering during this half of the period causes an
+    // extra length clock (blargg dmg_sound 03-trigger).
+    fn in_length_first_half(&self) -> bool {
+        (self.frame_sequencer & 1) != 0
+    }
+
+    fn tick_lengths(&mut self) {
+        if self.ch1_length_counter > 0 && (self.nr14 & 0x40) != 0 {
+            self.ch1_length_counter -= 1;
+            if self.ch1_length_counter == 0 {
+                self.ch1_enabled = false;
+            }
+        }
+        if self.ch2_length_counter > 0 && (self.nr24 & 0x40) != 0 {
+            self.ch2_length_counter -= 1;
+            if self.ch2_length_counter == 0 {
+                self.ch2_enabled = false;
+            }
+        }
+        if self.ch3_length_counter > 0 && (self.nr34 & 0x40) != 0 {
+            self.ch3_length_counter -= 1;
+            if self.ch3_length_counter == 0 {
+                self.ch3_enabled = false;
+            }
+        }
+        if self.ch4_length_counter > 0 && (self.nr44 & 0x40) != 0 {
+            self.ch4_length_counter -= 1;
+            if self.ch4_length_counter == 0 {
+                self.ch4_enabled = false;
+            }
+        }
+    }
+
+    fn tick_sweep(&mut self) {
+        if self.ch1_sweep_timer > 0 {
+            self.ch1_sweep_timer -= 1;
+        }
+
+        if self.ch1_sweep_timer == 0 {
+            let period = (self.nr10 >> 4) & 0x07;
+            // A period of 0 is treated as 8 for the timer
+            self.ch1_sweep_timer = if period == 0 { 8 } else { period };
+
+            if self.ch1_sweep_enabled && period != 0 {
+                let new_freq = self.sweep_calculate();
+                let shift = self.nr10 & 0x07;
+                if new_freq <= 2047 && shift != 0 {
+                    self.ch1_shadow_freq = new_freq;
+                    self.nr13 = (new_freq & 0xFF) as u8;
+                    self.nr14 = (self.nr14 & 0xF8) | ((new_freq >> 8) as u8 & 0x07);
+                    // Overflow check is run again with the new frequency but not written back
+                    self.sweep_calculate();
+                }
+            }
+        }
+    }
+
+    // Computes the next sweep frequency, disabling channel 1 on overflow
+    fn sweep_calculate(&mut self) -> u16 {
+        let shift = self.nr10 & 0x07;
+        let delta = self.ch1_shadow_freq >> shift;
+        let new_freq = if (self.nr10 & 0x08) != 0 {
+            self.ch1_sweep_negated = true;
+            self.ch1_shadow_freq.wrapping_sub(delta)
+        } else {
+            self.ch1_shadow_freq + delta
+        };
+
+        if new_freq > 2047 {
+            self.ch1_enabled = false;
+        }
+        new_freq
+    }
+
+    // Handles the length-related side effects of an NRx4 write. Must run
+    // before the trigger itself so a zero counter is reloaded correctly.
+    fn write_length_control(
+        counter: &mut u16,
+        enabled: &mut bool,
+        old_nrx4: u8,
+        value: u8,
+        max: u16,
+        first_half: bool,
+    ) {
+        let was_enabled = (old_nrx4 & 0x40) != 0;
+        let length_enabled = (value & 0x40) != 0;
+        let trigger = (value & 0x80) != 0;
+
+        // Enabling length in the first half of the period clocks it once extra
+        if first_half && !was_enabled && length_enabled && *counter > 0 {
+            *counter -= 1;
+            if *counter == 0 && !trigger {
+                *enabled = false;
+            }
+        }
+
+        // Triggering with a zero counter reloads it to max (minus the extra clock)
+        if trigger && *counter == 0 {
+            *counter = if length_enabled && first_half { max - 1 } else { max };
+        }
+    }
+
     fn tick_envelope_ch1(&mut self) {
         let period = self.nr12 & 0x07;
         if period == 0 {
@@ -486,33 +809,120 @@ impl Apu {
 
             0xFF24 => self.nr50,
             0xFF25 => self.nr51,
-            0xFF26 => self.nr52,
+            0xFF26 => {
+                // Low bits: which channels are playing, set by a trigger and
+                // cleared by length expiry, sweep overflow or the DAC turning off
+                let playing = [self.ch1_enabled, self.ch2_enabled, self.ch3_enabled, self.ch4_enabled];
+                let status = playing.iter().enumerate().fold(0, |bits, (i, &on)| bits | ((on as u8) << i));
+                (self.nr52 & 0x80) | 0x70 | status
+            }
 
-            0xFF30..=0xFF3F => self.wave_ram[(address - 0xFF30) as usize],
+            0xFF30..=0xFF3F => {
+                match self.wave_ram_index(address) {
+                    Some(index) => self.wave_ram[index],
+                    None => 0xFF,
+                }
+            }
 
             _ => 0xFF,
         }
     }
 
+    // The DAC of channels 1, 2 and 4 is on while the initial volume or the
+    // envelope direction (the top 5 bits of NRx2) are set
+    fn dac_on(nrx2: u8) -> bool {
+        (nrx2 & 0xF8) != 0
+    }
+
+    // Resolves a wave RAM access. While channel 3 is playing, the CPU only sees
+    // the byte the channel is currently reading; on DMG this is further limited
+    // to the moment the channel fetches it, otherwise the access fails.
+    fn wave_ram_index(&self, address: u16) -> Option<usize> {
+        if !self.ch3_enabled {
+            return Some((address - 0xFF30) as usize);
+        }
+
+        if self.is_gbc || self.ch3_since_fetch < 2 {
+            Some((self.ch3_wave_pos / 2) as usize)
+        } else {
+            None
+        }
+    }
+
+    // DMG bug: retriggering channel 3 just as it fetches a sample corrupts the
+    // first bytes of wave RAM with the bytes around the read position.
+    fn corrupt_wave_ram_on_retrigger(&mut self) {
+        if self.is_gbc || !self.ch3_enabled || self.ch3_freq_timer > 2 {
+            return;
+        }
+
+        let next = (((self.ch3_wave_pos + 1) & 31) / 2) as usize;
+        if next < 4 {
+            self.wave_ram[0] = self.wave_ram[next];
+        } else {
+            let block = next & !0x03;
+            for i in 0..4 {
+                self.wave_ram[i] = self.wave_ram[block + i];
+            }
+        }
+    }
+
     pub fn write_register(&mut self, address: u16, value: u8) {
-        // If APU is off, ignore writes (except to NR52)
-        if address != 0xFF26 && (self.nr52 & 0x80) == 0 {
+        if let Some(ref mut vgm) = self.vgm {
+            if let Err(e) = vgm.write_register(address, value) {
+                error!("Sound log failed: {}", e);
+                self.vgm = None;
+            }
+        }
+
+        // If APU is off, ignore writes (except to NR52 and wave RAM). The DMG
+        // still takes the length timers, without the duty bits.
+        if address != 0xFF26 && !(0xFF30..=0xFF3F).contains(&address) && (self.nr52 & 0x80) == 0 {
+            if !self.is_gbc {
+                match address {
+                    0xFF11 => self.ch1_length_counter = 64 - (value & 0x3F) as u16,
+                    0xFF16 => self.ch2_length_counter = 64 - (value & 0x3F) as u16,
+                    0xFF1B => self.ch3_length_counter = 256 - value as u16,
+                    0xFF20 => self.ch4_length_counter = 64 - (value & 0x3F) as u16,
+                    _ => {}
+                }
+            }
             return;
         }
 
         match address {
-            0xFF10 => self.nr10 = value,
+            0xFF10 => {
+                // Clearing negate after a subtraction was used disables the channel
+                if self.ch1_sweep_negated && (self.nr10 & 0x08) != 0 && (value & 0x08) == 0 {
+                    self.ch1_enabled = false;
+                }
+                self.nr10 = value;
+            }
             0xFF11 => {
                 self.nr11 = value;
                 self.ch1_length_counter = 64 - (value & 0x3F) as u16;
             }
-            0xFF12 => self.nr12 = value,
+            0xFF12 => {
+                self.nr12 = value;
+                if !Self::dac_on(value) {
+                    self.ch1_enabled = false;
+                }
+            }
             0xFF13 => self.nr13 = value,
             0xFF14 => {
+                let first_half = self.in_length_first_half();
+                Self::write_length_control(
+                    &mut self.ch1_length_counter,
+                    &mut self.ch1_enabled,
+                    self.nr14,
+                    value,
+                    64,
+                    first_half,
+                );
                 self.nr14 = value;
                 if (value & 0x80) != 0 {
-                    // Trigger channel 1
-                    self.ch1_enabled = true;
+                    // Trigger channel 1, which only plays with its DAC on
+                    self.ch1_enabled = Self::dac_on(self.nr12);
                     self.ch1_volume = (self.nr12 >> 4) & 0x0F;
                     self.ch1_volume_initial = self.ch1_volume;
                     self.ch1_envelope_timer = self.nr12 & 0x07;
@@ -520,9 +930,16 @@ impl Apu {
                     self.ch1_freq_timer = ((2048 - freq) * 4) as i32;
                     self.ch1_duty_pos = 0;
 
-                    // Length counter
-                    if self.ch1_length_counter == 0 {
-                        self.ch1_length_counter = 64;
+                    // Sweep
+                    let sweep_period = (self.nr10 >> 4) & 0x07;
+                    let sweep_shift = self.nr10 & 0x07;
+                    self.ch1_shadow_freq = freq;
+                    self.ch1_sweep_timer = if sweep_period == 0 { 8 } else { sweep_period };
+                    self.ch1_sweep_enabled = sweep_period != 0 || sweep_shift != 0;
+                    self.ch1_sweep_negated = false;
+                    if sweep_shift != 0 {
+                        // Immediate overflow check on trigger
+                        self.sweep_calculate();
                     }
                 }
             }
@@ -531,28 +948,43 @@ impl Apu {
                 self.nr21 = value;
                 self.ch2_length_counter = 64 - (value & 0x3F) as u16;
             }
-            0xFF17 => self.nr22 = value,
+            0xFF17 => {
+                self.nr22 = value;
+                if !Self::dac_on(value) {
+                    self.ch2_enabled = false;
+                }
+            }
             0xFF18 => self.nr23 = value,
             0xFF19 => {
+                let first_half = self.in_length_first_half();
+                Self::write_length_control(
+                    &mut self.ch2_length_counter,
+                    &mut self.ch2_enabled,
+                    self.nr24,
+                    value,
+                    64,
+                    first_half,
+                );
                 self.nr24 = value;
                 if (value & 0x80) != 0 {
                     // Trigger channel 2
-                    self.ch2_enabled = true;
+                    self.ch2_enabled = Self::dac_on(self.nr22);
                     self.ch2_volume = (self.nr22 >> 4) & 0x0F;
                     self.ch2_volume_initial = self.ch2_volume;
                     self.ch2_envelope_timer = self.nr22 & 0x07;
                     let freq = ((self.nr24 as u16 & 0x07) << 8) | self.nr23 as u16;
                     self.ch2_freq_timer = ((2048 - freq) * 4) as i32;
                     self.ch2_duty_pos = 0;
-
-                    // Length counter
-                    if self.ch2_length_counter == 0 {
-                        self.ch2_length_counter = 64;
-                    }
                 }
             }
 
-            0xFF1A => self.nr30 = value,
+            0xFF1A => {
+                self.nr30 = value;
+                // Turning the DAC off immediately disables the channel
+                if (value & 0x80) == 0 {
+                    self.ch3_enabled = false;
+                }
+            }
             0xFF1B => {
                 self.nr31 = value;
                 self.ch3_length_counter = 256 - value as u16;
@@ -560,18 +992,27 @@ impl Apu {
             0xFF1C => self.nr32 = value,
             0xFF1D => self.nr33 = value,
             0xFF1E => {
+                let first_half = self.in_length_first_half();
+                Self::write_length_control(
+                    &mut self.ch3_length_counter,
+                    &mut self.ch3_enabled,
+                    self.nr34,
+                    value,
+                    256,
+                    first_half,
+                );
                 self.nr34 = value;
                 if (value & 0x80) != 0 {
                     // Trigger channel 3
-                    self.ch3_enabled = true;
+                    self.corrupt_wave_ram_on_retrigger();
+                    // The channel only turns on if its DAC is enabled
+                    self.ch3_enabled = (self.nr30 & 0x80) != 0;
                     let freq = ((self.nr34 as u16 & 0x07) << 8) | self.nr33 as u16;
-                    self.ch3_freq_timer = ((2048 - freq) * 2) as i32;
+                    // First fetch is delayed by 3 APU cycles; the sample buffer
+                    // keeps its old value until then
+                    self.ch3_freq_timer = ((2048 - freq) * 2) as i32 + 6;
                     self.ch3_wave_pos = 0;
-
-                    // Length counter
-                    if self.ch3_length_counter == 0 {
-                        self.ch3_length_counter = 256;
-                    }
+                    self.ch3_since_fetch = u32::MAX;
                 }
             }
 
@@ -579,22 +1020,31 @@ impl Apu {
                 self.nr41 = value;
                 self.ch4_length_counter = 64 - (value & 0x3F) as u16;
             }
-            0xFF21 => self.nr42 = value,
+            0xFF21 => {
+                self.nr42 = value;
+                if !Self::dac_on(value) {
+                    self.ch4_enabled = false;
+                }
+            }
             0xFF22 => self.nr43 = value,
             0xFF23 => {
+                let first_half = self.in_length_first_half();
+                Self::write_length_control(
+                    &mut self.ch4_length_counter,
+                    &mut self.ch4_enabled,
+                    self.nr44,
+                    value,
+                    64,
+                    first_half,
+                );
                 self.nr44 = value;
                 if (value & 0x80) != 0 {
                     // Trigger channel 4
-                    self.ch4_enabled = true;
+                    self.ch4_enabled = Self::dac_on(self.nr42);
                     self.ch4_volume = (self.nr42 >> 4) & 0x0F;
                     self.ch4_volume_initial = self.ch4_volume;
                     self.ch4_envelope_timer = self.nr42 & 0x07;
                     self.ch4_lfsr = 0x7FFF;
-
-                    // Length counter
-                    if self.ch4_length_counter == 0 {
-                        self.ch4_length_counter = 64;
-                    }
                 }
             }
 
@@ -626,16 +1076,143 @@ impl Apu {
                     self.nr44 = 0;
                     self.nr50 = 0;
                     self.nr51 = 0;
+                    self.ch1_enabled = false;
+                    self.ch2_enabled = false;
+                    self.ch3_enabled = false;
+                    self.ch4_enabled = false;
+                    self.ch1_duty_pos = 0;
+                    self.ch2_duty_pos = 0;
+                    self.ch3_sample_buffer = 0;
+                    // The CGB also clears the length timers, the DMG keeps them
+                    if self.is_gbc {
+                        self.ch1_length_counter = 0;
+                        self.ch2_length_counter = 0;
+                        self.ch3_length_counter = 0;
+                        self.ch4_length_counter = 0;
+                    }
+                } else if !old_power && new_power {
+                    // Power on - the next frame sequencer step is 0
+                    self.frame_sequencer = 0;
                 }
 
-                self.nr52 = (value & 0x80) | (self.nr52 & 0x0F);
+                self.nr52 = value & 0x80;
             }
 
             0xFF30..=0xFF3F => {
-                self.wave_ram[(address - 0xFF30) as usize] = value;
+                if let Some(index) = self.wave_ram_index(address) {
+                    self.wave_ram[index] = value;
+                }
             }
 
             _ => {}
         }
     }
+
+    // Channel and register state; recordings and the output buffer stay as they are
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.sample_counter.to_bits());
+
+        w.i32(self.ch1_freq_timer);
+        w.bytes(&[self.ch1_duty_pos, self.ch1_volume, self.ch1_volume_initial, self.ch1_envelope_timer]);
+        w.bool(self.ch1_enabled);
+        w.u16(self.ch1_length_counter);
+        w.u8(self.ch1_sweep_timer);
+        w.bool(self.ch1_sweep_enabled);
+        w.u16(self.ch1_shadow_freq);
+        w.bool(self.ch1_sweep_negated);
+
+        w.i32(self.ch2_freq_timer);
+        w.bytes(&[self.ch2_duty_pos, self.ch2_volume, self.ch2_volume_initial, self.ch2_envelope_timer]);
+        w.bool(self.ch2_enabled);
+        w.u16(self.ch2_length_counter);
+
+        w.i32(self.ch3_freq_timer);
+        w.u8(self.ch3_wave_pos);
+        w.bool(self.ch3_enabled);
+        w.u16(self.ch3_length_counter);
+        w.u8(self.ch3_sample_buffer);
+        w.u32(self.ch3_since_fetch);
+
+        w.u16(self.ch4_lfsr);
+        w.i32(self.ch4_freq_timer);
+        w.bytes(&[self.ch4_volume, self.ch4_volume_initial, self.ch4_envelope_timer]);
+        w.bool(self.ch4_enabled);
+        w.u16(self.ch4_length_counter);
+
+        w.bytes(&[self.nr50, self.nr51, self.nr52]);
+        w.bytes(&[self.nr10, self.nr11, self.nr12, self.nr13, self.nr14]);
+        w.bytes(&[self.nr21, self.nr22, self.nr23, self.nr24]);
+        w.bytes(&[self.nr30, self.nr31, self.nr32, self.nr33, self.nr34]);
+        w.bytes(&self.wave_ram);
+        w.bytes(&[self.nr41, self.nr42, self.nr43, self.nr44]);
+        w.u8(self.frame_sequencer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.sample_counter = f32::from_bits(r.u32());
+
+        // Positions used as indexes are masked, so a damaged state can't
+        // index past the duty patterns or wave RAM
+        self.ch1_freq_timer = r.i32();
+        self.ch1_duty_pos = r.u8() & 0x07;
+        self.ch1_volume = r.u8();
+        self.ch1_volume_initial = r.u8();
+        self.ch1_envelope_timer = r.u8();
+        self.ch1_enabled = r.bool();
+        self.ch1_length_counter = r.u16();
+        self.ch1_sweep_timer = r.u8();
+        self.ch1_sweep_enabled = r.bool();
+        self.ch1_shadow_freq = r.u16();
+        self.ch1_sweep_negated = r.bool();
+
+        self.ch2_freq_timer = r.i32();
+        self.ch2_duty_pos = r.u8() & 0x07;
+        self.ch2_volume = r.u8();
+        self.ch2_volume_initial = r.u8();
+        self.ch2_envelope_timer = r.u8();
+        self.ch2_enabled = r.bool();
+        self.ch2_length_counter = r.u16();
+
+        self.ch3_freq_timer = r.i32();
+        self.ch3_wave_pos = r.u8() & 0x1F;
+        self.ch3_enabled = r.bool();
+        self.ch3_length_counter = r.u16();
+        self.ch3_sample_buffer = r.u8();
+        self.ch3_since_fetch = r.u32();
+
+        self.ch4_lfsr = r.u16();
+        self.ch4_freq_timer = r.i32();
+        self.ch4_volume = r.u8();
+        self.ch4_volume_initial = r.u8();
+        self.ch4_envelope_timer = r.u8();
+        self.ch4_enabled = r.bool();
+        self.ch4_length_counter = r.u16();
+
+        for register in [
+            &mut self.nr50,
+            &mut self.nr51,
+            &mut self.nr52,
+            &mut self.nr10,
+            &mut self.nr11,
+            &mut self.nr12,
+            &mut self.nr13,
+            &mut self.nr14,
+            &mut self.nr21,
+            &mut self.nr22,
+            &mut self.nr23,
+            &mut self.nr24,
+            &mut self.nr30,
+            &mut self.nr31,
+            &mut self.nr32,
+            &mut self.nr33,
+            &mut self.nr34,
+        ] {
+            *register = r.u8();
+        }
+        r.bytes(&mut self.wave_ram);
+        for register in [&mut self.nr41, &mut self.nr42, &mut self.nr43, &mut self.nr44] {
+            *register = r.u8();
+        }
+        self.frame_sequencer = r.u8() & 0x07;
+    }
 }
@@ -0,0 +1,81 @@
+// APU debug view: an oscilloscope of each channel's recent output with a
+// volume bar. Detailed channel state is printed by the debugger's `apu` command.
+
+use minifb::{Key, Window, WindowOptions};
+
+use crate::apu::{Apu, SCOPE_LEN};
+
+const LANE_HEIGHT: usize = 64;
+const BAR_WIDTH: usize = 8;
+const WIDTH: usize = BAR_WIDTH + SCOPE_LEN / 2;
+const HEIGHT: usize = LANE_HEIGHT * 4;
+
+const BACKGROUND: u32 = 0x101010;
+const DISABLED: u32 = 0x404040;
+const AXIS: u32 = 0x303030;
+const CHANNEL_COLORS: [u32; 4] = [0xFF6060, 0x60FF60, 0x6060FF, 0xFFFF60];
+
+pub struct ApuViewer {
+    window: Window,
+    buffer: Vec<u32>,
+}
+
+impl ApuViewer {
+    // Also turns on the APU's oscilloscope buffer
+    pub fn new(apu: &mut Apu) -> Result<Self, String> {
+        let mut window = Window::new("APU Channels (1-4, top to bottom)", WIDTH, HEIGHT, WindowOptions::default())
+            .map_err(|e| e.to_string())?;
+        window.limit_update_rate(None);
+        apu.set_scope(true);
+        Ok(ApuViewer { window, buffer: vec![BACKGROUND; WIDTH * HEIGHT] })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    pub fn update(&mut self, apu: &Apu) {
+        self.buffer.fill(BACKGROUND);
+        let status = apu.channel_status();
+
+        for (channel, status) in status.iter().enumerate() {
+            let top = channel * LANE_HEIGHT;
+            let mid = top + LANE_HEIGHT / 2;
+            let color = if status.enabled { CHANNEL_COLORS[channel] } else { DISABLED };
+
+            // Volume bar (channel 3 output level is 0-3)
+            let max_volume = if channel == 2 { 3 } else { 15 };
+            let level = match (channel, status.volume) {
+                (2, 0) => 0,
+                (2, shift) => 4 - shift, // 100%, 50%, 25%
+                (_, volume) => volume,
+            };
+            let bar = level as usize * (LANE_HEIGHT - 2) / max_volume.max(1);
+            for y in (top + LANE_HEIGHT - 1 - bar)..(top + LANE_HEIGHT - 1) {
+                for x in 0..BAR_WIDTH - 2 {
+                    self.buffer[y * WIDTH + x] = color;
+                }
+            }
+
+            // Center line and lane separator
+            for x in BAR_WIDTH..WIDTH {
+                self.buffer[mid * WIDTH + x] = AXIS;
+                self.buffer[(top + LANE_HEIGHT - 1) * WIDTH + x] = AXIS;
+            }
+
+            // Waveform, two samples per column
+            if let Some(scope) = apu.scope() {
+                let half = (LANE_HEIGHT / 2 - 2) as f32;
+                for (i, samples) in scope.iter().enumerate().step_by(2) {
+                    let x = BAR_WIDTH + i / 2;
+                    let y = (mid as f32 - samples[channel].clamp(-1.0, 1.0) * half) as usize;
+                    if x < WIDTH {
+                        self.buffer[y * WIDTH + x] = color;
+                    }
+                }
+            }
+        }
+
+        let _ = self.window.update_with_buffer(&self.buffer, WIDTH, HEIGHT);
+    }
+}
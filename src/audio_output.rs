@@ -0,0 +1,245 @@
+// Audio playback through cpal for the desktop frontends. The emulation pushes
+// the APU's raw output into an AudioOutput; the AudioDevice plays it on the
+// device picked by name, or the system default, and rebuilds its stream when
+// the device goes away or the default changes (e.g. a headset is plugged in).
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use crate::apu;
+use crate::mixer::{AudioFilter, Mixer};
+use log::{error, info, warn};
+
+// How often the device is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// How sound is played, from the command line and the settings file
+#[derive(Clone)]
+pub struct AudioConfig {
+    pub device: Option<String>, // Output device by name, None for the default
+    pub filter: AudioFilter,
+}
+
+// The APU's raw stereo output waiting for the cpal stream, which mixes it
+// for the device. Cloned handles can be filled from another thread.
+#[derive(Clone)]
+pub struct AudioOutput {
+    buffer: Arc<Mutex<Vec<f32>>>,
+    speed: Arc<AtomicU32>, // Emulation speed in percent, for the mixer
+}
+
+impl AudioOutput {
+    // Starts playing through the configured device, or the default one
+    pub fn open(config: AudioConfig) -> (AudioOutput, AudioDevice) {
+        let audio = AudioOutput { buffer: Arc::new(Mutex::new(Vec::new())), speed: Arc::new(AtomicU32::new(100)) };
+        let mut device = AudioDevice {
+            requested: config.device,
+            filter: config.filter,
+            buffer: Arc::clone(&audio.buffer),
+            speed: Arc::clone(&audio.speed),
+            stream: None,
+            name: None,
+            lost: Arc::new(AtomicBool::new(false)),
+            last_check: Instant::now(),
+            failing: false,
+        };
+        device.rebuild();
+        (audio, device)
+    }
+
+    pub fn push(&self, samples: &[f32]) {
+        if let Ok(mut buffer) = self.buffer.lock() {
+            let room = (self.capacity() * 2).saturating_sub(buffer.len());
+            buffer.extend_from_slice(&samples[..samples.len().min(room)]);
+        }
+    }
+
+    // Plays the samples faster or slower to match the emulation speed
+    pub fn set_speed(&self, percent: u32) {
+        self.speed.store(percent, Ordering::Relaxed);
+    }
+
+    // In stereo samples at apu::SAMPLE_RATE
+    pub fn queued(&self) -> usize {
+        self.buffer.lock().map(|b| b.len() / 2).unwrap_or(0)
+    }
+
+    // Stereo samples beyond this are dropped
+    pub fn capacity(&self) -> usize {
+        apu::BUFFER_SIZE * 2
+    }
+}
+
+// The cpal stream playing an AudioOutput. Stays on the thread that opened
+// it, since streams can't move between threads on every platform.
+pub struct AudioDevice {
+    requested: Option<String>, // Device name asked for, None follows the default
+    filter: AudioFilter,
+    buffer: Arc<Mutex<Vec<f32>>>,
+    speed: Arc<AtomicU32>,
+    stream: Option<cpal::Stream>,
+    name: Option<String>, // Of the device playing
+    lost: Arc<AtomicBool>, // Set by the stream's error callback
+    last_check: Instant,
+    failing: bool, // Opening a stream failed and was reported; retried quietly
+}
+
+impl AudioDevice {
+    // Rebuilds the stream when the device was lost, when the default device
+    // changed, or when the requested one shows up again. Call regularly.
+    pub fn check(&mut self) {
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+
+        let lost = self.lost.swap(false, Ordering::Relaxed);
+        let host = cpal::default_host();
+        let changed = match self.requested {
+            Some(ref requested) if self.name.as_ref() != Some(requested) => {
+                find_device(&host, requested).is_some()
+            }
+            Some(_) => false,
+            None => host.default_output_device().and_then(|d| d.name().ok()) != self.name,
+        };
+        if lost || changed || self.stream.is_none() {
+            self.rebuild();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.stream = None;
+        self.name = None;
+        self.lost.store(false, Ordering::Relaxed);
+        match self.open_stream() {
+            Ok((stream, name)) => {
+                info!("Audio output: {}", name);
+                self.stream = Some(stream);
+                self.name = Some(name);
+                self.failing = false;
+            }
+            Err(e) => {
+                if !self.failing {
+                    error!("{}", e);
+                }
+                self.failing = true;
+            }
+        }
+    }
+
+    // Opens the requested device, falling back to the default one
+    fn open_stream(&self) -> Result<(cpal::Stream, String), String> {
+        let host = cpal::default_host();
+        let requested = self.requested.as_ref().and_then(|name| {
+            let device = find_device(&host, name);
+            if device.is_none() && !self.failing {
+                warn!("Audio device '{}' not found, using the default", name);
+            }
+            device
+        });
+        let device = requested.or_else(|| host.default_output_device()).ok_or("No audio output device")?;
+        let name = device.name().unwrap_or_default();
+        let shared = Shared {
+            buffer: Arc::clone(&self.buffer),
+            speed: Arc::clone(&self.speed),
+            lost: Arc::clone(&self.lost),
+        };
+        let stream = build_stream(&device, self.filter, shared)
+            .map_err(|e| format!("Failed to open audio device {}: {}", name, e))?;
+        Ok((stream, name))
+    }
+}
+
+// Names of the output devices, for --list-audio-devices
+pub fn device_names() -> Vec<String> {
+    match cpal::default_host().output_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            error!("Failed to list audio devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Output device by name, ignoring case
+fn find_device(host: &cpal::Host, name: &str) -> Option<cpal::Device> {
+    host.output_devices()
+        .ok()?
+        .find(|d| d.name().is_ok_and(|n| n.eq_ignore_ascii_case(name)))
+}
+
+// What the stream callbacks share with the AudioOutput and AudioDevice
+struct Shared {
+    buffer: Arc<Mutex<Vec<f32>>>,
+    speed: Arc<AtomicU32>,
+    lost: Arc<AtomicBool>, // Set by the error callback
+}
+
+fn build_stream(device: &cpal::Device, filter: AudioFilter, shared: Shared) -> Result<cpal::Stream, String> {
+    let config = device.default_output_config().map_err(|e| e.to_string())?;
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => build_typed_stream::<f32>(device, &config.into(), filter, shared),
+        cpal::SampleFormat::I16 => build_typed_stream::<i16>(device, &config.into(), filter, shared),
+        cpal::SampleFormat::U16 => build_typed_stream::<u16>(device, &config.into(), filter, shared),
+        format => return Err(format!("unsupported sample format {}", format)),
+    }
+    .map_err(|e| e.to_string())?;
+    stream.play().map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+fn build_typed_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    filter: AudioFilter,
+    shared: Shared,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut mixer = Mixer::new(config.sample_rate.0, filter);
+    let mut mixed = Vec::new();
+    let mut speed = 100;
+    let Shared { buffer: audio_buffer, speed: shared_speed, lost } = shared;
+
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            // Takes just enough samples for this callback and mixes them
+            // outside the lock, so the emulation never waits on it for long
+            let frames = data.len() / channels;
+            let new_speed = shared_speed.load(Ordering::Relaxed);
+            if new_speed != speed {
+                speed = new_speed;
+                mixer.set_speed(speed);
+            }
+            if mixed.len() < frames {
+                let wanted = mixer.input_len(frames - mixed.len()) * 2;
+                let raw: Vec<f32> = {
+                    let mut buffer = audio_buffer.lock().unwrap();
+                    let len = wanted.min(buffer.len()) & !1;
+                    buffer.drain(..len).collect()
+                };
+                mixer.process(&raw, &mut mixed);
+            }
+
+            let available = mixed.len().min(frames);
+            let samples = mixed.drain(..available).chain(std::iter::repeat(0.0));
+            for (frame, sample) in data.chunks_mut(channels).zip(samples) {
+                for channel in frame.iter_mut() {
+                    *channel = T::from_sample(sample);
+                }
+            }
+        },
+        move |err| {
+            // Rebuilt by the next AudioDevice::check()
+            error!("Audio stream error: {}", err);
+            lost.store(true, Ordering::Relaxed);
+        },
+        None,
+    )
+}
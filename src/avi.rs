@@ -0,0 +1,232 @@
+// Minimal uncompressed AVI writer used for video recording: 24-bit RGB
+// frames plus an optional 16-bit stereo PCM audio stream
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+// AVI 1.0 files are limited to 1 GB; recording stops before that
+const MAX_MOVI_BYTES: u32 = 1_000_000_000;
+
+pub struct AviWriter {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    fps_num: u32,
+    fps_den: u32,
+    sample_rate: u32,
+    has_audio: bool,
+    frames: u32,
+    audio_frames: u64, // Stereo samples written
+    movi_bytes: u32,   // Size of the movi list contents after the 'movi' tag
+    index: Vec<u8>,    // idx1 entries
+    // Offsets of the header fields patched in finish()
+    total_frames_pos: u64,
+    video_length_pos: u64,
+    audio_length_pos: u64,
+    movi_size_pos: u64,
+}
+
+impl AviWriter {
+    // `fps_num / fps_den` is the frame rate; `sample_rate` is unused without audio
+    pub fn create(path: &str, width: usize, height: usize, fps_num: u32, fps_den: u32, sample_rate: u32, has_audio: bool) -> std::io::Result<Self> {
+        let frame_bytes = (width * height * 3) as u32;
+        let streams = if has_audio { 2 } else { 1 };
+
+        let mut h = Vec::new();
+        h.extend_from_slice(b"RIFF");
+        h.extend_from_slice(&0u32.to_le_bytes()); // Patched in finish()
+        h.extend_from_slice(b"AVI ");
+
+        // Header list - its size is known up front
+        let strl_video = 4 + (8 + 56) + (8 + 40);
+        let strl_audio = 4 + (8 + 56) + (8 + 16);
+        let hdrl_size = 4 + (8 + 56) + (8 + strl_video) + if has_audio { 8 + strl_audio } else { 0 };
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&(hdrl_size as u32).to_le_bytes());
+        h.extend_from_slice(b"hdrl");
+
+        // Main header
+        h.extend_from_slice(b"avih");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        h.extend_from_slice(&((1_000_000u64 * fps_den as u64 / fps_num as u64) as u32).to_le_bytes());
+        let max_bytes_per_sec = frame_bytes as u64 * fps_num as u64 / fps_den as u64 + sample_rate as u64 * 4;
+        h.extend_from_slice(&(max_bytes_per_sec as u32).to_le_bytes());
+        h.extend_from_slice(&0u32.to_le_bytes()); // Padding granularity
+        h.extend_from_slice(&0x10u32.to_le_bytes()); // AVIF_HASINDEX
+        let total_frames_pos = h.len() as u64;
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h.extend_from_slice(&0u32.to_le_bytes()); // Initial frames
+        h.extend_from_slice(&(streams as u32).to_le_bytes());
+        h.extend_from_slice(&frame_bytes.to_le_bytes()); // Suggested buffer size
+        h.extend_from_slice(&(width as u32).to_le_bytes());
+        h.extend_from_slice(&(height as u32).to_le_bytes());
+        h.extend_from_slice(&[0; 16]);
+
+        // Video stream
+        h.extend_from_slice(b"LIST");
+        h.extend_from_slice(&(strl_video as u32).to_le_bytes());
+        h.extend_from_slice(b"strl");
+        h.extend_from_slice(b"strh");
+        h.extend_from_slice(&56u32.to_le_bytes());
+        h.extend_from_slice(b"vids");
+        h.extend_from_slice(b"DIB ");
+        h.extend_from_slice(&[0; 12]); // Flags, priority, language, initial frames
+        h.extend_from_slice(&fps_den.to_le_bytes()); // Scale
+        h.extend_from_slice(&fps_num.to_le_bytes()); // Rate
+        h.extend_from_slice(&0u32.to_le_bytes()); // Start
+        let video_length_pos = h.len() as u64;
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h.extend_from_slice(&frame_bytes.to_le_bytes());
+        h.extend_from_slice(&u32::MAX.to_le_bytes()); // Default quality
+        h.extend_from_slice(&0u32.to_le_bytes()); // Sample size
+        h.extend_from_slice(&[0; 4]);
+        h.extend_from_slice(&(width as u16).to_le_bytes());
+        h.extend_from_slice(&(height as u16).to_le_bytes());
+
+        // BITMAPINFOHEADER, bottom-up rows
+        h.extend_from_slice(b"strf");
+        h.extend_from_slice(&40u32.to_le_bytes());
+        h.extend_from_slice(&40u32.to_le_bytes());
+        h.extend_from_slice(&(width as i32).to_le_bytes());
+        h.extend_from_slice(&(height as i32).to_le_bytes());
+        h.extend_from_slice(&1u16.to_le_bytes()); // Planes
+        h.extend_from_slice(&24u16.to_le_bytes()); // Bits per pixel
+        h.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB
+        h.extend_from_slice(&frame_bytes.to_le_bytes());
+        h.extend_from_slice(&[0; 16]);
+
+        // Audio stream
+        let mut audio_length_pos = 0;
+        if has_audio {
+            h.extend_from_slice(b"LIST");
+            h.extend_from_slice(&(strl_audio as u32).to_le_bytes());
+            h.extend_from_slice(b"strl");
+            h.extend_from_slice(b"strh");
+            h.extend_from_slice(&56u32.to_le_bytes());
+            h.extend_from_slice(b"auds");
+            h.extend_from_slice(&[0; 16]); // Handler, flags, priority, language, initial frames
+            h.extend_from_slice(&4u32.to_le_bytes()); // Scale: block align
+            h.extend_from_slice(&(sample_rate * 4).to_le_bytes()); // Rate: bytes per second
+            h.extend_from_slice(&0u32.to_le_bytes()); // Start
+            audio_length_pos = h.len() as u64;
+            h.extend_from_slice(&0u32.to_le_bytes());
+            h.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+            h.extend_from_slice(&u32::MAX.to_le_bytes());
+            h.extend_from_slice(&4u32.to_le_bytes()); // Sample size
+            h.extend_from_slice(&[0; 8]);
+
+            // WAVEFORMATEX
+            h.extend_from_slice(b"strf");
+            h.extend_from_slice(&16u32.to_le_bytes());
+            h.extend_from_slice(&1u16.to_le_bytes()); // PCM
+            h.extend_from_slice(&2u16.to_le_bytes());
+            h.extend_from_slice(&sample_rate.to_le_bytes());
+            h.extend_from_slice(&(sample_rate * 4).to_le_bytes());
+            h.extend_from_slice(&4u16.to_le_bytes());
+            h.extend_from_slice(&16u16.to_le_bytes());
+        }
+
+        h.extend_from_slice(b"LIST");
+        let movi_size_pos = h.len() as u64;
+        h.extend_from_slice(&0u32.to_le_bytes());
+        h.extend_from_slice(b"movi");
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&h)?;
+
+        Ok(AviWriter {
+            file,
+            width,
+            height,
+            fps_num,
+            fps_den,
+            sample_rate,
+            has_audio,
+            frames: 0,
+            audio_frames: 0,
+            movi_bytes: 4,
+            index: Vec::new(),
+            total_frames_pos,
+            video_length_pos,
+            audio_length_pos,
+            movi_size_pos,
+        })
+    }
+
+    // True once the file has reached the AVI 1.0 size limit
+    pub fn is_full(&self) -> bool {
+        self.movi_bytes >= MAX_MOVI_BYTES
+    }
+
+    // Writes one 0RGB frame and the stereo samples (-1.0..1.0, interleaved)
+    // produced while it was emulated
+    pub fn write_frame(&mut self, pixels: &[u32], samples: &[f32]) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(self.width * self.height * 3);
+        for row in pixels.chunks(self.width).take(self.height).rev() {
+            for &pixel in row {
+                data.extend_from_slice(&[pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8]);
+            }
+        }
+        self.write_chunk(b"00db", &data)?;
+        self.frames += 1;
+
+        if self.has_audio {
+            // The APU produces no samples while it is off; pad with silence so
+            // the audio stays in sync with the video
+            let expected = self.frames as u64 * self.sample_rate as u64 * self.fps_den as u64 / self.fps_num as u64;
+            let available = (samples.len() / 2) as u64;
+            let silence = expected.saturating_sub(self.audio_frames + available);
+
+            let mut data = Vec::with_capacity((available + silence) as usize * 4);
+            for &sample in samples {
+                let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            data.resize(data.len() + silence as usize * 4, 0);
+            self.audio_frames += available + silence;
+            self.write_chunk(b"01wb", &data)?;
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, id: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+        // Index offsets are relative to the 'movi' tag
+        self.index.extend_from_slice(id);
+        self.index.extend_from_slice(&0x10u32.to_le_bytes()); // AVIIF_KEYFRAME
+        self.index.extend_from_slice(&self.movi_bytes.to_le_bytes());
+        self.index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+        self.file.write_all(id)?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(data)?;
+        let mut size = 8 + data.len() as u32;
+        if !data.len().is_multiple_of(2) {
+            self.file.write_all(&[0])?; // Chunks are word aligned
+            size += 1;
+        }
+        self.movi_bytes += size;
+        Ok(())
+    }
+
+    // Writes the index, patches the header sizes and flushes the file
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.write_all(b"idx1")?;
+        self.file.write_all(&(self.index.len() as u32).to_le_bytes())?;
+        self.file.write_all(&self.index)?;
+
+        let riff_size = self.file.stream_position()? as u32 - 8;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&riff_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(self.total_frames_pos))?;
+        self.file.write_all(&self.frames.to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(self.video_length_pos))?;
+        self.file.write_all(&self.frames.to_le_bytes())?;
+        if self.has_audio {
+            self.file.seek(SeekFrom::Start(self.audio_length_pos))?;
+            self.file.write_all(&(self.audio_frames as u32).to_le_bytes())?;
+        }
+        self.file.seek(SeekFrom::Start(self.movi_size_pos))?;
+        self.file.write_all(&self.movi_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
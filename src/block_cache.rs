@@ -0,0 +1,171 @@
+// Cached interpreter for ROM code: instructions are decoded once into basic
+// blocks (runs up to the next jump, call, return, RST, HALT or STOP) with
+// their operand bytes, so running them again skips the opcode and operand
+// fetches through the memory map. The CPU still executes every instruction
+// with the same M-cycle timing.
+//
+// Blocks are keyed by ROM file offset, i.e. by (bank, address): a bank switch
+// maps other offsets in and never invalidates anything, and the next
+// instruction is looked up again whenever its offset isn't the one the block
+// expects (after a jump, an interrupt or a bank switch mid-block). Code in
+// writable memory (RAM, HRAM, VRAM) is never cached and is fetched as usual,
+// as is all code while fetches are observed (see Mmu::code_cacheable).
+
+use std::collections::HashMap;
+
+use crate::mmu::Mmu;
+
+const MAX_BLOCK_LEN: usize = 64;
+// Entries of the direct-mapped table of recently entered blocks in front of
+// the map of all blocks
+const RECENT_SIZE: usize = 1024;
+
+// Bytes of each instruction, by opcode
+#[rustfmt::skip]
+const LENGTHS: [u8; 256] = [
+    1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20
+    2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xA0
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xB0
+    1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xC0
+    1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // 0xD0
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xE0
+    2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xF0
+];
+
+// Whether the instruction after `opcode` may not be the next one in memory
+fn ends_block(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 // STOP, JR
+            | 0x76 // HALT
+            | 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9 // RET, RETI
+            | 0xC2 | 0xC3 | 0xCA | 0xD2 | 0xDA | 0xE9 // JP
+            | 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC // CALL
+            | 0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF // RST
+            | 0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD // Illegal
+    )
+}
+
+#[derive(Clone, Copy)]
+pub struct Instruction {
+    pub address: u16,
+    pub offset: usize, // In the ROM file
+    pub opcode: u8,
+    pub operands: [u8; 2], // Immediate bytes, or the opcode after 0xCB
+    pub operand_len: u8,
+}
+
+pub struct BlockCache {
+    blocks: Vec<Vec<Instruction>>,
+    starts: HashMap<usize, usize>,   // ROM offset -> block
+    recent: Vec<(usize, usize)>,     // ROM offset and block, by offset % RECENT_SIZE
+    current: Option<(usize, usize)>, // Block and index of the instruction expected next
+    mapping: u32,                    // Cartridge::mapping_changes() when `current` was set
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        BlockCache {
+            blocks: Vec::new(),
+            starts: HashMap::new(),
+            recent: vec![(usize::MAX, 0); RECENT_SIZE],
+            current: None,
+            mapping: 0,
+        }
+    }
+}
+
+impl BlockCache {
+    // The instruction at `address`, decoding a new block starting there if
+    // no block does. None if it has to be fetched as usual: it isn't in ROM,
+    // fetches are observed or it runs past its ROM bank.
+    pub fn fetch(&mut self, mmu: &Mmu, address: u16) -> Option<Instruction> {
+        if address >= 0x8000 || !mmu.code_cacheable() {
+            self.current = None;
+            return None;
+        }
+
+        // Next instruction of the block, as long as the banking didn't change
+        let mapping = mmu.cartridge.mapping_changes();
+        if let Some((block, index)) = self.current {
+            if let Some(&instruction) = self.blocks[block].get(index) {
+                if instruction.address == address && mapping == self.mapping {
+                    self.current = Some((block, index + 1));
+                    return Some(instruction);
+                }
+            }
+        }
+
+        let Some(offset) = mmu.cartridge.rom_offset(address).filter(|&o| o < mmu.cartridge.rom_len()) else {
+            self.current = None;
+            return None;
+        };
+
+        let slot = offset % RECENT_SIZE;
+        let block = if self.recent[slot].0 == offset {
+            self.recent[slot].1
+        } else if let Some(&block) = self.starts.get(&offset) {
+            block
+        } else {
+            let instructions = decode(mmu, address, offset);
+            if instructions.is_empty() {
+                self.current = None;
+                return None;
+            }
+            self.blocks.push(instructions);
+            self.starts.insert(offset, self.blocks.len() - 1);
+            self.blocks.len() - 1
+        };
+        self.recent[slot] = (offset, block);
+        self.current = Some((block, 1));
+        self.mapping = mapping;
+        Some(self.blocks[block][0])
+    }
+
+    // Number of decoded blocks
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+// Decodes the block at `address` with the current banking. Blocks stop at the
+// end of the ROM bank area (0x3FFF or 0x7FFF), where the next address may map
+// to another offset, and at the end of the ROM file.
+fn decode(mmu: &Mmu, address: u16, offset: usize) -> Vec<Instruction> {
+    let region_end = if address < 0x4000 { 0x4000 } else { 0x8000 };
+    let rom_len = mmu.cartridge.rom_len();
+    let mut instructions = Vec::new();
+    let (mut address, mut offset) = (address as usize, offset);
+    while instructions.len() < MAX_BLOCK_LEN {
+        let opcode = mmu.cartridge.read_rom(address as u16);
+        let length = LENGTHS[opcode as usize] as usize;
+        if address + length > region_end || offset + length > rom_len {
+            break;
+        }
+        let mut operands = [0; 2];
+        for (i, operand) in operands.iter_mut().enumerate().take(length - 1) {
+            *operand = mmu.cartridge.read_rom((address + 1 + i) as u16);
+        }
+        let operand_len = length as u8 - 1;
+        instructions.push(Instruction { address: address as u16, offset, opcode, operands, operand_len });
+        if ends_block(opcode) {
+            break;
+        }
+        address += length;
+        offset += length;
+    }
+    instructions
+}
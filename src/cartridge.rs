@@ -1,5 +1,60 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::gamedb;
+use crate::mapper::{HuC1, HuC3, LegacyState, Mapper, Mbc1, Mbc2, Mbc3, Mbc5, RomOnly, Tama5, WisdomTree};
+use crate::patch;
+use crate::savestate::{StateReader, StateWriter};
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+
+pub use crate::mapper::RtcFormat;
+
+// Nintendo logo at 0x104-0x133, checked by the boot ROM
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// Battery RAM is flushed this long after the first unsaved change, so games
+// writing it every frame are saved too
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(std::io::Error),
+    TooSmall(usize),  // File is smaller than the cartridge header
+    InvalidLogo,      // Header doesn't contain the Nintendo logo
+    Archive(String),  // Zip archive couldn't be read or contains no ROM
+    Patch(String),    // IPS/BPS patch couldn't be applied
+}
+
+impl std::fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "{}", e),
+            CartridgeError::TooSmall(len) => {
+                write!(f, "file is too small to be a Game Boy ROM ({} bytes)", len)
+            }
+            CartridgeError::InvalidLogo => {
+                write!(f, "not a Game Boy ROM (missing Nintendo logo in header)")
+            }
+            CartridgeError::Archive(msg) => write!(f, "archive error: {}", msg),
+            CartridgeError::Patch(msg) => write!(f, "patch error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<std::io::Error> for CartridgeError {
+    fn from(e: std::io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum CartridgeType {
@@ -8,44 +63,109 @@ enum CartridgeType {
     Mbc2,
     Mbc3,
     Mbc5,
-}
-
-#[derive(Clone, Copy)]
-enum BankMode {
-    Rom, // 16Mbit ROM/8KByte RAM mode
-    Ram, // 4Mbit ROM/32KByte RAM mode
+    HuC1,
+    HuC3,
+    Tama5,
+    WisdomTree,
 }
 
 pub struct Cartridge {
     rom: Vec<u8>,
-    ram: Vec<u8>,
-    cart_type: CartridgeType,
-    bank: u8,           // Combined bank register
-    bank_mode: BankMode,
-    ram_enabled: bool,
-    // MBC3 RTC registers
-    rtc_register: u8,
-    rtc_latched: bool,
-    // MBC5 registers
-    rom_bank_low: u8,   // MBC5: lower 8 bits of ROM bank
-    rom_bank_high: u8,  // MBC5: 9th bit of ROM bank
-    ram_bank: u8,       // MBC5: RAM bank (4 bits)
+    mapper: Box<dyn Mapper>,
     // Save file support
-    save_path: Option<String>,
+    save_path: Option<PathBuf>,
     #[allow(dead_code)]
     has_battery: bool,
+    has_rtc: bool,
+    force_dmg: bool, // Runs in DMG mode whatever the header says (game database)
+    dirty_since: Option<Instant>, // First battery RAM change since the last save
+    mapping_changes: u32, // Bumped whenever the ROM banking may have changed
 }
 
 impl Cartridge {
-    pub fn load(path: &str) -> Result<Self, std::io::Error> {
+    // Reads the ROM image, transparently extracting .zip and .gz archives
+    pub fn read_rom_file(path: &str) -> Result<Vec<u8>, CartridgeError> {
+        let lower = path.to_lowercase();
         let mut file = File::open(path)?;
         let mut rom = Vec::new();
-        file.read_to_end(&mut rom)?;
 
-        println!("Loaded ROM: {} bytes", rom.len());
+        if lower.ends_with(".zip") {
+            let mut archive = zip::ZipArchive::new(file)
+                .map_err(|e| CartridgeError::Archive(e.to_string()))?;
+
+            // Use the first .gb/.gbc entry
+            for i in 0..archive.len() {
+                let mut entry = archive
+                    .by_index(i)
+                    .map_err(|e| CartridgeError::Archive(e.to_string()))?;
+                let name = entry.name().to_lowercase();
+                if name.ends_with(".gb") || name.ends_with(".gbc") {
+                    info!("Extracting {} from archive", entry.name());
+                    entry.read_to_end(&mut rom)?;
+                    return Ok(rom);
+                }
+            }
+            return Err(CartridgeError::Archive("no .gb/.gbc file found in archive".to_string()));
+        }
+
+        if lower.ends_with(".gz") {
+            GzDecoder::new(file).read_to_end(&mut rom)?;
+        } else {
+            file.read_to_end(&mut rom)?;
+        }
+        Ok(rom)
+    }
+
+    // Save file name: ROM name without ROM/archive extensions, plus the global
+    // checksum so different ROMs with the same file name don't share a save
+    fn save_file_name(rom_path: &Path, rom: &[u8]) -> String {
+        let mut stem = rom_path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| "rom".to_string());
+
+        // "game.gb.gz" -> "game"
+        let lower = stem.to_lowercase();
+        if lower.ends_with(".gb") || lower.ends_with(".gbc") {
+            stem.truncate(stem.rfind('.').unwrap());
+        }
+
+        let checksum = ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16;
+        format!("{}-{:04X}.sav", stem, checksum)
+    }
+
+    // Where older versions kept the save: next to the ROM, with the archive
+    // and ROM extensions replaced by .sav ("game.gb.gz" -> "game.sav")
+    fn legacy_save_path(rom_path: &Path) -> PathBuf {
+        let mut name = rom_path
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        for extensions in [[".zip", ".gz"], [".gbc", ".gb"]] {
+            if let Some(ext) = extensions.iter().find(|ext| name.to_lowercase().ends_with(*ext)) {
+                name.truncate(name.len() - ext.len());
+            }
+        }
+        rom_path.with_file_name(format!("{}.sav", name))
+    }
+
+    // Creates a cartridge from a ROM image in memory, without a save file and
+    // with the RTC following emulated time, so no file system or wall clock
+    // is needed (web frontend, headless runs)
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        debug!("Loaded ROM: {} bytes", rom.len());
+
+        Self::validate_header(&rom)?;
+
+        // Known header mistakes and other quirks of this game
+        let quirks = gamedb::lookup(&rom);
+        if quirks != gamedb::Quirks::default() {
+            info!("Game database: {:?}", quirks);
+        }
 
         // Determine cartridge type
-        let cart_type_byte = if rom.len() >= 0x148 { rom[0x147] } else { 0 };
+        let cart_type_byte = quirks.cart_type.unwrap_or(if rom.len() >= 0x148 { rom[0x147] } else { 0 });
+        let has_rtc = matches!(cart_type_byte, 0x0F | 0x10 | 0xFD | 0xFE);
         let (cart_type, has_battery) = match cart_type_byte {
             0x00 => (CartridgeType::RomOnly, false),
             0x01 => (CartridgeType::Mbc1, false),
@@ -64,307 +184,378 @@ impl Cartridge {
             0x1C => (CartridgeType::Mbc5, false),
             0x1D => (CartridgeType::Mbc5, false),
             0x1E => (CartridgeType::Mbc5, true),
+            0xFD => (CartridgeType::Tama5, true),
+            0xFE => (CartridgeType::HuC3, true),
+            0xFF => (CartridgeType::HuC1, true),
             _ => {
-                println!("Warning: Unsupported cartridge type 0x{:02X}, defaulting to MBC1", cart_type_byte);
+                warn!("Unsupported cartridge type 0x{:02X}, defaulting to MBC1", cart_type_byte);
                 (CartridgeType::Mbc1, false)
             }
         };
 
+        let cart_type = if quirks.wisdom_tree { CartridgeType::WisdomTree } else { cart_type };
+
         // Print cartridge header info
         if rom.len() >= 0x150 {
             let title_bytes = &rom[0x134..0x144];
             let title = String::from_utf8_lossy(title_bytes).trim_matches('\0').to_string();
-            println!("Title: {}", title);
-            println!("Cartridge type: 0x{:02X} ({:?})", cart_type_byte, cart_type);
+            info!("Title: {}", title);
+            info!("Cartridge type: 0x{:02X} ({:?})", cart_type_byte, cart_type);
 
             let rom_size = rom[0x148];
-            println!("ROM size: 0x{:02X}", rom_size);
+            info!("ROM size: 0x{:02X}", rom_size);
         }
 
         // Initialize RAM based on cartridge type and RAM size byte
         let ram_size_byte = if rom.len() >= 0x149 { rom[0x149] } else { 0 };
-        let ram_size = match ram_size_byte {
-            0x00 => 0,
-            0x01 => 0x800,      // 2KB (unused)
-            0x02 => 0x2000,     // 8KB
-            0x03 => 0x8000,     // 32KB (4 banks)
-            0x04 => 0x20000,    // 128KB (16 banks)
-            0x05 => 0x10000,    // 64KB (8 banks)
-            _ => {
-                if cart_type == CartridgeType::Mbc2 {
-                    512 // MBC2 has built-in 512x4 bits RAM
-                } else {
-                    0
-                }
-            }
-        };
-        let mut ram = vec![0; ram_size];
-
-        // Generate save file path
-        let save_path = if has_battery && ram_size > 0 {
-            let save_file = if path.ends_with(".gbc") {
-                path.replace(".gbc", ".sav")
-            } else if path.ends_with(".gb") {
-                path.replace(".gb", ".sav")
-            } else {
-                format!("{}.sav", path)
-            };
-            Some(save_file)
-        } else {
-            None
+        // MBC2 has built-in 512x4 bits RAM, and its headers give no RAM size
+        let ram_size = quirks.ram_size.unwrap_or(match ram_size_byte {
+            _ if cart_type == CartridgeType::Mbc2 => 512,
+            0x01 => 0x800,   // 2KB (unused)
+            0x02 => 0x2000,  // 8KB
+            0x03 => 0x8000,  // 32KB (4 banks)
+            0x04 => 0x20000, // 128KB (16 banks)
+            0x05 => 0x10000, // 64KB (8 banks)
+            _ => 0,
+        });
+
+        let multicart = quirks.mbc1_multicart && cart_type == CartridgeType::Mbc1;
+        let mapper: Box<dyn Mapper> = match cart_type {
+            CartridgeType::RomOnly => Box::new(RomOnly),
+            CartridgeType::Mbc1 => Box::new(Mbc1::new(ram_size, multicart)),
+            CartridgeType::Mbc2 => Box::new(Mbc2::new(ram_size)),
+            CartridgeType::Mbc3 => Box::new(Mbc3::new(ram_size, has_rtc)),
+            CartridgeType::Mbc5 => Box::new(Mbc5::new(ram_size)),
+            CartridgeType::HuC1 => Box::new(HuC1::new(ram_size)),
+            CartridgeType::HuC3 => Box::new(HuC3::new(ram_size)),
+            CartridgeType::Tama5 => Box::new(Tama5::new()),
+            CartridgeType::WisdomTree => Box::new(WisdomTree::default()),
         };
+        let mut cartridge = Self::new(rom, mapper, has_battery);
+        cartridge.has_rtc = has_rtc;
+        cartridge.force_dmg = quirks.force_dmg;
+        Ok(cartridge)
+    }
 
-        // Load saved RAM if exists
-        if let Some(ref save_file) = save_path {
-            if let Ok(mut file) = File::open(save_file) {
-                let _ = file.read_to_end(&mut ram);
-                println!("Loaded save file: {}", save_file);
-            }
-        }
+    // A cartridge without header checks: `program` mapped as a flat ROM-only
+    // cartridge at 0x0000, padded with zeros (NOP) to 32KB. For tests and
+    // fuzzers running synthetic instruction sequences.
+    pub fn from_program(program: &[u8]) -> Self {
+        assert!(program.len() <= 0x8000, "a ROM-only cartridge holds at most 32KB");
+        let mut rom = program.to_vec();
+        rom.resize(0x8000, 0);
+        Self::new(rom, Box::new(RomOnly), false)
+    }
 
-        Ok(Cartridge {
+    fn new(rom: Vec<u8>, mapper: Box<dyn Mapper>, has_battery: bool) -> Self {
+        Cartridge {
             rom,
-            ram,
-            cart_type,
-            bank: 0x01, // Start with bank 1
-            bank_mode: BankMode::Rom,
-            ram_enabled: false,
-            rtc_register: 0,
-            rtc_latched: false,
-            rom_bank_low: 0x01,
-            rom_bank_high: 0x00,
-            ram_bank: 0x00,
-            save_path,
+            mapper,
+            save_path: None,
             has_battery,
-        })
+            has_rtc: false,
+            force_dmg: false,
+            dirty_since: None,
+            mapping_changes: 0,
+        }
     }
 
-    pub fn save(&self) {
-        if let Some(ref save_file) = self.save_path {
-            if let Ok(mut file) = File::create(save_file) {
-                use std::io::Write;
-                let _ = file.write_all(&self.ram);
-                println!("Saved to: {}", save_file);
-            }
-        }
+    pub fn load(path: &str, save_dir: &Path) -> Result<Self, CartridgeError> {
+        Self::load_patched(path, save_dir, None)
     }
 
-    fn rom_bank(&self) -> usize {
-        if self.cart_type == CartridgeType::Mbc5 {
-            // MBC5 uses 9-bit ROM bank (0-511)
-            let bank = ((self.rom_bank_high as usize & 0x01) << 8) | (self.rom_bank_low as usize);
-            return bank;
+    // Loads a ROM with a patch applied in memory, leaving the ROM file as it
+    // is: the patch given, or else a .bps or .ips file with the ROM's name
+    // next to it
+    pub fn load_patched(path: &str, save_dir: &Path, patch: Option<&Path>) -> Result<Self, CartridgeError> {
+        let rom_path = Path::new(path);
+        let mut rom = Self::read_rom_file(path)?;
+        let patch = patch
+            .map(Path::to_path_buf)
+            .or_else(|| ["bps", "ips"].iter().map(|ext| rom_path.with_extension(ext)).find(|p| p.exists()));
+        if let Some(patch_path) = patch {
+            let data = std::fs::read(&patch_path)?;
+            rom = patch::apply(&data, &rom)
+                .map_err(|e| CartridgeError::Patch(format!("{}: {}", patch_path.display(), e)))?;
+            info!("Applied patch {}", patch_path.display());
         }
+        let mut cartridge = Self::from_rom(rom)?;
+        cartridge.mapper.use_wall_clock();
 
-        let n = match self.bank_mode {
-            BankMode::Rom => self.bank & 0x7F, // Use all 7 bits
-            BankMode::Ram => self.bank & 0x1F, // Use only lower 5 bits
-        };
-        let bank = n as usize;
-        if bank == 0 { 1 } else { bank } // Bank 0 is mapped to bank 1
-    }
+        // Generate save file path (named after the archive for compressed ROMs)
+        if cartridge.battery_backed() {
+            cartridge.save_path = Some(save_dir.join(Self::save_file_name(rom_path, &cartridge.rom)));
+        }
+
+        // Saves from older versions live next to the ROM as <name>.sav
+        let legacy_save = Self::legacy_save_path(rom_path);
 
-    fn ram_bank(&self) -> usize {
-        if self.cart_type == CartridgeType::Mbc5 {
-            return (self.ram_bank & 0x0F) as usize;
+        // Load saved RAM if exists, with the clock the mapper saves after it
+        if let Some(ref save_file) = cartridge.save_path {
+            let save_file = if !save_file.exists() && legacy_save.exists() {
+                &legacy_save
+            } else {
+                save_file
+            };
+            if let Ok(mut file) = File::open(save_file) {
+                let mut data = Vec::new();
+                let _ = file.read_to_end(&mut data);
+                cartridge.mapper.load_save_data(&data);
+                info!("Loaded save file: {}", save_file.display());
+            }
         }
 
-        let n = match self.bank_mode {
-            BankMode::Rom => 0x00,                    // Always bank 0
-            BankMode::Ram => (self.bank & 0x60) >> 5, // Upper 2 bits
-        };
-        n as usize
+        Ok(cartridge)
     }
 
-    pub fn read_rom(&self, address: u16) -> u8 {
-        let addr = match address {
-            0x0000..=0x3FFF => {
-                // Bank 0 (or high ROM bank in RAM mode)
-                let bank = match self.bank_mode {
-                    BankMode::Rom => 0,
-                    BankMode::Ram => ((self.bank & 0x60) >> 5) as usize,
-                };
-                (bank * 0x4000) + (address as usize)
-            }
-            0x4000..=0x7FFF => {
-                // Switchable ROM bank
-                let bank = self.rom_bank();
-                (bank * 0x4000) + ((address - 0x4000) as usize)
-            }
-            _ => return 0xFF,
-        };
+    // Battery RAM or a clock that the game keeps between sessions
+    fn battery_backed(&self) -> bool {
+        self.has_battery && (!self.mapper.ram().is_empty() || self.has_rtc)
+    }
 
-        if addr < self.rom.len() {
-            self.rom[addr]
-        } else {
-            0xFF
+    // Writes the battery RAM (and clock) to `path` as a save file, for use
+    // elsewhere: a flash cart or another emulator
+    pub fn export_save(&mut self, path: &Path) -> std::io::Result<()> {
+        if !self.battery_backed() {
+            return Err(std::io::Error::new(ErrorKind::Unsupported, "the cartridge has no battery save"));
         }
+        std::fs::write(path, self.mapper.save_data())
     }
 
-    pub fn read_ram(&self, address: u16) -> u8 {
-        if !self.ram_enabled {
-            return 0xFF;
+    // Replaces the battery RAM (and clock) with a save file from elsewhere.
+    // The game's own save file is then updated as after a RAM write.
+    pub fn import_save(&mut self, path: &Path) -> std::io::Result<()> {
+        if !self.battery_backed() {
+            return Err(std::io::Error::new(ErrorKind::Unsupported, "the cartridge has no battery save"));
         }
+        let data = std::fs::read(path)?;
+        let ram_len = self.mapper.ram().len();
+        if data.len() < ram_len {
+            warn!("{} holds {} bytes, less than the {} bytes of cartridge RAM", path.display(), data.len(), ram_len);
+        }
+        self.mapper.load_save_data(&data);
+        self.mark_dirty();
+        Ok(())
+    }
 
-        // MBC2 has special RAM handling
-        if self.cart_type == CartridgeType::Mbc2 {
-            let addr = (address - 0xA000) as usize & 0x1FF; // Only 512 addresses
-            if addr < self.ram.len() {
-                return self.ram[addr] & 0x0F; // Only lower 4 bits
-            } else {
-                return 0xFF;
-            }
+    // Layout of the MBC3 clock in the save file
+    pub fn set_rtc_format(&mut self, format: RtcFormat) {
+        self.mapper.set_rtc_format(format);
+    }
+
+    // Rejects files that clearly aren't ROMs and warns about bad checksums
+    fn validate_header(rom: &[u8]) -> Result<(), CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(CartridgeError::TooSmall(rom.len()));
         }
 
-        // MBC3 RTC register read
-        if self.cart_type == CartridgeType::Mbc3 && self.rtc_register >= 0x08 && self.rtc_register <= 0x0C {
-            // Return dummy RTC values (not implemented)
-            return 0;
+        // The CGB boot ROM only checks the first half of the logo
+        if rom[0x104..0x11C] != NINTENDO_LOGO[..24] {
+            return Err(CartridgeError::InvalidLogo);
         }
 
-        let bank = self.ram_bank();
-        let addr = (bank * 0x2000) + ((address - 0xA000) as usize);
+        let header_checksum = rom[0x134..=0x14C]
+            .iter()
+            .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+        if header_checksum != rom[0x14D] {
+            warn!(
+                "Header checksum mismatch (expected 0x{:02X}, got 0x{:02X}), ROM may be corrupt",
+                rom[0x14D], header_checksum
+            );
+        }
 
-        if addr < self.ram.len() {
-            self.ram[addr]
-        } else {
-            0xFF
+        let global_checksum = rom
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+        let expected = ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16;
+        if global_checksum != expected {
+            warn!(
+                "Global checksum mismatch (expected 0x{:04X}, got 0x{:04X})",
+                expected, global_checksum
+            );
         }
+
+        Ok(())
     }
 
-    pub fn write_ram(&mut self, address: u16, value: u8) {
-        if !self.ram_enabled {
-            return;
+    // Global checksum from the header (0x14E-0x14F)
+    pub fn global_checksum(&self) -> u16 {
+        ((self.rom[0x14E] as u16) << 8) | self.rom[0x14F] as u16
+    }
+
+    // Starts from empty cartridge RAM and stops saving it, so that movies
+    // always run from the same power-on state
+    pub fn detach_save(&mut self) {
+        self.mapper.ram_mut().fill(0);
+        self.save_path = None;
+        self.dirty_since = None;
+    }
+
+    // Title from the header (0x134-0x143), up to the first byte that isn't
+    // printable ASCII: zero padding or, in CGB games, the CGB flag
+    pub fn title(&self) -> String {
+        self.rom[0x134..0x144]
+            .iter()
+            .take_while(|&&b| b.is_ascii_graphic() || b == b' ')
+            .map(|&b| b as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string()
+    }
+
+    // The header up to the licensee code (0x14B), or less of a short program
+    pub fn header(&self) -> &[u8] {
+        &self.rom[..self.rom.len().min(0x14C)]
+    }
+
+    // CGB flag (0x143): 0x80 = CGB enhanced, 0xC0 = CGB only
+    pub fn supports_cgb(&self) -> bool {
+        !self.force_dmg && self.rom.len() > 0x143 && (self.rom[0x143] & 0x80) != 0
+    }
+
+    // Only the first change is timed, which keeps the clock out of every RAM write
+    fn mark_dirty(&mut self) {
+        if self.save_path.is_some() && self.dirty_since.is_none() {
+            self.dirty_since = Some(Instant::now());
         }
+    }
 
-        // MBC2 has special RAM handling
-        if self.cart_type == CartridgeType::Mbc2 {
-            let addr = (address - 0xA000) as usize & 0x1FF; // Only 512 addresses
-            if addr < self.ram.len() {
-                self.ram[addr] = value & 0x0F; // Only lower 4 bits
+    // Saves only if battery RAM changed, once the first change is a moment old
+    pub fn flush_if_dirty(&mut self) {
+        if let Some(first_change) = self.dirty_since {
+            if first_change.elapsed() >= SAVE_DEBOUNCE {
+                self.save();
             }
-            return;
         }
+    }
 
-        // MBC3 RTC register write (not implemented, just ignore)
-        if self.cart_type == CartridgeType::Mbc3 && self.rtc_register >= 0x08 && self.rtc_register <= 0x0C {
+    pub fn save(&mut self) {
+        let data = self.mapper.save_data();
+        let Some(ref save_file) = self.save_path else {
             return;
+        };
+
+        // Write to a temporary file first so a crash can't leave a truncated save
+        let tmp_file = save_file.with_extension("sav.tmp");
+        let result = save_file
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&tmp_file, &data))
+            .and_then(|_| std::fs::rename(&tmp_file, save_file));
+
+        match result {
+            Ok(()) => {
+                self.dirty_since = None;
+                debug!("Saved to: {}", save_file.display());
+            }
+            Err(e) => error!("Failed to save {}: {}", save_file.display(), e),
         }
+    }
+
+    // ROM bank mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> usize {
+        self.mapper.rom_banks().1 & self.rom_bank_mask()
+    }
+
+    // Bank bits past the ROM's size aren't wired up, so bank numbers wrap: a
+    // 256KB ROM (16 banks) masks them to 0x0F
+    fn rom_bank_mask(&self) -> usize {
+        (self.rom.len() / 0x4000).max(1).next_power_of_two() - 1
+    }
 
-        let bank = self.ram_bank();
-        let addr = (bank * 0x2000) + ((address - 0xA000) as usize);
+    pub fn rom_len(&self) -> usize {
+        self.rom.len()
+    }
 
-        if addr < self.ram.len() {
-            self.ram[addr] = value;
+    pub fn read_rom(&self, address: u16) -> u8 {
+        match self.rom_offset(address) {
+            Some(offset) if offset < self.rom.len() => self.rom[offset],
+            _ => 0xFF,
         }
     }
 
+    // Offset in the ROM file of a 0x0000-0x7FFF address with the current banking
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
+        let (low, high) = self.mapper.rom_banks();
+        let (bank, offset) = match address {
+            0x0000..=0x3FFF => (low, address),
+            0x4000..=0x7FFF => (high, address - 0x4000),
+            _ => return None,
+        };
+        Some((bank & self.rom_bank_mask()) * 0x4000 + offset as usize)
+    }
+
+    // Drives the RTC from emulated time, starting from zero at power-on
+    pub fn use_emulated_clock(&mut self) {
+        self.mapper.use_emulated_clock();
+    }
+
+    // Advances the emulated clock by `cycles` normal-speed cycles
+    pub fn tick(&mut self, cycles: u32) {
+        self.mapper.tick(cycles);
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mapper.read_ram(address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        // Some mappers (TAMA5) switch ROM banks through 0xA000-0xBFFF
+        let banks = self.mapper.rom_banks();
+        if self.mapper.write_ram(address, value) {
+            self.mark_dirty();
+        }
+        if self.mapper.rom_banks() != banks {
+            self.mapping_changes = self.mapping_changes.wrapping_add(1);
+        }
+    }
+
+    // Changes whenever rom_offset() may map an address elsewhere
+    pub fn mapping_changes(&self) -> u32 {
+        self.mapping_changes
+    }
+
     pub fn write_rom(&mut self, address: u16, value: u8) {
-        match self.cart_type {
-            CartridgeType::RomOnly => {}
-
-            CartridgeType::Mbc1 => {
-                match address {
-                    0x0000..=0x1FFF => {
-                        // RAM Enable
-                        self.ram_enabled = (value & 0x0F) == 0x0A;
-                    }
-                    0x2000..=0x3FFF => {
-                        // ROM Bank Number (lower 5 bits)
-                        let lower = value & 0x1F;
-                        self.bank = (self.bank & 0x60) | lower;
-                    }
-                    0x4000..=0x5FFF => {
-                        // RAM Bank Number or Upper Bits of ROM Bank Number (upper 2 bits)
-                        let upper = (value & 0x03) << 5;
-                        self.bank = (self.bank & 0x1F) | upper;
-                    }
-                    0x6000..=0x7FFF => {
-                        // Banking Mode Select
-                        self.bank_mode = if (value & 0x01) != 0 {
-                            BankMode::Ram
-                        } else {
-                            BankMode::Rom
-                        };
-                    }
-                    _ => {}
-                }
-            }
+        self.mapping_changes = self.mapping_changes.wrapping_add(1);
+        self.mapper.write_rom(address, value);
+    }
 
-            CartridgeType::Mbc2 => {
-                match address {
-                    0x0000..=0x1FFF => {
-                        // RAM Enable (only if bit 8 of address is 0)
-                        if (address & 0x0100) == 0 {
-                            self.ram_enabled = (value & 0x0F) == 0x0A;
-                        }
-                    }
-                    0x2000..=0x3FFF => {
-                        // ROM Bank Number (only if bit 8 of address is 1)
-                        if (address & 0x0100) != 0 {
-                            self.bank = value & 0x0F; // Only 4 bits for MBC2
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    // The RAM is saved along with the mapper registers, so loading a state
+    // also brings back the battery save of that moment
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let ram = self.mapper.ram();
+        w.u32(ram.len() as u32);
+        w.bytes(ram);
+        self.mapper.save_state(w);
+    }
 
-            CartridgeType::Mbc3 => {
-                match address {
-                    0x0000..=0x1FFF => {
-                        // RAM and Timer Enable
-                        self.ram_enabled = (value & 0x0F) == 0x0A;
-                    }
-                    0x2000..=0x3FFF => {
-                        // ROM Bank Number (7 bits)
-                        self.bank = value & 0x7F;
-                        if self.bank == 0 {
-                            self.bank = 1;
-                        }
-                    }
-                    0x4000..=0x5FFF => {
-                        // RAM Bank Number or RTC Register Select
-                        if value <= 0x03 {
-                            // RAM bank
-                            self.bank = (self.bank & 0x7F) | ((value & 0x03) << 5);
-                        } else if value >= 0x08 && value <= 0x0C {
-                            // RTC register
-                            self.rtc_register = value;
-                        }
-                    }
-                    0x6000..=0x7FFF => {
-                        // Latch Clock Data
-                        if value == 0x01 {
-                            self.rtc_latched = true;
-                        } else if value == 0x00 {
-                            self.rtc_latched = false;
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.mapping_changes = self.mapping_changes.wrapping_add(1);
+        let ram = self.mapper.ram_mut();
+        if r.u32() as usize != ram.len() {
+            r.reject();
+            return;
+        }
+        r.bytes(ram);
+        // Each mapper saves its own registers since version 5
+        if r.version >= 5 {
+            self.mapper.load_state(r);
+        } else {
+            self.mapper.load_legacy_state(&LegacyState::read(r));
+        }
+        self.mark_dirty();
+    }
+}
 
-            CartridgeType::Mbc5 => {
-                match address {
-                    0x0000..=0x1FFF => {
-                        // RAM Enable
-                        self.ram_enabled = (value & 0x0F) == 0x0A;
-                    }
-                    0x2000..=0x2FFF => {
-                        // ROM Bank Number (lower 8 bits)
-                        self.rom_bank_low = value;
-                    }
-                    0x3000..=0x3FFF => {
-                        // ROM Bank Number (9th bit)
-                        self.rom_bank_high = value & 0x01;
-                    }
-                    0x4000..=0x5FFF => {
-                        // RAM Bank Number (4 bits)
-                        self.ram_bank = value & 0x0F;
-                    }
-                    _ => {}
-                }
-            }
+// Battery RAM changed since the last save is written when the cartridge goes
+// away, whatever the reason: a closed window, another ROM, or a panic
+// unwinding through the emulator
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if self.dirty_since.is_some() {
+            self.save();
         }
     }
 }
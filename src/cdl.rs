@@ -0,0 +1,46 @@
+// Code/data logger: one flags byte per ROM byte, indexed by ROM file offset so
+// every bank is logged separately. The file is the same size as the ROM, in
+// the common "byte per ROM byte" layout accepted by disassembly tools.
+
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+pub const CODE: u8 = 0x01;         // Fetched as an instruction byte (opcode or operand)
+pub const DATA: u8 = 0x02;         // Read as data by the CPU or a DMA
+pub const CODE_OPCODE: u8 = 0x04;  // First byte of an instruction
+
+pub struct Cdl {
+    pub flags: Vec<u8>,
+}
+
+impl Cdl {
+    pub fn new(rom_len: usize) -> Self {
+        Cdl { flags: vec![0; rom_len] }
+    }
+
+    // Loads an earlier log to keep adding to it
+    pub fn load(path: &Path, rom_len: usize) -> std::io::Result<Self> {
+        let flags = std::fs::read(path)?;
+        if flags.len() != rom_len {
+            return Err(Error::new(ErrorKind::InvalidData, "CDL file size does not match the ROM"));
+        }
+        Ok(Cdl { flags })
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, &self.flags)
+    }
+
+    pub fn mark(&mut self, offset: usize, flags: u8) {
+        if let Some(byte) = self.flags.get_mut(offset) {
+            *byte |= flags;
+        }
+    }
+
+    // Number of ROM bytes logged as code and as data
+    pub fn coverage(&self) -> (usize, usize) {
+        let code = self.flags.iter().filter(|&&f| f & CODE != 0).count();
+        let data = self.flags.iter().filter(|&&f| f & DATA != 0).count();
+        (code, data)
+    }
+}
@@ -0,0 +1,144 @@
+// Cheat codes: Game Genie codes patch ROM reads, GameShark codes write to
+// RAM every VBlank.
+//
+// Cheat files hold one code per line, optionally followed by a name. Lines
+// starting with '#' are comments and lines starting with '!' are disabled
+// codes, so codes can be switched on and off by editing the file and
+// reloading it.
+
+use std::path::Path;
+use log::warn;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CheatCode {
+    // Replaces the ROM byte at `address`, only when it equals `compare` if given
+    GameGenie { address: u16, value: u8, compare: Option<u8> },
+    // Writes `value` to `address` each frame, in whichever RAM bank is mapped
+    GameShark { address: u16, value: u8 },
+}
+
+impl CheatCode {
+    // Parses "ABC-DEF" / "ABC-DEF-GHI" (Game Genie) or "01VVLLHH" (GameShark)
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let digits: Vec<u8> = code
+            .chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|d| d as u8).ok_or_else(|| format!("invalid code '{}'", code)))
+            .collect::<Result<_, _>>()?;
+
+        match digits.len() {
+            6 | 9 => {
+                // Digits 0-1: new value, 2-5: address with the top nibble
+                // inverted and rotated to the end, 6 and 8: encoded compare byte
+                let value = (digits[0] << 4) | digits[1];
+                let address = (((digits[5] ^ 0x0F) as u16) << 12)
+                    | ((digits[2] as u16) << 8)
+                    | ((digits[3] as u16) << 4)
+                    | digits[4] as u16;
+                let compare = (digits.len() == 9)
+                    .then(|| ((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA);
+                if address >= 0x8000 {
+                    return Err(format!("Game Genie code '{}' does not patch ROM", code));
+                }
+                Ok(CheatCode::GameGenie { address, value, compare })
+            }
+            8 => {
+                // Code type, value, then the address low byte first
+                let code_type = (digits[0] << 4) | digits[1];
+                let value = (digits[2] << 4) | digits[3];
+                let address = (((digits[6] << 4) | digits[7]) as u16) << 8 | ((digits[4] << 4) | digits[5]) as u16;
+                // 01: plain write, 80-9F: write to a RAM bank
+                if code_type != 0x01 && !(0x80..=0x9F).contains(&code_type) {
+                    return Err(format!("unsupported GameShark code type {:02X} in '{}'", code_type, code));
+                }
+                Ok(CheatCode::GameShark { address, value })
+            }
+            _ => Err(format!("invalid code '{}'", code)),
+        }
+    }
+}
+
+pub struct Cheat {
+    pub code: CheatCode,
+    pub name: String,
+    pub enabled: bool,
+}
+
+pub struct Cheats {
+    pub enabled: bool, // Master switch
+    pub list: Vec<Cheat>,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats { enabled: true, list: Vec::new() }
+    }
+
+    // Replaces the cheat list with the codes from a cheat file. Invalid lines
+    // are reported and skipped.
+    pub fn load(&mut self, path: &Path) -> std::io::Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        self.parse(&text);
+        Ok(())
+    }
+
+    // Replaces the cheat list with codes in the cheat file format
+    pub fn parse(&mut self, text: &str) {
+        self.list.clear();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (enabled, line) = match line.strip_prefix('!') {
+                Some(rest) => (false, rest.trim_start()),
+                None => (true, line),
+            };
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            match CheatCode::parse(code) {
+                Ok(code) => self.list.push(Cheat { code, name: name.trim().to_string(), enabled }),
+                Err(e) => warn!("Skipping cheat: {}", e),
+            }
+        }
+    }
+
+    fn active(&self) -> impl Iterator<Item = &CheatCode> {
+        self.list
+            .iter()
+            .filter(move |cheat| self.enabled && cheat.enabled)
+            .map(|cheat| &cheat.code)
+    }
+
+    // Whether any Game Genie code is active
+    pub fn patches_rom(&self) -> bool {
+        self.active().any(|code| matches!(code, CheatCode::GameGenie { .. }))
+    }
+
+    // Applies Game Genie patches to a byte read from ROM
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        for code in self.active() {
+            if let CheatCode::GameGenie { address: a, value: v, compare } = *code {
+                if a == address && compare.is_none_or(|c| c == value) {
+                    return v;
+                }
+            }
+        }
+        value
+    }
+
+    // GameShark writes to perform at the start of VBlank
+    pub fn ram_writes(&self) -> Vec<(u16, u8)> {
+        self.active()
+            .filter_map(|code| match *code {
+                CheatCode::GameShark { address, value } => Some((address, value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
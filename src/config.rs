@@ -0,0 +1,107 @@
+// User configuration: defaults for the command line options, read from
+// config.txt in the platform config directory or the file given with
+// --config. Each line sets an option by its name without the dashes; options
+// without a value take true or false. The command line overrides the file,
+// and per-game settings (settings.rs) override its palette, mode and
+// keyboard layout.
+//
+//   fullscreen = true
+//   fit = integer
+//   filter = scanlines
+//   palette = pocket
+//   mode = cgb                     (or dmg)
+//   controller = wasd              (keyboard layout: arrows or wasd)
+//   audio-filter = dmg
+//   save-dir = /home/me/gb/saves
+//   speed = 150                    (percent)
+
+use std::path::PathBuf;
+
+use crate::paths;
+use crate::settings::GameSettings;
+use log::{error, info, warn};
+
+// Options taking a value
+const VALUE_OPTIONS: &[&str] = &[
+    "fit",
+    "filter",
+    "ghosting",
+    "palette-file",
+    "frameskip",
+    "audio-device",
+    "audio-filter",
+    "save-dir",
+    "ir",
+    "speed",
+    "rtc-format",
+    "cgb-palette",
+];
+// Options that are on or off
+const FLAG_OPTIONS: &[&str] =
+    &["fullscreen", "screenshot-scaled", "video-no-audio", "resume", "sdl", "vram-viewer", "apu-viewer"];
+
+#[derive(Default)]
+pub struct Config {
+    options: Vec<(String, Option<String>)>, // Name and value, None for flags that are on
+    pub game: GameSettings,                 // Palette, mode and keyboard layout for every game
+}
+
+impl Config {
+    // Loads the file given with --config, or config.txt if there is one.
+    // Invalid lines are reported and dropped.
+    pub fn load(cli_args: &[String]) -> Self {
+        let given = cli_args.iter().position(|a| a == "--config").and_then(|i| cli_args.get(i + 1));
+        let path = given.map(PathBuf::from).unwrap_or_else(|| paths::config_dir().join("config.txt"));
+        let mut config = Config::default();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && given.is_none() => return config,
+            Err(e) => {
+                error!("Failed to read {}: {}", path.display(), e);
+                return config;
+            }
+        };
+        info!("Config: {}", path.display());
+
+        for (number, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=').map(|(n, v)| (n.trim(), v.trim())) else {
+                warn!("{} line {}: expected 'name = value'", path.display(), number + 1);
+                continue;
+            };
+            let valid = if VALUE_OPTIONS.contains(&name) {
+                config.options.push((name.to_string(), Some(value.to_string())));
+                true
+            } else if FLAG_OPTIONS.contains(&name) {
+                match value.to_lowercase().as_str() {
+                    "true" | "on" | "yes" => config.options.push((name.to_string(), None)),
+                    "false" | "off" | "no" => {}
+                    _ => warn!("{} line {}: '{}' takes true or false", path.display(), number + 1, name),
+                }
+                true
+            } else {
+                matches!(name, "palette" | "mode" | "controller") && config.game.set(name, value)
+            };
+            if !valid {
+                warn!("{} line {}: ignoring '{}'", path.display(), number + 1, line);
+            }
+        }
+        config
+    }
+
+    // The command line followed by the configured options it doesn't set.
+    // Options are looked up by their first occurrence, so the command line wins.
+    pub fn apply(&self, cli_args: &[String]) -> Vec<String> {
+        let mut args = cli_args.to_vec();
+        for (name, value) in &self.options {
+            let option = format!("--{}", name);
+            if !cli_args.contains(&option) {
+                args.push(option);
+                args.extend(value.clone());
+            }
+        }
+        args
+    }
+}
@@ -1,3 +1,7 @@
+use crate::block_cache::BlockCache;
+use crate::cdl;
+use crate::savestate::{StateReader, StateWriter};
+
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -11,6 +15,12 @@ pub struct Registers {
     pub pc: u16, // Program counter
 }
 
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Registers {
     pub fn new() -> Self {
         Registers {
@@ -81,11 +91,51 @@ pub enum Flag {
     Carry = 0b0001_0000,
 }
 
+// Entry of the virtual call stack, pushed by CALL, RST and interrupts
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub target: u16,
+    pub bank: u8, // ROM bank mapped when the call was made
+    pub return_address: u16,
+    pub sp: u16, // Where the return address was pushed
+    pub interrupt: bool,
+}
+
+const MAX_CALL_DEPTH: usize = 256;
+
+// Illegal opcode that hung the CPU, and where it was executed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Lockup {
+    pub opcode: u8,
+    pub address: u16,
+}
+
+impl std::fmt::Display for Lockup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CPU locked up by illegal opcode ${:02X} at ${:04X}", self.opcode, self.address)
+    }
+}
+
 pub struct Cpu {
     pub registers: Registers,
     pub halted: bool,
+    pub stopped: bool, // STOP mode - system clock halted until joypad input
     pub ime: bool, // Interrupt Master Enable
     ime_scheduled: bool, // EI takes effect after next instruction
+    cycles: u32, // T-cycles already ticked during the current step
+    pub track_calls: bool, // Maintain call_stack (debugger/profiler only)
+    pub call_stack: Vec<CallFrame>,
+    pub locked: Option<Lockup>, // Hung by an illegal opcode until the system is reset
+    pub block_cache: Option<BlockCache>, // Decoded ROM code, None to fetch every instruction
+    operands: [u8; 2], // Operand bytes of the instruction taken from the block cache
+    operands_cached: u8,
+    operands_fetched: u8,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
@@ -93,8 +143,17 @@ impl Cpu {
         Cpu {
             registers: Registers::new(),
             halted: false,
+            stopped: false,
             ime: false,
             ime_scheduled: false,
+            cycles: 0,
+            track_calls: false,
+            call_stack: Vec::new(),
+            locked: None,
+            block_cache: Some(BlockCache::default()),
+            operands: [0; 2],
+            operands_cached: 0,
+            operands_fetched: 0,
         }
     }
 
@@ -111,43 +170,77 @@ impl Cpu {
         cpu
     }
 
+    // CGB running a DMG game, with `title_checksum` as the boot ROM leaves
+    // it in B (0 for games not licensed by Nintendo)
+    pub fn new_dmg_compat(title_checksum: u8) -> Self {
+        let mut cpu = Cpu::new();
+        cpu.registers.a = 0x11;
+        cpu.registers.f = 0x80;
+        cpu.registers.b = title_checksum;
+        cpu.registers.c = 0x00;
+        cpu.registers.d = 0x00;
+        cpu.registers.e = 0x08;
+        // Titles the boot ROM shows the DMG logo tilemap for end with HL elsewhere
+        let hl: u16 = if matches!(title_checksum, 0x43 | 0x58) { 0x991A } else { 0x007C };
+        cpu.registers.h = (hl >> 8) as u8;
+        cpu.registers.l = hl as u8;
+        cpu
+    }
+
+    // Runs one instruction (or interrupt dispatch / halted cycle), ticking the
+    // rest of the system through the MMU as it goes. Returns the T-cycles taken.
     pub fn step(&mut self, mmu: &mut crate::mmu::Mmu) -> u32 {
-        // Handle scheduled IME enable (EI takes effect after next instruction)
-        if self.ime_scheduled {
-            self.ime = true;
-            self.ime_scheduled = false;
+        // STOP is only exited by a button press on a selected joypad line.
+        // The system clock is halted, so nothing is ticked meanwhile.
+        if self.stopped {
+            if (mmu.joypad.read() & 0x0F) == 0x0F {
+                return 4;
+            }
+            self.stopped = false;
         }
 
+        self.cycles = 0;
+        let cycles = self.run(mmu);
+
+        // Internal cycles that didn't access memory
+        while self.cycles < cycles {
+            self.tick(mmu);
+        }
+
+        self.cycles
+    }
+
+    fn run(&mut self, mmu: &mut crate::mmu::Mmu) -> u32 {
+        // A locked CPU ignores interrupts; the rest of the system keeps running
+        if self.locked.is_some() {
+            return 4;
+        }
+
+        // EI takes effect after the following instruction, so the pending
+        // interrupt check below still sees the old IME
+        let enable_ime = self.ime_scheduled;
+        self.ime_scheduled = false;
+
         // Check for interrupts
-        let interrupt_flag = mmu.read_byte(0xFF0F);
-        let interrupt_enable = mmu.ie;
-        let triggered = interrupt_flag & interrupt_enable;
+        let triggered = mmu.if_reg & mmu.ie & 0x1F;
 
         if triggered != 0 {
+            let was_halted = self.halted;
             self.halted = false; // Wake from HALT
 
             if self.ime {
-                self.ime = false; // Disable interrupts
-
-                // Handle interrupts in priority order
-                let (vector, bit) = if (triggered & 0x01) != 0 {
-                    (0x0040, 0) // VBlank
-                } else if (triggered & 0x02) != 0 {
-                    (0x0048, 1) // LCD STAT
-                } else if (triggered & 0x04) != 0 {
-                    (0x0050, 2) // Timer
-                } else if (triggered & 0x08) != 0 {
-                    (0x0058, 3) // Serial
-                } else if (triggered & 0x10) != 0 {
-                    (0x0060, 4) // Joypad
+                let return_address = self.registers.pc;
+                // Leaving HALT costs one extra M-cycle before the dispatch
+                let cycles = if was_halted {
+                    self.tick(mmu);
+                    self.dispatch_interrupt(mmu) + 4
                 } else {
-                    (0x0040, 0)
+                    self.dispatch_interrupt(mmu)
                 };
-
-                mmu.write_byte(0xFF0F, interrupt_flag & !(1 << bit));
-                self.push_stack(mmu, self.registers.pc);
-                self.registers.pc = vector;
-                return 20;
+                if self.track_calls {
+                    self.push_call(mmu, return_address, true);
+                }
+                return cycles;
             }
         }
 
@@ -155,10 +248,104 @@ impl Cpu {
             return 4;
         }
 
-        let opcode = mmu.read_byte(self.registers.pc);
-        self.registers.pc = self.registers.pc.wrapping_add(1);
+        let (pc, sp) = (self.registers.pc, self.registers.sp);
+        if pc < 0x8000 && mmu.cdl.is_some() {
+            mmu.log_rom_access(pc, cdl::CODE_OPCODE);
+        }
+        let cached = match self.block_cache {
+            Some(ref mut cache) => cache.fetch(mmu, pc),
+            None => None,
+        };
+        let opcode = match cached {
+            Some(instruction) => {
+                // Same timing as the fetch; the operands are read from the cache too
+                self.tick(mmu);
+                self.registers.pc = pc.wrapping_add(1);
+                self.operands = instruction.operands;
+                self.operands_cached = instruction.operand_len;
+                self.operands_fetched = 0;
+                instruction.opcode
+            }
+            None => self.read_byte_pc(mmu),
+        };
+
+        let cycles = self.execute(opcode, mmu);
+        self.operands_cached = 0;
+
+        if self.track_calls {
+            self.track_call(opcode, pc, sp, mmu);
+        }
 
-        self.execute(opcode, mmu)
+        // A DI right after EI cancels the pending enable
+        if enable_ime && opcode != 0xF3 {
+            self.ime = true;
+        }
+
+        cycles
+    }
+
+    // Interrupt dispatch takes 5 M-cycles: two idle cycles, PC high byte push,
+    // PC low byte push, then the jump. The interrupt to serve is picked after
+    // the high byte push, so a push that overwrites IE (SP = 0x0000) can cancel
+    // it, in which case PC ends up at 0x0000 and no IF bit is cleared.
+    fn dispatch_interrupt(&mut self, mmu: &mut crate::mmu::Mmu) -> u32 {
+        self.ime = false;
+        let pc = self.registers.pc;
+
+        self.tick(mmu);
+        self.tick(mmu);
+
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write(mmu, self.registers.sp, (pc >> 8) as u8);
+
+        // Lowest set bit has the highest priority (VBlank, STAT, Timer, Serial, Joypad)
+        let pending = mmu.if_reg & mmu.ie & 0x1F;
+
+        self.registers.sp = self.registers.sp.wrapping_sub(1);
+        self.write(mmu, self.registers.sp, pc as u8);
+
+        self.registers.pc = if pending == 0 {
+            0x0000
+        } else {
+            let bit = pending.trailing_zeros();
+            mmu.if_reg &= !(1 << bit);
+            0x0040 + (bit as u16) * 8
+        };
+
+        20
+    }
+
+    // Updates the call stack after a taken CALL/RST or RET/RETI. `pc` and
+    // `sp` are the registers before the instruction.
+    fn track_call(&mut self, opcode: u8, pc: u16, sp: u16, mmu: &crate::mmu::Mmu) {
+        match opcode {
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC if self.registers.sp == sp.wrapping_sub(2) => {
+                self.push_call(mmu, pc.wrapping_add(3), false);
+            }
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                self.push_call(mmu, pc.wrapping_add(1), false);
+            }
+            0xC9 | 0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xD9 if self.registers.sp == sp.wrapping_add(2) => {
+                // Drop every frame whose return address is now off the stack,
+                // which also copes with code that pops return addresses itself
+                while self.call_stack.last().is_some_and(|frame| frame.sp < self.registers.sp) {
+                    self.call_stack.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn push_call(&mut self, mmu: &crate::mmu::Mmu, return_address: u16, interrupt: bool) {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            self.call_stack.remove(0);
+        }
+        let target = self.registers.pc;
+        let bank = match target {
+            0x4000..=0x7FFF => mmu.cartridge.rom_bank() as u8,
+            _ => 0,
+        };
+        self.call_stack.push(CallFrame { target, bank, return_address, sp: self.registers.sp, interrupt });
     }
 
     fn execute(&mut self, opcode: u8, mmu: &mut crate::mmu::Mmu) -> u32 {
@@ -222,26 +409,26 @@ impl Cpu {
             0x7D => { self.registers.a = self.registers.l; 4 } // LD A, L
             0x7F => { self.registers.a = self.registers.a; 4 } // LD A, A
 
-            0x02 => { let addr = self.registers.bc(); mmu.write_byte(addr, self.registers.a); 8 } // LD (BC), A
-            0x12 => { let addr = self.registers.de(); mmu.write_byte(addr, self.registers.a); 8 } // LD (DE), A
-            0x0A => { let addr = self.registers.bc(); self.registers.a = mmu.read_byte(addr); 8 } // LD A, (BC)
-            0x1A => { let addr = self.registers.de(); self.registers.a = mmu.read_byte(addr); 8 } // LD A, (DE)
-
-            0x36 => { let v = self.read_byte_pc(mmu); let addr = self.registers.hl(); mmu.write_byte(addr, v); 12 } // LD (HL), n
-            0x46 => { let addr = self.registers.hl(); self.registers.b = mmu.read_byte(addr); 8 } // LD B, (HL)
-            0x4E => { let addr = self.registers.hl(); self.registers.c = mmu.read_byte(addr); 8 } // LD C, (HL)
-            0x56 => { let addr = self.registers.hl(); self.registers.d = mmu.read_byte(addr); 8 } // LD D, (HL)
-            0x5E => { let addr = self.registers.hl(); self.registers.e = mmu.read_byte(addr); 8 } // LD E, (HL)
-            0x66 => { let addr = self.registers.hl(); self.registers.h = mmu.read_byte(addr); 8 } // LD H, (HL)
-            0x6E => { let addr = self.registers.hl(); self.registers.l = mmu.read_byte(addr); 8 } // LD L, (HL)
-            0x7E => { let addr = self.registers.hl(); self.registers.a = mmu.read_byte(addr); 8 } // LD A, (HL)
-            0x70 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.b); 8 } // LD (HL), B
-            0x71 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.c); 8 } // LD (HL), C
-            0x72 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.d); 8 } // LD (HL), D
-            0x73 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.e); 8 } // LD (HL), E
-            0x74 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.h); 8 } // LD (HL), H
-            0x75 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.l); 8 } // LD (HL), L
-            0x77 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.a); 8 } // LD (HL), A
+            0x02 => { let addr = self.registers.bc(); self.write(mmu, addr, self.registers.a); 8 } // LD (BC), A
+            0x12 => { let addr = self.registers.de(); self.write(mmu, addr, self.registers.a); 8 } // LD (DE), A
+            0x0A => { let addr = self.registers.bc(); self.registers.a = self.read(mmu, addr); 8 } // LD A, (BC)
+            0x1A => { let addr = self.registers.de(); self.registers.a = self.read(mmu, addr); 8 } // LD A, (DE)
+
+            0x36 => { let v = self.read_byte_pc(mmu); let addr = self.registers.hl(); self.write(mmu, addr, v); 12 } // LD (HL), n
+            0x46 => { let addr = self.registers.hl(); self.registers.b = self.read(mmu, addr); 8 } // LD B, (HL)
+            0x4E => { let addr = self.registers.hl(); self.registers.c = self.read(mmu, addr); 8 } // LD C, (HL)
+            0x56 => { let addr = self.registers.hl(); self.registers.d = self.read(mmu, addr); 8 } // LD D, (HL)
+            0x5E => { let addr = self.registers.hl(); self.registers.e = self.read(mmu, addr); 8 } // LD E, (HL)
+            0x66 => { let addr = self.registers.hl(); self.registers.h = self.read(mmu, addr); 8 } // LD H, (HL)
+            0x6E => { let addr = self.registers.hl(); self.registers.l = self.read(mmu, addr); 8 } // LD L, (HL)
+            0x7E => { let addr = self.registers.hl(); self.registers.a = self.read(mmu, addr); 8 } // LD A, (HL)
+            0x70 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.b); 8 } // LD (HL), B
+            0x71 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.c); 8 } // LD (HL), C
+            0x72 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.d); 8 } // LD (HL), D
+            0x73 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.e); 8 } // LD (HL), E
+            0x74 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.h); 8 } // LD (HL), H
+            0x75 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.l); 8 } // LD (HL), L
+            0x77 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.a); 8 } // LD (HL), A
 
             // 16-bit loads
             0x01 => { let v = self.read_word_pc(mmu); self.registers.set_bc(v); 12 } // LD BC, nn
@@ -266,7 +453,7 @@ impl Cpu {
             0x1C => { self.registers.e = self.inc(self.registers.e); 4 } // INC E
             0x2C => { self.registers.l = self.inc(self.registers.l); 4 } // INC L
             0x3C => { self.registers.a = self.inc(self.registers.a); 4 } // INC A
-            0x34 => { let addr = self.registers.hl(); let v = self.inc(mmu.read_byte(addr)); mmu.write_byte(addr, v); 12 } // INC (HL)
+            0x34 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.inc(v); self.write(mmu, addr, v); 12 } // INC (HL)
 
             0x05 => { self.registers.b = self.dec(self.registers.b); 4 } // DEC B
             0x15 => { self.registers.d = self.dec(self.registers.d); 4 } // DEC D
@@ -275,7 +462,7 @@ impl Cpu {
             0x1D => { self.registers.e = self.dec(self.registers.e); 4 } // DEC E
             0x2D => { self.registers.l = self.dec(self.registers.l); 4 } // DEC L
             0x3D => { self.registers.a = self.dec(self.registers.a); 4 } // DEC A
-            0x35 => { let addr = self.registers.hl(); let v = self.dec(mmu.read_byte(addr)); mmu.write_byte(addr, v); 12 } // DEC (HL)
+            0x35 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.dec(v); self.write(mmu, addr, v); 12 } // DEC (HL)
 
             // Jumps
             0xC3 => { let addr = self.read_word_pc(mmu); self.registers.pc = addr; 16 } // JP nn
@@ -321,7 +508,7 @@ impl Cpu {
             0x83 => { self.add(self.registers.e); 4 } // ADD A, E
             0x84 => { self.add(self.registers.h); 4 } // ADD A, H
             0x85 => { self.add(self.registers.l); 4 } // ADD A, L
-            0x86 => { let v = mmu.read_byte(self.registers.hl()); self.add(v); 8 } // ADD A, (HL)
+            0x86 => { let v = self.read(mmu, self.registers.hl()); self.add(v); 8 } // ADD A, (HL)
             0xC6 => { let v = self.read_byte_pc(mmu); self.add(v); 8 } // ADD A, n
 
             0x09 => { self.add_hl(self.registers.bc()); 8 } // ADD HL, BC
@@ -337,7 +524,7 @@ impl Cpu {
             0x8B => { self.adc(self.registers.e); 4 } // ADC A, E
             0x8C => { self.adc(self.registers.h); 4 } // ADC A, H
             0x8D => { self.adc(self.registers.l); 4 } // ADC A, L
-            0x8E => { let v = mmu.read_byte(self.registers.hl()); self.adc(v); 8 } // ADC A, (HL)
+            0x8E => { let v = self.read(mmu, self.registers.hl()); self.adc(v); 8 } // ADC A, (HL)
             0xCE => { let v = self.read_byte_pc(mmu); self.adc(v); 8 } // ADC A, n
 
             0x97 => { self.sub(self.registers.a); 4 } // SUB A
@@ -347,7 +534,7 @@ impl Cpu {
             0x93 => { self.sub(self.registers.e); 4 } // SUB E
             0x94 => { self.sub(self.registers.h); 4 } // SUB H
             0x95 => { self.sub(self.registers.l); 4 } // SUB L
-            0x96 => { let v = mmu.read_byte(self.registers.hl()); self.sub(v); 8 } // SUB (HL)
+            0x96 => { let v = self.read(mmu, self.registers.hl()); self.sub(v); 8 } // SUB (HL)
             0xD6 => { let v = self.read_byte_pc(mmu); self.sub(v); 8 } // SUB n
             0x9F => { self.sbc(self.registers.a); 4 } // SBC A, A
             0x98 => { self.sbc(self.registers.b); 4 } // SBC A, B
@@ -356,7 +543,7 @@ impl Cpu {
             0x9B => { self.sbc(self.registers.e); 4 } // SBC A, E
             0x9C => { self.sbc(self.registers.h); 4 } // SBC A, H
             0x9D => { self.sbc(self.registers.l); 4 } // SBC A, L
-            0x9E => { let v = mmu.read_byte(self.registers.hl()); self.sbc(v); 8 } // SBC A, (HL)
+            0x9E => { let v = self.read(mmu, self.registers.hl()); self.sbc(v); 8 } // SBC A, (HL)
             0xDE => { let v = self.read_byte_pc(mmu); self.sbc(v); 8 } // SBC A, n
 
             0xA7 => { self.and(self.registers.a); 4 } // AND A
@@ -366,7 +553,7 @@ impl Cpu {
             0xA3 => { self.and(self.registers.e); 4 } // AND E
             0xA4 => { self.and(self.registers.h); 4 } // AND H
             0xA5 => { self.and(self.registers.l); 4 } // AND L
-            0xA6 => { let v = mmu.read_byte(self.registers.hl()); self.and(v); 8 } // AND (HL)
+            0xA6 => { let v = self.read(mmu, self.registers.hl()); self.and(v); 8 } // AND (HL)
             0xE6 => { let v = self.read_byte_pc(mmu); self.and(v); 8 } // AND n
 
             0xB7 => { self.or(self.registers.a); 4 } // OR A
@@ -376,7 +563,7 @@ impl Cpu {
             0xB3 => { self.or(self.registers.e); 4 } // OR E
             0xB4 => { self.or(self.registers.h); 4 } // OR H
             0xB5 => { self.or(self.registers.l); 4 } // OR L
-            0xB6 => { let v = mmu.read_byte(self.registers.hl()); self.or(v); 8 } // OR (HL)
+            0xB6 => { let v = self.read(mmu, self.registers.hl()); self.or(v); 8 } // OR (HL)
             0xF6 => { let v = self.read_byte_pc(mmu); self.or(v); 8 } // OR n
 
             0xAF => { self.xor(self.registers.a); 4 } // XOR A
@@ -386,7 +573,7 @@ impl Cpu {
             0xAB => { self.xor(self.registers.e); 4 } // XOR E
             0xAC => { self.xor(self.registers.h); 4 } // XOR H
             0xAD => { self.xor(self.registers.l); 4 } // XOR L
-            0xAE => { let v = mmu.read_byte(self.registers.hl()); self.xor(v); 8 } // XOR (HL)
+            0xAE => { let v = self.read(mmu, self.registers.hl()); self.xor(v); 8 } // XOR (HL)
             0xEE => { let v = self.read_byte_pc(mmu); self.xor(v); 8 } // XOR n
 
             0xBF => { self.cp(self.registers.a); 4 } // CP A
@@ -396,21 +583,21 @@ impl Cpu {
             0xBB => { self.cp(self.registers.e); 4 } // CP E
             0xBC => { self.cp(self.registers.h); 4 } // CP H
             0xBD => { self.cp(self.registers.l); 4 } // CP L
-            0xBE => { let v = mmu.read_byte(self.registers.hl()); self.cp(v); 8 } // CP (HL)
+            0xBE => { let v = self.read(mmu, self.registers.hl()); self.cp(v); 8 } // CP (HL)
             0xFE => { let v = self.read_byte_pc(mmu); self.cp(v); 8 } // CP n
 
             // Memory operations
-            0x22 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.a); self.registers.set_hl(addr.wrapping_add(1)); 8 } // LD (HL+), A
-            0x32 => { let addr = self.registers.hl(); mmu.write_byte(addr, self.registers.a); self.registers.set_hl(addr.wrapping_sub(1)); 8 } // LD (HL-), A
-            0x2A => { let addr = self.registers.hl(); self.registers.a = mmu.read_byte(addr); self.registers.set_hl(addr.wrapping_add(1)); 8 } // LD A, (HL+)
-            0x3A => { let addr = self.registers.hl(); self.registers.a = mmu.read_byte(addr); self.registers.set_hl(addr.wrapping_sub(1)); 8 } // LD A, (HL-)
-
-            0xE0 => { let offset = self.read_byte_pc(mmu); mmu.write_byte(0xFF00 + offset as u16, self.registers.a); 12 } // LDH (n), A
-            0xF0 => { let offset = self.read_byte_pc(mmu); self.registers.a = mmu.read_byte(0xFF00 + offset as u16); 12 } // LDH A, (n)
-            0xE2 => { mmu.write_byte(0xFF00 + self.registers.c as u16, self.registers.a); 8 } // LD (C), A
-            0xF2 => { self.registers.a = mmu.read_byte(0xFF00 + self.registers.c as u16); 8 } // LD A, (C)
-            0xEA => { let addr = self.read_word_pc(mmu); mmu.write_byte(addr, self.registers.a); 16 } // LD (nn), A
-            0xFA => { let addr = self.read_word_pc(mmu); self.registers.a = mmu.read_byte(addr); 16 } // LD A, (nn)
+            0x22 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.a); self.registers.set_hl(addr.wrapping_add(1)); 8 } // LD (HL+), A
+            0x32 => { let addr = self.registers.hl(); self.write(mmu, addr, self.registers.a); self.registers.set_hl(addr.wrapping_sub(1)); 8 } // LD (HL-), A
+            0x2A => { let addr = self.registers.hl(); self.registers.a = self.read(mmu, addr); self.registers.set_hl(addr.wrapping_add(1)); 8 } // LD A, (HL+)
+            0x3A => { let addr = self.registers.hl(); self.registers.a = self.read(mmu, addr); self.registers.set_hl(addr.wrapping_sub(1)); 8 } // LD A, (HL-)
+
+            0xE0 => { let offset = self.read_byte_pc(mmu); self.write(mmu, 0xFF00 + offset as u16, self.registers.a); 12 } // LDH (n), A
+            0xF0 => { let offset = self.read_byte_pc(mmu); self.registers.a = self.read(mmu, 0xFF00 + offset as u16); 12 } // LDH A, (n)
+            0xE2 => { self.write(mmu, 0xFF00 + self.registers.c as u16, self.registers.a); 8 } // LD (C), A
+            0xF2 => { self.registers.a = self.read(mmu, 0xFF00 + self.registers.c as u16); 8 } // LD A, (C)
+            0xEA => { let addr = self.read_word_pc(mmu); self.write(mmu, addr, self.registers.a); 16 } // LD (nn), A
+            0xFA => { let addr = self.read_word_pc(mmu); self.registers.a = self.read(mmu, addr); 16 } // LD A, (nn)
 
             // Misc
             0x00 => 4, // NOP
@@ -419,17 +606,19 @@ impl Cpu {
                 // Read and discard the next byte (always 0x00)
                 self.read_byte_pc(mmu);
 
+                // DIV is reset when entering STOP
+                mmu.reset_div();
+
                 // On GBC with KEY1 bit 0 set, this performs speed switching
-                // Otherwise, it acts like HALT (stops until interrupt)
-                let key1 = mmu.read_byte(0xFF4D);
-                if (key1 & 0x01) != 0 {
-                    // Speed switch requested - toggle speed and clear bit 0
-                    mmu.write_byte(0xFF4D, key1 ^ 0x80);
+                // Otherwise the system clock stops until a joypad input
+                if mmu.speed_switch() {
+                    // The CPU is paused for 2050 M-cycles while the clock settles
+                    8200
+                } else {
+                    self.stopped = true;
+                    mmu.enter_stop();
+                    4
                 }
-
-                // STOP always halts like HALT
-                self.halted = true;
-                4
             }
             0x76 => { self.halted = true; 4 } // HALT
             0xF3 => { self.ime = false; self.ime_scheduled = false; 4 } // DI
@@ -454,13 +643,15 @@ impl Cpu {
             0xFF => { self.push_stack(mmu, self.registers.pc); self.registers.pc = 0x38; 16 } // RST 38
 
             0xF9 => { self.registers.sp = self.registers.hl(); 8 } // LD SP, HL
-            0x08 => { let addr = self.read_word_pc(mmu); mmu.write_byte(addr, self.registers.sp as u8); mmu.write_byte(addr + 1, (self.registers.sp >> 8) as u8); 20 } // LD (nn), SP
+            0x08 => { let addr = self.read_word_pc(mmu); self.write(mmu, addr, self.registers.sp as u8); self.write(mmu, addr + 1, (self.registers.sp >> 8) as u8); 20 } // LD (nn), SP
             0xF8 => { let v = self.read_byte_pc(mmu) as i8; let result = self.registers.sp.wrapping_add(v as u16); self.registers.set_flag(Flag::Zero, false); self.registers.set_flag(Flag::Subtract, false); self.registers.set_flag(Flag::HalfCarry, ((self.registers.sp & 0x0F) + ((v as u16) & 0x0F)) > 0x0F); self.registers.set_flag(Flag::Carry, ((self.registers.sp & 0xFF) + ((v as u16) & 0xFF)) > 0xFF); self.registers.set_hl(result); 12 } // LD HL, SP+n
 
             0xCB => self.execute_cb(mmu),
 
-            _ => {
-                println!("Unknown opcode: 0x{:02X} at PC: 0x{:04X}", opcode, self.registers.pc - 1);
+            // Not part of the instruction set: the CPU stops fetching
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                self.registers.pc = self.registers.pc.wrapping_sub(1);
+                self.locked = Some(Lockup { opcode, address: self.registers.pc });
                 4
             }
         }
@@ -476,7 +667,7 @@ impl Cpu {
             0x03 => { self.registers.e = self.rlc_reg(self.registers.e); 8 }
             0x04 => { self.registers.h = self.rlc_reg(self.registers.h); 8 }
             0x05 => { self.registers.l = self.rlc_reg(self.registers.l); 8 }
-            0x06 => { let addr = self.registers.hl(); let v = self.rlc_reg(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x06 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.rlc_reg(v); self.write(mmu, addr, v); 16 }
             0x07 => { self.registers.a = self.rlc_reg(self.registers.a); 8 }
 
             // RRC - Rotate right with carry
@@ -486,7 +677,7 @@ impl Cpu {
             0x0B => { self.registers.e = self.rrc_reg(self.registers.e); 8 }
             0x0C => { self.registers.h = self.rrc_reg(self.registers.h); 8 }
             0x0D => { self.registers.l = self.rrc_reg(self.registers.l); 8 }
-            0x0E => { let addr = self.registers.hl(); let v = self.rrc_reg(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x0E => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.rrc_reg(v); self.write(mmu, addr, v); 16 }
             0x0F => { self.registers.a = self.rrc_reg(self.registers.a); 8 }
 
             // RL - Rotate left through carry
@@ -496,7 +687,7 @@ impl Cpu {
             0x13 => { self.registers.e = self.rl_reg_full(self.registers.e); 8 }
             0x14 => { self.registers.h = self.rl_reg_full(self.registers.h); 8 }
             0x15 => { self.registers.l = self.rl_reg_full(self.registers.l); 8 }
-            0x16 => { let addr = self.registers.hl(); let v = self.rl_reg_full(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x16 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.rl_reg_full(v); self.write(mmu, addr, v); 16 }
             0x17 => { self.registers.a = self.rl_reg_full(self.registers.a); 8 }
 
             // RR - Rotate right through carry
@@ -506,7 +697,7 @@ impl Cpu {
             0x1B => { self.registers.e = self.rr_reg_full(self.registers.e); 8 }
             0x1C => { self.registers.h = self.rr_reg_full(self.registers.h); 8 }
             0x1D => { self.registers.l = self.rr_reg_full(self.registers.l); 8 }
-            0x1E => { let addr = self.registers.hl(); let v = self.rr_reg_full(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x1E => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.rr_reg_full(v); self.write(mmu, addr, v); 16 }
             0x1F => { self.registers.a = self.rr_reg_full(self.registers.a); 8 }
 
             // SLA - Shift left arithmetic
@@ -516,7 +707,7 @@ impl Cpu {
             0x23 => { self.registers.e = self.sla(self.registers.e); 8 }
             0x24 => { self.registers.h = self.sla(self.registers.h); 8 }
             0x25 => { self.registers.l = self.sla(self.registers.l); 8 }
-            0x26 => { let addr = self.registers.hl(); let v = self.sla(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x26 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.sla(v); self.write(mmu, addr, v); 16 }
             0x27 => { self.registers.a = self.sla(self.registers.a); 8 }
 
             // SRA - Shift right arithmetic
@@ -526,7 +717,7 @@ impl Cpu {
             0x2B => { self.registers.e = self.sra(self.registers.e); 8 }
             0x2C => { self.registers.h = self.sra(self.registers.h); 8 }
             0x2D => { self.registers.l = self.sra(self.registers.l); 8 }
-            0x2E => { let addr = self.registers.hl(); let v = self.sra(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x2E => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.sra(v); self.write(mmu, addr, v); 16 }
             0x2F => { self.registers.a = self.sra(self.registers.a); 8 }
 
             // SWAP
@@ -536,7 +727,7 @@ impl Cpu {
             0x33 => { self.registers.e = self.swap(self.registers.e); 8 }
             0x34 => { self.registers.h = self.swap(self.registers.h); 8 }
             0x35 => { self.registers.l = self.swap(self.registers.l); 8 }
-            0x36 => { let addr = self.registers.hl(); let v = self.swap(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x36 => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.swap(v); self.write(mmu, addr, v); 16 }
             0x37 => { self.registers.a = self.swap(self.registers.a); 8 }
 
             // SRL - Shift right logical
@@ -546,7 +737,7 @@ impl Cpu {
             0x3B => { self.registers.e = self.srl(self.registers.e); 8 }
             0x3C => { self.registers.h = self.srl(self.registers.h); 8 }
             0x3D => { self.registers.l = self.srl(self.registers.l); 8 }
-            0x3E => { let addr = self.registers.hl(); let v = self.srl(mmu.read_byte(addr)); mmu.write_byte(addr, v); 16 }
+            0x3E => { let addr = self.registers.hl(); let v = self.read(mmu, addr); let v = self.srl(v); self.write(mmu, addr, v); 16 }
             0x3F => { self.registers.a = self.srl(self.registers.a); 8 }
 
             // BIT - Test bit
@@ -560,7 +751,7 @@ impl Cpu {
                     3 => self.registers.e,
                     4 => self.registers.h,
                     5 => self.registers.l,
-                    6 => mmu.read_byte(self.registers.hl()),
+                    6 => self.read(mmu, self.registers.hl()),
                     7 => self.registers.a,
                     _ => 0,
                 };
@@ -580,7 +771,7 @@ impl Cpu {
                     3 => { self.registers.e &= mask; 8 }
                     4 => { self.registers.h &= mask; 8 }
                     5 => { self.registers.l &= mask; 8 }
-                    6 => { let addr = self.registers.hl(); let v = mmu.read_byte(addr) & mask; mmu.write_byte(addr, v); 16 }
+                    6 => { let addr = self.registers.hl(); let v = self.read(mmu, addr) & mask; self.write(mmu, addr, v); 16 }
                     7 => { self.registers.a &= mask; 8 }
                     _ => 8,
                 }
@@ -598,7 +789,7 @@ impl Cpu {
                     3 => { self.registers.e |= mask; 8 }
                     4 => { self.registers.h |= mask; 8 }
                     5 => { self.registers.l |= mask; 8 }
-                    6 => { let addr = self.registers.hl(); let v = mmu.read_byte(addr) | mask; mmu.write_byte(addr, v); 16 }
+                    6 => { let addr = self.registers.hl(); let v = self.read(mmu, addr) | mask; self.write(mmu, addr, v); 16 }
                     7 => { self.registers.a |= mask; 8 }
                     _ => 8,
                 }
@@ -607,8 +798,42 @@ impl Cpu {
     }
 
     // Helper methods
+
+    // Advances the rest of the system by one M-cycle
+    fn tick(&mut self, mmu: &mut crate::mmu::Mmu) {
+        self.cycles += 4;
+        mmu.tick(4);
+    }
+
+    // Memory accesses take one M-cycle each; the system is ticked first so
+    // the access sees the PPU/timer state at the end of that cycle
+    fn read(&mut self, mmu: &mut crate::mmu::Mmu, address: u16) -> u8 {
+        self.tick(mmu);
+        if address < 0x8000 && mmu.cdl.is_some() {
+            mmu.log_rom_access(address, cdl::DATA);
+        }
+        mmu.read_byte(address)
+    }
+
+    fn write(&mut self, mmu: &mut crate::mmu::Mmu, address: u16, value: u8) {
+        self.tick(mmu);
+        mmu.write_byte(address, value);
+    }
+
     fn read_byte_pc(&mut self, mmu: &mut crate::mmu::Mmu) -> u8 {
-        let byte = mmu.read_byte(self.registers.pc);
+        if self.operands_fetched < self.operands_cached {
+            let byte = self.operands[self.operands_fetched as usize];
+            self.operands_fetched += 1;
+            self.tick(mmu);
+            self.registers.pc = self.registers.pc.wrapping_add(1);
+            return byte;
+        }
+        let pc = self.registers.pc;
+        self.tick(mmu);
+        if pc < 0x8000 && mmu.cdl.is_some() {
+            mmu.log_rom_access(pc, cdl::CODE);
+        }
+        let byte = mmu.read_byte(pc);
         self.registers.pc = self.registers.pc.wrapping_add(1);
         byte
     }
@@ -619,17 +844,19 @@ impl Cpu {
         (high << 8) | low
     }
 
+    // Every push is preceded by an internal M-cycle (PUSH, CALL, RST)
     fn push_stack(&mut self, mmu: &mut crate::mmu::Mmu, value: u16) {
+        self.tick(mmu);
         self.registers.sp = self.registers.sp.wrapping_sub(1);
-        mmu.write_byte(self.registers.sp, (value >> 8) as u8);
+        self.write(mmu, self.registers.sp, (value >> 8) as u8);
         self.registers.sp = self.registers.sp.wrapping_sub(1);
-        mmu.write_byte(self.registers.sp, value as u8);
+        self.write(mmu, self.registers.sp, value as u8);
     }
 
     fn pop_stack(&mut self, mmu: &mut crate::mmu::Mmu) -> u16 {
-        let low = mmu.read_byte(self.registers.sp) as u16;
+        let low = self.read(mmu, self.registers.sp) as u16;
         self.registers.sp = self.registers.sp.wrapping_add(1);
-        let high = mmu.read_byte(self.registers.sp) as u16;
+        let high = self.read(mmu, self.registers.sp) as u16;
         self.registers.sp = self.registers.sp.wrapping_add(1);
         (high << 8) | low
     }
@@ -907,4 +1134,40 @@ impl Cpu {
         self.registers.set_flag(Flag::Carry, (a as u16) < (value as u16) + (carry as u16));
         self.registers.a = result;
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        for value in [r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.f] {
+            w.u8(value);
+        }
+        w.u16(r.sp);
+        w.u16(r.pc);
+        w.bool(self.halted);
+        w.bool(self.stopped);
+        w.bool(self.ime);
+        w.bool(self.ime_scheduled);
+        w.bool(self.locked.is_some());
+        let lockup = self.locked.unwrap_or(Lockup { opcode: 0, address: 0 });
+        w.u8(lockup.opcode);
+        w.u16(lockup.address);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        let regs = &mut self.registers;
+        for value in [&mut regs.a, &mut regs.b, &mut regs.c, &mut regs.d, &mut regs.e, &mut regs.h, &mut regs.l] {
+            *value = r.u8();
+        }
+        regs.f = r.u8() & 0xF0;
+        regs.sp = r.u16();
+        regs.pc = r.u16();
+        self.halted = r.bool();
+        self.stopped = r.bool();
+        self.ime = r.bool();
+        self.ime_scheduled = r.bool();
+        let locked = r.bool();
+        let lockup = Lockup { opcode: r.u8(), address: r.u16() };
+        self.locked = locked.then_some(lockup);
+        // Calls made before the state was saved are unknown
+        self.call_stack.clear();
+    }
 }
\ No newline at end of file
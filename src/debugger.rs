@@ -0,0 +1,491 @@
+// Console debugger. Commands are typed into the terminal while the game keeps
+// running and are executed between frames.
+
+use std::sync::mpsc::{self, Receiver};
+
+use crate::emulator::{Breakpoint, Emulator};
+use crate::png;
+use crate::profiler::Profiler;
+use crate::symbols::Symbols;
+use crate::vram_viewer;
+
+const PAGE_SIZE: u16 = 0x100;
+
+// Named memory regions for the memory viewer
+const REGIONS: [(&str, u16); 9] = [
+    ("rom0", 0x0000),
+    ("romx", 0x4000),
+    ("vram", 0x8000),
+    ("sram", 0xA000),
+    ("wram", 0xC000),
+    ("wramx", 0xD000),
+    ("oam", 0xFE00),
+    ("io", 0xFF00),
+    ("hram", 0xFF80),
+];
+
+// I/O register names accepted by the I/O watchpoint commands
+const IO_REGISTERS: [(&str, u16); 37] = [
+    ("P1", 0xFF00),
+    ("SB", 0xFF01),
+    ("SC", 0xFF02),
+    ("DIV", 0xFF04),
+    ("TIMA", 0xFF05),
+    ("TMA", 0xFF06),
+    ("TAC", 0xFF07),
+    ("IF", 0xFF0F),
+    ("NR10", 0xFF10),
+    ("NR11", 0xFF11),
+    ("NR12", 0xFF12),
+    ("NR14", 0xFF14),
+    ("NR50", 0xFF24),
+    ("NR51", 0xFF25),
+    ("NR52", 0xFF26),
+    ("LCDC", 0xFF40),
+    ("STAT", 0xFF41),
+    ("SCY", 0xFF42),
+    ("SCX", 0xFF43),
+    ("LY", 0xFF44),
+    ("LYC", 0xFF45),
+    ("DMA", 0xFF46),
+    ("BGP", 0xFF47),
+    ("OBP0", 0xFF48),
+    ("OBP1", 0xFF49),
+    ("WY", 0xFF4A),
+    ("WX", 0xFF4B),
+    ("KEY1", 0xFF4D),
+    ("VBK", 0xFF4F),
+    ("HDMA5", 0xFF55),
+    ("BCPS", 0xFF68),
+    ("BCPD", 0xFF69),
+    ("OCPS", 0xFF6A),
+    ("OCPD", 0xFF6B),
+    ("SVBK", 0xFF70),
+    ("PCM12", 0xFF76),
+    ("PCM34", 0xFF77),
+];
+
+// A memory range printed again whenever its contents change
+struct Watch {
+    address: u16,
+    len: u16,
+    last: Vec<u8>,
+}
+
+// Changes to the run state asked for by a command
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RunRequest {
+    Pause,
+    Resume,
+}
+
+pub struct Debugger {
+    commands: Receiver<String>,
+    view: u16, // Start of the memory viewer page
+    watches: Vec<Watch>,
+    pub symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new(symbols: Symbols) -> Self {
+        // Read the terminal on its own thread so emulation never blocks on it
+        let (sender, commands) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else { break };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        println!("Debugger enabled, type 'help' for commands");
+        Debugger {
+            commands,
+            view: 0xC000,
+            watches: Vec::new(),
+            symbols,
+        }
+    }
+
+    // Runs pending commands and refreshes watches; called once per frame.
+    // Returns the last pause/resume request made by the commands.
+    pub fn update(&mut self, emu: &mut Emulator) -> Option<RunRequest> {
+        let mut request = None;
+        while let Ok(line) = self.commands.try_recv() {
+            if let Some(r) = self.execute(line.trim(), emu) {
+                request = Some(r);
+            }
+        }
+
+        for watch in &mut self.watches {
+            let current = read_range(emu, watch.address, watch.len);
+            if current != watch.last {
+                dump(emu, watch.address, watch.len);
+                watch.last = current;
+            }
+        }
+        request
+    }
+
+    // Address with its label, if the symbol file has one
+    pub fn location(&self, emu: &Emulator, address: u16) -> String {
+        self.symbols.format(emu.bank_of(address), address)
+    }
+
+    // Reports where emulation stopped after a breakpoint or I/O watchpoint
+    pub fn report_break(&self, emu: &Emulator) {
+        match emu.io_break {
+            Some((pc, access)) => println!(
+                "I/O {} {} = ${:02X} by instruction at {}",
+                if access.write { "write" } else { "read" },
+                io_name(access.address),
+                access.value,
+                self.location(emu, pc)
+            ),
+            None => match emu.cpu.locked {
+                Some(lockup) => println!("CPU locked up by illegal opcode ${:02X} at {}", lockup.opcode, self.location(emu, lockup.address)),
+                None => println!("Breakpoint at {}", self.location(emu, emu.cpu.registers.pc)),
+            },
+        }
+        self.print_registers(emu);
+    }
+
+    fn print_registers(&self, emu: &Emulator) {
+        let r = &emu.cpu.registers;
+        println!(
+            "PC={} SP=${:04X} AF=${:04X} BC=${:04X} DE=${:04X} HL=${:04X} IME={}{}",
+            self.location(emu, r.pc),
+            r.sp,
+            r.af(),
+            r.bc(),
+            r.de(),
+            r.hl(),
+            emu.cpu.ime as u8,
+            if emu.cpu.locked.is_some() {
+                " (locked)"
+            } else if emu.cpu.halted {
+                " (halted)"
+            } else {
+                ""
+            }
+        );
+    }
+
+    // Parses "C000", "$C000", "BB:AAAA" or a label into a breakpoint
+    fn parse_breakpoint(&self, text: &str) -> Option<Breakpoint> {
+        if let Some((bank, address)) = self.symbols.lookup(text) {
+            let bank = (0x4000..0x8000).contains(&address).then_some(bank);
+            return Some(Breakpoint { bank, address });
+        }
+        if let Some((bank, address)) = text.split_once(':') {
+            let bank = u8::from_str_radix(bank, 16).ok()?;
+            return Some(Breakpoint { bank: Some(bank), address: parse_address(address)? });
+        }
+        Some(Breakpoint { bank: None, address: parse_address(text)? })
+    }
+
+    fn execute(&mut self, line: &str, emu: &mut Emulator) -> Option<RunRequest> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let &command = args.first()?;
+
+        match command {
+            "c" | "continue" => return Some(RunRequest::Resume),
+            "pause" => {
+                self.print_registers(emu);
+                return Some(RunRequest::Pause);
+            }
+            "s" | "step" => {
+                emu.step_instruction();
+                self.print_registers(emu);
+                return Some(RunRequest::Pause);
+            }
+            "r" | "regs" => self.print_registers(emu),
+            "b" | "break" => match args.get(1).and_then(|a| self.parse_breakpoint(a)) {
+                Some(breakpoint) => {
+                    let bank = breakpoint.bank.map_or(String::new(), |b| format!(" in bank {}", b));
+                    println!("Breakpoint {} at {}{}", emu.breakpoints.len(), self.location(emu, breakpoint.address), bank);
+                    emu.breakpoints.push(breakpoint);
+                }
+                None => eprintln!("Usage: b <address|bank:address|label>"),
+            },
+            "bl" => {
+                for (i, b) in emu.breakpoints.iter().enumerate() {
+                    let bank = b.bank.unwrap_or(0);
+                    println!("{}: {}", i, self.symbols.format(bank, b.address));
+                }
+                for bit in 0..0x80 {
+                    let read = emu.mmu.io_watch_read & (1 << bit) != 0;
+                    let write = emu.mmu.io_watch_write & (1 << bit) != 0;
+                    if read || write {
+                        let mode = match (read, write) {
+                            (true, true) => "read/write",
+                            (true, false) => "read",
+                            _ => "write",
+                        };
+                        println!("I/O {} on {}", mode, io_name(0xFF00 | bit));
+                    }
+                }
+            }
+            "bio" => {
+                let Some(address) = args.get(1).and_then(|a| parse_io_register(a)) else {
+                    eprintln!("Usage: bio <register> [r|w|rw]");
+                    return None;
+                };
+                let bit = 1u128 << (address & 0x7F);
+                let mode = args.get(2).copied().unwrap_or("w");
+                if mode.contains('r') {
+                    emu.mmu.io_watch_read |= bit;
+                }
+                if mode.contains('w') {
+                    emu.mmu.io_watch_write |= bit;
+                }
+                println!("Watching {} on {}", mode, io_name(address));
+            }
+            "biod" => match args.get(1) {
+                Some(&"all") => {
+                    emu.mmu.io_watch_read = 0;
+                    emu.mmu.io_watch_write = 0;
+                }
+                Some(arg) => match parse_io_register(arg) {
+                    Some(address) => {
+                        let bit = 1u128 << (address & 0x7F);
+                        emu.mmu.io_watch_read &= !bit;
+                        emu.mmu.io_watch_write &= !bit;
+                    }
+                    None => eprintln!("Unknown I/O register: {}", arg),
+                },
+                None => eprintln!("Usage: biod <register|all>"),
+            },
+            "bd" => match args.get(1) {
+                Some(&"all") => emu.breakpoints.clear(),
+                Some(n) => match n.parse::<usize>() {
+                    Ok(i) if i < emu.breakpoints.len() => {
+                        emu.breakpoints.remove(i);
+                    }
+                    _ => eprintln!("No breakpoint {}", n),
+                },
+                None => eprintln!("Usage: bd <number|all>"),
+            },
+            "bt" => {
+                if emu.cpu.call_stack.is_empty() {
+                    println!("Call stack is empty");
+                }
+                for (depth, frame) in emu.cpu.call_stack.iter().rev().enumerate() {
+                    println!(
+                        "#{:<2} {}{} (returns to {})",
+                        depth,
+                        self.symbols.format(frame.bank, frame.target),
+                        if frame.interrupt { " [interrupt]" } else { "" },
+                        self.location(emu, frame.return_address)
+                    );
+                }
+            }
+            "prof" => match args.get(1).copied() {
+                Some("start") => {
+                    let sample_every = args.get(2).and_then(|a| a.parse().ok()).unwrap_or(1);
+                    emu.profiler = Some(Profiler::new(sample_every));
+                    if sample_every > 1 {
+                        println!("Profiling every {} instructions", sample_every);
+                    } else {
+                        println!("Profiling every instruction");
+                    }
+                }
+                Some("stop") => match emu.profiler.take() {
+                    Some(profiler) => profiler.report(&self.symbols, 20),
+                    None => eprintln!("Profiler is not running"),
+                },
+                Some("report") => match emu.profiler {
+                    Some(ref profiler) => {
+                        let count = args.get(2).and_then(|a| a.parse().ok()).unwrap_or(20);
+                        profiler.report(&self.symbols, count);
+                    }
+                    None => eprintln!("Profiler is not running"),
+                },
+                _ => eprintln!("Usage: prof start [interval] | report [count] | stop"),
+            },
+            "sym" => match args.get(1) {
+                Some(path) => match Symbols::load(std::path::Path::new(path)) {
+                    Ok(symbols) => {
+                        println!("Loaded {} symbols from {}", symbols.len(), path);
+                        self.symbols = symbols;
+                    }
+                    Err(e) => eprintln!("Failed to load {}: {}", path, e),
+                },
+                None => eprintln!("Usage: sym <file.sym>"),
+            },
+            "help" | "h" => print_help(),
+            "m" | "mem" => {
+                if let Some(arg) = args.get(1) {
+                    match parse_address(arg) {
+                        Some(address) => self.view = address,
+                        None => {
+                            eprintln!("Invalid address or region: {}", arg);
+                            return None;
+                        }
+                    }
+                }
+                dump(emu, self.view, PAGE_SIZE);
+            }
+            "n" | "next" => {
+                self.view = self.view.wrapping_add(PAGE_SIZE);
+                dump(emu, self.view, PAGE_SIZE);
+            }
+            "p" | "prev" => {
+                self.view = self.view.wrapping_sub(PAGE_SIZE);
+                dump(emu, self.view, PAGE_SIZE);
+            }
+            "w" | "poke" => {
+                // Writes go through the bus like CPU writes
+                let Some(address) = args.get(1).and_then(|a| parse_address(a)) else {
+                    eprintln!("Usage: w <address> <byte> [byte...]");
+                    return None;
+                };
+                for (i, arg) in args[2..].iter().enumerate() {
+                    match parse_byte(arg) {
+                        Some(value) => emu.mmu.write_byte(address.wrapping_add(i as u16), value),
+                        None => {
+                            eprintln!("Invalid byte: {}", arg);
+                            return None;
+                        }
+                    }
+                }
+                dump(emu, address & 0xFFF0, 0x10);
+            }
+            "watch" => {
+                let Some(address) = args.get(1).and_then(|a| parse_address(a)) else {
+                    eprintln!("Usage: watch <address> [length]");
+                    return None;
+                };
+                let len = args.get(2).and_then(|a| parse_address(a)).unwrap_or(0x10).max(1);
+                let last = read_range(emu, address, len);
+                dump(emu, address, len);
+                self.watches.push(Watch { address, len, last });
+            }
+            "vram" => {
+                let Some(path) = args.get(1) else {
+                    eprintln!("Usage: vram <file.png> [overlay]");
+                    return None;
+                };
+                let mut buffer = vec![0; vram_viewer::WIDTH * vram_viewer::HEIGHT];
+                vram_viewer::render(&emu.mmu.ppu, args.get(2) == Some(&"overlay"), &mut buffer);
+                match png::write(std::path::Path::new(path), &buffer, vram_viewer::WIDTH, vram_viewer::HEIGHT) {
+                    Ok(()) => println!("VRAM dumped to {}", path),
+                    Err(e) => eprintln!("Failed to write {}: {}", path, e),
+                }
+            }
+            "apu" => print_apu(emu),
+            "unwatch" => {
+                self.watches.clear();
+                println!("Watches cleared");
+            }
+            _ => eprintln!("Unknown command '{}', type 'help' for commands", command),
+        }
+        None
+    }
+}
+
+fn print_help() {
+    println!("Debugger commands (addresses in hex, breakpoints also take labels):");
+    println!("  c / pause                  Continue / pause emulation");
+    println!("  s                          Execute one instruction");
+    println!("  r                          Show CPU registers");
+    println!("  b <address|bank:address|label>  Add a breakpoint");
+    println!("  bl / bd <n|all>            List / delete breakpoints");
+    println!("  bio <register> [r|w|rw]    Break on I/O register reads/writes (default w)");
+    println!("  biod <register|all>        Remove I/O watchpoints");
+    println!("  bt                         Show the call stack");
+    println!("  prof start [interval]      Profile every (or every Nth) instruction");
+    println!("  prof report [count] / stop Show the hottest functions / stop and show them");
+    println!("  sym <file.sym>             Load an RGBDS symbol file");
+    println!("  m [address|region]         Show a 256-byte memory page");
+    println!("  n / p                      Next / previous page");
+    println!("  w <address> <bytes...>     Write bytes to memory");
+    println!("  watch <address> [len]      Print a range whenever it changes");
+    println!("  unwatch                    Remove all watches");
+    println!("  vram <file.png> [overlay]  Save the VRAM viewer image");
+    println!("  apu                        Show the state of each sound channel");
+    let names: Vec<&str> = REGIONS.iter().map(|(name, _)| *name).collect();
+    println!("Regions: {}", names.join(", "));
+}
+
+fn print_apu(emu: &Emulator) {
+    const DUTY: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+    const NAMES: [&str; 4] = ["Square 1", "Square 2", "Wave", "Noise"];
+    const WAVE_LEVEL: [&str; 4] = ["mute", "100%", "50%", "25%"];
+
+    // NR52 as the game reads it: power and the channels playing
+    let nr52 = emu.mmu.apu.read_register(0xFF26);
+    println!("NR52 ${:02X}: sound {}", nr52, if (nr52 & 0x80) != 0 { "on" } else { "off" });
+    for (i, ch) in emu.mmu.apu.channel_status().iter().enumerate() {
+        let duty = ch.duty.map_or(String::new(), |d| format!(" duty {}", DUTY[d as usize]));
+        let volume = if i == 2 {
+            format!("level {}", WAVE_LEVEL[ch.volume as usize])
+        } else {
+            format!("volume {:2}", ch.volume)
+        };
+        let envelope = ch.envelope.map_or(String::new(), |(up, period)| {
+            format!(" envelope {} {}", if up { "up" } else { "down" }, period)
+        });
+        println!(
+            "{} {:<8} {}: {:8.1} Hz{} {}{} length {}{} output {}",
+            i + 1,
+            NAMES[i],
+            if ch.enabled { "on " } else { "off" },
+            ch.frequency,
+            duty,
+            volume,
+            envelope,
+            ch.length,
+            if ch.length_enabled { "" } else { " (disabled)" },
+            ch.output
+        );
+    }
+}
+
+// Parses a hex address ("C000", "$C000", "0xC000") or a region name
+fn parse_address(text: &str) -> Option<u16> {
+    if let Some(&(_, address)) = REGIONS.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+        return Some(address);
+    }
+    let hex = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).ok()
+}
+
+// Parses an I/O register name ("LCDC") or address (FF40-FF7F)
+fn parse_io_register(text: &str) -> Option<u16> {
+    if let Some(&(_, address)) = IO_REGISTERS.iter().find(|(name, _)| name.eq_ignore_ascii_case(text)) {
+        return Some(address);
+    }
+    parse_address(text).filter(|a| (0xFF00..=0xFF7F).contains(a))
+}
+
+// "LCDC ($FF40)", or just the address for unnamed registers
+fn io_name(address: u16) -> String {
+    match IO_REGISTERS.iter().find(|(_, a)| *a == address) {
+        Some((name, _)) => format!("{} (${:04X})", name, address),
+        None => format!("${:04X}", address),
+    }
+}
+
+fn parse_byte(text: &str) -> Option<u8> {
+    let hex = text.trim_start_matches('$').trim_start_matches("0x");
+    u8::from_str_radix(hex, 16).ok()
+}
+
+fn read_range(emu: &Emulator, address: u16, len: u16) -> Vec<u8> {
+    (0..len).map(|i| emu.mmu.read_byte(address.wrapping_add(i))).collect()
+}
+
+// Prints memory as hex and ASCII, 16 bytes per row
+fn dump(emu: &Emulator, address: u16, len: u16) {
+    let bytes = read_range(emu, address, len);
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' })
+            .collect();
+        println!("{:04X}: {:<47} |{}|", address.wrapping_add(row as u16 * 16), hex.join(" "), ascii);
+    }
+}
@@ -0,0 +1,359 @@
+// The emulated system: CPU plus everything behind the MMU
+
+use std::collections::HashSet;
+
+use log::warn;
+
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::joypad::JoypadState;
+use crate::mmu::{MemoryAccess, Mmu};
+use crate::pacer;
+use crate::palette;
+use crate::profiler::Profiler;
+
+// Hashes of a deterministic run, for comparing two runs of the same input
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RunHashes {
+    pub frame: u64, // Final framebuffer
+    pub audio: u64, // Every stereo sample produced
+}
+
+// Stops emulation before the instruction at `address` is executed. `bank`
+// restricts 0x4000-0x7FFF breakpoints to one ROM bank.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Breakpoint {
+    pub bank: Option<u8>,
+    pub address: u16,
+}
+
+// Receives each completed frame (160x144, 0xRRGGBB)
+pub type FrameCallback = Box<dyn FnMut(&[u32]) + Send>;
+// Receives the samples of each run, as Apu::take_samples() returns them
+pub type AudioCallback = Box<dyn FnMut(&[f32]) + Send>;
+
+// Console to emulate: the DMG, the CGB (running DMG games in compatibility
+// mode) or the one the cartridge header asks for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+    Auto,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "dmg" | "gb" => Some(Model::Dmg),
+            "cgb" | "gbc" => Some(Model::Cgb),
+            "auto" => Some(Model::Auto),
+            _ => None,
+        }
+    }
+
+    // Whether the cartridge runs on a CGB
+    pub fn is_gbc(self, cartridge: &Cartridge) -> bool {
+        match self {
+            Model::Dmg => false,
+            Model::Cgb => true,
+            Model::Auto => cartridge.supports_cgb(),
+        }
+    }
+}
+
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub mmu: Mmu,
+    pub breakpoints: Vec<Breakpoint>,
+    pub break_hit: bool, // Stopped at an instruction boundary by a breakpoint or single step
+    pub io_break: Option<(u16, MemoryAccess)>, // Watched I/O access that stopped run_frame(), with the PC of its instruction
+    pub break_on_lockup: bool, // Stop run_frame() when an illegal opcode hangs the CPU
+    pub profiler: Option<Profiler>,
+    echo_ram_warned: HashSet<u16>, // Instructions already reported for accessing echo RAM
+    frame_callback: Option<FrameCallback>,
+    audio_callback: Option<AudioCallback>,
+}
+
+impl Emulator {
+    pub fn new(cartridge: Cartridge, is_gbc: bool) -> Self {
+        let mmu = Mmu::new(cartridge, is_gbc);
+        let cpu = if mmu.dmg_compat() {
+            Cpu::new_dmg_compat(palette::boot_rom_checksum(mmu.cartridge.header()).unwrap_or(0))
+        } else if is_gbc {
+            Cpu::new_gbc()
+        } else {
+            Cpu::new()
+        };
+        Emulator {
+            cpu,
+            mmu,
+            breakpoints: Vec::new(),
+            break_hit: false,
+            io_break: None,
+            break_on_lockup: false,
+            profiler: None,
+            echo_ram_warned: HashSet::new(),
+            frame_callback: None,
+            audio_callback: None,
+        }
+    }
+
+    // Runs `program` as a ROM-only cartridge in DMG mode, from `start` or the
+    // usual entry point 0x0100. See Cartridge::from_program().
+    pub fn with_program(program: &[u8], start: Option<u16>) -> Self {
+        let mut emu = Emulator::new(Cartridge::from_program(program), false);
+        if let Some(address) = start {
+            emu.cpu.registers.pc = address;
+        }
+        emu
+    }
+
+    // Calls `callback` with each frame run_frame() completes, so embedders
+    // don't have to check Ppu::frame_ready themselves
+    pub fn set_frame_callback(&mut self, callback: impl FnMut(&[u32]) + Send + 'static) {
+        self.frame_callback = Some(Box::new(callback));
+    }
+
+    // Calls `callback` with the audio produced by each run_frame(). The
+    // samples are handed over, so Apu::take_samples() no longer returns them.
+    pub fn set_audio_callback(&mut self, callback: impl FnMut(&[f32]) + Send + 'static) {
+        self.audio_callback = Some(Box::new(callback));
+    }
+
+    pub fn clear_callbacks(&mut self) {
+        self.frame_callback = None;
+        self.audio_callback = None;
+    }
+
+    // Hands the frame and audio of the last run to the callbacks
+    pub(crate) fn deliver_output(&mut self) {
+        if let Some(ref mut callback) = self.frame_callback {
+            if let Some(frame) = self.mmu.ppu.take_frame() {
+                callback(frame);
+            }
+        }
+        if let Some(ref mut callback) = self.audio_callback {
+            let samples = self.mmu.apu.take_samples();
+            if !samples.is_empty() {
+                callback(&samples);
+            }
+        }
+    }
+
+    // ROM bank an address currently maps to (0 outside switchable ROM)
+    pub fn bank_of(&self, address: u16) -> u8 {
+        match address {
+            0x4000..=0x7FFF => self.mmu.cartridge.rom_bank() as u8,
+            _ => 0,
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.cpu.registers.pc;
+        self.breakpoints
+            .iter()
+            .any(|b| b.address == pc && b.bank.is_none_or(|bank| bank == self.bank_of(pc)))
+    }
+
+    // Executes a single instruction. The next run_frame() starts with the
+    // following instruction even if it has a breakpoint.
+    pub fn step_instruction(&mut self) -> u32 {
+        let cycles = self.step();
+        self.break_hit = true;
+        cycles
+    }
+
+    // Reports an echo RAM access, once per instruction address
+    fn warn_echo_ram(&mut self, pc: u16, access: MemoryAccess) {
+        if self.echo_ram_warned.insert(pc) {
+            warn!(
+                "Echo RAM {} 0x{:04X} by the instruction at 0x{:04X} (mirror of 0x{:04X})",
+                if access.write { "write to" } else { "read from" },
+                access.address,
+                pc,
+                access.address - 0x2000
+            );
+        }
+    }
+
+    // Executes one CPU step, feeding the profiler when it is running
+    pub(crate) fn step(&mut self) -> u32 {
+        let Some(ref mut profiler) = self.profiler else {
+            return self.cpu.step(&mut self.mmu);
+        };
+        let pc = self.cpu.registers.pc;
+        let bank = match pc {
+            0x4000..=0x7FFF => self.mmu.cartridge.rom_bank() as u8,
+            _ => 0,
+        };
+        let cycles = self.cpu.step(&mut self.mmu);
+        profiler.record((bank, pc), cycles, &self.cpu.call_stack);
+        cycles
+    }
+
+    // Runs the CPU until the PPU completes a frame and returns the number of
+    // normal-speed cycles emulated. With the LCD off no frame is ever produced,
+    // so a frame's worth of cycles is used instead. Stops early, with
+    // `break_hit` set, when a breakpoint is reached, after an instruction that
+    // hit an I/O watchpoint, or when the CPU locks up if `break_on_lockup` is set.
+    pub fn run_frame(&mut self) -> u32 {
+        self.run_frame_with(&mut |_, _| {})
+    }
+
+    // Like run_frame(), calling `on_access` after each instruction for the
+    // accesses it made to addresses hooked with Mmu::add_hook()
+    pub fn run_frame_with(&mut self, on_access: &mut dyn FnMut(&mut Emulator, MemoryAccess)) -> u32 {
+        let cycles = self.run_until_frame(on_access);
+        self.deliver_output();
+        cycles
+    }
+
+    fn run_until_frame(&mut self, on_access: &mut dyn FnMut(&mut Emulator, MemoryAccess)) -> u32 {
+        self.mmu.ppu.frame_ready = false;
+        let mut cycles_this_frame = 0;
+
+        // When resuming from a breakpoint, its instruction runs first
+        let mut resuming = self.break_hit;
+        self.break_hit = false;
+        self.io_break = None;
+        // Drop accesses made by the debugger or scripts between frames
+        self.mmu.take_io_hit();
+        self.mmu.take_echo_hit();
+        self.mmu.take_hook_hits();
+
+        loop {
+            if !resuming && !self.cpu.halted && self.cpu.locked.is_none() && !self.breakpoints.is_empty() && self.at_breakpoint() {
+                self.break_hit = true;
+                break;
+            }
+            resuming = false;
+
+            let pc = self.cpu.registers.pc;
+            let was_locked = self.cpu.locked.is_some();
+            let cycles = self.step();
+
+            if self.break_on_lockup && !was_locked && self.cpu.locked.is_some() {
+                self.break_hit = true;
+                return cycles_this_frame + self.mmu.normal_speed_cycles(cycles);
+            }
+
+            if let Some(access) = self.mmu.take_echo_hit() {
+                self.warn_echo_ram(pc, access);
+            }
+
+            if let Some(access) = self.mmu.take_io_hit() {
+                self.io_break = Some((pc, access));
+                self.break_hit = true;
+                return cycles_this_frame + self.mmu.normal_speed_cycles(cycles);
+            }
+
+            if self.mmu.has_hooks() {
+                for access in self.mmu.take_hook_hits() {
+                    on_access(self, access);
+                }
+            }
+
+            // In STOP mode the system clock is halted: nothing else advances
+            if self.cpu.stopped {
+                cycles_this_frame += cycles;
+                if cycles_this_frame >= pacer::CYCLES_PER_FRAME {
+                    break;
+                }
+                continue;
+            }
+
+            // The CPU has already ticked the rest of the system during the
+            // instruction; frames are counted in normal-speed cycles
+            let cycles = self.mmu.normal_speed_cycles(cycles);
+
+            cycles_this_frame += cycles;
+
+            let lcd_on = (self.mmu.ppu.lcdc & 0x80) != 0;
+            if self.mmu.ppu.frame_ready || (!lcd_on && cycles_this_frame >= pacer::CYCLES_PER_FRAME) {
+                break;
+            }
+        }
+        cycles_this_frame
+    }
+
+    // Hash of the last completed frame
+    pub fn frame_hash(&self) -> u64 {
+        let mut hash = Fnv::new();
+        for &pixel in self.mmu.ppu.frame() {
+            hash.write(&pixel.to_le_bytes());
+        }
+        hash.0
+    }
+
+    // Runs one frame per input from the
+    // current state, without any wall-clock dependency: battery RAM starts
+    // empty and is not saved, and the RTC follows emulated time. Should be
+    // called on a freshly created emulator so identical inputs always produce
+    // identical hashes.
+    pub fn run_deterministic(&mut self, inputs: &[JoypadState]) -> RunHashes {
+        self.mmu.cartridge.detach_save();
+        self.mmu.cartridge.use_emulated_clock();
+        self.mmu.apu.set_capture(true);
+
+        let mut audio = Fnv::new();
+        for &buttons in inputs {
+            self.mmu.joypad.set_state(buttons);
+            self.run_frame();
+            for sample in self.mmu.apu.take_captured() {
+                audio.write(&sample.to_bits().to_le_bytes());
+            }
+        }
+        self.mmu.apu.set_capture(false);
+
+        RunHashes { frame: self.frame_hash(), audio: audio.0 }
+    }
+}
+
+// 64-bit FNV-1a
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xCBF29CE484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001B3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Plays a note on channel 2, then copies the d-pad bits of the joypad
+    // register into BGP forever, so both hashes depend on what runs
+    const PROGRAM: [u8; 30] = [
+        0x3E, 0x77, 0xE0, 0x24, // ld a, $77 / ldh [NR50], a
+        0x3E, 0xFF, 0xE0, 0x25, // ld a, $FF / ldh [NR51], a
+        0x3E, 0xF0, 0xE0, 0x17, // ld a, $F0 / ldh [NR22], a
+        0x3E, 0x87, 0xE0, 0x19, // ld a, $87 / ldh [NR24], a
+        0x3E, 0x20, 0xE0, 0x00, // ld a, $20 / ldh [P1], a
+        0xF0, 0x00, 0xE0, 0x47, // loop: ldh a, [P1] / ldh [BGP], a
+        0x00, 0x00, 0x00, 0x00, // nop
+        0x18, 0xF6, // jr loop
+    ];
+
+    fn inputs(pressed: u8) -> Vec<JoypadState> {
+        (0..60u8).map(|frame| JoypadState::from_bits(if frame % 20 >= 10 { pressed } else { 0 })).collect()
+    }
+
+    #[test]
+    fn deterministic_runs_match() {
+        let first = Emulator::with_program(&PROGRAM, Some(0)).run_deterministic(&inputs(0x01));
+        let second = Emulator::with_program(&PROGRAM, Some(0)).run_deterministic(&inputs(0x01));
+        assert_eq!(first, second);
+
+        // Left instead of right held at the end, another BGP
+        let other = Emulator::with_program(&PROGRAM, Some(0)).run_deterministic(&inputs(0x02));
+        assert_ne!(first.frame, other.frame);
+    }
+}
@@ -0,0 +1,118 @@
+// Tiny 3x5 pixel font for text drawn over the screen. Lowercase letters are
+// drawn as uppercase.
+
+pub const GLYPH_WIDTH: i32 = 3;
+pub const GLYPH_HEIGHT: i32 = 5;
+pub const ADVANCE: i32 = GLYPH_WIDTH + 1;
+
+// Characters 0x20-0x60 followed by { | } ~, one bit per pixel, rows top to
+// bottom with the leftmost pixel in the highest bit
+const GLYPHS: [u16; 69] = [
+    0b000_000_000_000_000, // Space
+    0b010_010_010_000_010, // !
+    0b101_101_000_000_000, // "
+    0b101_111_101_111_101, // #
+    0b011_110_010_011_110, // $
+    0b101_001_010_100_101, // %
+    0b010_101_010_101_011, // &
+    0b010_010_000_000_000, // '
+    0b001_010_010_010_001, // (
+    0b100_010_010_010_100, // )
+    0b000_101_010_101_000, // *
+    0b000_010_111_010_000, // +
+    0b000_000_000_010_100, // ,
+    0b000_000_111_000_000, // -
+    0b000_000_000_000_010, // .
+    0b001_001_010_100_100, // /
+    0b111_101_101_101_111, // 0
+    0b010_110_010_010_111, // 1
+    0b111_001_111_100_111, // 2
+    0b111_001_111_001_111, // 3
+    0b101_101_111_001_001, // 4
+    0b111_100_111_001_111, // 5
+    0b111_100_111_101_111, // 6
+    0b111_001_001_010_010, // 7
+    0b111_101_111_101_111, // 8
+    0b111_101_111_001_111, // 9
+    0b000_010_000_010_000, // :
+    0b000_010_000_010_100, // ;
+    0b001_010_100_010_001, // <
+    0b000_111_000_111_000, // =
+    0b100_010_001_010_100, // >
+    0b111_001_010_000_010, // ?
+    0b010_101_111_100_011, // @
+    0b010_101_111_101_101, // A
+    0b110_101_110_101_110, // B
+    0b011_100_100_100_011, // C
+    0b110_101_101_101_110, // D
+    0b111_100_110_100_111, // E
+    0b111_100_110_100_100, // F
+    0b011_100_101_101_011, // G
+    0b101_101_111_101_101, // H
+    0b111_010_010_010_111, // I
+    0b001_001_001_101_010, // J
+    0b101_101_110_101_101, // K
+    0b100_100_100_100_111, // L
+    0b101_111_111_101_101, // M
+    0b110_101_101_101_101, // N
+    0b010_101_101_101_010, // O
+    0b110_101_110_100_100, // P
+    0b010_101_101_110_011, // Q
+    0b110_101_110_101_101, // R
+    0b011_100_010_001_110, // S
+    0b111_010_010_010_010, // T
+    0b101_101_101_101_111, // U
+    0b101_101_101_101_010, // V
+    0b101_101_111_111_101, // W
+    0b101_101_010_101_101, // X
+    0b101_101_010_010_010, // Y
+    0b111_001_010_100_111, // Z
+    0b011_010_010_010_011, // [
+    0b100_100_010_001_001, // \
+    0b110_010_010_010_110, // ]
+    0b010_101_000_000_000, // ^
+    0b000_000_000_000_111, // _
+    0b100_010_000_000_000, // `
+    0b011_010_110_010_011, // {
+    0b010_010_010_010_010, // |
+    0b110_010_011_010_110, // }
+    0b000_011_110_000_000, // ~
+];
+
+fn glyph(c: char) -> u16 {
+    let c = c.to_ascii_uppercase() as u32;
+    match c {
+        0x20..=0x60 => GLYPHS[(c - 0x20) as usize],
+        0x7B..=0x7E => GLYPHS[(c - 0x7B + 0x41) as usize],
+        _ => GLYPHS[('?' as u32 - 0x20) as usize],
+    }
+}
+
+// Draws `text` with its top-left corner at (x, y), clipped to the buffer.
+// '\n' starts a new line.
+pub fn draw_text(buffer: &mut [u32], width: usize, height: usize, x: i32, y: i32, text: &str, color: u32) {
+    let (mut cx, mut cy) = (x, y);
+    for c in text.chars() {
+        if c == '\n' {
+            cx = x;
+            cy += GLYPH_HEIGHT + 1;
+            continue;
+        }
+        let bits = glyph(c);
+        for row in 0..GLYPH_HEIGHT {
+            for col in 0..GLYPH_WIDTH {
+                let bit = 14 - (row * GLYPH_WIDTH + col);
+                if bits & (1 << bit) != 0 {
+                    put_pixel(buffer, width, height, cx + col, cy + row, color);
+                }
+            }
+        }
+        cx += ADVANCE;
+    }
+}
+
+pub fn put_pixel(buffer: &mut [u32], width: usize, height: usize, x: i32, y: i32, color: u32) {
+    if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+        buffer[y as usize * width + x as usize] = color;
+    }
+}
@@ -0,0 +1,87 @@
+// Interface between the emulator and whatever shows it: a window, a terminal,
+// a browser canvas... A frontend only presents frames, reports the held
+// buttons and plays audio; step() and run() drive the emulator with it.
+
+use crate::apu;
+use crate::cpu::Lockup;
+use crate::emulator::Emulator;
+use crate::joypad::JoypadState;
+use crate::movie::MovieSession;
+use crate::pacer::{self, FramePacer};
+use log::error;
+
+pub trait Frontend {
+    // Shows a finished frame (160x144, 0xRRGGBB)
+    fn present(&mut self, fb: &[u32]);
+
+    // Handles pending events and returns the buttons held for the next frame
+    fn poll_input(&mut self) -> JoypadState;
+
+    // Plays the samples produced by the last frame: the APU's raw stereo mix,
+    // interleaved at apu::SAMPLE_RATE, which a mixer::Mixer makes playable
+    fn push_audio(&mut self, samples: &[f32]);
+
+    // False once the user closed the frontend
+    fn is_open(&self) -> bool {
+        true
+    }
+
+    // While paused frames are still presented, but no emulation runs
+    fn is_paused(&self) -> bool {
+        false
+    }
+
+    // Samples waiting to be played, counted at apu::SAMPLE_RATE, which run()
+    // keeps near apu::BUFFER_SIZE by adjusting the speed slightly. None paces
+    // on the clock alone.
+    fn audio_queued(&self) -> Option<usize> {
+        None
+    }
+
+    // Called once when an illegal opcode hangs the CPU. Emulation goes on,
+    // but the game won't respond anymore.
+    fn crashed(&mut self, lockup: Lockup) {
+        error!("{}", lockup);
+    }
+}
+
+// Runs one frame with the frontend's input, a movie replacing or recording
+// it, then presents the frame and hands over its audio. Returns the cycles
+// run, 0 while paused.
+pub fn step(emu: &mut Emulator, frontend: &mut dyn Frontend, movie: Option<&mut MovieSession>) -> u32 {
+    let live = frontend.poll_input();
+    let cycles = if frontend.is_paused() {
+        0
+    } else {
+        let buttons = match movie {
+            Some(session) => session.next_frame(live),
+            None => live,
+        };
+        emu.mmu.joypad.set_state(buttons);
+        let was_locked = emu.cpu.locked.is_some();
+        let cycles = emu.run_frame();
+        if let Some(lockup) = emu.cpu.locked.filter(|_| !was_locked) {
+            frontend.crashed(lockup);
+        }
+        cycles
+    };
+
+    frontend.present(emu.mmu.ppu.frame());
+    frontend.push_audio(&emu.mmu.apu.take_samples());
+    cycles
+}
+
+// Runs frames at real-time speed until the frontend is closed
+pub fn run(emu: &mut Emulator, frontend: &mut dyn Frontend, mut movie: Option<&mut MovieSession>) {
+    let mut pacer = FramePacer::new(apu::BUFFER_SIZE);
+    while frontend.is_open() {
+        let cycles = step(emu, frontend, movie.as_deref_mut());
+
+        // While paused the frontend is still refreshed at the normal frame rate
+        let audio_fill = frontend.audio_queued().unwrap_or(apu::BUFFER_SIZE);
+        pacer.wait(if cycles == 0 { pacer::CYCLES_PER_FRAME } else { cycles }, audio_fill);
+
+        // Save battery RAM once the game has stopped writing to it
+        emu.mmu.cartridge.flush_if_dirty();
+    }
+}
@@ -0,0 +1,71 @@
+// Known cartridge quirks, looked up by header title and global checksum when
+// a ROM is loaded: a wrong mapper or RAM size byte in the header, games that
+// have to run in DMG mode, and MBC1 multicarts (several games on one
+// cartridge, with the upper bank bits wired one bit lower). Multicarts are
+// also detected by the Nintendo logo at the start of their second game, as
+// other emulators do, so revisions missing from the table still work. Wisdom
+// Tree's unlicensed games are detected by the publisher's name in the header.
+
+use crate::cartridge::NINTENDO_LOGO;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Quirks {
+    pub cart_type: Option<u8>,   // Mapper byte (0x147) to use instead of the header's
+    pub ram_size: Option<usize>, // Bytes of cartridge RAM, instead of the header's
+    pub force_dmg: bool,         // Runs in DMG mode even though the header allows CGB
+    pub mbc1_multicart: bool,
+    pub wisdom_tree: bool, // Wisdom Tree mapper, behind a ROM-only mapper byte
+}
+
+struct Entry {
+    title: &'static str,
+    checksum: Option<u16>, // Global checksum (0x14E-0x14F), None for every revision
+    quirks: Quirks,
+}
+
+const MULTICART: Quirks =
+    Quirks { cart_type: None, ram_size: None, force_dmg: false, mbc1_multicart: true, wisdom_tree: false };
+
+const ENTRIES: &[Entry] = &[
+    Entry { title: "MORTALKOMBATI&II", checksum: None, quirks: MULTICART },
+    Entry { title: "MOMOCOL", checksum: None, quirks: MULTICART },
+];
+
+// Quirks of a ROM, default if it has none
+pub fn lookup(rom: &[u8]) -> Quirks {
+    if rom.len() < 0x150 {
+        return Quirks::default();
+    }
+    let title = title(rom);
+    let checksum = ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16;
+    let mut quirks = ENTRIES
+        .iter()
+        .find(|e| e.title == title && e.checksum.is_none_or(|c| c == checksum))
+        .map_or(Quirks::default(), |e| e.quirks);
+
+    // 1MB MBC1 cartridge with a second game header in bank 0x10
+    let mbc1 = matches!(rom[0x147], 0x01..=0x03);
+    if mbc1 && rom.len() == 0x100000 && rom[0x40104..0x40134] == NINTENDO_LOGO {
+        quirks.mbc1_multicart = true;
+    }
+
+    // "WISDOM TREE" in the title, or split by a zero in some revisions
+    let header = &rom[0x134..0x150];
+    let names: [&[u8]; 2] = [b"WISDOM TREE", b"WISDOM\0TREE"];
+    let named = names.iter().any(|name| header.windows(name.len()).any(|w| w == *name));
+    if rom[0x147] == 0x00 && named {
+        quirks.wisdom_tree = true;
+    }
+    quirks
+}
+
+// Header title up to the first byte that isn't printable ASCII
+fn title(rom: &[u8]) -> String {
+    rom[0x134..0x144]
+        .iter()
+        .take_while(|&&b| b.is_ascii_graphic() || b == b' ')
+        .map(|&b| b as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
@@ -0,0 +1,127 @@
+// Golden-frame regression runs (--golden <list>): test and homebrew ROMs are
+// run headlessly for a number of frames, and the hash of the last frame is
+// compared with the one stored in the list. With --bless the list is updated
+// to the current hashes instead. Frames are saved as PNGs next to the list,
+// <rom>.png when blessed and <rom>.actual.png when they differ, to see what
+// changed.
+//
+// List file, one ROM per line, paths relative to the list:
+//
+//   # rom                  frames  hash
+//   roms/cpu_instrs.gb     3000    3F2A9C0D11E4B875
+//   roms/dmg-acid2.gb      120                        (no hash yet: --bless)
+
+use std::path::Path;
+
+use crate::png;
+use gameboy_emulator::cartridge::Cartridge;
+use gameboy_emulator::emulator::Emulator;
+use gameboy_emulator::joypad::JoypadState;
+use gameboy_emulator::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Runs every ROM of the list and returns whether all matched. With `bless`
+// the list is rewritten with the hashes of this run.
+pub fn run(list_path: &Path, bless: bool) -> bool {
+    let text = match std::fs::read_to_string(list_path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", list_path.display(), e);
+            return false;
+        }
+    };
+    let dir = list_path.parent().unwrap_or(Path::new("."));
+
+    let mut lines = Vec::new();
+    let (mut passed, mut total) = (0, 0);
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            lines.push(line.to_string());
+            continue;
+        }
+        // A malformed line counts as a failed ROM, so a typo can't drop it from the run
+        total += 1;
+        let Some(frames) = fields.get(1).and_then(|f| f.parse::<usize>().ok()) else {
+            eprintln!("{} line {}: expected '<rom> <frames> [hash]'", list_path.display(), number + 1);
+            lines.push(line.to_string());
+            continue;
+        };
+        let rom = fields[0];
+        let expected = fields.get(2).and_then(|h| u64::from_str_radix(h, 16).ok());
+
+        let Some(emu) = run_rom(&dir.join(rom), frames) else {
+            lines.push(line.to_string());
+            continue;
+        };
+        let hash = emu.frame_hash();
+        let image = dir.join(format!("{}.png", rom));
+        let actual_image = dir.join(format!("{}.actual.png", rom));
+        if bless {
+            println!("{}: {:016X}", rom, hash);
+            save_frame(&image, &emu);
+            let _ = std::fs::remove_file(&actual_image);
+            lines.push(format!("{} {} {:016X}", rom, frames, hash));
+            passed += 1;
+            continue;
+        }
+        lines.push(line.to_string());
+        match expected {
+            Some(expected) if expected == hash => {
+                println!("{}: ok", rom);
+                let _ = std::fs::remove_file(&actual_image);
+                passed += 1;
+            }
+            Some(expected) => {
+                let actual = actual_image.display();
+                println!("{}: FAILED, expected {:016X}, got {:016X} (see {})", rom, expected, hash, actual);
+                save_frame(&actual_image, &emu);
+            }
+            None => println!("{}: no golden hash, run with --bless to store {:016X}", rom, hash),
+        }
+    }
+
+    if bless {
+        let mut text = lines.join("\n");
+        text.push('\n');
+        if let Err(e) = std::fs::write(list_path, text) {
+            eprintln!("Failed to update {}: {}", list_path.display(), e);
+            return false;
+        }
+        println!("\nStored the hashes of {} ROMs in {}", passed, list_path.display());
+    } else {
+        println!("\n{} of {} ROMs match their golden frame", passed, total);
+    }
+    passed == total
+}
+
+// Runs a ROM deterministically (no save file, emulated RTC, no input) in the
+// mode its header asks for
+fn run_rom(path: &Path, frames: usize) -> Option<Emulator> {
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let cartridge = match Cartridge::from_rom(rom) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let is_gbc = cartridge.supports_cgb();
+    let mut emu = Emulator::new(cartridge, is_gbc);
+    emu.run_deterministic(&vec![JoypadState::default(); frames]);
+    Some(emu)
+}
+
+fn save_frame(path: &Path, emu: &Emulator) {
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    if let Err(e) = png::write(path, emu.mmu.ppu.frame(), SCREEN_WIDTH, SCREEN_HEIGHT) {
+        eprintln!("Failed to save {}: {}", path.display(), e);
+    }
+}
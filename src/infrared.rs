@@ -0,0 +1,65 @@
+// GBC infrared communication port (RP register, 0xFF56)
+
+use crate::savestate::{StateReader, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IrMode {
+    Dark,     // Nothing ever received (no partner in range)
+    Loopback, // Our own LED is seen by our receiver
+}
+
+pub struct Infrared {
+    rp: u8, // Bit 0: LED on, bits 6-7: read enable
+    pub mode: IrMode,
+}
+
+impl Default for Infrared {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Infrared {
+    pub fn new() -> Self {
+        Infrared {
+            rp: 0,
+            mode: IrMode::Dark,
+        }
+    }
+
+    fn led_on(&self) -> bool {
+        (self.rp & 0x01) != 0
+    }
+
+    fn receiving(&self) -> bool {
+        match self.mode {
+            IrMode::Dark => false,
+            IrMode::Loopback => self.led_on(),
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        // Bits 2-5 are unused and read as 1
+        let mut result = 0x3C | (self.rp & 0xC1);
+
+        // Bit 1 reads 0 while a signal is received, only when reading is enabled
+        let read_enabled = (self.rp & 0xC0) == 0xC0;
+        if !(read_enabled && self.receiving()) {
+            result |= 0x02;
+        }
+
+        result
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.rp = value & 0xC1;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rp);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.rp = r.u8();
+    }
+}
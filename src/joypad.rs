@@ -1,3 +1,59 @@
+use crate::savestate::{StateReader, StateWriter};
+
+// Buttons held during a frame: the unit of input passed from frontends,
+// scripts and movies to the joypad
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct JoypadState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl JoypadState {
+    // From a bitmask, bit 0-7: Right, Left, Up, Down, A, B, Select, Start
+    pub fn from_bits(bits: u8) -> Self {
+        let bit = |n: u8| bits & (1 << n) != 0;
+        JoypadState {
+            right: bit(0),
+            left: bit(1),
+            up: bit(2),
+            down: bit(3),
+            a: bit(4),
+            b: bit(5),
+            select: bit(6),
+            start: bit(7),
+        }
+    }
+
+    // As a bitmask in the from_bits() order, as stored in movie files
+    pub fn bits(&self) -> u8 {
+        [self.right, self.left, self.up, self.down, self.a, self.b, self.select, self.start]
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (bit, &pressed)| mask | ((pressed as u8) << bit))
+    }
+}
+
+// Buttons held in either state
+impl std::ops::BitOr for JoypadState {
+    type Output = JoypadState;
+
+    fn bitor(self, other: JoypadState) -> JoypadState {
+        JoypadState::from_bits(self.bits() | other.bits())
+    }
+}
+
+impl std::ops::BitOrAssign for JoypadState {
+    fn bitor_assign(&mut self, other: JoypadState) {
+        *self = *self | other;
+    }
+}
+
 pub struct Joypad {
     pub select_button: bool, // Bit 5 - select button keys
     pub select_dpad: bool,   // Bit 4 - select direction keys
@@ -19,6 +75,12 @@ pub struct Joypad {
     pub interrupt_requested: bool,
 }
 
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
@@ -93,45 +155,42 @@ impl Joypad {
         self.prev_state = new_state;
     }
 
-    // D-pad controls
-    pub fn set_up(&mut self, pressed: bool) {
-        self.up = pressed;
-        self.check_interrupt(self.read());
-    }
-
-    pub fn set_down(&mut self, pressed: bool) {
-        self.down = pressed;
-        self.check_interrupt(self.read());
-    }
-
-    pub fn set_left(&mut self, pressed: bool) {
-        self.left = pressed;
+    // Sets all buttons for the next frame at once
+    pub fn set_state(&mut self, state: JoypadState) {
+        self.right = state.right;
+        self.left = state.left;
+        self.up = state.up;
+        self.down = state.down;
+        self.a = state.a;
+        self.b = state.b;
+        self.select = state.select;
+        self.start = state.start;
         self.check_interrupt(self.read());
     }
 
-    pub fn set_right(&mut self, pressed: bool) {
-        self.right = pressed;
-        self.check_interrupt(self.read());
-    }
-
-    // Button controls
-    pub fn set_a(&mut self, pressed: bool) {
-        self.a = pressed;
-        self.check_interrupt(self.read());
-    }
-
-    pub fn set_b(&mut self, pressed: bool) {
-        self.b = pressed;
-        self.check_interrupt(self.read());
+    pub fn state(&self) -> JoypadState {
+        JoypadState {
+            right: self.right,
+            left: self.left,
+            up: self.up,
+            down: self.down,
+            a: self.a,
+            b: self.b,
+            select: self.select,
+            start: self.start,
+        }
     }
 
-    pub fn set_start(&mut self, pressed: bool) {
-        self.start = pressed;
-        self.check_interrupt(self.read());
+    // Only the selected lines: the buttons held come from the frontend
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.select_button);
+        w.bool(self.select_dpad);
+        w.u8(self.prev_state);
     }
 
-    pub fn set_select(&mut self, pressed: bool) {
-        self.select = pressed;
-        self.check_interrupt(self.read());
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.select_button = r.bool();
+        self.select_dpad = r.bool();
+        self.prev_state = r.u8();
     }
 }
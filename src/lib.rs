@@ -0,0 +1,30 @@
+// Emulator core, free of any window, audio or file-dialog dependencies so it
+// also builds for wasm32-unknown-unknown. The desktop frontend is main.rs.
+
+pub mod apu;
+pub mod block_cache;
+pub mod cartridge;
+pub mod cdl;
+pub mod cheats;
+pub mod cpu;
+pub mod emulator;
+pub mod frontend;
+pub mod gamedb;
+pub mod infrared;
+pub mod joypad;
+pub mod link;
+pub mod mapper;
+pub mod mixer;
+pub mod mmu;
+pub mod movie;
+pub mod pacer;
+pub mod palette;
+pub mod patch;
+pub mod ppu;
+pub mod profiler;
+pub mod savestate;
+pub mod serial;
+pub mod symbols;
+pub mod timer;
+pub mod vgm;
+pub mod wav;
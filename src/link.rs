@@ -0,0 +1,63 @@
+// Link cable between two emulators in one process. Both run in lockstep, one
+// instruction at a time from whichever is behind, so a transfer sees the other
+// side as it is at the same emulated time.
+
+use crate::emulator::Emulator;
+use crate::pacer;
+
+pub struct Link {
+    pub emus: [Emulator; 2],
+}
+
+impl Link {
+    pub fn new(mut first: Emulator, mut second: Emulator) -> Self {
+        first.mmu.serial.connected = true;
+        second.mmu.serial.connected = true;
+        Link { emus: [first, second] }
+    }
+
+    // Runs both emulators until each has completed a frame, like
+    // Emulator::run_frame(), and returns the normal-speed cycles each ran.
+    // Breakpoints and the other debugging stops are ignored.
+    pub fn run_frame(&mut self) -> [u32; 2] {
+        for emu in self.emus.iter_mut() {
+            emu.mmu.ppu.frame_ready = false;
+        }
+        let mut cycles = [0; 2];
+        let mut done = [false; 2];
+        while !(done[0] && done[1]) {
+            let side = if done[0] || (!done[1] && cycles[1] < cycles[0]) { 1 } else { 0 };
+            let emu = &mut self.emus[side];
+            let step = emu.step();
+            // The system clock is halted in STOP mode, as in run_frame()
+            cycles[side] += if emu.cpu.stopped { step } else { emu.mmu.normal_speed_cycles(step) };
+
+            let lcd_on = (emu.mmu.ppu.lcdc & 0x80) != 0;
+            done[side] = emu.mmu.ppu.frame_ready || (!lcd_on && cycles[side] >= pacer::CYCLES_PER_FRAME);
+            self.exchange(side);
+        }
+        for emu in self.emus.iter_mut() {
+            emu.deliver_output();
+        }
+        cycles
+    }
+
+    // Finishes a transfer `side` clocked itself: both get the other's byte
+    // and an interrupt. A partner not waiting on the external clock sends 0xFF.
+    fn exchange(&mut self, side: usize) {
+        let [first, second] = &mut self.emus;
+        let (master, partner) = if side == 0 { (first, second) } else { (second, first) };
+        let Some(sent) = master.mmu.serial.take_sent() else {
+            return;
+        };
+        let received = match partner.mmu.serial.external_transfer(sent) {
+            Some(byte) => {
+                partner.mmu.if_reg |= 0x08;
+                byte
+            }
+            None => 0xFF,
+        };
+        master.mmu.serial.complete(received);
+        master.mmu.if_reg |= 0x08;
+    }
+}
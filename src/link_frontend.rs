@@ -0,0 +1,101 @@
+// Link mode (--link): two games side by side in one window, connected by a
+// link cable, each with its own keys. Their audio is mixed into one output.
+
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::apu;
+use crate::audio_output::{AudioConfig, AudioOutput};
+use crate::joypad::JoypadState;
+use crate::link::Link;
+use crate::pacer::{self, FramePacer};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::video::Letterbox;
+use log::{error, info};
+
+const SCALE: usize = 3;
+const WIDTH: usize = SCREEN_WIDTH * 2;
+
+// Keys for each JoypadState::bits() bit, for the left and right game
+const PLAYER_KEYS: [[&[Key]; 8]; 2] = [
+    // WASD, G = A, F = B, Left Shift = Select, Tab = Start
+    [&[Key::D], &[Key::A], &[Key::W], &[Key::S], &[Key::G], &[Key::F], &[Key::LeftShift], &[Key::Tab]],
+    // Arrows, . = A, , = B, Right Shift = Select, Enter = Start
+    [
+        &[Key::Right],
+        &[Key::Left],
+        &[Key::Up],
+        &[Key::Down],
+        &[Key::Period],
+        &[Key::Comma],
+        &[Key::RightShift],
+        &[Key::Enter],
+    ],
+];
+
+pub fn run(link: &mut Link, title: &str, mut letterbox: Letterbox, audio: AudioConfig) -> Result<(), String> {
+    let options = WindowOptions { resize: true, ..WindowOptions::default() };
+    let mut window =
+        Window::new(title, WIDTH * SCALE, SCREEN_HEIGHT * SCALE, options).map_err(|e| e.to_string())?;
+    window.limit_update_rate(None);
+    let (audio, mut audio_device) = AudioOutput::open(audio);
+
+    let mut frame = vec![0; WIDTH * SCREEN_HEIGHT];
+    let mut pacer = FramePacer::new(apu::BUFFER_SIZE);
+    let mut paused = false;
+    let mut crashed = [false; 2];
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        audio_device.check();
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            paused = !paused;
+            info!("{}", if paused { "Paused" } else { "Resumed" });
+        }
+
+        let cycles = if paused {
+            pacer::CYCLES_PER_FRAME
+        } else {
+            for (emu, keys) in link.emus.iter_mut().zip(PLAYER_KEYS) {
+                let held = keys.iter().enumerate().fold(0, |mask, (bit, keys)| {
+                    mask | ((keys.iter().any(|&key| window.is_key_down(key)) as u8) << bit)
+                });
+                emu.mmu.joypad.set_state(JoypadState::from_bits(held));
+            }
+            let cycles = link.run_frame();
+            cycles[0].max(cycles[1])
+        };
+
+        for (side, emu) in link.emus.iter().enumerate() {
+            if let Some(lockup) = emu.cpu.locked.filter(|_| !crashed[side]) {
+                error!("Player {}: {}", side + 1, lockup);
+                crashed[side] = true;
+            }
+            for (y, row) in emu.mmu.ppu.frame().chunks_exact(SCREEN_WIDTH).enumerate() {
+                let start = y * WIDTH + side * SCREEN_WIDTH;
+                frame[start..start + SCREEN_WIDTH].copy_from_slice(row);
+            }
+        }
+        let (win_width, win_height) = window.get_size();
+        if win_width > 0 && win_height > 0 {
+            let output = letterbox.apply(&frame, WIDTH, SCREEN_HEIGHT, win_width, win_height);
+            window.update_with_buffer(output, win_width, win_height).map_err(|e| e.to_string())?;
+        } else {
+            window.update();
+        }
+
+        audio.push(&mix_audio(link));
+        pacer.wait(cycles, audio.queued());
+
+        for emu in link.emus.iter_mut() {
+            emu.mmu.cartridge.flush_if_dirty();
+        }
+    }
+    Ok(())
+}
+
+// Averages the samples both games produced during the frame
+fn mix_audio(link: &mut Link) -> Vec<f32> {
+    let [first, second] = link.emus.each_mut().map(|emu| emu.mmu.apu.take_samples());
+    (0..first.len().max(second.len()))
+        .map(|i| (first.get(i).copied().unwrap_or(0.0) + second.get(i).copied().unwrap_or(0.0)) / 2.0)
+        .collect()
+}
@@ -1,94 +1,455 @@
-mod cpu;
-mod mmu;
-mod cartridge;
-mod ppu;
-mod joypad;
-mod timer;
-mod apu;
-
-use cpu::Cpu;
+mod paths;
+mod video;
+mod png;
+mod avi;
+mod debugger;
+mod font;
+mod script;
+mod settings;
+mod config;
+mod osd;
+mod slot_browser;
+mod vram_viewer;
+mod apu_viewer;
+#[cfg(feature = "sdl")]
+mod sdl_frontend;
+mod tui_frontend;
+mod link_frontend;
+mod window_frontend;
+mod triple_buffer;
+mod audio_output;
+mod sm83_tests;
+mod golden;
+mod rom_info;
+
+// The emulator core lives in the library so other frontends (e.g. web/) can use it
+use gameboy_emulator::{apu, cartridge, cdl, cheats, cpu, emulator, frontend, infrared, joypad, link, mixer, mmu, movie, pacer, palette, ppu, profiler, savestate, symbols};
+
+use emulator::{Emulator, Model};
+use cpu::Lockup;
+use joypad::JoypadState;
 use mmu::Mmu;
 use cartridge::Cartridge;
-use minifb::{Key, Window, WindowOptions};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::sync::{Arc, Mutex};
-
-const SCALE: usize = 3;
+use pacer::FramePacer;
+use frontend::Frontend;
+use osd::PerfStats;
+use slot_browser::SlotBrowser;
+use minifb::{Key, KeyRepeat};
+use std::io::Write;
+use std::sync::{mpsc, Mutex};
+use log::{debug, error, info, warn, Level, LevelFilter};
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    // Header inspection only, with nothing else printed so --json output can be piped
+    if let Some(path) = arg_value(&args, "--info") {
+        if !rom_info::run(std::path::Path::new(&path), has_flag(&args, "--json")) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("========================================");
     println!("  Game Boy Emulator");
     println!("========================================\n");
 
-    // Open file dialog to select ROM
-    let rom_path = match rfd::FileDialog::new()
-        .add_filter("Game Boy ROM", &["gb", "gbc"])
-        .set_title("Select a Game Boy ROM")
-        .pick_file()
-    {
-        Some(path) => path,
-        None => {
-            println!("No ROM file selected. Exiting.");
-            return;
+    init_logging(&args);
+
+    // Defaults from the config file for the options not on the command line
+    let config = config::Config::load(&args);
+    let args = config.apply(&args);
+
+    let mut settings = settings::Settings::load();
+    settings.defaults = config.game;
+
+    if has_flag(&args, "--list-audio-devices") {
+        println!("Audio output devices:");
+        for name in audio_output::device_names() {
+            println!("  {}", name);
+        }
+        return;
+    }
+    if let Some(path) = arg_value(&args, "--sm83-tests") {
+        if !sm83_tests::run(std::path::Path::new(&path)) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = arg_value(&args, "--golden") {
+        if !golden::run(std::path::Path::new(&path), has_flag(&args, "--bless")) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    // Output device by name, from the command line or the settings file, and the output filter
+    let audio_filter = match arg_value(&args, "--audio-filter") {
+        Some(name) => mixer::AudioFilter::from_name(&name).unwrap_or_else(|| {
+            warn!("Unknown audio filter '{}', using smooth", name);
+            mixer::AudioFilter::Smooth
+        }),
+        None => mixer::AudioFilter::Smooth,
+    };
+    let audio = audio_output::AudioConfig {
+        device: arg_value(&args, "--audio-device").or_else(|| settings.audio_device.clone()),
+        filter: audio_filter,
+    };
+
+    let save_dir = arg_value(&args, "--save-dir")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(paths::save_dir);
+
+    // Layout of the MBC3 clock written to battery saves, by default the one the save file already had
+    let rtc_format = arg_value(&args, "--rtc-format").and_then(|name| {
+        let format = cartridge::RtcFormat::from_name(&name);
+        if format.is_none() {
+            warn!("Unknown RTC format '{}', keeping the save file's", name);
+        }
+        format
+    });
+
+    // Two games connected by a link cable
+    if let Some(i) = args.iter().position(|a| a == "--link") {
+        match (args.get(i + 1), args.get(i + 2)) {
+            (Some(first), Some(second)) => {
+                run_link(&args, first.into(), second.into(), &save_dir, rtc_format, audio)
+            }
+            _ => println!("--link needs two ROM files"),
+        }
+        return;
+    }
+
+    // ROM from --rom or the recent list, otherwise a file dialog
+    let rom_path = match arg_value(&args, "--recent") {
+        Some(n) => match n.parse::<usize>().ok().and_then(|n| settings.recent.get(n.checked_sub(1)?)) {
+            Some(path) => Some(path.clone()),
+            None => {
+                println!("No recent ROM number {}", n);
+                print_recent(&settings.recent);
+                return;
+            }
+        },
+        None => arg_value(&args, "--rom").map(std::path::PathBuf::from).or_else(|| {
+            print_recent(&settings.recent);
+            pick_rom(settings.recent.first())
+        }),
+    };
+    let Some(rom_path) = rom_path else {
+        println!("No ROM file selected. Exiting.");
+        return;
+    };
+
+    // IPS/BPS patch applied in memory; by default one next to the ROM with its name
+    let patch = arg_value(&args, "--patch").map(std::path::PathBuf::from);
+
+    let Some(mut cartridge) = load_cartridge(&rom_path, &save_dir, patch.as_deref(), rtc_format) else {
+        // A movie check that can't run fails, like --golden with a missing ROM
+        if arg_value(&args, "--verify-movie").is_some() {
+            std::process::exit(1);
+        }
+        return;
+    };
+    settings.add_recent(&rom_path);
+    settings.save();
+    let mut game_key = settings::game_key(&cartridge);
+    let game = settings.game(&game_key);
+
+    // Save RAM from or for a flash cart or another emulator: imported into
+    // the game's save before it runs, or exported without running it
+    if let Some(path) = arg_value(&args, "--import-sav") {
+        match cartridge.import_save(std::path::Path::new(&path)) {
+            Ok(()) => {
+                info!("Imported save {}", path);
+                cartridge.save();
+            }
+            Err(e) => {
+                error!("Failed to import {}: {}", path, e);
+                return;
+            }
+        }
+    }
+    if let Some(path) = arg_value(&args, "--export-sav") {
+        match cartridge.export_save(std::path::Path::new(&path)) {
+            Ok(()) => println!("Exported the save to {}", path),
+            Err(e) => error!("Failed to export the save to {}: {}", path, e),
+        }
+        return;
+    }
+
+    // Headless re-run check of a movie
+    if let Some(path) = arg_value(&args, "--verify-movie") {
+        if !verify_movie(&rom_path.to_string_lossy(), &save_dir, patch.as_deref(), std::path::Path::new(&path)) {
+            std::process::exit(1);
         }
+        return;
+    }
+
+    // Input movie playback or recording
+    let mut movie = if let Some(path) = arg_value(&args, "--play-movie") {
+        match movie::MovieSession::play(path.into(), !has_flag(&args, "--movie-rw")) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                error!("Failed to load movie: {}", e);
+                return;
+            }
+        }
+    } else {
+        arg_value(&args, "--record-movie").map(|path| {
+            movie::MovieSession::record(path.into(), cartridge.global_checksum(), false)
+        })
     };
+    if let Some(ref session) = movie {
+        if session.movie.rom_checksum != cartridge.global_checksum() {
+            warn!("Movie was recorded with a different ROM");
+        }
+        // Movies run from power-on without the battery save, with the RTC
+        // following emulated time
+        cartridge.detach_save();
+        cartridge.use_emulated_clock();
+    }
+
+    // Select GBC mode from the cartridge header, unless overridden. A played
+    // movie uses the mode it was recorded in.
+    let forced_model = forced_model(&args);
+    let is_gbc = if let Some(session) = movie.as_ref().filter(|m| !m.movie.frames.is_empty()) {
+        session.movie.is_gbc
+    } else {
+        pick_gbc(forced_model, game.gbc, &cartridge)
+    };
+    info!("Mode: {}", mode_name(is_gbc, &cartridge));
+    if let Some(ref mut session) = movie {
+        session.movie.is_gbc = is_gbc;
+    }
+
+    // Boot ROM palette for DMG games: a button combo given with
+    // --cgb-palette, or the one picked from the title
+    let cgb_palette = arg_value(&args, "--cgb-palette").and_then(|name| {
+        palette::combo_from_name(&name).or_else(|| {
+            warn!("Unknown button combo '{}', using the game's palette", name);
+            None
+        })
+    });
+    let mut compat_palette = cgb_palette.unwrap_or_else(|| palette::boot_rom_palette(cartridge.header()));
+    let mut emu = Emulator::new(cartridge, is_gbc);
 
-    let rom_path_str = rom_path.to_string_lossy().to_string();
-    println!("Loading ROM: {}", rom_path_str);
+    // Infrared port: dark (default) or loopback
+    if arg_value(&args, "--ir").as_deref() == Some("loopback") {
+        emu.mmu.infrared.mode = infrared::IrMode::Loopback;
+    }
+    if has_flag(&args, "--no-block-cache") {
+        emu.cpu.block_cache = None;
+    }
+    emu.mmu.warn_echo_ram = has_flag(&args, "--warn-echo-ram");
 
-    // Detect GBC mode based on file extension
-    let is_gbc = rom_path_str.to_lowercase().ends_with(".gbc");
+    if let Some(path) = arg_value(&args, "--record-audio") {
+        if let Err(e) = emu.mmu.apu.start_recording(&path) {
+            error!("Failed to start audio recording: {}", e);
+        }
+    }
+    if let Some(path) = arg_value(&args, "--record-vgm") {
+        let title = emu.mmu.cartridge.title();
+        if let Err(e) = emu.mmu.apu.start_vgm_log(&path, &title) {
+            error!("Failed to start sound log: {}", e);
+        }
+    }
 
-    let cartridge = match Cartridge::load(&rom_path_str) {
-        Ok(cart) => cart,
+    // DMG palette: a user palette file is used when given or present in the data dir
+    let palette_file = arg_value(&args, "--palette-file")
+        .map(std::path::PathBuf::from)
+        .or_else(|| Some(paths::data_dir().join("palette.txt")).filter(|p| p.exists()));
+    let custom_palette = palette_file.and_then(|path| match palette::load(&path) {
+        Ok(p) => Some(p),
         Err(e) => {
-            eprintln!("Failed to load ROM: {}", e);
-            return;
+            error!("Failed to load palette {}: {}", path.display(), e);
+            None
+        }
+    });
+    let cli_palette = arg_value(&args, "--palette").map(|name| {
+        palette::PalettePreset::from_name(&name).unwrap_or_else(|| {
+            warn!("Unknown palette '{}', using green", name);
+            palette::PalettePreset::Green
+        })
+    });
+    // A DMG game run on the CGB is colored like the boot ROM does by default
+    let default_palette = |dmg_compat: bool| {
+        if dmg_compat {
+            palette::PalettePreset::GbcAuto
+        } else if custom_palette.is_some() {
+            palette::PalettePreset::Custom
+        } else {
+            palette::PalettePreset::Green
         }
     };
+    let mut palette_preset = cli_palette.or(game.palette).unwrap_or(default_palette(emu.mmu.ppu.dmg_compat));
+    emu.mmu.ppu.dmg_palette = palette::resolve(palette_preset, compat_palette, custom_palette.as_ref());
+    emu.mmu.ppu.blank_screen();
 
-    let mut mmu = Mmu::new(cartridge, is_gbc);
-    let mut cpu = if is_gbc { Cpu::new_gbc() } else { Cpu::new() };
+    // Optional LCD ghosting: blend each frame with the previous one
+    let ghosting = arg_value(&args, "--ghosting").map(|v| {
+        v.parse::<f32>().unwrap_or_else(|_| {
+            warn!("Invalid ghosting persistence '{}', using 0.5", v);
+            0.5
+        })
+    });
+    let blender = ghosting.map(video::FrameBlender::new);
 
-    // Setup audio output
-    let audio_buffer = mmu.apu.get_audio_buffer();
-    let _stream = setup_audio(Arc::clone(&audio_buffer));
+    // Software scaling filter applied before the frame is shown
+    let filter = match arg_value(&args, "--filter") {
+        Some(name) => video::ScaleFilter::from_name(&name).unwrap_or_else(|| {
+            warn!("Unknown filter '{}', using none", name);
+            video::ScaleFilter::None
+        }),
+        None => video::ScaleFilter::None,
+    };
+    let scaler = video::Scaler::new(filter);
 
     // Print initial state
-    println!("Initial CPU state:");
-    println!("  PC: 0x{:04X}", cpu.registers.pc);
-    println!("  SP: 0x{:04X}", cpu.registers.sp);
-    println!("  AF: 0x{:04X}", cpu.registers.af());
-    println!("Initial PPU state:");
-    println!("  LCDC: 0x{:02X}", mmu.ppu.lcdc);
-    println!("  BGP: 0x{:02X}", mmu.ppu.bgp);
-    println!("  OBP0: 0x{:02X}", mmu.ppu.obp0);
-    println!("  OBP1: 0x{:02X}", mmu.ppu.obp1);
-    println!("");
+    debug!(
+        "Initial CPU state: PC=0x{:04X} SP=0x{:04X} AF=0x{:04X}",
+        emu.cpu.registers.pc,
+        emu.cpu.registers.sp,
+        emu.cpu.registers.af()
+    );
+    debug!(
+        "Initial PPU state: LCDC=0x{:02X} BGP=0x{:02X} OBP0=0x{:02X} OBP1=0x{:02X}",
+        emu.mmu.ppu.lcdc,
+        emu.mmu.ppu.bgp,
+        emu.mmu.ppu.obp0,
+        emu.mmu.ppu.obp1
+    );
 
-    // Extract ROM name for window title
-    let rom_name = rom_path
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("Game Boy");
+    // Frames emulated without drawing them when rendering can't keep up
+    let frame_skip = match arg_value(&args, "--frameskip") {
+        Some(name) => pacer::FrameSkip::from_name(&name).unwrap_or_else(|| {
+            warn!("Invalid frame skip '{}', using off", name);
+            pacer::FrameSkip::Off
+        }),
+        None => pacer::FrameSkip::Off,
+    };
+
+    // Emulation speed in percent, changed at runtime with the speed hotkeys
+    let mut speed = match arg_value(&args, "--speed") {
+        Some(text) => pacer::parse_speed(&text).unwrap_or_else(|| {
+            warn!("Invalid speed '{}' (allowed: {}-{}%), using 100%", text, pacer::MIN_SPEED, pacer::MAX_SPEED);
+            100
+        }),
+        None => 100,
+    };
 
+    // Extract ROM name for window title
+    let mut rom_name = rom_name_of(&rom_path);
     let window_title = format!("Game Boy Emulator - {}", rom_name);
 
-    let mut window = Window::new(
-        &window_title,
-        ppu::SCREEN_WIDTH * SCALE,
-        ppu::SCREEN_HEIGHT * SCALE,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| {
-        panic!("Failed to create window: {}", e);
+    // Cheat codes, from a file given on the command line, the settings file
+    // or a file named after the ROM
+    let cli_cheats = arg_value(&args, "--cheats").map(std::path::PathBuf::from);
+    let mut cheat_file = load_game_cheats(&mut emu.mmu.cheats, cli_cheats, &game, &rom_name);
+
+    // Console debugger reading commands from the terminal
+    let debugger = has_flag(&args, "--debugger").then(|| {
+        // Symbols from --sym, or a .sym file next to the ROM
+        let sym_path = arg_value(&args, "--sym")
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| rom_path.with_extension("sym"));
+        let symbols = match symbols::Symbols::load(&sym_path) {
+            Ok(symbols) => {
+                info!("Loaded {} symbols from {}", symbols.len(), sym_path.display());
+                symbols
+            }
+            Err(e) => {
+                if arg_value(&args, "--sym").is_some() {
+                    error!("Failed to load symbols {}: {}", sym_path.display(), e);
+                }
+                symbols::Symbols::new()
+            }
+        };
+        debugger::Debugger::new(symbols)
     });
+    // Code/data log of the ROM, added to an existing log file
+    let cdl_path = arg_value(&args, "--cdl").map(std::path::PathBuf::from);
+    if let Some(ref path) = cdl_path {
+        let rom_len = emu.mmu.cartridge.rom_len();
+        emu.mmu.cdl = Some(if path.exists() {
+            match cdl::Cdl::load(path, rom_len) {
+                Ok(log) => log,
+                Err(e) => {
+                    error!("Failed to load {}: {}, starting a new log", path.display(), e);
+                    cdl::Cdl::new(rom_len)
+                }
+            }
+        } else {
+            cdl::Cdl::new(rom_len)
+        });
+    }
 
-    window.set_target_fps(60);
+    // The call stack is only tracked for the debugger's backtrace and profiler
+    emu.cpu.track_calls = debugger.is_some();
+    // Illegal opcodes also break into the debugger
+    emu.break_on_lockup = debugger.is_some();
 
-    // Performance tracking
-    let mut frame_count = 0;
-    let start_time = std::time::Instant::now();
+    // Alternative frontends, without the debugging windows and hotkeys
+    #[cfg(feature = "sdl")]
+    if has_flag(&args, "--sdl") {
+        let fullscreen = has_flag(&args, "--fullscreen");
+        let result =
+            sdl_frontend::run(&mut emu, &window_title, fullscreen, blender, scaler, audio_filter, movie.as_mut());
+        if let Err(e) = result {
+            error!("SDL frontend failed: {}", e);
+        }
+        emu.mmu.cartridge.save();
+        emu.mmu.apu.stop_vgm_log();
+        if let Some(session) = movie.take() {
+            session.finish();
+        }
+        return;
+    }
+
+    if has_flag(&args, "--tui") {
+        if let Err(e) = tui_frontend::run(&mut emu, movie.as_mut()) {
+            error!("Terminal frontend failed: {}", e);
+        }
+        emu.mmu.cartridge.save();
+        emu.mmu.apu.stop_vgm_log();
+        if let Some(session) = movie.take() {
+            session.finish();
+        }
+        return;
+    }
+
+    // VRAM tile/tilemap viewer window
+    let mut vram_viewer = if has_flag(&args, "--vram-viewer") { open_vram_viewer() } else { None };
+
+    // APU channel oscilloscope window
+    let mut apu_viewer = if has_flag(&args, "--apu-viewer") { open_apu_viewer(&mut emu) } else { None };
+
+    // The frame is fitted into the (resizable) window with black bars
+    let fit = match arg_value(&args, "--fit") {
+        Some(name) => video::FitMode::from_name(&name).unwrap_or_else(|| {
+            warn!("Unknown fit mode '{}', using aspect", name);
+            video::FitMode::Aspect
+        }),
+        None => video::FitMode::Aspect,
+    };
+    let letterbox = video::Letterbox::new(fit);
+    let mut screen = window_frontend::WindowFrontend::new(
+        &window_title,
+        has_flag(&args, "--fullscreen"),
+        letterbox,
+        blender,
+        scaler,
+        audio,
+    );
+    let cli_layout = arg_value(&args, "--controller").and_then(|name| {
+        let layout = window_frontend::KeyLayout::from_name(&name);
+        if layout.is_none() {
+            warn!("Unknown controller layout '{}', using the game's", name);
+        }
+        layout
+    });
+    screen.layout = cli_layout.or(game.controller).unwrap_or(window_frontend::KeyLayout::Arrows);
+
+    // Screenshots are saved at 160x144 unless the scaled window contents are requested
+    let screenshot_scaled = has_flag(&args, "--screenshot-scaled");
 
     println!("\nControls:");
     println!("  Arrow Keys - D-Pad");
@@ -96,123 +457,1096 @@ fn main() {
     println!("  X - B Button");
     println!("  Enter - Start");
     println!("  Shift - Select");
+    println!("  P - Pause/resume");
+    println!("  N - Advance one frame (pauses)");
+    println!("  - / = - Slower / faster ({}-{}%)", pacer::MIN_SPEED, pacer::MAX_SPEED);
+    println!("  0 - Normal speed");
+    println!("  Alt+Enter - Toggle fullscreen");
+    println!("  Ctrl+S - Save state to the selected slot");
+    println!("  Ctrl+L - Load state from the selected slot");
+    println!("  Tab (hold) - Show the state slots, pick one with the arrows or 1-9");
+    println!("  Ctrl+U - Undo the last state load");
+    println!("  F1 - Show/hide performance overlay");
+    println!("  F2 - Cycle DMG palette");
+    println!("  F3 - Cycle scaling filter");
+    println!("  F4 - Enable/disable cheats");
+    println!("  F5 - Reload cheat file");
+    println!("  F6 - Open/close VRAM viewer");
+    println!("  F7 - Open/close APU viewer");
+    println!("  F8 - Open another ROM");
+    println!("  F9 - Start/stop video recording (.avi)");
+    println!("  F10 - Start/stop audio recording (.wav)");
+    println!("  Shift+F10 - Start/stop sound log (.vgm)");
+    println!("  F11 - Show/hide layers and sprite overflow");
+    println!("  F12 - Save screenshot (.png)");
     println!("  ESC - Exit");
-    println!("\nSave files (.sav) are stored in the same directory as your ROM");
-    println!("Auto-saves every 5 seconds");
+    println!("\nSave files (.sav) are stored in {}", save_dir.display());
+    println!("Battery RAM is saved shortly after the game writes to it, and on exit even after a crash");
+    println!("Savestates (.state) are stored in {}", paths::state_dir().display());
     println!("\nStarting emulation...\n");
 
-    let mut last_save_frame = 0;
+    // Emulation runs on its own thread so window stalls (dragging, resizing)
+    // don't hold it up. Frames come back through a triple buffer; this thread
+    // only locks the emulator briefly for hotkeys and the debug windows.
+    let (frame_writer, mut frames) = triple_buffer::new(emu.mmu.ppu.frame().to_vec());
+    let emu = Mutex::new(emu);
+    let (commands, command_receiver) = mpsc::channel();
+    let (frame_ready, frame_ready_receiver) = mpsc::channel();
+    let (notices, notice_receiver) = mpsc::channel();
+    let perf = Mutex::new(PerfStats::default());
+    let emulation = EmulationThread {
+        emu: &emu,
+        perf: &perf,
+        commands: command_receiver,
+        frames: frame_writer,
+        frame_ready,
+        notices,
+        audio: screen.audio_output(),
+        script_path: arg_value(&args, "--script"),
+        debugger,
+        movie: movie.as_mut(),
+        cdl_path: cdl_path.clone(),
+        video_audio: !has_flag(&args, "--video-no-audio"),
+        frame_skip,
+        speed,
+        rom_name: rom_name.clone(),
+        undo_state: None,
+        resume: has_flag(&args, "--resume"),
+    };
 
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        // Handle input
-        mmu.joypad.set_up(window.is_key_down(Key::Up));
-        mmu.joypad.set_down(window.is_key_down(Key::Down));
-        mmu.joypad.set_left(window.is_key_down(Key::Left));
-        mmu.joypad.set_right(window.is_key_down(Key::Right));
-        mmu.joypad.set_a(window.is_key_down(Key::Z));
-        mmu.joypad.set_b(window.is_key_down(Key::X));
-        mmu.joypad.set_start(window.is_key_down(Key::Enter));
-        mmu.joypad.set_select(window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift));
+    // Savestate slot used by Ctrl+S and Ctrl+L
+    let mut slot = 1;
 
-        // Run until frame is complete
-        mmu.ppu.frame_ready = false;
-        let mut cycles_this_frame = 0;
+    let frame_count = std::thread::scope(|scope| {
+        let emulation = scope.spawn(|| emulation.run());
 
-        while !mmu.ppu.frame_ready && cycles_this_frame < 80000 {
-            let cycles = cpu.step(&mut mmu);
-            mmu.step(cycles); // Step timer and DMA
-            mmu.ppu.step(cycles);
+        while screen.is_open() {
+            // Handle input; fullscreen and the filter are toggled by the window frontend.
+            // The game gets no input while the slot browser takes the arrows.
+            let buttons = screen.poll_input();
+            let buttons = if screen.osd.slots.is_some() { JoypadState::default() } else { buttons };
+            let _ = commands.send(Command::Buttons(buttons));
 
-            // Check for STAT interrupt
-            if mmu.ppu.stat_interrupt {
-                mmu.if_reg |= 0x02; // STAT interrupt
+            // Pause, and step a single frame while paused
+            if screen.window.is_key_pressed(Key::P, KeyRepeat::No) {
+                let _ = commands.send(Command::TogglePause);
+            }
+            if screen.window.is_key_pressed(Key::N, KeyRepeat::Yes) {
+                let _ = commands.send(Command::Advance);
             }
 
-            // Check for joypad interrupt
-            if mmu.joypad.interrupt_requested {
-                mmu.if_reg |= 0x10; // Joypad interrupt
-                mmu.joypad.interrupt_requested = false;
+            // Emulation speed: slow motion, fast forward and back to normal
+            let new_speed = if screen.window.is_key_pressed(Key::Minus, KeyRepeat::Yes) {
+                pacer::slower(speed)
+            } else if screen.window.is_key_pressed(Key::Equal, KeyRepeat::Yes) {
+                pacer::faster(speed)
+            } else if screen.window.is_key_pressed(Key::Key0, KeyRepeat::No) {
+                100
+            } else {
+                speed
+            };
+            if new_speed != speed {
+                speed = new_speed;
+                let _ = commands.send(Command::SetSpeed(speed));
+                screen.notify(format!("Speed: {}%", speed));
             }
 
-            cycles_this_frame += cycles;
-        }
+            // Performance overlay, refreshed below while it is shown
+            if screen.window.is_key_pressed(Key::F1, KeyRepeat::No) {
+                screen.osd.perf = if screen.osd.perf.is_some() { None } else { Some(PerfStats::default()) };
+            }
 
-        // VBlank interrupt
-        if mmu.ppu.frame_ready {
-            mmu.if_reg |= 0x01;
-        }
+            // Cycle DMG palette
+            if screen.window.is_key_pressed(Key::F2, KeyRepeat::No) {
+                palette_preset = palette_preset.next(custom_palette.is_some());
+                emu.lock().unwrap().mmu.ppu.dmg_palette =
+                    palette::resolve(palette_preset, compat_palette, custom_palette.as_ref());
+                screen.notify(format!("Palette: {}", palette_preset.name()));
+                // Remembered for this game
+                settings.game_mut(&game_key).palette = Some(palette_preset);
+                settings.save();
+            }
 
-        // Update screen
-        window
-            .update_with_buffer(&mmu.ppu.framebuffer, ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT)
-            .unwrap();
+            let take_screenshot = screen.window.is_key_pressed(Key::F12, KeyRepeat::No);
 
-        frame_count += 1;
-        if frame_count % 60 == 0 {
-            let elapsed = start_time.elapsed().as_secs_f64();
-            let fps = frame_count as f64 / elapsed;
-            println!("FPS: {:.2} | Frames: {} | Cycles/Frame: {}", fps, frame_count, cycles_this_frame);
-        }
+            // Cheats: master switch, and reloading the file after editing it
+            if screen.window.is_key_pressed(Key::F4, KeyRepeat::No) {
+                let cheats = &mut emu.lock().unwrap().mmu.cheats;
+                cheats.enabled = !cheats.enabled;
+                screen.notify(format!("Cheats {}", if cheats.enabled { "enabled" } else { "disabled" }));
+            }
+            if screen.window.is_key_pressed(Key::F5, KeyRepeat::No) {
+                match cheat_file {
+                    Some(ref path) => {
+                        if load_cheats(&mut emu.lock().unwrap().mmu.cheats, path) {
+                            screen.osd.show("Cheats reloaded");
+                        }
+                    }
+                    None => screen.notify("No cheat file loaded (use --cheats <file>)".to_string()),
+                }
+            }
+
+            // VRAM viewer window
+            if screen.window.is_key_pressed(Key::F6, KeyRepeat::No) {
+                vram_viewer = if vram_viewer.is_some() { None } else { open_vram_viewer() };
+            }
+
+            // APU viewer window
+            if screen.window.is_key_pressed(Key::F7, KeyRepeat::No) {
+                let mut emu = emu.lock().unwrap();
+                if apu_viewer.take().is_some() {
+                    emu.mmu.apu.set_scope(false);
+                } else {
+                    apu_viewer = open_apu_viewer(&mut emu);
+                }
+            }
+
+            // Open another ROM. minifb has no file drop events, so this
+            // uses the file dialog.
+            if screen.window.is_key_pressed(Key::F8, KeyRepeat::No) {
+                let opened = pick_rom(settings.recent.first())
+                    .and_then(|path| Some((load_cartridge(&path, &save_dir, None, rtc_format)?, path)));
+                if let Some((cartridge, path)) = opened {
+                    settings.add_recent(&path);
+                    settings.save();
+                    game_key = settings::game_key(&cartridge);
+                    let game = settings.game(&game_key);
+                    rom_name = rom_name_of(&path);
+                    screen.set_title(&format!("Game Boy Emulator - {}", rom_name));
+                    screen.layout = cli_layout.or(game.controller).unwrap_or(window_frontend::KeyLayout::Arrows);
+
+                    let is_gbc = pick_gbc(forced_model, game.gbc, &cartridge);
+                    info!("Mode: {}", mode_name(is_gbc, &cartridge));
+                    compat_palette = cgb_palette.unwrap_or_else(|| palette::boot_rom_palette(cartridge.header()));
+                    let mut new_emu = Emulator::new(cartridge, is_gbc);
+                    carry_over_settings(&mut new_emu, &emu.lock().unwrap());
+
+                    palette_preset =
+                        cli_palette.or(game.palette).unwrap_or(default_palette(new_emu.mmu.ppu.dmg_compat));
+                    new_emu.mmu.ppu.dmg_palette =
+                        palette::resolve(palette_preset, compat_palette, custom_palette.as_ref());
+                    new_emu.mmu.ppu.blank_screen();
+
+                    // Cheats given with --cheats were for the previous game
+                    cheat_file = load_game_cheats(&mut new_emu.mmu.cheats, None, &game, &rom_name);
+
+                    let _ = commands.send(Command::LoadRom(Box::new(new_emu), rom_name.clone()));
+                    screen.osd.show(format!("Loaded {}", rom_name));
+                }
+            }
+
+            // Savestate slot browser, open while Tab is held
+            if screen.window.is_key_down(Key::Tab) {
+                let browser = screen.osd.slots.get_or_insert_with(|| SlotBrowser::open(&rom_name, slot));
+                browser.handle_keys(&screen.window);
+                slot = browser.selected;
+            } else if screen.osd.slots.take().is_some() {
+                screen.osd.show(format!("State slot {}", slot));
+            }
+
+            // Savestate of the running game
+            let ctrl = screen.window.is_key_down(Key::LeftCtrl) || screen.window.is_key_down(Key::RightCtrl);
+            if ctrl && screen.window.is_key_pressed(Key::S, KeyRepeat::No) {
+                let _ = commands.send(Command::SaveState(slot));
+            }
+            if ctrl && screen.window.is_key_pressed(Key::L, KeyRepeat::No) {
+                let _ = commands.send(Command::LoadState(slot));
+            }
+            if ctrl && screen.window.is_key_pressed(Key::U, KeyRepeat::No) {
+                let _ = commands.send(Command::UndoLoadState);
+            }
+
+            // Battery save to or from a file elsewhere
+            if ctrl && screen.window.is_key_pressed(Key::E, KeyRepeat::No) {
+                let dialog = rfd::FileDialog::new().add_filter("Save RAM", &["sav"]).set_title("Export the save");
+                if let Some(path) = dialog.set_file_name(format!("{}.sav", rom_name)).save_file() {
+                    let _ = commands.send(Command::ExportSave(path));
+                }
+            }
+            if ctrl && screen.window.is_key_pressed(Key::I, KeyRepeat::No) {
+                let dialog = rfd::FileDialog::new().add_filter("Save RAM", &["sav", "srm"]).set_title("Import a save");
+                if let Some(path) = dialog.pick_file() {
+                    let _ = commands.send(Command::ImportSave(path));
+                }
+            }
+
+            // Toggle video recording
+            if screen.window.is_key_pressed(Key::F9, KeyRepeat::No) {
+                let _ = commands.send(Command::ToggleVideoRecording);
+            }
 
-        // Auto-save every 5 seconds (300 frames at 60fps)
-        if frame_count - last_save_frame >= 300 {
-            mmu.cartridge.save();
-            last_save_frame = frame_count;
+            // Toggle audio recording, or with Shift the VGM sound log
+            let f10 = screen.window.is_key_pressed(Key::F10, KeyRepeat::No);
+            let shift = screen.window.is_key_down(Key::LeftShift) || screen.window.is_key_down(Key::RightShift);
+            if f10 && shift {
+                let mmu = &mut emu.lock().unwrap().mmu;
+                if mmu.apu.is_logging_vgm() {
+                    mmu.apu.stop_vgm_log();
+                    screen.osd.show("Sound log stopped");
+                } else {
+                    let timestamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    let path = format!("{}_{}.vgm", rom_name, timestamp);
+                    match mmu.apu.start_vgm_log(&path, &mmu.cartridge.title()) {
+                        Ok(()) => screen.osd.show("Logging sound"),
+                        Err(e) => error!("Failed to start sound log: {}", e),
+                    }
+                }
+            } else if f10 {
+                let apu = &mut emu.lock().unwrap().mmu.apu;
+                if apu.is_recording() {
+                    apu.stop_recording();
+                    screen.osd.show("Audio recording stopped");
+                } else {
+                    let timestamp = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0);
+                    let path = format!("{}_{}.wav", rom_name, timestamp);
+                    match apu.start_recording(&path) {
+                        Ok(()) => screen.osd.show("Recording audio"),
+                        Err(e) => error!("Failed to start audio recording: {}", e),
+                    }
+                }
+            }
+
+            // Layer view: tints pixels by layer and marks lines with dropped sprites
+            if screen.window.is_key_pressed(Key::F11, KeyRepeat::No) {
+                let ppu = &mut emu.lock().unwrap().mmu.ppu;
+                ppu.layer_view = !ppu.layer_view;
+                screen.osd.show(format!("Layer view {}", if ppu.layer_view { "on" } else { "off" }));
+            }
+
+            // Refresh debug windows
+            if vram_viewer.as_ref().is_some_and(|v| !v.is_open()) {
+                vram_viewer = None;
+            }
+            if apu_viewer.as_ref().is_some_and(|v| !v.is_open()) {
+                apu_viewer = None;
+                emu.lock().unwrap().mmu.apu.set_scope(false);
+            }
+            if vram_viewer.is_some() || apu_viewer.is_some() {
+                let emu = emu.lock().unwrap();
+                if let Some(ref mut viewer) = vram_viewer {
+                    viewer.update(&emu.mmu.ppu);
+                }
+                if let Some(ref mut viewer) = apu_viewer {
+                    viewer.update(&emu.mmu.apu);
+                }
+            }
+
+            while let Ok(notice) = notice_receiver.try_recv() {
+                match notice {
+                    Notice::Message(text) => screen.osd.show(text),
+                    Notice::Crashed(lockup) => screen.crashed(lockup),
+                }
+            }
+
+            if let Some(ref mut shown) = screen.osd.perf {
+                shown.clone_from(&perf.lock().unwrap());
+            }
+
+            // Show the newest frame, or the last one again if there is none
+            screen.paused = !frames.update();
+            screen.present(frames.front());
+
+            if take_screenshot {
+                let saved = if screenshot_scaled {
+                    screen
+                        .scaled_frame()
+                        .is_some_and(|(frame, width, height)| save_screenshot(&rom_name, frame, width, height))
+                } else {
+                    save_screenshot(&rom_name, frames.front(), ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT)
+                };
+                if saved {
+                    screen.osd.show("Screenshot saved");
+                }
+            }
+
+            // Wait for the next frame. The timeout keeps the window
+            // responsive while the emulation is paused.
+            let _ = frame_ready_receiver.recv_timeout(std::time::Duration::from_millis(17));
+            while frame_ready_receiver.try_recv().is_ok() {}
         }
-    }
+
+        let _ = commands.send(Command::Quit);
+        emulation.join().unwrap()
+    });
 
     // Final save on exit
-    mmu.cartridge.save();
+    let mut emu = emu.into_inner().unwrap();
+    emu.mmu.cartridge.save();
+    emu.mmu.apu.stop_recording();
+    emu.mmu.apu.stop_vgm_log();
+    if let Some(session) = movie.take() {
+        session.finish();
+    }
+    if let (Some(path), Some(log)) = (cdl_path, emu.mmu.cdl.as_ref()) {
+        save_cdl(&path, log);
+    }
 
     println!("\nEmulator closed.");
     println!("Total frames rendered: {}", frame_count);
 }
 
-fn setup_audio(audio_buffer: Arc<Mutex<Vec<f32>>>) -> cpal::Stream {
-    let host = cpal::default_host();
-    let device = host.default_output_device().expect("No audio output device");
-    let config = device.default_output_config().expect("No default audio config");
-
-    let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config.into(), audio_buffer),
-        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config.into(), audio_buffer),
-        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config.into(), audio_buffer),
-        _ => panic!("Unsupported sample format"),
-    };
-
-    stream.play().expect("Failed to play audio stream");
-    println!("Audio output initialized");
-    stream
-}
-
-fn build_stream<T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
-    audio_buffer: Arc<Mutex<Vec<f32>>>,
-) -> cpal::Stream
-where
-    T: cpal::Sample + cpal::SizedSample + cpal::FromSample<f32>,
-{
-    let channels = config.channels as usize;
-
-    device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            let mut buffer = audio_buffer.lock().unwrap();
-            for frame in data.chunks_mut(channels) {
-                let sample = if !buffer.is_empty() {
-                    buffer.remove(0)
-                } else {
-                    0.0
+// Requests from the window thread to the emulation thread
+enum Command {
+    Buttons(JoypadState),
+    TogglePause,
+    Advance,
+    SetSpeed(u32), // In percent
+    ToggleVideoRecording,
+    SaveState(usize), // To a slot
+    LoadState(usize), // From a slot
+    UndoLoadState,
+    ExportSave(std::path::PathBuf), // Battery RAM to a file
+    ImportSave(std::path::PathBuf), // Battery RAM from a file
+    LoadRom(Box<Emulator>, String), // Replaces the running game, with the new ROM's name
+    Quit,
+}
+
+// Shown by the window thread for the emulation thread
+enum Notice {
+    Message(String), // On screen
+    Crashed(Lockup),
+}
+
+// Everything the emulation thread works with. The emulator itself is shared
+// with the window thread.
+struct EmulationThread<'a> {
+    emu: &'a Mutex<Emulator>,
+    perf: &'a Mutex<PerfStats>, // Shown by the window thread's performance overlay
+    commands: mpsc::Receiver<Command>,
+    frames: triple_buffer::Writer<Vec<u32>>,
+    frame_ready: mpsc::Sender<()>,
+    notices: mpsc::Sender<Notice>,
+    audio: audio_output::AudioOutput,
+    script_path: Option<String>,
+    debugger: Option<debugger::Debugger>,
+    movie: Option<&'a mut movie::MovieSession>,
+    cdl_path: Option<std::path::PathBuf>, // Where the first game's code/data log is saved
+    video_audio: bool, // Record videos with the audio track
+    frame_skip: pacer::FrameSkip,
+    speed: u32, // In percent
+    rom_name: String,
+    undo_state: Option<Vec<u8>>, // The game as it was before the last state was loaded
+    resume: bool, // Save the game on exit and continue from there on the next start
+}
+
+impl EmulationThread<'_> {
+    // Shows a message on screen
+    fn show(&self, text: &str) {
+        let _ = self.notices.send(Notice::Message(text.to_string()));
+    }
+
+    // Shows a message on screen and logs it
+    fn notify(&self, text: &str) {
+        info!("{}", text);
+        self.show(text);
+    }
+
+    fn save_state(&self, slot: usize) {
+        let data = savestate::save(&self.emu.lock().unwrap());
+        let path = slot_browser::slot_path(&self.rom_name, slot);
+        let result = std::fs::create_dir_all(paths::state_dir()).and_then(|()| std::fs::write(&path, data));
+        match result {
+            Ok(()) => self.notify(&format!("State saved to slot {}", slot)),
+            Err(e) => error!("Failed to save state to {}: {}", path.display(), e),
+        }
+    }
+
+    // Written on exit with --resume
+    fn auto_state_path(&self) -> std::path::PathBuf {
+        paths::state_dir().join(format!("{}.auto.state", self.rom_name))
+    }
+
+    // Movies replay from power-on, so states can't be loaded into one
+    fn load_state(&mut self, slot: usize) {
+        if self.movie.is_some() {
+            self.show("Can't load a state while a movie is playing or recording");
+            return;
+        }
+        let path = slot_browser::slot_path(&self.rom_name, slot);
+        match std::fs::read(&path) {
+            Ok(data) => {
+                if let Err(e) = self.apply_state(&data, &format!("State {} loaded (Ctrl+U to undo)", slot)) {
+                    error!("Failed to load {}: {}", path.display(), e);
+                    self.show(&format!("State not loaded: {}", e));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => self.show(&format!("Slot {} is empty", slot)),
+            Err(e) => error!("Failed to read {}: {}", path.display(), e),
+        }
+    }
+
+    fn export_save(&mut self, path: &std::path::Path) {
+        let result = self.emu.lock().unwrap().mmu.cartridge.export_save(path);
+        match result {
+            Ok(()) => self.show(&format!("Save exported to {}", path.display())),
+            Err(e) => {
+                error!("Failed to export the save to {}: {}", path.display(), e);
+                self.show(&format!("Save not exported: {}", e));
+            }
+        }
+    }
+
+    // Replaces the battery RAM. Most games only read it when they start, so
+    // the imported save shows up after opening the ROM again.
+    fn import_save(&mut self, path: &std::path::Path) {
+        if self.movie.is_some() {
+            self.show("Can't import a save while a movie is playing or recording");
+            return;
+        }
+        let result = self.emu.lock().unwrap().mmu.cartridge.import_save(path);
+        match result {
+            Ok(()) => self.show("Save imported (open the ROM again if the game doesn't see it)"),
+            Err(e) => {
+                error!("Failed to import {}: {}", path.display(), e);
+                self.show(&format!("Save not imported: {}", e));
+            }
+        }
+    }
+
+    // Loads a state, keeping the game as it was for undo_load_state()
+    fn apply_state(&mut self, data: &[u8], message: &str) -> std::io::Result<()> {
+        let mut emu = self.emu.lock().unwrap();
+        let before = savestate::save(&emu);
+        savestate::load(&mut emu, data)?;
+        self.show_frame(&emu);
+        drop(emu);
+        self.undo_state = Some(before);
+        self.notify(message);
+        Ok(())
+    }
+
+    // Continues from the state written when the game was last closed. A
+    // state of another ROM with the same file name is refused by its header.
+    fn resume_game(&mut self) {
+        if !self.resume || self.movie.is_some() {
+            return;
+        }
+        let path = self.auto_state_path();
+        let Ok(data) = std::fs::read(&path) else {
+            return;
+        };
+        if let Err(e) = self.apply_state(&data, "Resumed where you left off (Ctrl+U to restart)") {
+            warn!("Not resuming from {}: {}", path.display(), e);
+        }
+    }
+
+    // Saves the game for resume_game(), unless it crashed
+    fn write_auto_state(&self, emu: &Emulator) {
+        if !self.resume || self.movie.is_some() || emu.cpu.locked.is_some() {
+            return;
+        }
+        let path = self.auto_state_path();
+        let data = savestate::save(emu);
+        match std::fs::create_dir_all(paths::state_dir()).and_then(|()| std::fs::write(&path, data)) {
+            Ok(()) => info!("Game saved for resuming: {}", path.display()),
+            Err(e) => error!("Failed to save state to {}: {}", path.display(), e),
+        }
+    }
+
+    // Goes back to the game as it was before the last state was loaded
+    fn undo_load_state(&mut self) {
+        let Some(before) = self.undo_state.take() else {
+            self.show("No state load to undo");
+            return;
+        };
+        let mut emu = self.emu.lock().unwrap();
+        match savestate::load(&mut emu, &before) {
+            Ok(()) => {
+                self.show_frame(&emu);
+                drop(emu);
+                self.notify("State load undone");
+            }
+            Err(e) => error!("Failed to undo the state load: {}", e),
+        }
+    }
+
+    // Shows the current frame right away, also while paused
+    fn show_frame(&mut self, emu: &Emulator) {
+        self.frames.back().copy_from_slice(emu.mmu.ppu.frame());
+        self.frames.publish();
+        let _ = self.frame_ready.send(());
+    }
+
+    // Runs frames until the window thread quits, paced by the audio output.
+    // Returns the number of frames run.
+    fn run(mut self) -> u64 {
+        self.resume_game();
+
+        // The Lua state can't move between threads, so the script is loaded here
+        let mut script = self.script_path.as_ref().and_then(|path| {
+            match script::Script::load(std::path::Path::new(path), &mut self.emu.lock().unwrap()) {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    error!("Failed to load script {}: {}", path, e);
+                    None
+                }
+            }
+        });
+
+        // Video recording to uncompressed AVI
+        let mut video_recorder: Option<avi::AviWriter> = None;
+        let mut pacer = FramePacer::new(apu::BUFFER_SIZE);
+        pacer.frame_skip = self.frame_skip;
+        pacer.speed = self.speed;
+        self.audio.set_speed(self.speed);
+        let mut live_buttons = JoypadState::default();
+        let mut paused = false;
+        let mut frame_count = 0;
+
+        'running: loop {
+            let frame_start = std::time::Instant::now();
+            let mut advance = false;
+            loop {
+                match self.commands.try_recv() {
+                    Ok(Command::Buttons(buttons)) => live_buttons = buttons,
+                    Ok(Command::TogglePause) => {
+                        paused = !paused;
+                        self.notify(if paused { "Paused" } else { "Resumed" });
+                    }
+                    Ok(Command::Advance) => {
+                        advance = true;
+                        if !paused {
+                            paused = true;
+                            self.notify("Paused");
+                        }
+                    }
+                    Ok(Command::SetSpeed(speed)) => {
+                        self.speed = speed;
+                        pacer.speed = speed;
+                        self.audio.set_speed(speed);
+                    }
+                    Ok(Command::ToggleVideoRecording) => {
+                        let mmu = &mut self.emu.lock().unwrap().mmu;
+                        if let Some(recorder) = video_recorder.take() {
+                            finish_video_recording(recorder, mmu);
+                            self.show("Video recording stopped");
+                        } else {
+                            video_recorder = start_video_recording(&self.rom_name, self.video_audio, mmu);
+                            if video_recorder.is_some() {
+                                self.show("Recording video");
+                            }
+                        }
+                    }
+                    Ok(Command::SaveState(slot)) => self.save_state(slot),
+                    Ok(Command::LoadState(slot)) => self.load_state(slot),
+                    Ok(Command::UndoLoadState) => self.undo_load_state(),
+                    Ok(Command::ExportSave(path)) => self.export_save(&path),
+                    Ok(Command::ImportSave(path)) => self.import_save(&path),
+                    Ok(Command::LoadRom(new_emu, rom_name)) => {
+                        let mut emu = self.emu.lock().unwrap();
+                        if let Some(recorder) = video_recorder.take() {
+                            finish_video_recording(recorder, &mut emu.mmu);
+                        }
+                        // The movie and code/data log belong to the previous game
+                        if self.movie.take().is_some() {
+                            info!("Movie stopped for the new ROM");
+                        }
+                        self.write_auto_state(&emu);
+                        let mut old = std::mem::replace(&mut *emu, *new_emu);
+                        old.mmu.cartridge.save();
+                        old.mmu.apu.stop_recording();
+                        old.mmu.apu.stop_vgm_log();
+                        if let (Some(path), Some(log)) = (self.cdl_path.take(), old.mmu.cdl.as_ref()) {
+                            save_cdl(&path, log);
+                        }
+                        self.rom_name = rom_name;
+                        self.undo_state = None;
+                        drop(emu);
+                        self.resume_game();
+                        pacer = FramePacer::new(apu::BUFFER_SIZE);
+                        pacer.frame_skip = self.frame_skip;
+                        pacer.speed = self.speed;
+                    }
+                    Ok(Command::Quit) | Err(mpsc::TryRecvError::Disconnected) => break 'running,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                }
+            }
+
+            let mut emu = self.emu.lock().unwrap();
+
+            // Emulate one frame unless paused
+            let run = !paused || advance;
+            if run {
+                // Buttons pressed by the script are added to the live input
+                let mut input = live_buttons;
+                if let Some(ref mut script) = script {
+                    script.frame_start(&mut emu);
+                    input |= script.take_buttons();
+                }
+
+                // A movie replaces or records the input once per frame
+                let buttons = match self.movie.as_mut() {
+                    Some(session) => session.next_frame(input),
+                    None => input,
                 };
+                emu.mmu.joypad.set_state(buttons);
+            }
+            // Videos get every frame
+            let skip = run && video_recorder.is_none() && pacer.skip_frame();
+            emu.mmu.ppu.skip_render = skip;
+            let was_locked = emu.cpu.locked.is_some();
+            let cycles_this_frame = match (run, script.as_mut()) {
+                (false, _) => 0,
+                (true, None) => emu.run_frame(),
+                (true, Some(script)) => {
+                    let cycles = emu.run_frame_with(&mut |emu, access| script.memory_access(emu, access));
+                    if !emu.break_hit {
+                        script.frame_end(&mut emu);
+                    }
+                    cycles
+                }
+            };
+
+            if let Some(lockup) = emu.cpu.locked.filter(|_| !was_locked) {
+                let _ = self.notices.send(Notice::Crashed(lockup));
+            }
 
-                for channel in frame.iter_mut() {
-                    *channel = T::from_sample(sample);
+            // Debugger commands run between frames, also while paused
+            if let Some(ref mut debugger) = self.debugger {
+                if run && emu.break_hit {
+                    paused = true;
+                    debugger.report_break(&emu);
+                }
+                match debugger.update(&mut emu) {
+                    Some(debugger::RunRequest::Pause) => paused = true,
+                    Some(debugger::RunRequest::Resume) => paused = false,
+                    None => {}
                 }
             }
-        },
-        |err| eprintln!("Audio stream error: {}", err),
-        None,
-    )
-    .expect("Failed to build audio stream")
+
+            // Add the frame to the video recording
+            if let Some(recorder) = video_recorder.as_mut().filter(|_| run) {
+                let samples = emu.mmu.apu.take_captured();
+                let result = recorder.write_frame(emu.mmu.ppu.frame(), &samples);
+                if let Err(e) = result {
+                    error!("Video recording failed: {}", e);
+                    video_recorder = None;
+                    emu.mmu.apu.set_capture(false);
+                } else if recorder.is_full() {
+                    info!("Video recording reached the AVI size limit");
+                    finish_video_recording(video_recorder.take().unwrap(), &mut emu.mmu);
+                }
+            }
+
+            // Hand the frame and its audio over
+            if run && !skip {
+                self.frames.back().copy_from_slice(emu.mmu.ppu.frame());
+                self.frames.publish();
+                let _ = self.frame_ready.send(());
+            }
+            self.audio.push(&emu.mmu.apu.take_samples());
+
+            // Save battery RAM once the game has stopped writing to it
+            emu.mmu.cartridge.flush_if_dirty();
+            drop(emu);
+
+            let audio_fill = self.audio.queued();
+            let fill_level = audio_fill as f64 / self.audio.capacity() as f64;
+            self.perf.lock().unwrap().record_frame(frame_start.elapsed(), cycles_this_frame, skip, fill_level);
+
+            // Wait for real time to catch up with the emulated frame, also
+            // at the normal frame rate while paused
+            if !run {
+                pacer.wait(pacer::CYCLES_PER_FRAME * pacer.speed / 100, audio_fill);
+                continue;
+            }
+            pacer.wait(cycles_this_frame, audio_fill);
+            frame_count += 1;
+        }
+
+        let mut emu = self.emu.lock().unwrap();
+        if let Some(recorder) = video_recorder.take() {
+            finish_video_recording(recorder, &mut emu.mmu);
+        }
+        self.write_auto_state(&emu);
+        drop(emu);
+        frame_count
+    }
+}
+
+// Runs two games side by side, connected by a link cable. The same ROM can
+// be used twice; the second copy then keeps its save in a player2 folder.
+fn run_link(
+    args: &[String],
+    first: std::path::PathBuf,
+    second: std::path::PathBuf,
+    save_dir: &std::path::Path,
+    rtc_format: Option<cartridge::RtcFormat>,
+    audio: audio_output::AudioConfig,
+) {
+    let second_save_dir = if rom_name_of(&first) == rom_name_of(&second) {
+        save_dir.join("player2")
+    } else {
+        save_dir.to_path_buf()
+    };
+    let (Some(first_cart), Some(second_cart)) = (
+        load_cartridge(&first, save_dir, None, rtc_format),
+        load_cartridge(&second, &second_save_dir, None, rtc_format),
+    ) else {
+        return;
+    };
+
+    let forced_model = forced_model(args);
+    let emus = [first_cart, second_cart].map(|cartridge| {
+        let is_gbc = forced_model.unwrap_or(Model::Auto).is_gbc(&cartridge);
+        let mut emu = Emulator::new(cartridge, is_gbc);
+        emu.mmu.ppu.blank_screen();
+        emu
+    });
+    let [first_emu, second_emu] = emus;
+    let mut link = link::Link::new(first_emu, second_emu);
+
+    println!("\nLink mode:");
+    println!("  Left game  - WASD, G = A, F = B, Left Shift = Select, Tab = Start");
+    println!("  Right game - Arrow keys, . = A, , = B, Right Shift = Select, Enter = Start");
+    println!("  P - Pause/resume");
+    println!("  ESC - Exit\n");
+
+    let title = format!("Game Boy Emulator - {} + {}", rom_name_of(&first), rom_name_of(&second));
+    let fit = arg_value(args, "--fit")
+        .and_then(|name| video::FitMode::from_name(&name))
+        .unwrap_or(video::FitMode::Aspect);
+    if let Err(e) = link_frontend::run(&mut link, &title, video::Letterbox::new(fit), audio) {
+        error!("Link window failed: {}", e);
+    }
+    for emu in link.emus.iter_mut() {
+        emu.mmu.cartridge.save();
+    }
+}
+
+// Plays a movie twice without a window and checks that both runs produce
+// the same frame and audio hashes. Returns false if not or if it can't run.
+fn verify_movie(
+    rom_path: &str,
+    save_dir: &std::path::Path,
+    patch: Option<&std::path::Path>,
+    movie_path: &std::path::Path,
+) -> bool {
+    let movie = match movie::Movie::load(movie_path) {
+        Ok(movie) => movie,
+        Err(e) => {
+            eprintln!("Failed to load movie: {}", e);
+            return false;
+        }
+    };
+
+    let mut results = Vec::new();
+    for run in 1..=2 {
+        let cartridge = match Cartridge::load_patched(rom_path, save_dir, patch) {
+            Ok(cart) => cart,
+            Err(e) => {
+                eprintln!("Failed to load ROM: {}", e);
+                return false;
+            }
+        };
+        let mut emu = Emulator::new(cartridge, movie.is_gbc);
+        let hashes = emu.run_deterministic(&movie.frames);
+        println!(
+            "Run {}: {} frames, frame hash {:016X}, audio hash {:016X}",
+            run,
+            movie.frames.len(),
+            hashes.frame,
+            hashes.audio
+        );
+        results.push(hashes);
+    }
+
+    if results[0] == results[1] {
+        println!("Movie replays deterministically");
+        true
+    } else {
+        eprintln!("Movie replay is NOT deterministic");
+        false
+    }
+}
+
+// File dialog starting in the folder of the last played ROM
+fn pick_rom(last: Option<&std::path::PathBuf>) -> Option<std::path::PathBuf> {
+    let mut dialog = rfd::FileDialog::new()
+        .add_filter("Game Boy ROM", &["gb", "gbc", "zip", "gz"])
+        .set_title("Select a Game Boy ROM");
+    if let Some(dir) = last.and_then(|path| path.parent()) {
+        dialog = dialog.set_directory(dir);
+    }
+    dialog.pick_file()
+}
+
+fn print_recent(recent: &[std::path::PathBuf]) {
+    if recent.is_empty() {
+        return;
+    }
+    println!("Recent ROMs (start one with --recent <n>):");
+    for (n, path) in recent.iter().enumerate() {
+        println!("  {}. {}", n + 1, path.display());
+    }
+    println!();
+}
+
+// Loads a ROM with its battery save, applying `patch` (or the one found next
+// to the ROM) and writing the save's clock in `rtc_format` if given
+fn load_cartridge(
+    path: &std::path::Path,
+    save_dir: &std::path::Path,
+    patch: Option<&std::path::Path>,
+    rtc_format: Option<cartridge::RtcFormat>,
+) -> Option<Cartridge> {
+    info!("Loading ROM: {}", path.display());
+    match Cartridge::load_patched(&path.to_string_lossy(), save_dir, patch) {
+        Ok(mut cart) => {
+            if let Some(format) = rtc_format {
+                cart.set_rtc_format(format);
+            }
+            Some(cart)
+        }
+        Err(e) => {
+            error!("Failed to load ROM: {}", e);
+            None
+        }
+    }
+}
+
+// Model given with --model, or forced with --dmg or --cgb
+fn forced_model(args: &[String]) -> Option<Model> {
+    if let Some(name) = arg_value(args, "--model") {
+        return Some(Model::from_name(&name).unwrap_or_else(|| {
+            warn!("Unknown model '{}', using auto", name);
+            Model::Auto
+        }));
+    }
+    if has_flag(args, "--dmg") {
+        Some(Model::Dmg)
+    } else if has_flag(args, "--cgb") {
+        Some(Model::Cgb)
+    } else {
+        None
+    }
+}
+
+// CGB mode or not: the model given on the command line, else the game's
+// setting, else the cartridge header
+fn pick_gbc(model: Option<Model>, game_gbc: Option<bool>, cartridge: &cartridge::Cartridge) -> bool {
+    match model {
+        Some(model) => model.is_gbc(cartridge),
+        None => game_gbc.unwrap_or_else(|| cartridge.supports_cgb()),
+    }
+}
+
+fn mode_name(is_gbc: bool, cartridge: &cartridge::Cartridge) -> &'static str {
+    match is_gbc {
+        true if !cartridge.supports_cgb() => "Game Boy Color (DMG compatibility)",
+        true => "Game Boy Color",
+        false => "Game Boy (DMG)",
+    }
+}
+
+// File name without extension, for the window title and output files
+fn rom_name_of(path: &std::path::Path) -> String {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Game Boy")
+        .to_string()
+}
+
+// Cheat file named after the ROM in the data directory, if there is one
+fn rom_cheat_file(rom_name: &str) -> Option<std::path::PathBuf> {
+    Some(paths::data_dir().join("cheats").join(format!("{}.txt", rom_name))).filter(|p| p.exists())
+}
+
+// Gives an emulator for a ROM opened while another game runs the settings of
+// the running one: infrared mode, cheats switch, breakpoints and debugging
+// options
+fn carry_over_settings(emu: &mut Emulator, old: &Emulator) {
+    emu.mmu.infrared.mode = old.mmu.infrared.mode;
+    emu.mmu.cheats.enabled = old.mmu.cheats.enabled;
+    emu.mmu.warn_echo_ram = old.mmu.warn_echo_ram;
+    emu.breakpoints = old.breakpoints.clone();
+    emu.cpu.track_calls = old.cpu.track_calls;
+    if old.cpu.block_cache.is_none() {
+        emu.cpu.block_cache = None;
+    }
+    emu.break_on_lockup = old.break_on_lockup;
+    emu.mmu.ppu.layer_view = old.mmu.ppu.layer_view;
+    // Keep feeding an open APU viewer
+    emu.mmu.apu.set_scope(old.mmu.apu.scope().is_some());
+}
+
+fn open_vram_viewer() -> Option<vram_viewer::VramViewer> {
+    match vram_viewer::VramViewer::new() {
+        Ok(viewer) => Some(viewer),
+        Err(e) => {
+            error!("Failed to open VRAM viewer: {}", e);
+            None
+        }
+    }
+}
+
+fn open_apu_viewer(emu: &mut Emulator) -> Option<apu_viewer::ApuViewer> {
+    match apu_viewer::ApuViewer::new(&mut emu.mmu.apu) {
+        Ok(viewer) => Some(viewer),
+        Err(e) => {
+            error!("Failed to open APU viewer: {}", e);
+            None
+        }
+    }
+}
+
+// Loads a game's cheats: the file given with --cheats, the cheats listed in
+// the settings file or the cheat file named after the ROM. Returns the file
+// F5 reloads.
+fn load_game_cheats(
+    cheats: &mut cheats::Cheats,
+    cli_file: Option<std::path::PathBuf>,
+    game: &settings::GameSettings,
+    rom_name: &str,
+) -> Option<std::path::PathBuf> {
+    if cli_file.is_none() && !game.cheats.is_empty() {
+        cheats.parse(&game.cheats.join("\n"));
+        info!("Loaded {} cheats from the settings file", cheats.list.len());
+        return None;
+    }
+    let file = cli_file.or_else(|| rom_cheat_file(rom_name));
+    if let Some(ref path) = file {
+        load_cheats(cheats, path);
+    }
+    file
+}
+
+fn load_cheats(cheats: &mut cheats::Cheats, path: &std::path::Path) -> bool {
+    match cheats.load(path) {
+        Ok(()) => {
+            let enabled = cheats.list.iter().filter(|c| c.enabled).count();
+            info!("Loaded {} cheats ({} enabled) from {}", cheats.list.len(), enabled, path.display());
+            for cheat in cheats.list.iter().filter(|c| c.enabled && !c.name.is_empty()) {
+                info!("  {}", cheat.name);
+            }
+            true
+        }
+        Err(e) => {
+            error!("Failed to load cheats {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+fn save_cdl(path: &std::path::Path, log: &cdl::Cdl) {
+    let (code, data) = log.coverage();
+    match log.save(path) {
+        Ok(()) => info!(
+            "Code/data log saved to: {} ({} code bytes, {} data bytes of {})",
+            path.display(),
+            code,
+            data,
+            log.flags.len()
+        ),
+        Err(e) => error!("Failed to save {}: {}", path.display(), e),
+    }
+}
+
+// Starts recording to a timestamped AVI file in the working directory
+fn start_video_recording(rom_name: &str, with_audio: bool, mmu: &mut Mmu) -> Option<avi::AviWriter> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = format!("{}_{}.avi", rom_name, timestamp);
+    match avi::AviWriter::create(
+        &path,
+        ppu::SCREEN_WIDTH,
+        ppu::SCREEN_HEIGHT,
+        4194304,
+        pacer::CYCLES_PER_FRAME,
+        apu::SAMPLE_RATE,
+        with_audio,
+    ) {
+        Ok(recorder) => {
+            info!("Recording video to: {}", path);
+            mmu.apu.set_capture(with_audio);
+            Some(recorder)
+        }
+        Err(e) => {
+            error!("Failed to start video recording: {}", e);
+            None
+        }
+    }
+}
+
+fn finish_video_recording(recorder: avi::AviWriter, mmu: &mut Mmu) {
+    mmu.apu.set_capture(false);
+    match recorder.finish() {
+        Ok(()) => info!("Video recording stopped"),
+        Err(e) => error!("Failed to finish video recording: {}", e),
+    }
+}
+
+// Saves a frame as a timestamped PNG in the screenshot directory. Returns
+// false if it failed.
+fn save_screenshot(rom_name: &str, pixels: &[u32], width: usize, height: usize) -> bool {
+    let dir = paths::screenshot_dir();
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        error!("Failed to create screenshot directory {}: {}", dir.display(), e);
+        return false;
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let path = dir.join(format!("{}_{}.png", rom_name, timestamp));
+    match png::write(&path, pixels, width, height) {
+        Ok(()) => {
+            info!("Screenshot saved to {}", path.display());
+            true
+        }
+        Err(e) => {
+            error!("Failed to save screenshot: {}", e);
+            false
+        }
+    }
+}
+
+// Log messages at info level and above, debug with --verbose. RUST_LOG
+// overrides the level, also per module (e.g. RUST_LOG=gameboy_emulator::cpu=debug).
+// Output goes to stderr, or with timestamps to the --log-file file.
+fn init_logging(args: &[String]) {
+    let level = if has_flag(args, "--verbose") { LevelFilter::Debug } else { LevelFilter::Info };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+
+    let log_file = arg_value(args, "--log-file").and_then(|path| match std::fs::File::create(&path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            eprintln!("Failed to create log file {}: {}", path, e);
+            None
+        }
+    });
+    if let Some(file) = log_file {
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+        builder.format(|buf, record| {
+            writeln!(buf, "{} {:5} {}: {}", buf.timestamp_millis(), record.level(), record.target(), record.args())
+        });
+    } else {
+        builder.format(|buf, record| match record.level() {
+            Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{}: {}", level, record.args()),
+        });
+    }
+    builder.init();
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
+
+// Returns the value following a `--flag value` command line option
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
 }
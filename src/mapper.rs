@@ -0,0 +1,1340 @@
+// Memory bank controllers, one struct per chip behind the Mapper trait. A
+// mapper decides which ROM banks are mapped, answers 0xA000-0xBFFF (RAM,
+// clocks, IR) and takes the register writes to 0x0000-0x7FFF. The ROM stays
+// with the Cartridge, which masks the banks by the ROM's size, and the RAM and
+// mapper registers are saved separately so that a new mapper only has to
+// handle its own registers.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::savestate::{StateReader, StateWriter};
+use log::{debug, warn};
+
+// Bits of the MBC3 RTC registers: seconds, minutes, hours, day counter low,
+// and day counter bit 8, halt (bit 6) and day counter carry (bit 7)
+const RTC_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+pub trait Mapper: Send {
+    // ROM banks mapped at 0x0000-0x3FFF and 0x4000-0x7FFF
+    fn rom_banks(&self) -> (usize, usize);
+
+    // Register write to 0x0000-0x7FFF
+    fn write_rom(&mut self, address: u16, value: u8);
+
+    fn read_ram(&self, _address: u16) -> u8 {
+        0xFF
+    }
+
+    // Write to 0xA000-0xBFFF, true if it changed battery-backed data
+    fn write_ram(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    // Contents of the battery save file: the RAM, then any clock footer
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram().to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram = self.ram_mut();
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    // Clocks follow emulated time until a ROM is loaded from a file
+    fn use_wall_clock(&mut self) {}
+
+    fn use_emulated_clock(&mut self) {}
+
+    // Advances an emulated clock by `cycles` normal-speed cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    fn set_rtc_format(&mut self, _format: RtcFormat) {}
+
+    // Registers, without the RAM
+    fn save_state(&self, w: &mut StateWriter);
+
+    fn load_state(&mut self, r: &mut StateReader);
+
+    // Registers from a state written before version 5, when every mapper
+    // saved the same set
+    fn load_legacy_state(&mut self, old: &LegacyState);
+}
+
+// Layout of the MBC3 clock appended to the battery RAM in save files, as
+// other emulators write it: the live and then the latched registers (seconds,
+// minutes, hours, day low, day high) as u32 LE each, then the Unix time of
+// the save, as u64 LE (48 bytes: BGB, VBA-M, SameBoy) or u32 LE (44 bytes:
+// older VBA builds). Either is detected when a save is loaded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RtcFormat {
+    Bytes48,
+    Bytes44,
+}
+
+impl RtcFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "48" | "bgb" | "sameboy" | "vba-m" => Some(RtcFormat::Bytes48),
+            "44" | "vba" => Some(RtcFormat::Bytes44),
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            RtcFormat::Bytes48 => 48,
+            RtcFormat::Bytes44 => 44,
+        }
+    }
+}
+
+// Mapper registers as states before version 5 stored them, one set shared
+// by every mapper
+pub struct LegacyState {
+    pub bank: u8, // Combined bank register
+    pub ram_mode: bool,
+    pub ram_enabled: bool,
+    pub rtc_register: u8,
+    pub rtc_latch_armed: bool,
+    pub rom_bank_low: u8,
+    pub rom_bank_high: u8,
+    pub ram_bank: u8,
+    pub huc_mode: u8,
+    pub huc3_command: u8,
+    pub huc3_response: u8,
+    pub huc3_address: u8,
+    pub huc3_memory: [u8; 0x100],
+    pub huc3_rtc_base: u64,
+    pub emulated_clock: bool,
+    pub clock: u64,
+    pub rtc: Option<([u8; 5], [u8; 5], u64)>, // Live and latched registers and their time (version 3)
+}
+
+impl LegacyState {
+    pub fn read(r: &mut StateReader) -> Self {
+        let mut state = LegacyState {
+            bank: r.u8(),
+            ram_mode: r.bool(),
+            ram_enabled: r.bool(),
+            rtc_register: r.u8(),
+            rtc_latch_armed: r.bool(),
+            rom_bank_low: r.u8(),
+            rom_bank_high: r.u8(),
+            ram_bank: r.u8(),
+            huc_mode: r.u8(),
+            huc3_command: r.u8(),
+            huc3_response: r.u8(),
+            huc3_address: r.u8(),
+            huc3_memory: [0; 0x100],
+            huc3_rtc_base: 0,
+            emulated_clock: false,
+            clock: 0,
+            rtc: None,
+        };
+        r.bytes(&mut state.huc3_memory);
+        state.huc3_rtc_base = r.u64();
+        state.emulated_clock = r.bool();
+        state.clock = r.u64();
+        if r.version >= 3 {
+            let (mut rtc, mut latch) = ([0; 5], [0; 5]);
+            r.bytes(&mut rtc);
+            r.bytes(&mut latch);
+            state.rtc = Some((rtc, latch, r.u64()));
+        }
+        state
+    }
+}
+
+// Time source of a cartridge clock: normal-speed cycles since power-on when
+// it follows emulated time (deterministic runs), else the wall clock
+struct Clock {
+    emulated: Option<u64>,
+}
+
+impl Clock {
+    fn emulated() -> Self {
+        Clock { emulated: Some(0) }
+    }
+
+    // Current time in seconds
+    fn now(&self) -> u64 {
+        match self.emulated {
+            Some(cycles) => cycles / 4_194_304,
+            None => unix_time(),
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(ref mut clock) = self.emulated {
+            *clock += cycles as u64;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.emulated.is_some());
+        w.u64(self.emulated.unwrap_or(0));
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        let emulated = r.bool();
+        let cycles = r.u64();
+        self.restore(emulated, cycles);
+    }
+
+    // A deterministic run keeps following emulated time
+    fn restore(&mut self, emulated: bool, cycles: u64) {
+        if emulated || self.emulated.is_some() {
+            self.emulated = Some(cycles);
+        }
+    }
+}
+
+// Byte of 8KB-banked RAM at 0xA000-0xBFFF, 0xFF past its end
+fn read_banked(ram: &[u8], bank: usize, address: u16) -> u8 {
+    ram.get(bank * 0x2000 + (address - 0xA000) as usize).copied().unwrap_or(0xFF)
+}
+
+fn write_banked(ram: &mut [u8], bank: usize, address: u16, value: u8) -> bool {
+    match ram.get_mut(bank * 0x2000 + (address - 0xA000) as usize) {
+        Some(byte) => {
+            *byte = value;
+            true
+        }
+        None => false,
+    }
+}
+
+// Bank 0 written to a bank register selects bank 1
+fn nonzero(bank: u8) -> usize {
+    if bank == 0 { 1 } else { bank as usize }
+}
+
+// No mapper: 32KB of ROM and nothing at 0xA000-0xBFFF
+pub struct RomOnly;
+
+impl Mapper for RomOnly {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, 1)
+    }
+
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) {}
+
+    fn load_legacy_state(&mut self, _old: &LegacyState) {}
+}
+
+#[derive(Clone, Copy)]
+enum BankMode {
+    Rom, // 16Mbit ROM/8KByte RAM mode
+    Ram, // 4Mbit ROM/32KByte RAM mode
+}
+
+pub struct Mbc1 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank: u8, // 2-bit register in bits 5-6, 5-bit register in bits 0-4
+    bank_mode: BankMode,
+    multicart: bool, // Multicart: the upper bank bits select 16-bank games
+}
+
+impl Mbc1 {
+    pub fn new(ram_size: usize, multicart: bool) -> Self {
+        Mbc1 { ram: vec![0; ram_size], ram_enabled: false, bank: 0x01, bank_mode: BankMode::Rom, multicart }
+    }
+
+    fn ram_bank(&self) -> usize {
+        match self.bank_mode {
+            BankMode::Rom => 0,                                  // Always bank 0
+            BankMode::Ram => ((self.bank & 0x60) >> 5) as usize, // Upper 2 bits
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn rom_banks(&self) -> (usize, usize) {
+        let (upper, low) = if self.multicart {
+            // The 2-bit register picks the game, the low 4 bits of the 5-bit one its bank
+            let low = if self.bank & 0x1F == 0 { 1 } else { (self.bank & 0x0F) as usize };
+            (((self.bank & 0x60) >> 1) as usize, low)
+        } else {
+            // The zero check only sees the 5-bit register, so banks 0x20, 0x40 and
+            // 0x60 map to the bank after them. The upper bits apply in both modes.
+            ((self.bank & 0x60) as usize, nonzero(self.bank & 0x1F))
+        };
+        // RAM mode also switches 0x0000-0x3FFF to bank 0x20/0x40/0x60 (the first
+        // bank of the game on multicarts)
+        match self.bank_mode {
+            BankMode::Rom => (0, upper | low),
+            BankMode::Ram => (upper, upper | low),
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                // RAM Enable
+                self.ram_enabled = (value & 0x0F) == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                // ROM Bank Number (lower 5 bits)
+                let lower = value & 0x1F;
+                self.bank = (self.bank & 0x60) | lower;
+            }
+            0x4000..=0x5FFF => {
+                // RAM Bank Number or Upper Bits of ROM Bank Number (upper 2 bits)
+                let upper = (value & 0x03) << 5;
+                self.bank = (self.bank & 0x1F) | upper;
+            }
+            0x6000..=0x7FFF => {
+                // Banking Mode Select
+                self.bank_mode = if (value & 0x01) != 0 { BankMode::Ram } else { BankMode::Rom };
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        read_banked(&self.ram, self.ram_bank(), address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        let bank = self.ram_bank();
+        self.ram_enabled && write_banked(&mut self.ram, bank, address, value)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.bool(matches!(self.bank_mode, BankMode::Ram));
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.bank = r.u8();
+        self.bank_mode = if r.bool() { BankMode::Ram } else { BankMode::Rom };
+        self.ram_enabled = r.bool();
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.bank = old.bank;
+        self.bank_mode = if old.ram_mode { BankMode::Ram } else { BankMode::Rom };
+        self.ram_enabled = old.ram_enabled;
+    }
+}
+
+// MBC2: 16 ROM banks and 512x4 bits of built-in RAM, the registers told
+// apart by bit 8 of the address
+pub struct Mbc2 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(ram_size: usize) -> Self {
+        Mbc2 { ram: vec![0; ram_size], ram_enabled: false, bank: 0x01 }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, nonzero(self.bank))
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                // RAM Enable (only if bit 8 of address is 0)
+                if (address & 0x0100) == 0 {
+                    self.ram_enabled = (value & 0x0F) == 0x0A;
+                }
+            }
+            0x2000..=0x3FFF => {
+                // ROM Bank Number (only if bit 8 of address is 1)
+                if (address & 0x0100) != 0 {
+                    self.bank = value & 0x0F; // Only 4 bits for MBC2
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        let addr = (address - 0xA000) as usize & 0x1FF; // Only 512 addresses
+        match self.ram.get(addr) {
+            Some(&nibble) if self.ram_enabled => 0xF0 | (nibble & 0x0F), // The upper bits are open bus
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        let addr = (address - 0xA000) as usize & 0x1FF;
+        match self.ram.get_mut(addr) {
+            Some(nibble) if self.ram_enabled => {
+                *nibble = value & 0x0F; // Only lower 4 bits
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.bank = r.u8();
+        self.ram_enabled = r.bool();
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.bank = old.bank;
+        self.ram_enabled = old.ram_enabled;
+    }
+}
+
+// MBC3: 7-bit ROM bank, 4 RAM banks and, on some cartridges, a real-time clock
+pub struct Mbc3 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank: u8,
+    ram_bank: u8,
+    has_rtc: bool,
+    rtc_register: u8,          // Selected with 0x08-0x0C, 0 while RAM is mapped
+    rtc_latch_armed: bool,     // 0x00 was written to 0x6000-0x7FFF, so 0x01 latches
+    rtc: [u8; 5],              // Live registers, as of rtc_time
+    rtc_latch: [u8; 5],        // Registers as the game reads them
+    rtc_time: u64,             // clock.now() when `rtc` was last brought up to date
+    rtc_format: RtcFormat,     // Layout of the clock in the save file
+    clock: Clock,
+}
+
+impl Mbc3 {
+    pub fn new(ram_size: usize, has_rtc: bool) -> Self {
+        Mbc3 {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            bank: 0x01,
+            ram_bank: 0x00,
+            has_rtc,
+            rtc_register: 0,
+            rtc_latch_armed: false,
+            rtc: [0; 5],
+            rtc_latch: [0; 5],
+            rtc_time: 0,
+            rtc_format: RtcFormat::Bytes48,
+            clock: Clock::emulated(),
+        }
+    }
+
+    fn rtc_selected(&self) -> bool {
+        (0x08..=0x0C).contains(&self.rtc_register)
+    }
+
+    // Advances the live RTC registers to now, unless the clock is halted.
+    // The day counter wraps after 511 and sets the carry bit.
+    fn update_rtc(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_sub(self.rtc_time);
+        self.rtc_time = now;
+        if elapsed == 0 || self.rtc[4] & 0x40 != 0 {
+            return;
+        }
+        let days = self.rtc[3] as u64 | ((self.rtc[4] as u64 & 0x01) << 8);
+        let total = self.rtc[0] as u64 + self.rtc[1] as u64 * 60 + self.rtc[2] as u64 * 3600 + days * 86400 + elapsed;
+        let days = total / 86400;
+        self.rtc[0] = (total % 60) as u8;
+        self.rtc[1] = (total / 60 % 60) as u8;
+        self.rtc[2] = (total / 3600 % 24) as u8;
+        self.rtc[3] = days as u8;
+        self.rtc[4] = (self.rtc[4] & 0xC0) | ((days >> 8) & 0x01) as u8;
+        if days > 0x1FF {
+            self.rtc[4] |= 0x80;
+        }
+    }
+
+    // Reads a clock footer of either layout. A save without one (or with one
+    // of another size) starts the clock from zero.
+    fn load_rtc_footer(&mut self, footer: &[u8]) {
+        let format = match footer.len() {
+            48 => RtcFormat::Bytes48,
+            44 => RtcFormat::Bytes44,
+            0 => return,
+            len => {
+                warn!("Ignoring an RTC footer of {} bytes in the save file (expected 44 or 48)", len);
+                return;
+            }
+        };
+        let word = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+        for (i, mask) in RTC_MASKS.iter().enumerate() {
+            self.rtc[i] = word(i) as u8 & mask;
+            self.rtc_latch[i] = word(5 + i) as u8 & mask;
+        }
+        self.rtc_time = match format {
+            RtcFormat::Bytes48 => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            RtcFormat::Bytes44 => word(10) as u64,
+        };
+        self.rtc_format = format;
+        debug!("Loaded the RTC from a {}-byte footer", format.len());
+    }
+
+    // Appends the clock, as of the last update_rtc(), to the RAM being saved
+    fn push_rtc_footer(&self, data: &mut Vec<u8>) {
+        for &register in self.rtc.iter().chain(&self.rtc_latch) {
+            data.extend_from_slice(&(register as u32).to_le_bytes());
+        }
+        match self.rtc_format {
+            RtcFormat::Bytes48 => data.extend_from_slice(&self.rtc_time.to_le_bytes()),
+            RtcFormat::Bytes44 => data.extend_from_slice(&(self.rtc_time as u32).to_le_bytes()),
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, nonzero(self.bank))
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                // RAM and Timer Enable
+                self.ram_enabled = (value & 0x0F) == 0x0A;
+            }
+            0x2000..=0x3FFF => {
+                // ROM Bank Number (7 bits)
+                self.bank = value & 0x7F;
+            }
+            0x4000..=0x5FFF => {
+                // RAM Bank Number or RTC Register Select
+                if value <= 0x03 {
+                    // RAM bank
+                    self.ram_bank = value;
+                    self.rtc_register = 0;
+                } else if (0x08..=0x0C).contains(&value) {
+                    // RTC register
+                    self.rtc_register = value;
+                }
+            }
+            0x6000..=0x7FFF => {
+                // Latch Clock Data: writing 0x00 then 0x01 copies the clock
+                if value == 0x01 && self.rtc_latch_armed {
+                    self.update_rtc();
+                    self.rtc_latch = self.rtc;
+                }
+                self.rtc_latch_armed = value == 0x00;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // RTC register read, from the latched registers
+        if self.rtc_selected() {
+            if !self.has_rtc {
+                return 0xFF;
+            }
+            return self.rtc_latch[(self.rtc_register - 0x08) as usize];
+        }
+        read_banked(&self.ram, (self.ram_bank & 0x0F) as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        // RTC register write, setting the live clock
+        if self.rtc_selected() {
+            if self.has_rtc {
+                self.update_rtc();
+                let index = (self.rtc_register - 0x08) as usize;
+                self.rtc[index] = value & RTC_MASKS[index];
+            }
+            return self.has_rtc;
+        }
+        write_banked(&mut self.ram, (self.ram_bank & 0x0F) as usize, address, value)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if self.has_rtc {
+            self.update_rtc();
+            self.push_rtc_footer(&mut data);
+        }
+        data
+    }
+
+    // Anything past the RAM is the clock footer
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        self.load_rtc_footer(&data[len..]);
+    }
+
+    fn use_wall_clock(&mut self) {
+        self.clock.emulated = None;
+        self.rtc_time = unix_time();
+    }
+
+    fn use_emulated_clock(&mut self) {
+        self.clock = Clock::emulated();
+        self.rtc = [0; 5];
+        self.rtc_latch = [0; 5];
+        self.rtc_time = 0;
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.clock.tick(cycles);
+    }
+
+    fn set_rtc_format(&mut self, format: RtcFormat) {
+        self.rtc_format = format;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+        w.bool(self.ram_enabled);
+        w.u8(self.ram_bank);
+        w.u8(self.rtc_register);
+        w.bool(self.rtc_latch_armed);
+        w.bytes(&self.rtc);
+        w.bytes(&self.rtc_latch);
+        w.u64(self.rtc_time);
+        self.clock.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.bank = r.u8();
+        self.ram_enabled = r.bool();
+        self.ram_bank = r.u8();
+        self.rtc_register = r.u8();
+        self.rtc_latch_armed = r.bool();
+        r.bytes(&mut self.rtc);
+        r.bytes(&mut self.rtc_latch);
+        self.rtc_time = r.u64();
+        self.clock.load_state(r);
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.bank = old.bank;
+        self.ram_enabled = old.ram_enabled;
+        self.ram_bank = old.ram_bank;
+        self.rtc_register = old.rtc_register;
+        self.rtc_latch_armed = old.rtc_latch_armed;
+        if let Some((rtc, latch, time)) = old.rtc {
+            (self.rtc, self.rtc_latch, self.rtc_time) = (rtc, latch, time);
+        }
+        self.clock.restore(old.emulated_clock, old.clock);
+    }
+}
+
+// MBC5: 9-bit ROM bank, which unlike the older mappers can select bank 0,
+// and 16 RAM banks
+pub struct Mbc5 {
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank_low: u8,  // Lower 8 bits of ROM bank
+    rom_bank_high: u8, // 9th bit of ROM bank
+    ram_bank: u8,      // 4 bits
+}
+
+impl Mbc5 {
+    pub fn new(ram_size: usize) -> Self {
+        Mbc5 { ram: vec![0; ram_size], ram_enabled: false, rom_bank_low: 0x01, rom_bank_high: 0x00, ram_bank: 0x00 }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, ((self.rom_bank_high as usize & 0x01) << 8) | (self.rom_bank_low as usize))
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                // RAM Enable
+                self.ram_enabled = (value & 0x0F) == 0x0A;
+            }
+            0x2000..=0x2FFF => {
+                // ROM Bank Number (lower 8 bits)
+                self.rom_bank_low = value;
+            }
+            0x3000..=0x3FFF => {
+                // ROM Bank Number (9th bit)
+                self.rom_bank_high = value & 0x01;
+            }
+            0x4000..=0x5FFF => {
+                // RAM Bank Number (4 bits)
+                self.ram_bank = value & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        read_banked(&self.ram, self.ram_bank as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        self.ram_enabled && write_banked(&mut self.ram, self.ram_bank as usize, address, value)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.rom_bank_low, self.rom_bank_high, self.ram_bank]);
+        w.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.rom_bank_low = r.u8();
+        self.rom_bank_high = r.u8();
+        self.ram_bank = r.u8();
+        self.ram_enabled = r.bool();
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.rom_bank_low = old.rom_bank_low;
+        self.rom_bank_high = old.rom_bank_high;
+        self.ram_bank = old.ram_bank;
+        self.ram_enabled = old.ram_enabled;
+    }
+}
+
+// HuC1: 6-bit ROM bank and 4 RAM banks, with an IR LED and sensor mapped
+// over the RAM instead of a RAM enable
+pub struct HuC1 {
+    ram: Vec<u8>,
+    mode: u8, // Value written to 0x0000-0x1FFF: 0x0E selects IR, anything else RAM
+    bank: u8,
+    ram_bank: u8,
+}
+
+impl HuC1 {
+    pub fn new(ram_size: usize) -> Self {
+        HuC1 { ram: vec![0; ram_size], mode: 0x00, bank: 0x01, ram_bank: 0x00 }
+    }
+}
+
+impl Mapper for HuC1 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, nonzero(self.bank))
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.mode = value & 0x0F,
+            0x2000..=0x3FFF => self.bank = value & 0x3F, // ROM Bank Number (6 bits)
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        // IR mode: 0xC0 = no light received
+        if self.mode == 0x0E {
+            return 0xC0;
+        }
+        read_banked(&self.ram, self.ram_bank as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        // IR LED (not connected)
+        self.mode != 0x0E && write_banked(&mut self.ram, self.ram_bank as usize, address, value)
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.mode, self.bank, self.ram_bank]);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.mode = r.u8();
+        self.bank = r.u8();
+        self.ram_bank = r.u8();
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.mode = old.huc_mode;
+        self.bank = old.bank;
+        self.ram_bank = old.ram_bank;
+    }
+}
+
+// HuC3: 7-bit ROM bank, 4 RAM banks, IR, and a clock counting minutes and
+// days, driven by commands written to 0xA000-0xBFFF
+pub struct HuC3 {
+    ram: Vec<u8>,
+    mode: u8,               // 0x0A RAM, 0x0B/0x0C/0x0D RTC, 0x0E IR, written to 0x0000-0x1FFF
+    bank: u8,
+    ram_bank: u8,
+    command: u8,            // Last RTC command written in mode 0x0B
+    response: u8,           // RTC response nibble read in mode 0x0C
+    address: u8,            // RTC memory access address
+    memory: [u8; 0x100],    // RTC memory nibbles (0-2 minutes, 3-5 days)
+    rtc_base: u64,          // Time at which the RTC counter was zero
+    clock: Clock,
+}
+
+impl HuC3 {
+    pub fn new(ram_size: usize) -> Self {
+        HuC3 {
+            ram: vec![0; ram_size],
+            mode: 0x00,
+            bank: 0x01,
+            ram_bank: 0x00,
+            command: 0x00,
+            response: 0x00,
+            address: 0x00,
+            memory: [0; 0x100],
+            rtc_base: 0,
+            clock: Clock::emulated(),
+        }
+    }
+
+    // Current RTC as (minutes of day, day counter)
+    fn time(&self) -> (u16, u16) {
+        let minutes = self.clock.now().saturating_sub(self.rtc_base) / 60;
+        ((minutes % 1440) as u16, ((minutes / 1440) & 0xFFF) as u16)
+    }
+
+    // Executes an RTC command written in mode 0x0B, true if it set the clock
+    fn rtc_command(&mut self, value: u8) -> bool {
+        self.command = value;
+        let arg = value & 0x0F;
+        match (value >> 4) & 0x07 {
+            0x1 => {
+                // Read nibble and advance
+                self.response = self.memory[self.address as usize] & 0x0F;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x3 => {
+                // Write nibble and advance
+                self.memory[self.address as usize] = arg;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = (self.address & 0xF0) | arg,
+            0x5 => self.address = (self.address & 0x0F) | (arg << 4),
+            0x6 => match arg {
+                0x0 => {
+                    // Latch the clock into RTC memory
+                    let (minutes, days) = self.time();
+                    for i in 0..3 {
+                        self.memory[i] = ((minutes >> (i * 4)) & 0x0F) as u8;
+                        self.memory[3 + i] = ((days >> (i * 4)) & 0x0F) as u8;
+                    }
+                }
+                0x1 => {
+                    // Set the clock from RTC memory
+                    let mut minutes = 0u64;
+                    let mut days = 0u64;
+                    for i in 0..3 {
+                        minutes |= (self.memory[i] as u64 & 0x0F) << (i * 4);
+                        days |= (self.memory[3 + i] as u64 & 0x0F) << (i * 4);
+                    }
+                    self.rtc_base = self.clock.now().saturating_sub((days * 1440 + minutes) * 60);
+                    return true;
+                }
+                0x2 => self.response = 0x01, // Status: clock running
+                _ => {}
+            },
+            _ => {}
+        }
+        false
+    }
+}
+
+impl Mapper for HuC3 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, nonzero(self.bank))
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.mode = value & 0x0F,
+            0x2000..=0x3FFF => self.bank = value & 0x7F, // ROM Bank Number (7 bits)
+            0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.mode {
+            0x0C => (self.command & 0xF0) | self.response,
+            0x0D => 0x01, // RTC semaphore: ready
+            0x0E => 0xC0, // IR: no light received
+            0x00 | 0x0A => read_banked(&self.ram, self.ram_bank as usize, address),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        match self.mode {
+            0x0A => write_banked(&mut self.ram, self.ram_bank as usize, address, value),
+            0x0B => self.rtc_command(value),
+            _ => false, // IR LED, read-only RAM and semaphore writes are ignored
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // The footer is the RTC base time as little-endian Unix seconds
+    fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&self.rtc_base.to_le_bytes());
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        if let Some(footer) = data.get(len..len + 8) {
+            self.rtc_base = u64::from_le_bytes(footer.try_into().unwrap());
+        }
+    }
+
+    fn use_wall_clock(&mut self) {
+        self.clock.emulated = None;
+        self.rtc_base = unix_time();
+    }
+
+    fn use_emulated_clock(&mut self) {
+        self.clock = Clock::emulated();
+        self.rtc_base = 0;
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.clock.tick(cycles);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.mode, self.bank, self.ram_bank]);
+        w.bytes(&[self.command, self.response, self.address]);
+        w.bytes(&self.memory);
+        w.u64(self.rtc_base);
+        self.clock.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.mode = r.u8();
+        self.bank = r.u8();
+        self.ram_bank = r.u8();
+        self.command = r.u8();
+        self.response = r.u8();
+        self.address = r.u8();
+        r.bytes(&mut self.memory);
+        self.rtc_base = r.u64();
+        self.clock.load_state(r);
+    }
+
+    fn load_legacy_state(&mut self, old: &LegacyState) {
+        self.mode = old.huc_mode;
+        self.bank = old.bank;
+        self.ram_bank = old.ram_bank;
+        self.command = old.huc3_command;
+        self.response = old.huc3_response;
+        self.address = old.huc3_address;
+        self.memory = old.huc3_memory;
+        self.rtc_base = old.huc3_rtc_base;
+        self.clock.restore(old.emulated_clock, old.clock);
+    }
+}
+
+// TAMA5 registers, written as nibbles to 0xA000 after selecting them
+// through 0xA001
+const TAMA5_BANK_LOW: usize = 0x0;
+const TAMA5_BANK_HIGH: usize = 0x1;
+const TAMA5_DATA_LOW: usize = 0x4;
+const TAMA5_DATA_HIGH: usize = 0x5;
+const TAMA5_ADDRESS_HIGH: usize = 0x6; // Bit 0: address bit 4, bits 1-3: command
+const TAMA5_ADDRESS_LOW: usize = 0x7; // Runs the command
+const TAMA5_READY: u8 = 0xA; // Reads 1 once the chip answers
+const TAMA5_READ_LOW: u8 = 0xC;
+const TAMA5_READ_HIGH: u8 = 0xD;
+
+// Registers of a TAMA6 clock page: BCD digits, ones first
+const TAMA6_REGISTERS: usize = 13;
+const TAMA6_SECOND: usize = 0;
+const TAMA6_MINUTE: usize = 2;
+const TAMA6_HOUR: usize = 4;
+const TAMA6_WEEKDAY: usize = 6;
+const TAMA6_DAY: usize = 7;
+const TAMA6_MONTH: usize = 9;
+const TAMA6_YEAR: usize = 11;
+
+// Save file footer: the four clock pages, whether the clock runs, and the
+// Unix time of the save (u64 LE)
+const TAMA5_FOOTER_LEN: usize = 4 * TAMA6_REGISTERS + 1 + 8;
+
+// Bandai TAMA5 (Tamagotchi 3): everything goes through two registers at
+// 0xA000 (data) and 0xA001 (register select). Commands written through them
+// switch the ROM bank, read and write 32 bytes of RAM, and drive a TAMA6
+// clock chip with a running timer page, an alarm page and two free pages.
+pub struct Tama5 {
+    ram: Vec<u8>,
+    select: u8,
+    registers: [u8; 8],
+    pages: [[u8; TAMA6_REGISTERS]; 4], // Timer (live as of rtc_time), alarm, free 0, free 1
+    rtc_enabled: bool,
+    rtc_time: u64, // clock.now() when the timer page was last brought up to date
+    clock: Clock,
+}
+
+impl Tama5 {
+    pub fn new() -> Self {
+        let mut registers = [0; 8];
+        registers[TAMA5_BANK_LOW] = 0x01;
+        Tama5 {
+            ram: vec![0; 0x20],
+            select: 0,
+            registers,
+            pages: [[0; TAMA6_REGISTERS]; 4],
+            rtc_enabled: true,
+            rtc_time: 0,
+            clock: Clock::emulated(),
+        }
+    }
+
+    // RAM address (5 bits) and command of the registers written
+    fn address(&self) -> usize {
+        ((self.registers[TAMA5_ADDRESS_HIGH] as usize & 0x01) << 4) | self.registers[TAMA5_ADDRESS_LOW] as usize
+    }
+
+    fn command(&self) -> u8 {
+        self.registers[TAMA5_ADDRESS_HIGH] >> 1
+    }
+
+    fn data(&self) -> u8 {
+        (self.registers[TAMA5_DATA_HIGH] << 4) | self.registers[TAMA5_DATA_LOW]
+    }
+
+    // The timer page as of now, advanced by the time since rtc_time
+    fn timer(&self) -> [u8; TAMA6_REGISTERS] {
+        let mut timer = self.pages[0];
+        let elapsed = self.clock.now().saturating_sub(self.rtc_time);
+        if !self.rtc_enabled || elapsed == 0 {
+            return timer;
+        }
+        let bcd = |t: &[u8; TAMA6_REGISTERS], i: usize| (t[i] + t[i + 1] * 10) as u64;
+        let seconds = bcd(&timer, TAMA6_SECOND) + bcd(&timer, TAMA6_MINUTE) * 60 + bcd(&timer, TAMA6_HOUR) * 3600;
+        let total = seconds + elapsed;
+        let set = |t: &mut [u8; TAMA6_REGISTERS], i: usize, value: u64| {
+            t[i] = (value % 10) as u8;
+            t[i + 1] = (value / 10) as u8;
+        };
+        set(&mut timer, TAMA6_SECOND, total % 60);
+        set(&mut timer, TAMA6_MINUTE, total / 60 % 60);
+        set(&mut timer, TAMA6_HOUR, total / 3600 % 24);
+
+        // Whole days, through the calendar (two-digit years, leap every 4)
+        let (mut day, mut month, mut year) =
+            (bcd(&timer, TAMA6_DAY), bcd(&timer, TAMA6_MONTH), bcd(&timer, TAMA6_YEAR));
+        for _ in 0..total / 86400 {
+            timer[TAMA6_WEEKDAY] = (timer[TAMA6_WEEKDAY] + 1) % 7;
+            let days = match month {
+                2 if year % 4 == 0 => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                _ => 31,
+            };
+            day += 1;
+            if day > days {
+                day = 1;
+                month = month % 12 + 1;
+                if month == 1 {
+                    year = (year + 1) % 100;
+                }
+            }
+        }
+        set(&mut timer, TAMA6_DAY, day);
+        set(&mut timer, TAMA6_MONTH, month);
+        set(&mut timer, TAMA6_YEAR, year);
+        timer
+    }
+
+    fn update_rtc(&mut self) {
+        self.pages[0] = self.timer();
+        self.rtc_time = self.clock.now();
+    }
+
+    // Runs the command of the registers, once the address's low nibble is
+    // written. True if it changed RAM or the clock.
+    fn run_command(&mut self) -> bool {
+        let address = self.address();
+        match self.command() {
+            0x0 => {
+                self.ram[address] = self.data();
+                true
+            }
+            0x2 => {
+                // Clock control
+                self.update_rtc();
+                let data = self.data();
+                match address {
+                    0x00 => self.rtc_enabled = false,
+                    0x01 => self.rtc_enabled = true,
+                    0x04 => self.pages[0][TAMA6_MINUTE..TAMA6_MINUTE + 2].copy_from_slice(&[data & 0x0F, data >> 4]),
+                    0x05 => self.pages[0][TAMA6_HOUR..TAMA6_HOUR + 2].copy_from_slice(&[data & 0x0F, data >> 4]),
+                    _ => return false,
+                }
+                true
+            }
+            0x4 => {
+                // Clock register write: the register in the data's low nibble, the
+                // value in its high nibble, even address nibbles picking the page
+                let register = self.registers[TAMA5_DATA_LOW] as usize;
+                let page = (self.registers[TAMA5_ADDRESS_LOW] >> 1) as usize;
+                if register >= TAMA6_REGISTERS || self.registers[TAMA5_ADDRESS_LOW] & 1 != 0 || page > 3 {
+                    return false;
+                }
+                self.update_rtc();
+                self.pages[page][register] = self.registers[TAMA5_DATA_HIGH];
+                true
+            }
+            _ => false, // RAM reads happen when the result is read
+        }
+    }
+
+    // Byte the read registers return for the last command
+    fn result(&self) -> u8 {
+        let address = self.address();
+        match self.command() {
+            0x1 => self.ram[address],
+            0x2 => {
+                let timer = self.timer();
+                match address {
+                    0x06 => (timer[TAMA6_MINUTE + 1] << 4) | timer[TAMA6_MINUTE],
+                    0x07 => (timer[TAMA6_HOUR + 1] << 4) | timer[TAMA6_HOUR],
+                    _ => 0,
+                }
+            }
+            0x4 => {
+                // Clock register read, odd address nibbles
+                let register = self.registers[TAMA5_DATA_LOW] as usize;
+                let page = (self.registers[TAMA5_ADDRESS_LOW] >> 1) as usize;
+                match (page, register) {
+                    (0, r) if r < TAMA6_REGISTERS => self.timer()[r],
+                    (1..=3, r) if r < TAMA6_REGISTERS => self.pages[page][r],
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl Default for Tama5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for Tama5 {
+    fn rom_banks(&self) -> (usize, usize) {
+        (0, (self.registers[TAMA5_BANK_HIGH] as usize) << 4 | self.registers[TAMA5_BANK_LOW] as usize)
+    }
+
+    // Nothing is mapped at 0x0000-0x7FFF
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if address & 1 != 0 {
+            return 0xFF;
+        }
+        match self.select {
+            TAMA5_READY => 0xF1,
+            TAMA5_READ_LOW => 0xF0 | (self.result() & 0x0F),
+            TAMA5_READ_HIGH => 0xF0 | (self.result() >> 4),
+            _ => 0xF0,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) -> bool {
+        if address & 1 != 0 {
+            self.select = value & 0x0F;
+            return false;
+        }
+        let Some(register) = self.registers.get_mut(self.select as usize) else {
+            return false;
+        };
+        *register = value & 0x0F;
+        self.select as usize == TAMA5_ADDRESS_LOW && self.run_command()
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.update_rtc();
+        let mut data = self.ram.clone();
+        data.extend(self.pages.iter().flatten());
+        data.push(self.rtc_enabled as u8);
+        data.extend_from_slice(&self.rtc_time.to_le_bytes());
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        let footer = &data[len..];
+        if footer.len() != TAMA5_FOOTER_LEN {
+            if !footer.is_empty() {
+                warn!("Ignoring a TAMA5 clock footer of {} bytes (expected {})", footer.len(), TAMA5_FOOTER_LEN);
+            }
+            return;
+        }
+        for (page, bytes) in self.pages.iter_mut().zip(footer.chunks(TAMA6_REGISTERS)) {
+            page.copy_from_slice(bytes);
+        }
+        self.rtc_enabled = footer[4 * TAMA6_REGISTERS] != 0;
+        self.rtc_time = u64::from_le_bytes(footer[4 * TAMA6_REGISTERS + 1..].try_into().unwrap());
+    }
+
+    fn use_wall_clock(&mut self) {
+        self.clock.emulated = None;
+        self.rtc_time = unix_time();
+    }
+
+    fn use_emulated_clock(&mut self) {
+        self.clock = Clock::emulated();
+        self.pages[0] = [0; TAMA6_REGISTERS];
+        self.rtc_time = 0;
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        self.clock.tick(cycles);
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.bytes(&self.registers);
+        for page in &self.pages {
+            w.bytes(page);
+        }
+        w.bool(self.rtc_enabled);
+        w.u64(self.rtc_time);
+        self.clock.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.select = r.u8();
+        r.bytes(&mut self.registers);
+        for page in &mut self.pages {
+            r.bytes(page);
+        }
+        self.rtc_enabled = r.bool();
+        self.rtc_time = r.u64();
+        self.clock.load_state(r);
+    }
+
+    // Older versions ran these games as MBC1
+    fn load_legacy_state(&mut self, _old: &LegacyState) {}
+}
+
+// Wisdom Tree: unlicensed games switching the whole 32KB at
+// 0x0000-0x7FFF at once. The bank is the low byte of the address written to
+// in 0x0000-0x3FFF, and the value written is ignored.
+#[derive(Default)]
+pub struct WisdomTree {
+    bank: u8, // 32KB bank
+}
+
+impl Mapper for WisdomTree {
+    fn rom_banks(&self) -> (usize, usize) {
+        let first = self.bank as usize * 2;
+        (first, first + 1)
+    }
+
+    fn write_rom(&mut self, address: u16, _value: u8) {
+        if address < 0x4000 {
+            self.bank = address as u8;
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) {
+        self.bank = r.u8();
+    }
+
+    // Older versions ran these games as ROM only
+    fn load_legacy_state(&mut self, _old: &LegacyState) {}
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
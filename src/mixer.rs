@@ -0,0 +1,116 @@
+// Turns the APU's raw output (stereo at apu::SAMPLE_RATE) into mono samples
+// at the rate of the audio device: resampled with linear interpolation, then
+// filtered as picked by AudioFilter. Runs where the audio is played, e.g. in
+// the device callback, so the emulation itself only collects samples.
+
+use crate::apu::SAMPLE_RATE;
+
+const CPU_CLOCK: f64 = 4194304.0;
+// Charge the DMG's output capacitor keeps per CPU cycle (hardware measurement)
+const CAPACITOR_CHARGE: f64 = 0.999958;
+// Cutoff of the low-pass filter in the smooth mode
+const SMOOTH_CUTOFF: f64 = 1200.0; // Hz
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioFilter {
+    Raw,    // Unfiltered, including the DC offset of the channel DACs
+    Dmg,    // The DMG's output capacitor only, as on hardware
+    Smooth, // The capacitor plus a low-pass filter softening the square waves
+}
+
+impl AudioFilter {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "raw" => Some(AudioFilter::Raw),
+            "dmg" => Some(AudioFilter::Dmg),
+            "smooth" => Some(AudioFilter::Smooth),
+            _ => None,
+        }
+    }
+}
+
+// The Game Boy's output capacitor: a high-pass filter that removes the DC
+// offset of the channel DACs, so a DAC turned on or off fades in or out
+// instead of leaving the output off center
+pub struct HighPass {
+    capacitor: f32,
+    charge: f32,
+}
+
+impl HighPass {
+    pub fn new(rate: u32) -> Self {
+        HighPass { capacitor: 0.0, charge: CAPACITOR_CHARGE.powf(CPU_CLOCK / rate as f64) as f32 }
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        let filtered = sample - self.capacitor;
+        self.capacitor = sample - filtered * self.charge;
+        filtered
+    }
+}
+
+pub struct Mixer {
+    rate_step: f64, // Input samples per output sample at full speed
+    step: f64,      // Input samples per output sample
+    position: f64, // Of the next output sample, between `last` and the next input sample
+    last: f32,     // Previous input sample, mixed to mono
+    filter: AudioFilter,
+    high_pass: HighPass,
+    last_output: f32,
+    smoothing: f32, // Of the low-pass filter, higher = more smoothing
+}
+
+impl Mixer {
+    pub fn new(output_rate: u32, filter: AudioFilter) -> Self {
+        Mixer {
+            rate_step: SAMPLE_RATE as f64 / output_rate as f64,
+            step: SAMPLE_RATE as f64 / output_rate as f64,
+            position: 0.0,
+            last: 0.0,
+            filter,
+            high_pass: HighPass::new(output_rate),
+            last_output: 0.0,
+            smoothing: (-2.0 * std::f64::consts::PI * SMOOTH_CUTOFF / output_rate as f64).exp() as f32,
+        }
+    }
+
+    // For emulation running at `speed` percent: the input is played that
+    // much faster or slower, so it keeps up and the pitch changes with it
+    pub fn set_speed(&mut self, speed: u32) {
+        self.step = self.rate_step * speed as f64 / 100.0;
+    }
+
+    // Stereo input samples needed for about this many output samples
+    pub fn input_len(&self, output_len: usize) -> usize {
+        (output_len as f64 * self.step).ceil() as usize
+    }
+
+    // Mixes interleaved stereo input down and appends the output samples
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        for frame in input.chunks_exact(2) {
+            let sample = (frame[0] + frame[1]) * 0.5;
+            while self.position < 1.0 {
+                let resampled = self.last + (sample - self.last) * self.position as f32;
+                output.push(self.filter(resampled));
+                self.position += self.step;
+            }
+            self.position -= 1.0;
+            self.last = sample;
+        }
+    }
+
+    fn filter(&mut self, sample: f32) -> f32 {
+        if self.filter == AudioFilter::Raw {
+            return sample;
+        }
+        // High-pass filter to remove DC offset (capacitor charge/discharge)
+        let filtered = self.high_pass.apply(sample);
+        if self.filter == AudioFilter::Dmg {
+            return filtered;
+        }
+
+        // Simple one-pole low-pass filter (reduces aliasing and harshness)
+        self.last_output = self.last_output * self.smoothing + filtered * (1.0 - self.smoothing);
+        self.last_output
+    }
+}
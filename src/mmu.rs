@@ -3,16 +3,66 @@ use crate::ppu::Ppu;
 use crate::joypad::Joypad;
 use crate::timer::Timer;
 use crate::apu::Apu;
+use crate::infrared::Infrared;
+use crate::serial::Serial;
+use crate::cheats::Cheats;
+use crate::cdl::{self, Cdl};
+use crate::palette;
+use crate::savestate::{StateReader, StateWriter};
+use std::cell::{Cell, RefCell};
 
 const WRAM_SIZE: usize = 0x2000; // 8KB work RAM (DMG) or per-bank (GBC)
 const HRAM_SIZE: usize = 0x7F;   // High RAM
 
+// A memory access that matched a watchpoint or hook
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// Addresses hooked by a script, and the accesses to them not yet handled
+pub struct MemoryHooks {
+    read: Vec<u64>, // One bit per address
+    write: Vec<u64>,
+    hits: RefCell<Vec<MemoryAccess>>,
+}
+
+impl MemoryHooks {
+    fn new() -> Self {
+        MemoryHooks { read: vec![0; 0x400], write: vec![0; 0x400], hits: RefCell::new(Vec::new()) }
+    }
+
+    fn is_set(bits: &[u64], address: u16) -> bool {
+        bits[address as usize / 64] & (1 << (address % 64)) != 0
+    }
+
+    fn record(&self, address: u16, value: u8, write: bool) {
+        let bits = if write { &self.write } else { &self.read };
+        if Self::is_set(bits, address) {
+            self.hits.borrow_mut().push(MemoryAccess { address, value, write });
+        }
+    }
+}
+
 pub struct Mmu {
     pub cartridge: Cartridge,
     pub ppu: Ppu,
     pub joypad: Joypad,
     pub timer: Timer,
     pub apu: Apu,
+    pub infrared: Infrared,
+    pub serial: Serial,
+    pub cheats: Cheats,
+    pub io_watch_read: u128,  // I/O watchpoints, one bit per register 0xFF00-0xFF7F
+    pub io_watch_write: u128,
+    io_hit: Cell<Option<MemoryAccess>>, // Last watched access, set from read_io() too
+    pub warn_echo_ram: bool, // Developer mode: record echo RAM accesses for a warning (take_echo_hit)
+    echo_hit: Cell<Option<MemoryAccess>>,
+    pub cdl: Option<Cdl>, // Code/data logging of ROM accesses
+    hooks: Option<MemoryHooks>,
+    flat_ram: Option<Box<[u8; 0x10000]>>, // CPU tests: the whole address space as plain RAM
     wram: [[u8; WRAM_SIZE]; 8],  // GBC: 8 banks of 4KB each
     wram_bank: u8,               // GBC: WRAM bank select (0xFF70)
     hram: [u8; HRAM_SIZE],
@@ -21,44 +71,198 @@ pub struct Mmu {
     is_gbc: bool,
 
     // GBC-specific
+    key0: u8,        // 0xFF4C - Mode the boot ROM picked: the CGB flag of a color game, 0x04 for DMG compatibility
     key1: u8,        // 0xFF4D - Speed switch
     hdma_source: u16,
     hdma_dest: u16,
+    hdma_active: bool, // HBlank DMA in progress
+    hdma_remaining: u8, // Remaining 16-byte blocks minus one (0xFF55 bits 0-6)
+    undocumented: [u8; 4], // 0xFF72-0xFF75: scratch registers without a known use
 }
 
 impl Mmu {
     pub fn new(cartridge: Cartridge, is_gbc: bool) -> Self {
+        // The CGB boot ROM runs games without CGB support in DMG
+        // compatibility mode, with object priority by X coordinate and the
+        // colors it picks for the game
+        let key0 = match is_gbc {
+            true if cartridge.supports_cgb() => cartridge.header()[0x143],
+            true => 0x04,
+            false => 0,
+        };
+        let mut ppu = Ppu::new(is_gbc);
+        if key0 & 0x04 != 0 {
+            ppu.dmg_compat = true;
+            ppu.opri = 1;
+            ppu.dmg_palette = palette::compat_palette(palette::boot_rom_palette(cartridge.header()));
+            ppu.blank_screen();
+        }
         Mmu {
             cartridge,
-            ppu: Ppu::new(is_gbc),
+            ppu,
             joypad: Joypad::new(),
             timer: Timer::new(),
-            apu: Apu::new(),
+            apu: Apu::new(is_gbc),
+            infrared: Infrared::new(),
+            serial: Serial::new(is_gbc),
+            cheats: Cheats::new(),
+            io_watch_read: 0,
+            io_watch_write: 0,
+            io_hit: Cell::new(None),
+            warn_echo_ram: false,
+            echo_hit: Cell::new(None),
+            cdl: None,
+            hooks: None,
+            flat_ram: None,
             wram: [[0; WRAM_SIZE]; 8],
-            wram_bank: if is_gbc { 0xF8 } else { 1 }, // Post-boot: 0xF8 for GBC (maps to bank 0/1)
+            wram_bank: if is_gbc { 0 } else { 1 }, // Post-boot: 0 for GBC (reads back 0xF8, maps bank 1)
             hram: [0; HRAM_SIZE],
             ie: 0,
             if_reg: if is_gbc { 0xE1 } else { 0 }, // Post-boot value
             is_gbc,
+            key0,
             key1: if is_gbc { 0x7E } else { 0 }, // Post-boot: 0x7E for GBC
             hdma_source: 0,
             hdma_dest: 0,
+            hdma_active: false,
+            hdma_remaining: 0x7F,
+            // 0xFF74 is locked to 0xFF in DMG compatibility mode, 0xFF75 only keeps bits 4-6
+            undocumented: [0, 0, if key0 & 0x04 != 0 { 0xFF } else { 0 }, 0x8F],
+        }
+    }
+
+    // CGB running a DMG game: the CGB registers are locked
+    pub fn dmg_compat(&self) -> bool {
+        self.key0 & 0x04 != 0
+    }
+
+    // Whether the CGB registers (banks, speed switch, HDMA, color palettes) are there
+    fn cgb_registers(&self) -> bool {
+        self.is_gbc && !self.dmg_compat()
+    }
+
+    // GBC double-speed mode (KEY1 bit 7)
+    pub fn double_speed(&self) -> bool {
+        (self.key1 & 0x80) != 0
+    }
+
+    // Converts CPU cycles to normal-speed cycles (PPU/APU clock)
+    pub fn normal_speed_cycles(&self, cycles: u32) -> u32 {
+        if self.double_speed() { cycles / 2 } else { cycles }
+    }
+
+    // Performs the speed switch armed by KEY1 bit 0, as triggered by STOP.
+    // Returns false if no switch was requested.
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.is_gbc || (self.key1 & 0x01) == 0 {
+            return false;
+        }
+
+        // Toggle the current speed and clear the prepare bit
+        self.key1 = (self.key1 ^ 0x80) & 0xFE;
+        self.reset_div();
+        true
+    }
+
+    // Resets DIV, which can clock the APU frame sequencer like the timer
+    pub fn reset_div(&mut self) {
+        self.apu.reset_div(self.timer.div, self.double_speed());
+        self.timer.write_div();
+    }
+
+    // Called when the CPU enters STOP mode (without a speed switch)
+    pub fn enter_stop(&mut self) {
+        // With the clock stopped the DMG LCD shows a blank screen
+        if !self.is_gbc {
+            self.ppu.blank_screen();
         }
     }
 
-    pub fn step(&mut self, cycles: u32) {
-        // Step timer and check for interrupt
+    // Advances every component by `cycles` CPU cycles. Called by the CPU for
+    // each M-cycle so memory accesses see up-to-date PPU and timer state.
+    pub fn tick(&mut self, cycles: u32) {
+        if self.flat_ram.is_some() {
+            return;
+        }
+        // Step timer and check for interrupt (runs at CPU speed)
+        let div = self.timer.div;
         if self.timer.step(cycles) {
             self.if_reg |= 0x04; // Timer interrupt
         }
+        // The serial port and the APU frame sequencer are clocked by the divider too
+        if self.serial.step(div, cycles) {
+            self.if_reg |= 0x08; // Serial interrupt
+        }
+        self.apu.step_div(div, cycles, self.double_speed());
 
-        // Step APU
+        // The APU and PPU run at normal speed even in GBC double-speed mode
+        let cycles = self.normal_speed_cycles(cycles);
         self.apu.step(cycles);
+        self.ppu.step(cycles);
+        self.cartridge.tick(cycles);
+
+        // GBC HBlank DMA
+        if self.ppu.hblank_started {
+            self.hdma_hblank();
+        }
+
+        if self.ppu.vblank_interrupt {
+            self.if_reg |= 0x01; // VBlank interrupt
+
+            // GameShark codes are applied once per frame
+            for (address, value) in self.cheats.ram_writes() {
+                self.write_byte(address, value);
+            }
+        }
+        if self.ppu.stat_interrupt {
+            self.if_reg |= 0x02; // STAT interrupt
+            self.ppu.stat_interrupt = false;
+        }
+        if self.joypad.interrupt_requested {
+            self.if_reg |= 0x10; // Joypad interrupt
+            self.joypad.interrupt_requested = false;
+        }
 
         // DMA is handled instantly when triggered (in write_io)
         // No need to step it here
     }
 
+    // Records a ROM access in the code/data log, if logging
+    pub fn log_rom_access(&mut self, address: u16, flags: u8) {
+        if let Some(ref mut log) = self.cdl {
+            if let Some(offset) = self.cartridge.rom_offset(address) {
+                log.mark(offset, flags);
+            }
+        }
+    }
+
+    // Copies one 16-byte block from the HDMA source to VRAM, advancing both addresses
+    fn hdma_transfer_block(&mut self) {
+        for _ in 0..0x10 {
+            self.log_rom_access(self.hdma_source, cdl::DATA);
+            let byte = self.read_byte(self.hdma_source);
+            self.ppu.write_vram(0x8000 | (self.hdma_dest & 0x1FFF), byte);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = self.hdma_dest.wrapping_add(1);
+        }
+    }
+
+    // Called at the start of each HBlank to run one block of an active HBlank DMA
+    pub fn hdma_hblank(&mut self) {
+        if !self.hdma_active {
+            return;
+        }
+
+        self.hdma_transfer_block();
+
+        if self.hdma_remaining == 0 {
+            self.hdma_active = false;
+            self.hdma_remaining = 0x7F; // Reads back as 0xFF when finished
+        } else {
+            self.hdma_remaining -= 1;
+        }
+    }
+
     fn do_dma(&mut self, source: u16) {
         // DMA transfers 160 bytes from source to OAM instantly
         // In reality this takes 160 M-cycles, but we do it atomically
@@ -68,20 +272,15 @@ impl Mmu {
 
             // Read from source
             let value = match source_addr {
-                0x0000..=0x7FFF => self.cartridge.read_rom(source_addr),
+                0x0000..=0x7FFF => {
+                    self.log_rom_access(source_addr, cdl::DATA);
+                    self.cartridge.read_rom(source_addr)
+                }
                 0x8000..=0x9FFF => self.ppu.read_vram(source_addr),
                 0xA000..=0xBFFF => self.cartridge.read_ram(source_addr),
-                0xC000..=0xCFFF => self.wram[0][(source_addr - 0xC000) as usize],
-                0xD000..=0xDFFF => {
-                    let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                    let bank = if bank == 0 { 1 } else { bank };
-                    self.wram[bank][(source_addr - 0xD000) as usize]
-                }
-                0xE000..=0xEFFF => self.wram[0][(source_addr - 0xE000) as usize],
-                0xF000..=0xFDFF => {
-                    let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                    let bank = if bank == 0 { 1 } else { bank };
-                    self.wram[bank][(source_addr - 0xF000) as usize]
+                0xC000..=0xFDFF => {
+                    let (bank, offset) = self.wram_location(source_addr);
+                    self.wram[bank][offset]
                 }
                 _ => 0xFF,
             };
@@ -91,26 +290,72 @@ impl Mmu {
         }
     }
 
+    // Reports reads or writes of `address` through take_hook_hits()
+    pub fn add_hook(&mut self, address: u16, write: bool) {
+        let hooks = self.hooks.get_or_insert_with(MemoryHooks::new);
+        let bits = if write { &mut hooks.write } else { &mut hooks.read };
+        bits[address as usize / 64] |= 1 << (address % 64);
+    }
+
+    pub fn has_hooks(&self) -> bool {
+        self.hooks.is_some()
+    }
+
+    // Returns and clears the accesses to hooked addresses
+    pub fn take_hook_hits(&self) -> Vec<MemoryAccess> {
+        match self.hooks {
+            Some(ref hooks) => hooks.hits.take(),
+            None => Vec::new(),
+        }
+    }
+
+    // For CPU tests: every address reads back what was written there, and
+    // nothing else is clocked, so there are no interrupts, DMA or I/O
+    pub fn use_flat_ram(&mut self) {
+        self.flat_ram = Some(Box::new([0; 0x10000]));
+    }
+
+    // Whether code may run from the CPU's block cache: no cheat patches ROM
+    // and no code/data log or memory hook watches instructions being fetched
+    pub fn code_cacheable(&self) -> bool {
+        self.flat_ram.is_none() && self.cdl.is_none() && self.hooks.is_none() && !self.cheats.patches_rom()
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
+        let value = match self.flat_ram {
+            Some(ref ram) => ram[address as usize],
+            None => self.read_memory(address),
+        };
+        if let Some(ref hooks) = self.hooks {
+            hooks.record(address, value, false);
+        }
+        value
+    }
+
+    fn read_memory(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0x7FFF => self.cartridge.read_rom(address), // ROM
+            0x0000..=0x7FFF => self.cheats.patch_rom(address, self.cartridge.read_rom(address)), // ROM
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => 0xFF, // Locked in mode 3
             0x8000..=0x9FFF => self.ppu.read_vram(address), // VRAM
             0xA000..=0xBFFF => self.cartridge.read_ram(address), // External RAM
-            0xC000..=0xCFFF => self.wram[0][(address - 0xC000) as usize], // WRAM bank 0
-            0xD000..=0xDFFF => {
-                // WRAM switchable bank (1-7 for GBC, always 1 for DMG)
-                let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                let bank = if bank == 0 { 1 } else { bank }; // Bank 0 acts as bank 1
-                self.wram[bank][(address - 0xD000) as usize]
-            }
-            0xE000..=0xEFFF => self.wram[0][(address - 0xE000) as usize], // Echo RAM
-            0xF000..=0xFDFF => {
-                let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                let bank = if bank == 0 { 1 } else { bank }; // Bank 0 acts as bank 1
-                self.wram[bank][(address - 0xF000) as usize]
+            0xC000..=0xFDFF => {
+                let (bank, offset) = self.wram_location(address);
+                let value = self.wram[bank][offset];
+                if address >= 0xE000 && self.warn_echo_ram {
+                    self.echo_hit.set(Some(MemoryAccess { address, value, write: false }));
+                }
+                value
             }
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => 0xFF, // Locked in modes 2-3
             0xFE00..=0xFE9F => self.ppu.read_oam(address), // OAM
-            0xFEA0..=0xFEFF => 0, // Unusable
+            // Unusable: locked with OAM, else 0 on the DMG and the high nibble of
+            // the low address byte twice on the CGB (revision E), e.g. 0xAA at 0xFEAx
+            0xFEA0..=0xFEFF if !self.ppu.oam_accessible() => 0xFF,
+            0xFEA0..=0xFEFF if self.is_gbc => {
+                let nibble = (address as u8) >> 4;
+                (nibble << 4) | nibble
+            }
+            0xFEA0..=0xFEFF => 0,
             0xFF00..=0xFF7F => self.read_io(address), // I/O registers
             0xFF80..=0xFFFE => self.hram[(address - 0xFF80) as usize],
             0xFFFF => self.ie,
@@ -118,22 +363,27 @@ impl Mmu {
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(ref hooks) = self.hooks {
+            hooks.record(address, value, true);
+        }
+        if let Some(ref mut ram) = self.flat_ram {
+            ram[address as usize] = value;
+            return;
+        }
+
         match address {
             0x0000..=0x7FFF => self.cartridge.write_rom(address, value), // ROM bank switching
+            0x8000..=0x9FFF if !self.ppu.vram_accessible() => {}, // Locked in mode 3
             0x8000..=0x9FFF => self.ppu.write_vram(address, value), // VRAM
             0xA000..=0xBFFF => self.cartridge.write_ram(address, value), // External RAM
-            0xC000..=0xCFFF => self.wram[0][(address - 0xC000) as usize] = value,
-            0xD000..=0xDFFF => {
-                let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                let bank = if bank == 0 { 1 } else { bank }; // Bank 0 acts as bank 1
-                self.wram[bank][(address - 0xD000) as usize] = value;
-            }
-            0xE000..=0xEFFF => self.wram[0][(address - 0xE000) as usize] = value,
-            0xF000..=0xFDFF => {
-                let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
-                let bank = if bank == 0 { 1 } else { bank }; // Bank 0 acts as bank 1
-                self.wram[bank][(address - 0xF000) as usize] = value;
+            0xC000..=0xFDFF => {
+                let (bank, offset) = self.wram_location(address);
+                self.wram[bank][offset] = value;
+                if address >= 0xE000 && self.warn_echo_ram {
+                    self.echo_hit.set(Some(MemoryAccess { address, value, write: true }));
+                }
             }
+            0xFE00..=0xFE9F if !self.ppu.oam_accessible() => {}, // Locked in modes 2-3
             0xFE00..=0xFE9F => self.ppu.write_oam(address, value), // OAM
             0xFEA0..=0xFEFF => {}, // Unusable
             0xFF00..=0xFF7F => self.write_io(address, value),
@@ -142,11 +392,41 @@ impl Mmu {
         }
     }
 
+    // Returns and clears the last access that hit an I/O watchpoint
+    pub fn take_io_hit(&self) -> Option<MemoryAccess> {
+        self.io_hit.take()
+    }
+
+    // Returns and clears the last echo RAM access, recorded with warn_echo_ram
+    pub fn take_echo_hit(&self) -> Option<MemoryAccess> {
+        self.echo_hit.take()
+    }
+
+    // WRAM bank and offset of an address in 0xC000-0xFDFF. Bank 0 is at
+    // 0xC000, the switchable bank (1-7 on the GBC, 0 selecting 1, always 1 on
+    // the DMG) at 0xD000, and echo RAM (0xE000-0xFDFF) mirrors 0xC000-0xDDFF.
+    fn wram_location(&self, address: u16) -> (usize, usize) {
+        let offset = (address as usize - 0xC000) % 0x2000;
+        if offset < 0x1000 {
+            return (0, offset);
+        }
+        let bank = if self.is_gbc { (self.wram_bank & 0x07) as usize } else { 1 };
+        (bank.max(1), offset - 0x1000)
+    }
+
     fn read_io(&self, address: u16) -> u8 {
+        let value = self.read_io_register(address);
+        if self.io_watch_read & (1 << (address & 0x7F)) != 0 {
+            self.io_hit.set(Some(MemoryAccess { address, value, write: false }));
+        }
+        value
+    }
+
+    fn read_io_register(&self, address: u16) -> u8 {
         match address {
             0xFF00 => self.joypad.read(),
-            0xFF01 => 0xFF, // Serial data (not implemented)
-            0xFF02 => 0x7E, // Serial control (not implemented, bit 7=0)
+            0xFF01 => self.serial.read_sb(),
+            0xFF02 => self.serial.read_sc(),
             0xFF04 => self.timer.read_div(),
             0xFF05 => self.timer.read_tima(),
             0xFF06 => self.timer.read_tma(),
@@ -166,22 +446,40 @@ impl Mmu {
             0xFF4B => self.ppu.wx,
 
             // GBC registers
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 if !self.cgb_registers() => 0xFF,
             0xFF4D => self.key1, // Speed switch
-            0xFF4F => self.ppu.vram_bank, // VRAM bank
-            0xFF51..=0xFF55 => 0xFF, // HDMA (not fully readable)
-            0xFF68 => self.ppu.bcps, // BG color palette spec
+            0xFF4F => 0xFE | self.ppu.vram_bank, // VRAM bank, bits 1-7 unused
+            0xFF51..=0xFF54 => 0xFF, // HDMA source/dest (write-only)
+            0xFF56 if self.is_gbc => self.infrared.read(), // RP - Infrared port
+            0xFF55 => {
+                // HDMA remaining length, bit 7 clear while an HBlank DMA is active
+                if self.hdma_active {
+                    self.hdma_remaining
+                } else {
+                    0x80 | self.hdma_remaining
+                }
+            }
+            0xFF68 => 0x40 | self.ppu.bcps, // BG color palette spec, bit 6 unused
+            0xFF69 | 0xFF6B if !self.ppu.vram_accessible() => 0xFF, // Palette data locked in mode 3
             0xFF69 => {
                 // BG color palette data
                 let addr = (self.ppu.bcps & 0x3F) as usize;
                 self.ppu.bcpd[addr]
             }
-            0xFF6A => self.ppu.ocps, // OBJ color palette spec
+            0xFF6A => 0x40 | self.ppu.ocps, // OBJ color palette spec, bit 6 unused
             0xFF6B => {
                 // OBJ color palette data
                 let addr = (self.ppu.ocps & 0x3F) as usize;
                 self.ppu.ocpd[addr]
             }
-            0xFF70 => self.wram_bank, // WRAM bank
+            0xFF6C if self.is_gbc => 0xFE | self.ppu.opri, // OPRI - Object priority mode
+            0xFF70 => 0xF8 | self.wram_bank, // WRAM bank, bits 3-7 unused
+            0xFF4C => 0xFF, // KEY0, locked once the boot ROM has set the mode
+
+            // Undocumented CGB registers, also there in DMG compatibility mode
+            0xFF72..=0xFF77 if !self.is_gbc => 0xFF,
+            0xFF72..=0xFF75 => self.undocumented[(address - 0xFF72) as usize],
+            0xFF76 | 0xFF77 => self.apu.read_pcm(address), // PCM12, PCM34: live channel output
 
             // APU registers
             0xFF10..=0xFF26 => self.apu.read_register(address),
@@ -192,21 +490,25 @@ impl Mmu {
     }
 
     fn write_io(&mut self, address: u16, value: u8) {
+        if self.io_watch_write & (1 << (address & 0x7F)) != 0 {
+            self.io_hit.set(Some(MemoryAccess { address, value, write: true }));
+        }
+
         match address {
             0xFF00 => self.joypad.write(value),
-            0xFF01 => {}, // Serial data (not implemented)
-            0xFF02 => {}, // Serial control (not implemented)
-            0xFF04 => self.timer.write_div(),
+            0xFF01 => self.serial.write_sb(value),
+            0xFF02 => self.serial.write_sc(value),
+            0xFF04 => self.reset_div(),
             0xFF05 => self.timer.write_tima(value),
             0xFF06 => self.timer.write_tma(value),
             0xFF07 => self.timer.write_tac(value),
             0xFF0F => self.if_reg = value & 0x1F, // Only lower 5 bits writable
-            0xFF40 => self.ppu.lcdc = value,
-            0xFF41 => self.ppu.stat = (value & 0xF8) | (self.ppu.stat & 0x07), // Only bits 3-6 writable
+            0xFF40 => self.ppu.write_lcdc(value),
+            0xFF41 => self.ppu.write_stat(value),
             0xFF42 => self.ppu.scy = value,
             0xFF43 => self.ppu.scx = value,
             0xFF44 => {}, // LY is read-only
-            0xFF45 => self.ppu.lyc = value,
+            0xFF45 => self.ppu.write_lyc(value),
             0xFF46 => {
                 // DMA transfer - copies 160 bytes from XX00-XX9F to OAM (FE00-FE9F)
                 // This happens instantly (atomically)
@@ -218,7 +520,8 @@ impl Mmu {
             0xFF4A => self.ppu.wy = value,
             0xFF4B => self.ppu.wx = value,
 
-            // GBC registers
+            // GBC registers, ignored on the DMG and in DMG compatibility mode
+            0xFF4D | 0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6C | 0xFF70 if !self.cgb_registers() => {}
             0xFF4D => {
                 // KEY1 - Speed switch (prepare)
                 self.key1 = (self.key1 & 0x80) | (value & 0x01);
@@ -232,49 +535,68 @@ impl Mmu {
             0xFF53 => self.hdma_dest = (self.hdma_dest & 0x00FF) | ((value as u16) << 8),
             0xFF54 => self.hdma_dest = (self.hdma_dest & 0xFF00) | (value as u16),
             0xFF55 => {
-                // HDMA start
-                let len = ((value & 0x7F) as u16 + 1) * 16;
-                let src = self.hdma_source & 0xFFF0;
-                let mut dst = (self.hdma_dest & 0x1FF0) | 0x8000;
-
-                // Perform HDMA transfer (general-purpose)
-                for _ in 0..len {
-                    let byte = self.read_byte(src);
-                    self.ppu.write_vram(dst, byte);
-                    self.hdma_source = self.hdma_source.wrapping_add(1);
-                    dst = dst.wrapping_add(1);
+                if self.hdma_active && (value & 0x80) == 0 {
+                    // Writing bit 7 = 0 during an HBlank DMA cancels it
+                    self.hdma_active = false;
+                    return;
+                }
+
+                self.hdma_source &= 0xFFF0;
+                self.hdma_dest &= 0x1FF0;
+
+                if (value & 0x80) != 0 {
+                    // HBlank DMA - one 16-byte block per HBlank
+                    self.hdma_active = true;
+                    self.hdma_remaining = value & 0x7F;
+                } else {
+                    // General-purpose DMA - transfer everything at once
+                    for _ in 0..=(value & 0x7F) {
+                        self.hdma_transfer_block();
+                    }
+                    self.hdma_remaining = 0x7F;
                 }
             }
+            0xFF56 if self.is_gbc => self.infrared.write(value), // RP - Infrared port
             0xFF68 => {
-                // BCPS - BG color palette spec
-                self.ppu.bcps = value;
+                // BCPS - BG color palette spec, bit 6 isn't stored
+                self.ppu.bcps = value & 0xBF;
             }
             0xFF69 => {
                 // BCPD - BG color palette data
                 let addr = (self.ppu.bcps & 0x3F) as usize;
-                self.ppu.bcpd[addr] = value;
+                // Writes are dropped in mode 3, but the index still advances
+                if self.ppu.vram_accessible() {
+                    self.ppu.bcpd[addr] = value;
+                }
                 // Auto-increment if bit 7 is set
                 if (self.ppu.bcps & 0x80) != 0 {
                     self.ppu.bcps = (self.ppu.bcps & 0x80) | ((self.ppu.bcps + 1) & 0x3F);
                 }
             }
             0xFF6A => {
-                // OCPS - OBJ color palette spec
-                self.ppu.ocps = value;
+                // OCPS - OBJ color palette spec, bit 6 isn't stored
+                self.ppu.ocps = value & 0xBF;
             }
             0xFF6B => {
                 // OCPD - OBJ color palette data
                 let addr = (self.ppu.ocps & 0x3F) as usize;
-                self.ppu.ocpd[addr] = value;
+                // Writes are dropped in mode 3, but the index still advances
+                if self.ppu.vram_accessible() {
+                    self.ppu.ocpd[addr] = value;
+                }
                 // Auto-increment if bit 7 is set
                 if (self.ppu.ocps & 0x80) != 0 {
                     self.ppu.ocps = (self.ppu.ocps & 0x80) | ((self.ppu.ocps + 1) & 0x3F);
                 }
             }
+            0xFF6C if self.is_gbc => self.ppu.opri = value & 0x01, // OPRI - Object priority mode
             0xFF70 => {
-                // WRAM bank select (1-7, 0 acts as 1)
-                self.wram_bank = if value & 0x07 == 0 { 1 } else { value & 0x07 };
+                // WRAM bank select (1-7, 0 acts as 1 but reads back as 0)
+                self.wram_bank = value & 0x07;
             }
+            0xFF72 | 0xFF73 if self.is_gbc => self.undocumented[(address - 0xFF72) as usize] = value,
+            0xFF74 if self.cgb_registers() => self.undocumented[2] = value,
+            0xFF75 if self.is_gbc => self.undocumented[3] = 0x8F | (value & 0x70),
 
             // APU registers
             0xFF10..=0xFF26 => self.apu.write_register(address, value),
@@ -283,4 +605,135 @@ impl Mmu {
             _ => {}
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.wram {
+            w.bytes(bank);
+        }
+        w.u8(self.wram_bank);
+        w.bytes(&self.hram);
+        w.u8(self.ie);
+        w.u8(self.if_reg);
+        w.u8(self.key1);
+        w.u16(self.hdma_source);
+        w.u16(self.hdma_dest);
+        w.bool(self.hdma_active);
+        w.u8(self.hdma_remaining);
+        w.bytes(&self.undocumented);
+
+        self.cartridge.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.timer.save_state(w);
+        self.serial.save_state(w);
+        self.joypad.save_state(w);
+        self.infrared.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        for bank in &mut self.wram {
+            r.bytes(bank);
+        }
+        self.wram_bank = r.u8() & 0x07;
+        r.bytes(&mut self.hram);
+        self.ie = r.u8();
+        self.if_reg = r.u8();
+        self.key1 = r.u8();
+        self.hdma_source = r.u16();
+        self.hdma_dest = r.u16();
+        self.hdma_active = r.bool();
+        self.hdma_remaining = r.u8();
+        if r.version >= 4 {
+            r.bytes(&mut self.undocumented);
+        }
+
+        self.cartridge.load_state(r);
+        self.ppu.load_state(r);
+        self.apu.load_state(r);
+        self.timer.load_state(r);
+        self.serial.load_state(r);
+        self.joypad.load_state(r);
+        self.infrared.load_state(r);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A CGB-only program, so the CGB registers are there, with the LCD off
+    // to keep VRAM accessible
+    fn cgb_mmu() -> Mmu {
+        let mut program = vec![0; 0x150];
+        program[0x143] = 0xC0;
+        let mut mmu = Mmu::new(Cartridge::from_program(&program), true);
+        mmu.write_byte(0xFF40, 0x00);
+        mmu
+    }
+
+    #[test]
+    fn wram_bank_round_trip() {
+        let mut mmu = cgb_mmu();
+        for bank in 1..=7 {
+            mmu.write_byte(0xFF70, bank);
+            assert_eq!(mmu.read_byte(0xFF70), 0xF8 | bank);
+            mmu.write_byte(0xD000, 0x10 + bank);
+        }
+        for bank in 1..=7 {
+            mmu.write_byte(0xFF70, bank);
+            assert_eq!(mmu.read_byte(0xD000), 0x10 + bank);
+        }
+
+        // Bank 0 reads back as 0 but maps bank 1
+        mmu.write_byte(0xFF70, 0);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF8);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+
+        // Bank 0 at 0xC000 doesn't switch
+        mmu.write_byte(0xC000, 0x55);
+        mmu.write_byte(0xFF70, 5);
+        assert_eq!(mmu.read_byte(0xC000), 0x55);
+    }
+
+    #[test]
+    fn wram_bank_masked_to_three_bits() {
+        let mut mmu = cgb_mmu();
+        mmu.write_byte(0xFF70, 3);
+        mmu.write_byte(0xD000, 0x33);
+        mmu.write_byte(0xFF70, 0xFB); // Bank 3 with the unused bits set
+        assert_eq!(mmu.read_byte(0xFF70), 0xFB);
+        assert_eq!(mmu.read_byte(0xD000), 0x33);
+        mmu.write_byte(0xFF70, 0xF8); // Bank 0, so bank 1
+        assert_eq!(mmu.read_byte(0xFF70), 0xF8);
+        assert_ne!(mmu.read_byte(0xD000), 0x33);
+    }
+
+    #[test]
+    fn vram_bank_round_trip() {
+        let mut mmu = cgb_mmu();
+        for bank in 0..=1 {
+            mmu.write_byte(0xFF4F, bank);
+            assert_eq!(mmu.read_byte(0xFF4F), 0xFE | bank);
+            mmu.write_byte(0x8000, 0xA0 + bank);
+            mmu.write_byte(0x9FFF, 0xB0 + bank);
+        }
+        for bank in 0..=1 {
+            mmu.write_byte(0xFF4F, bank);
+            assert_eq!(mmu.read_byte(0x8000), 0xA0 + bank);
+            assert_eq!(mmu.read_byte(0x9FFF), 0xB0 + bank);
+        }
+    }
+
+    #[test]
+    fn vram_bank_masked_to_one_bit() {
+        let mut mmu = cgb_mmu();
+        mmu.write_byte(0xFF4F, 1);
+        mmu.write_byte(0x8000, 0x77);
+        mmu.write_byte(0xFF4F, 0xFF); // Bank 1 with the unused bits set
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0x77);
+        mmu.write_byte(0xFF4F, 0xFE); // Bank 0
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        assert_ne!(mmu.read_byte(0x8000), 0x77);
+    }
 }
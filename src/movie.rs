@@ -0,0 +1,147 @@
+// Input movies: per-frame joypad state recorded from power-on and replayed
+// deterministically, for tool-assisted runs and regression testing.
+//
+// File format: "GBMV", version byte, flags byte (bit 0 = GBC mode), ROM
+// global checksum (u16 LE), frame count (u32 LE), then one byte per frame
+// with the buttons bitmask of JoypadState::bits().
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use log::{error, info};
+
+use crate::joypad::JoypadState;
+
+const MAGIC: &[u8; 4] = b"GBMV";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 12;
+
+pub struct Movie {
+    pub rom_checksum: u16,
+    pub is_gbc: bool,
+    pub frames: Vec<JoypadState>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a movie file"));
+        }
+        if data[4] != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unsupported movie version {}", data[4])));
+        }
+
+        let count = u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize;
+        let frames = data.get(HEADER_SIZE..HEADER_SIZE + count)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "movie file is truncated"))?;
+
+        Ok(Movie {
+            rom_checksum: u16::from_le_bytes([data[6], data[7]]),
+            is_gbc: (data[5] & 0x01) != 0,
+            frames: frames.iter().map(|&bits| JoypadState::from_bits(bits)).collect(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.frames.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.push(self.is_gbc as u8);
+        data.extend_from_slice(&self.rom_checksum.to_le_bytes());
+        data.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        data.extend(self.frames.iter().map(|state| state.bits()));
+        std::fs::write(path, data)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum MovieMode {
+    Recording,
+    Playing { read_only: bool },
+    Finished, // Read-only playback ended, live input is used again
+}
+
+pub struct MovieSession {
+    pub movie: Movie,
+    path: PathBuf,
+    mode: MovieMode,
+    frame: usize,
+    modified: bool,
+}
+
+impl MovieSession {
+    pub fn record(path: PathBuf, rom_checksum: u16, is_gbc: bool) -> Self {
+        info!("Recording movie to: {}", path.display());
+        MovieSession {
+            movie: Movie { rom_checksum, is_gbc, frames: Vec::new() },
+            path,
+            mode: MovieMode::Recording,
+            frame: 0,
+            modified: true,
+        }
+    }
+
+    // In read/write mode pressing any button, or reaching the end of the
+    // movie, switches to recording from the current frame
+    pub fn play(path: PathBuf, read_only: bool) -> std::io::Result<Self> {
+        let movie = Movie::load(&path)?;
+        info!(
+            "Playing movie: {} ({} frames, {})",
+            path.display(),
+            movie.frames.len(),
+            if read_only { "read-only" } else { "read/write" }
+        );
+        Ok(MovieSession {
+            movie,
+            path,
+            mode: MovieMode::Playing { read_only },
+            frame: 0,
+            modified: false,
+        })
+    }
+
+    fn start_recording(&mut self) {
+        info!("Movie: recording from frame {}", self.frame);
+        self.movie.frames.truncate(self.frame);
+        self.mode = MovieMode::Recording;
+        self.modified = true;
+    }
+
+    // Called once per emulated frame with the live input; returns the
+    // buttons the game should see this frame
+    pub fn next_frame(&mut self, live: JoypadState) -> JoypadState {
+        if let MovieMode::Playing { read_only } = self.mode {
+            if self.frame >= self.movie.frames.len() {
+                if read_only {
+                    info!("Movie playback finished at frame {}", self.frame);
+                    self.mode = MovieMode::Finished;
+                } else {
+                    self.start_recording();
+                }
+            } else if !read_only && live != JoypadState::default() {
+                self.start_recording();
+            } else {
+                let buttons = self.movie.frames[self.frame];
+                self.frame += 1;
+                return buttons;
+            }
+        }
+
+        if self.mode == MovieMode::Recording {
+            self.movie.frames.push(live);
+            self.frame += 1;
+        }
+        live
+    }
+
+    // Writes the movie back to its file if anything was recorded
+    pub fn finish(self) {
+        if !self.modified {
+            return;
+        }
+        match self.movie.save(&self.path) {
+            Ok(()) => info!("Movie saved to: {} ({} frames)", self.path.display(), self.movie.frames.len()),
+            Err(e) => error!("Failed to save movie {}: {}", self.path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// On-screen display: short messages drawn over the game for a few seconds,
+// so hotkey feedback is visible without a terminal. Newest at the bottom.
+// The performance overlay sits at the top left while it is switched on, and
+// the savestate slot browser covers the screen while it is open.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::font;
+use crate::pacer::{CPU_CLOCK_HZ, CYCLES_PER_FRAME};
+use crate::slot_browser::SlotBrowser;
+
+const DURATION: Duration = Duration::from_secs(3);
+const MAX_MESSAGES: usize = 4;
+const TEXT_COLOR: u32 = 0xFFFFFF;
+const BACKGROUND: u32 = 0x000000;
+
+// FPS and speed are averaged over this long
+const PERF_WINDOW: Duration = Duration::from_millis(500);
+const GRAPH_FRAMES: usize = 60;
+const GRAPH_HEIGHT: i32 = 16; // The frame budget is half of it
+const GRAPH_COLOR: u32 = 0x40C040;
+const GRAPH_SLOW_COLOR: u32 = 0xE04040; // Frames that took longer than real time
+const BUDGET_COLOR: u32 = 0x808080;
+
+pub struct Osd {
+    messages: VecDeque<(String, Instant)>, // With the time they disappear
+    pub perf: Option<PerfStats>,           // Shown in the performance overlay
+    pub slots: Option<SlotBrowser>,        // Open while Tab is held
+}
+
+// Emulation performance, recorded by the emulation thread
+#[derive(Clone, Default)]
+pub struct PerfStats {
+    pub fps: f64,
+    pub speed: f64,                      // Emulated time per real time, in percent
+    pub skipped: f64,                    // Frames emulated without drawing them, in percent
+    pub audio_fill: f64,                 // Audio output buffer fill level, in percent
+    pub frame_times: VecDeque<Duration>, // Time spent emulating each recent frame, oldest first
+    window_start: Option<Instant>,
+    window_frames: u32,
+    window_skipped: u32,
+    window_cycles: u64,
+}
+
+impl PerfStats {
+    // Called once per loop of the emulation thread. Paused loops run no
+    // cycles and only keep FPS and speed up to date.
+    pub fn record_frame(&mut self, frame_time: Duration, cycles: u32, skipped: bool, audio_fill: f64) {
+        let now = Instant::now();
+        let start = *self.window_start.get_or_insert(now);
+        if cycles > 0 {
+            self.window_frames += 1;
+            self.window_skipped += skipped as u32;
+            self.window_cycles += cycles as u64;
+            if self.frame_times.len() == GRAPH_FRAMES {
+                self.frame_times.pop_front();
+            }
+            self.frame_times.push_back(frame_time);
+        }
+        self.audio_fill = audio_fill * 100.0;
+
+        let elapsed = (now - start).as_secs_f64();
+        if elapsed >= PERF_WINDOW.as_secs_f64() {
+            self.fps = self.window_frames as f64 / elapsed;
+            self.speed = self.window_cycles as f64 / CPU_CLOCK_HZ / elapsed * 100.0;
+            self.skipped = self.window_skipped as f64 / self.window_frames.max(1) as f64 * 100.0;
+            self.window_start = Some(now);
+            self.window_frames = 0;
+            self.window_skipped = 0;
+            self.window_cycles = 0;
+        }
+    }
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Osd { messages: VecDeque::new(), perf: None, slots: None }
+    }
+
+    pub fn show(&mut self, text: impl Into<String>) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((text.into(), Instant::now() + DURATION));
+    }
+
+    // False once every message has expired and the overlays are off
+    pub fn is_active(&mut self) -> bool {
+        let now = Instant::now();
+        self.messages.retain(|(_, until)| *until > now);
+        !self.messages.is_empty() || self.perf.is_some() || self.slots.is_some()
+    }
+
+    // Draws the messages at the bottom left of a frame, each on a dark strip
+    pub fn draw(&self, frame: &mut [u32], width: usize, height: usize) {
+        if let Some(ref slots) = self.slots {
+            slots.draw(frame, width, height);
+        }
+        let line_height = font::GLYPH_HEIGHT + 2;
+        let count = self.messages.len() as i32;
+        for (i, (text, _)) in self.messages.iter().enumerate() {
+            let y = height as i32 - (count - i as i32) * line_height;
+            let text_width = text.chars().count() as i32 * font::ADVANCE + 1;
+            fill(frame, width, height, 0, y, text_width, line_height);
+            font::draw_text(frame, width, height, 1, y + 1, text, TEXT_COLOR);
+        }
+        if let (Some(perf), None) = (&self.perf, &self.slots) {
+            draw_perf(perf, frame, width, height);
+        }
+    }
+}
+
+// FPS, speed and audio fill, with a graph of recent frame times below
+fn draw_perf(perf: &PerfStats, frame: &mut [u32], width: usize, height: usize) {
+    let lines = [
+        format!("{:.1} FPS", perf.fps),
+        format!("SPEED {:.0}%", perf.speed),
+        format!("AUDIO {:.0}%", perf.audio_fill),
+        format!("SKIP {:.0}%", perf.skipped),
+    ];
+    let line_height = font::GLYPH_HEIGHT + 2;
+    let text_height = lines.len() as i32 * line_height;
+    fill(frame, width, height, 0, 0, GRAPH_FRAMES as i32 + 2, text_height + GRAPH_HEIGHT + 2);
+    for (i, text) in lines.iter().enumerate() {
+        font::draw_text(frame, width, height, 1, i as i32 * line_height + 1, text, TEXT_COLOR);
+    }
+
+    let budget = CYCLES_PER_FRAME as f64 / CPU_CLOCK_HZ;
+    let bottom = text_height + GRAPH_HEIGHT;
+    for x in 1..=GRAPH_FRAMES as i32 {
+        font::put_pixel(frame, width, height, x, bottom - GRAPH_HEIGHT / 2, BUDGET_COLOR);
+    }
+    for (i, time) in perf.frame_times.iter().enumerate() {
+        let ratio = time.as_secs_f64() / budget;
+        let bar = ((ratio * (GRAPH_HEIGHT / 2) as f64).ceil() as i32).clamp(1, GRAPH_HEIGHT);
+        let color = if ratio > 1.0 { GRAPH_SLOW_COLOR } else { GRAPH_COLOR };
+        for y in bottom - bar..bottom {
+            font::put_pixel(frame, width, height, i as i32 + 1, y, color);
+        }
+    }
+}
+
+fn fill(frame: &mut [u32], width: usize, height: usize, x: i32, y: i32, w: i32, h: i32) {
+    for py in y..y + h {
+        for px in x..x + w {
+            font::put_pixel(frame, width, height, px, py, BACKGROUND);
+        }
+    }
+}
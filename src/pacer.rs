@@ -0,0 +1,118 @@
+// Frame pacing based on emulated cycles, nudged by the audio buffer fill level
+// so emulation speed follows the audio device instead of drifting away from it
+
+use std::time::{Duration, Instant};
+
+pub const CPU_CLOCK_HZ: f64 = 4_194_304.0;
+pub const CYCLES_PER_FRAME: u32 = 70224; // 154 lines × 456 dots
+
+// How far the emulation speed may be adjusted to keep the audio buffer at its target
+const MAX_RATE_ADJUST: f64 = 0.005;
+// If we fall further behind than this, resync instead of trying to catch up
+const MAX_LAG: Duration = Duration::from_millis(100);
+// Automatic frame skipping still draws at least every this many frames
+const MAX_AUTO_SKIP: u32 = 4;
+
+// Emulation speed range, in percent of the real Game Boy
+pub const MIN_SPEED: u32 = 25;
+pub const MAX_SPEED: u32 = 1000;
+// Steps of the speed hotkeys
+const SPEED_STEPS: &[u32] = &[25, 50, 75, 100, 125, 150, 200, 300, 400, 500, 750, 1000];
+
+// A speed in percent, e.g. "150" or "150%", within MIN_SPEED..=MAX_SPEED
+pub fn parse_speed(text: &str) -> Option<u32> {
+    let percent = text.trim().trim_end_matches('%').parse().ok()?;
+    (MIN_SPEED..=MAX_SPEED).contains(&percent).then_some(percent)
+}
+
+// The next speed step above `speed`
+pub fn faster(speed: u32) -> u32 {
+    SPEED_STEPS.iter().copied().find(|&step| step > speed).unwrap_or(MAX_SPEED)
+}
+
+// The next speed step below `speed`
+pub fn slower(speed: u32) -> u32 {
+    SPEED_STEPS.iter().copied().rev().find(|&step| step < speed).unwrap_or(MIN_SPEED)
+}
+
+// Frames emulated without drawing them, to keep the game and audio at full
+// speed when rendering can't keep up
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameSkip {
+    Off,
+    Fixed(u32), // Skip this many frames after each drawn one
+    Auto,       // Skip while emulation is behind real time
+}
+
+impl FrameSkip {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "auto" => Some(FrameSkip::Auto),
+            "off" | "0" => Some(FrameSkip::Off),
+            n => n.parse().ok().map(FrameSkip::Fixed),
+        }
+    }
+}
+
+pub struct FramePacer {
+    deadline: Instant,
+    audio_target: usize,
+    pub frame_skip: FrameSkip,
+    skipped: u32, // Frames skipped in a row
+    late: bool,   // The last frame finished after its deadline
+    pub speed: u32, // In percent: 50 runs the game at half speed, 200 at double
+}
+
+impl FramePacer {
+    pub fn new(audio_target: usize) -> Self {
+        FramePacer {
+            deadline: Instant::now(),
+            audio_target,
+            frame_skip: FrameSkip::Off,
+            skipped: 0,
+            late: false,
+            speed: 100,
+        }
+    }
+
+    // Whether the next frame should be emulated without drawing it
+    pub fn skip_frame(&mut self) -> bool {
+        let skip = match self.frame_skip {
+            FrameSkip::Off => false,
+            FrameSkip::Fixed(n) => self.skipped < n,
+            FrameSkip::Auto => self.late && self.skipped < MAX_AUTO_SKIP,
+        };
+        self.skipped = if skip { self.skipped + 1 } else { 0 };
+        skip
+    }
+
+    // Waits until the wall clock catches up with `cycles` of emulated time,
+    // scaled by the speed. A buffer fuller than the target slows us down
+    // slightly, an emptier one speeds us up, so the audio device effectively
+    // becomes the master clock.
+    pub fn wait(&mut self, cycles: u32, audio_fill: usize) {
+        let fill_error = (audio_fill as f64 - self.audio_target as f64) / self.audio_target as f64;
+        let rate_adjust = 1.0 + fill_error.clamp(-1.0, 1.0) * MAX_RATE_ADJUST;
+
+        let emulated = cycles as f64 / CPU_CLOCK_HZ * rate_adjust * 100.0 / self.speed as f64;
+        self.deadline += Duration::from_secs_f64(emulated);
+
+        let now = Instant::now();
+        self.late = self.deadline < now;
+        if self.late {
+            if now - self.deadline > MAX_LAG {
+                self.deadline = now;
+            }
+            return;
+        }
+
+        // Sleep for most of the remaining time, then spin for precision
+        let remaining = self.deadline - now;
+        if remaining > Duration::from_millis(2) {
+            std::thread::sleep(remaining - Duration::from_millis(1));
+        }
+        while Instant::now() < self.deadline {
+            std::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,265 @@
+// DMG color palettes: built-in presets, the CGB boot ROM colorization and
+// user palettes loaded from a file
+
+use std::path::Path;
+
+// Colors are in minifb's 0RGB format, from lightest (color 0) to darkest (color 3)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DmgPalette {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl DmgPalette {
+    const fn uniform(colors: [u32; 4]) -> Self {
+        DmgPalette { bg: colors, obj0: colors, obj1: colors }
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> Self {
+        GREEN
+    }
+}
+
+const GREEN: DmgPalette = DmgPalette::uniform([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
+const GRAYSCALE: DmgPalette = DmgPalette::uniform([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+const POCKET: DmgPalette = DmgPalette::uniform([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+
+// CGB boot ROM colorization of DMG games. The boot ROM picks one of 51
+// palettes from the sum of the title bytes (and, for some sums, the 4th
+// letter of the title) of games licensed by Nintendo, and the player can
+// pick one of 12 instead by holding a button combo while the logo shows.
+
+// Colors of the palettes, RGB555 as in CGB palette RAM
+#[rustfmt::skip]
+const COMPAT_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Offsets into COMPAT_COLORS of the OBJ0, OBJ1 and BG colors of each
+// palette. Three start in the middle of a row, as they do in the boot ROM.
+#[rustfmt::skip]
+const COMPAT_PALETTES: [[u8; 3]; 51] = [
+    [16, 16, 116], [72, 72, 72], [80, 80, 80], [96, 96, 96], [36, 36, 36], [0, 0, 0],
+    [108, 108, 108], [20, 20, 20], [48, 48, 48], [104, 104, 104], [64, 32, 32], [16, 112, 112],
+    [16, 8, 8], [12, 16, 16], [16, 116, 116], [112, 16, 112], [8, 68, 8], [64, 64, 32],
+    [16, 16, 28], [16, 16, 72], [16, 16, 80], [76, 76, 36], [15, 15, 44], [68, 68, 8],
+    [16, 16, 8], [16, 16, 12], [112, 112, 0], [12, 12, 0], [0, 0, 4], [72, 88, 72],
+    [80, 88, 80], [96, 88, 96], [64, 88, 32], [68, 16, 52], [111, 0, 56], [111, 16, 60],
+    [76, 88, 36], [64, 112, 40], [16, 92, 112], [68, 88, 8], [16, 0, 8], [16, 112, 12],
+    [112, 12, 0], [12, 112, 16], [84, 112, 16], [12, 112, 0], [100, 12, 112], [0, 112, 32],
+    [16, 12, 112], [112, 12, 24], [16, 112, 116],
+];
+
+// Title sums the boot ROM knows. The last 14 are shared by several games,
+// told apart by the 4th letter of the title: FOURTH_LETTERS has a row of 14
+// letters per game sharing a sum.
+#[rustfmt::skip]
+const TITLE_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58,
+    0xC9, 0x3E, 0x70, 0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95,
+    0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97, 0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6,
+    0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE, 0x0C, 0x29, 0xE8, 0xB7,
+    0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F, 0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D,
+    0xF4,
+];
+const UNIQUE_CHECKSUMS: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette of each title: one per unique sum, then one per 4th letter
+#[rustfmt::skip]
+const TITLE_PALETTES: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17,
+    46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// Palettes of the boot ROM button combos
+const BUTTON_COMBOS: [(&str, usize); 12] = [
+    ("up", 5),
+    ("up+a", 43),
+    ("up+b", 28),
+    ("left", 48),
+    ("left+a", 40),
+    ("left+b", 7),
+    ("down", 8),
+    ("down+a", 3),
+    ("down+b", 49),
+    ("right", 1),
+    ("right+a", 0),
+    ("right+b", 6),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PalettePreset {
+    Green,
+    Grayscale,
+    Pocket,
+    GbcAuto, // Picked from the title like the CGB boot ROM does
+    Custom,  // Loaded from a palette file
+}
+
+impl PalettePreset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "green" => Some(PalettePreset::Green),
+            "grayscale" | "gray" | "grey" => Some(PalettePreset::Grayscale),
+            "pocket" => Some(PalettePreset::Pocket),
+            "gbc" | "auto" => Some(PalettePreset::GbcAuto),
+            "custom" => Some(PalettePreset::Custom),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PalettePreset::Green => "green",
+            PalettePreset::Grayscale => "grayscale",
+            PalettePreset::Pocket => "pocket",
+            PalettePreset::GbcAuto => "gbc",
+            PalettePreset::Custom => "custom",
+        }
+    }
+
+    // Next preset for the runtime hotkey; Custom is skipped when no file was loaded
+    pub fn next(&self, has_custom: bool) -> Self {
+        match self {
+            PalettePreset::Green => PalettePreset::Grayscale,
+            PalettePreset::Grayscale => PalettePreset::Pocket,
+            PalettePreset::Pocket => PalettePreset::GbcAuto,
+            PalettePreset::GbcAuto if has_custom => PalettePreset::Custom,
+            PalettePreset::GbcAuto | PalettePreset::Custom => PalettePreset::Green,
+        }
+    }
+}
+
+// Resolves a preset to concrete colors. `compat` is the boot ROM palette
+// for the game (boot_rom_palette or combo_from_name), `custom` the user
+// palette if one was loaded.
+pub fn resolve(preset: PalettePreset, compat: usize, custom: Option<&DmgPalette>) -> DmgPalette {
+    match preset {
+        PalettePreset::Green => GREEN,
+        PalettePreset::Grayscale => GRAYSCALE,
+        PalettePreset::Pocket => POCKET,
+        PalettePreset::GbcAuto => compat_palette(compat),
+        PalettePreset::Custom => custom.copied().unwrap_or(GREEN),
+    }
+}
+
+// Sum of the title bytes (0x134-0x143) of a game licensed by Nintendo, by
+// the old or the new licensee code. The boot ROM only colors those games.
+pub fn boot_rom_checksum(header: &[u8]) -> Option<u8> {
+    let title = header.get(0x134..0x144)?;
+    let nintendo = match header.get(0x14B) {
+        Some(0x01) => true,
+        Some(0x33) => header.get(0x144..0x146) == Some(b"01"),
+        _ => false,
+    };
+    nintendo.then(|| title.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)))
+}
+
+// Palette the CGB boot ROM gives a DMG game, from its header (0x134-0x14B)
+pub fn boot_rom_palette(header: &[u8]) -> usize {
+    let Some(sum) = boot_rom_checksum(header) else {
+        return 0;
+    };
+    let title = &header[0x134..0x144];
+    match TITLE_CHECKSUMS.iter().position(|&c| c == sum) {
+        Some(i) if i < UNIQUE_CHECKSUMS => TITLE_PALETTES[i] as usize,
+        Some(i) => (i - UNIQUE_CHECKSUMS..FOURTH_LETTERS.len())
+            .step_by(TITLE_CHECKSUMS.len() - UNIQUE_CHECKSUMS)
+            .find(|&letter| FOURTH_LETTERS[letter] == title[3])
+            .map_or(0, |letter| TITLE_PALETTES[UNIQUE_CHECKSUMS + letter] as usize),
+        None => 0,
+    }
+}
+
+// Boot ROM palette of a button combo such as "left+b"
+pub fn combo_from_name(name: &str) -> Option<usize> {
+    let name = name.to_lowercase().replace(' ', "");
+    BUTTON_COMBOS.iter().find(|(combo, _)| *combo == name).map(|&(_, palette)| palette)
+}
+
+// Colors of a boot ROM palette
+pub fn compat_palette(index: usize) -> DmgPalette {
+    let [obj0, obj1, bg] = COMPAT_PALETTES[index % COMPAT_PALETTES.len()];
+    let colors = |offset: u8| std::array::from_fn(|i| rgb555(COMPAT_COLORS[offset as usize + i]));
+    DmgPalette { bg: colors(bg), obj0: colors(obj0), obj1: colors(obj1) }
+}
+
+// RGB555 to 0RGB, the 5-bit channels widened as the PPU does
+fn rgb555(color: u16) -> u32 {
+    [0, 5, 10].iter().fold(0, |rgb, &shift| {
+        let channel = ((color >> shift) & 0x1F) as u32;
+        (rgb << 8) | (channel << 3) | (channel >> 2)
+    })
+}
+
+// Loads a palette file: one line of 4 hex colors (lightest first) used for
+// everything, or three lines for BG, OBJ0 and OBJ1. Blank lines and lines
+// starting with '#' are ignored.
+pub fn load(path: &Path) -> Result<DmgPalette, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let mut rows = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let colors = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let hex = s.trim_start_matches('#').trim_start_matches("0x");
+                u32::from_str_radix(hex, 16).map_err(|_| format!("invalid color '{}'", s))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        let colors: [u32; 4] = colors
+            .try_into()
+            .map_err(|_| format!("expected 4 colors per line: '{}'", line))?;
+        rows.push(colors);
+    }
+
+    match rows.as_slice() {
+        [all] => Ok(DmgPalette::uniform(*all)),
+        [bg, obj0, obj1] => Ok(DmgPalette { bg: *bg, obj0: *obj0, obj1: *obj1 }),
+        _ => Err("expected 1 or 3 lines of colors".to_string()),
+    }
+}
@@ -0,0 +1,175 @@
+// ROM patches applied in memory when a game is loaded (soft-patching), so ROM
+// hacks and translations run without modifying the original ROM file.
+//
+// IPS: "PATCH", then records of a 3-byte offset and 2-byte length (both big
+// endian) followed by that many bytes, or a zero length followed by a 2-byte
+// run length and the byte to repeat. "EOF" ends the records, optionally
+// followed by a 3-byte size to truncate the ROM to.
+//
+// BPS: "BPS1", source, target and metadata sizes as variable-length numbers,
+// the metadata, then actions building the target from the source ROM, the
+// patch and the target itself, and CRC32s of the source, target and patch.
+
+use std::io::{Error, ErrorKind};
+
+use flate2::Crc;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// Applies an IPS or BPS patch, told apart by their magic bytes
+pub fn apply(patch: &[u8], rom: &[u8]) -> std::io::Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(patch, rom)
+    } else {
+        Err(invalid("not an IPS or BPS patch"))
+    }
+}
+
+fn apply_ips(patch: &[u8], rom: &[u8]) -> std::io::Result<Vec<u8>> {
+    let truncated = || invalid("IPS patch is truncated");
+    let mut target = rom.to_vec();
+    let mut position = 5;
+    loop {
+        let bytes = |position: usize, len: usize| patch.get(position..position + len).ok_or_else(truncated);
+        let offset = bytes(position, 3)?;
+        if offset == b"EOF" {
+            // Optional size to truncate to
+            if let Some(size) = patch.get(position + 3..position + 6) {
+                target.truncate(u32::from_be_bytes([0, size[0], size[1], size[2]]) as usize);
+            }
+            return Ok(target);
+        }
+        let offset = u32::from_be_bytes([0, offset[0], offset[1], offset[2]]) as usize;
+        let len = bytes(position + 3, 2)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        position += 5;
+
+        let data = if len == 0 {
+            // Run of one byte
+            let run = bytes(position, 3)?;
+            position += 3;
+            vec![run[2]; u16::from_be_bytes([run[0], run[1]]) as usize]
+        } else {
+            position += len;
+            bytes(position - len, len)?.to_vec()
+        };
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(&data);
+    }
+}
+
+fn apply_bps(patch: &[u8], rom: &[u8]) -> std::io::Result<Vec<u8>> {
+    if patch.len() < 4 + 12 {
+        return Err(invalid("BPS patch is truncated"));
+    }
+    let footer = patch.len() - 12;
+    let crc = |i: usize| u32::from_le_bytes(patch[footer + i * 4..footer + i * 4 + 4].try_into().unwrap());
+    if crc32(&patch[..footer + 8]) != crc(2) {
+        return Err(invalid("BPS patch is damaged"));
+    }
+    if crc32(rom) != crc(0) {
+        return Err(invalid("BPS patch is for another ROM (or one already patched)"));
+    }
+
+    let mut reader = BpsReader { patch: &patch[..footer], position: 4 };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.take(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(invalid("BPS patch is for a ROM of another size"));
+    }
+
+    let mut target = Vec::new();
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    while reader.position < reader.patch.len() {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if len > target_size - target.len() {
+            return Err(invalid("BPS patch writes past the end of the ROM"));
+        }
+        let out_of_range = || invalid("BPS patch reads outside the ROM");
+        match action & 3 {
+            // Source read: the source bytes at the same offset
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start + len).ok_or_else(out_of_range)?);
+            }
+            // Target read: bytes from the patch
+            1 => target.extend_from_slice(reader.take(len)?),
+            // Source copy: source bytes at a relative offset
+            2 => {
+                source_offset = reader.relative(source_offset)?;
+                let source = rom.get(source_offset..source_offset.saturating_add(len));
+                target.extend_from_slice(source.ok_or_else(out_of_range)?);
+                source_offset += len;
+            }
+            // Target copy: earlier target bytes, one at a time as the runs can overlap
+            _ => {
+                target_offset = reader.relative(target_offset)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset).ok_or_else(out_of_range)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32(&target) != crc(1) {
+        return Err(invalid("BPS patch produced a different ROM than expected"));
+    }
+    Ok(target)
+}
+
+struct BpsReader<'a> {
+    patch: &'a [u8], // Without the CRC32 footer
+    position: usize,
+}
+
+impl BpsReader<'_> {
+    fn take(&mut self, len: usize) -> std::io::Result<&[u8]> {
+        let end = self.position.checked_add(len).filter(|&end| end <= self.patch.len());
+        let end = end.ok_or_else(|| invalid("BPS patch is truncated"))?;
+        let bytes = &self.patch[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    // Variable-length number: 7 bits per byte, least significant first, the
+    // last byte marked by bit 7
+    fn number(&mut self) -> std::io::Result<usize> {
+        let overflow = || invalid("BPS number overflows");
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.take(1)?[0];
+            let part = ((byte & 0x7F) as usize).checked_mul(shift).ok_or_else(overflow)?;
+            value = value.checked_add(part).ok_or_else(overflow)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or_else(overflow)?;
+            value = value.checked_add(shift).ok_or_else(overflow)?;
+        }
+    }
+
+    // Offset moved by a signed number: bit 0 is the sign
+    fn relative(&mut self, offset: usize) -> std::io::Result<usize> {
+        let number = self.number()?;
+        let moved = if number & 1 != 0 { offset.checked_sub(number >> 1) } else { offset.checked_add(number >> 1) };
+        moved.ok_or_else(|| invalid("BPS patch reads outside the ROM"))
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
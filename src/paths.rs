@@ -0,0 +1,50 @@
+// Platform-specific locations for emulator data
+
+use std::path::PathBuf;
+
+const APP_NAME: &str = "gameboy_emulator";
+
+// Per-user data directory (e.g. ~/.local/share/gameboy_emulator)
+pub fn data_dir() -> PathBuf {
+    #[cfg(target_os = "windows")]
+    let base = std::env::var_os("APPDATA").map(PathBuf::from);
+
+    #[cfg(target_os = "macos")]
+    let base = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join("Library").join("Application Support"));
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")));
+
+    base.unwrap_or_else(|| PathBuf::from(".")).join(APP_NAME)
+}
+
+// Per-user configuration directory (e.g. ~/.config/gameboy_emulator). The
+// same as the data directory on Windows and macOS.
+pub fn config_dir() -> PathBuf {
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    if let Some(base) = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    {
+        return base.join(APP_NAME);
+    }
+    data_dir()
+}
+
+// Default directory for battery saves
+pub fn save_dir() -> PathBuf {
+    data_dir().join("saves")
+}
+
+// Savestates, one per game
+pub fn state_dir() -> PathBuf {
+    data_dir().join("states")
+}
+
+// Default directory for screenshots
+pub fn screenshot_dir() -> PathBuf {
+    data_dir().join("screenshots")
+}
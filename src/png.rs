@@ -0,0 +1,54 @@
+// Minimal PNG (8-bit RGB, zlib compressed) writer used for screenshots
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+// Writes a 0RGB framebuffer as a PNG file
+pub fn write(path: &Path, pixels: &[u32], width: usize, height: usize) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, deflate, no filter, no interlace
+    write_chunk(&mut file, b"IHDR", &header)?;
+
+    // Each row starts with its filter type (0 = none)
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks(width).take(height) {
+        let mut line = Vec::with_capacity(1 + width * 3);
+        line.push(0);
+        for &pixel in row {
+            line.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+        encoder.write_all(&line)?;
+    }
+    write_chunk(&mut file, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(&mut file, b"IEND", &[])?;
+    file.flush()
+}
+
+fn write_chunk(file: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    file.write_all(&(data.len() as u32).to_be_bytes())?;
+    file.write_all(kind)?;
+    file.write_all(data)?;
+    let crc = crc32(&[kind.as_slice(), data].concat());
+    file.write_all(&crc.to_be_bytes())
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
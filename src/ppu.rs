@@ -1,10 +1,56 @@
+use crate::palette::DmgPalette;
+use crate::savestate::{StateReader, StateWriter};
+
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 
+// Tile data area $8000-$97FF of each VRAM bank
+const TILES_PER_BANK: usize = 384;
+
+// Each byte with bit n moved to bit 0 of byte n, so a row of one bitplane
+// becomes eight 0/1 pixels, the leftmost (bit 7) first in big-endian order.
+// A 2bpp row is then SPREAD_BITS[low] | SPREAD_BITS[high] << 1.
+const SPREAD_BITS: [u64; 256] = spread_bits();
+
+const fn spread_bits() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            table[byte] |= (((byte >> bit) & 1) as u64) << (bit * 8);
+            bit += 1;
+        }
+        byte += 1;
+    }
+    table
+}
+
+// Layer view: pixels are blended with the color of the layer that drew them,
+// and lines where the 10 sprite limit dropped sprites get a marker on the right
+const BACKGROUND_TINT: u32 = 0x0000FF;
+const WINDOW_TINT: u32 = 0x00FF00;
+const SPRITE_TINT: u32 = 0xFF0000;
+const OVERFLOW_MARKER: u32 = 0xFFFF00;
+const OVERFLOW_MARKER_WIDTH: usize = 4;
+
+// Which layer drew a pixel of the current line
+#[derive(Clone, Copy, PartialEq)]
+enum Layer {
+    None, // Background disabled
+    Background,
+    Window,
+    Sprite,
+}
+
 pub struct Ppu {
     pub vram: [[u8; 0x2000]; 2], // 16KB VRAM (2 banks for GBC)
     pub oam: [u8; 0xA0],         // Object Attribute Memory (sprites)
-    pub framebuffer: [u32; SCREEN_WIDTH * SCREEN_HEIGHT],
+    pub framebuffer: [u32; SCREEN_WIDTH * SCREEN_HEIGHT], // Drawn line by line
+    // Last completed frame, copied from the framebuffer when VBlank starts,
+    // so frontends never show a frame that is still being drawn
+    front: Box<[u32; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+    new_frame: bool, // A frame completed since the last take_frame()
 
     // LCD Control registers
     pub lcdc: u8,  // 0xFF40
@@ -25,63 +71,101 @@ pub struct Ppu {
     pub bcpd: [u8; 64],          // BG Color Palette Data (8 palettes × 4 colors × 2 bytes)
     pub ocps: u8,                // 0xFF6A - OBJ Color Palette Spec
     pub ocpd: [u8; 64],          // OBJ Color Palette Data (8 palettes × 4 colors × 2 bytes)
+    pub opri: u8,                // 0xFF6C - Object priority mode (bit 0: 0 = OAM index, 1 = X coordinate)
     pub is_gbc: bool,
+    // DMG game on the CGB: drawn as on a DMG, its shades colored by the
+    // palette the boot ROM picked (dmg_palette)
+    pub dmg_compat: bool,
+
+    // Colors used for the DMG shades
+    pub dmg_palette: DmgPalette,
+
+    // Debug view tinting pixels by layer and marking sprite overflow
+    pub layer_view: bool,
+    // Frame skipping: the PPU keeps its timing and interrupts but draws nothing
+    pub skip_render: bool,
 
     dots: u32, // Dot counter for timing (0-455 per scanline)
     pub frame_ready: bool,
-    pub stat_interrupt: bool, // Set when STAT interrupt should fire
+    pub stat_interrupt: bool, // Set on a rising edge of the STAT line, cleared by the MMU
+    stat_line: bool, // OR of all enabled STAT interrupt sources
+    pub vblank_interrupt: bool, // Set when VBlank interrupt should fire
+    pub hblank_started: bool, // Set when entering HBlank (drives GBC HBlank DMA)
+
+    // Decoded tile data of both banks, as color numbers per pixel. VRAM
+    // writes mark a tile dirty so it's decoded again when next drawn.
+    tile_cache: Box<[[[u8; 8]; 8]; TILES_PER_BANK * 2]>,
+    tile_dirty: [bool; TILES_PER_BANK * 2],
 
     // Priority buffer: stores (bg_color_num) for sprite priority checks
     bg_priority: [u8; SCREEN_WIDTH],
+    // Layer of each pixel on the current line, for the layer view
+    layers: [Layer; SCREEN_WIDTH],
 
     // Window internal line counter
     window_line: u8,
+    // Set once LY has matched WY during this frame; WY isn't checked again until the next frame
+    window_y_triggered: bool,
+
+    // The first line after the LCD is switched on has no OAM scan (mode 0 instead of mode 2)
+    first_line: bool,
 }
 
 impl Ppu {
+    // Palette RAM as the CGB boot ROM leaves it for color games: every color
+    // white (RGB555 0x7FFF, little-endian). Games set their own palettes
+    // before turning the screen on.
     fn default_gbc_palette() -> [u8; 64] {
         let mut palette = [0u8; 64];
-        // Initialize palette 0 with test colors
-        // RGB555 format: 0BBBBBGGGGGRRRRR
-        let test_colors = [
-            (31, 31, 31), // White
-            (31, 0, 0),   // Red
-            (0, 31, 0),   // Green
-            (0, 0, 31),   // Blue
-        ];
-
-        // Palette 0: test colors
-        for (col_idx, &(r, g, b)) in test_colors.iter().enumerate() {
-            let base = col_idx * 2;
-            let color15 = (r & 0x1F) | ((g & 0x1F) << 5) | ((b & 0x1F) << 10);
-            palette[base] = (color15 & 0xFF) as u8;
-            palette[base + 1] = ((color15 >> 8) & 0xFF) as u8;
-        }
-
-        // Palettes 1-7: grayscale
-        let gray_colors = [
-            (31, 31, 31), // White
-            (21, 21, 21), // Light gray
-            (10, 10, 10), // Dark gray
-            (0, 0, 0),    // Black
-        ];
-        for pal in 1..8 {
-            for (col_idx, &(r, g, b)) in gray_colors.iter().enumerate() {
-                let base = pal * 8 + col_idx * 2;
-                let color15 = (r & 0x1F) | ((g & 0x1F) << 5) | ((b & 0x1F) << 10);
-                palette[base] = (color15 & 0xFF) as u8;
-                palette[base + 1] = ((color15 >> 8) & 0xFF) as u8;
-            }
+        for color in palette.chunks_exact_mut(2) {
+            color.copy_from_slice(&[0xFF, 0x7F]);
         }
         palette
     }
 
+    // Color shown by a blank (disabled) LCD
+    fn blank_color(&self) -> u32 {
+        if self.cgb_rendering() { 0xFFFFFF } else { self.dmg_palette.bg[0] }
+    }
+
+    // Tile attributes, CGB palettes and VRAM bank 1 in use
+    fn cgb_rendering(&self) -> bool {
+        self.is_gbc && !self.dmg_compat
+    }
+
+    pub fn blank_screen(&mut self) {
+        let color = self.blank_color();
+        self.framebuffer.fill(color);
+        self.complete_frame();
+    }
+
+    fn complete_frame(&mut self) {
+        self.front.copy_from_slice(&self.framebuffer);
+        self.new_frame = true;
+    }
+
+    // The last completed frame (160x144, 0xRRGGBB)
+    pub fn frame(&self) -> &[u32] {
+        &self.front[..]
+    }
+
+    // For overlays drawn on the completed frame (scripts)
+    pub fn frame_mut(&mut self) -> &mut [u32] {
+        &mut self.front[..]
+    }
+
+    // The frame completed since the last call, if any
+    pub fn take_frame(&mut self) -> Option<&[u32]> {
+        std::mem::take(&mut self.new_frame).then_some(&self.front[..])
+    }
+
     pub fn new(is_gbc: bool) -> Self {
-        let default_color = if is_gbc { 0xFFFFFF } else { 0x9BBC0F };
-        Ppu {
+        let mut ppu = Ppu {
             vram: [[0; 0x2000]; 2],
             oam: [0xFF; 0xA0], // Initialize OAM to 0xFF (invalid sprites)
-            framebuffer: [default_color; SCREEN_WIDTH * SCREEN_HEIGHT],
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            front: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            new_frame: false,
             lcdc: 0x91, // Post-boot ROM value
             stat: 0x85, // Post-boot value (varies)
             scy: 0,
@@ -93,22 +177,38 @@ impl Ppu {
             obp1: 0xFF,
             wy: 0,
             wx: 0,
-            vram_bank: if is_gbc { 0xFE } else { 0 }, // Post-boot: 0xFE for GBC
-            bcps: if is_gbc { 0xC8 } else { 0 },
+            vram_bank: 0,
+            bcps: if is_gbc { 0x88 } else { 0 },
             bcpd: Self::default_gbc_palette(),
-            ocps: if is_gbc { 0xD0 } else { 0 },
+            ocps: if is_gbc { 0x90 } else { 0 },
             ocpd: Self::default_gbc_palette(),
+            opri: if is_gbc { 0 } else { 1 },
             is_gbc,
+            dmg_compat: false,
+            dmg_palette: DmgPalette::default(),
+            layer_view: false,
+            skip_render: false,
             dots: 0,
             frame_ready: false,
             stat_interrupt: false,
+            stat_line: false,
+            vblank_interrupt: false,
+            hblank_started: false,
+            tile_cache: Box::new([[[0; 8]; 8]; TILES_PER_BANK * 2]),
+            tile_dirty: [true; TILES_PER_BANK * 2],
             bg_priority: [0; SCREEN_WIDTH],
+            layers: [Layer::None; SCREEN_WIDTH],
             window_line: 0,
-        }
+            window_y_triggered: false,
+            first_line: false,
+        };
+        ppu.blank_screen();
+        ppu
     }
 
     pub fn step(&mut self, cycles: u32) {
-        self.stat_interrupt = false;
+        self.vblank_interrupt = false;
+        self.hblank_started = false;
 
         // If LCD is disabled, don't process
         if (self.lcdc & 0x80) == 0 {
@@ -116,6 +216,7 @@ impl Ppu {
             self.ly = 0;
             self.stat = self.stat & 0xFC;
             self.dots = 0;
+            self.stat_line = false;
             return;
         }
 
@@ -147,48 +248,32 @@ impl Ppu {
                     if self.dots >= 252 {
                         self.stat = (self.stat & 0xFC) | 0; // Enter HBlank
                         self.render_scanline();
-
-                        // HBlank interrupt (STAT bit 3)
-                        if (self.stat & 0x08) != 0 {
-                            self.stat_interrupt = true;
-                        }
+                        self.hblank_started = true;
                     }
                 }
                 // Mode 0: HBlank (252-455 dots)
                 0 => {
-                    if self.dots >= 456 {
+                    if self.first_line {
+                        // Shortened line 0 after LCD enable goes straight to mode 3
+                        if self.dots >= 80 {
+                            self.first_line = false;
+                            self.stat = (self.stat & 0xFC) | 3;
+                        }
+                    } else if self.dots >= 456 {
                         self.dots -= 456;
                         self.ly += 1;
-
-                        // Check LY=LYC coincidence
-                        let lyc_match = self.ly == self.lyc;
-                        if lyc_match {
-                            self.stat |= 0x04; // Set coincidence flag
-                            // LYC interrupt (STAT bit 6)
-                            if (self.stat & 0x40) != 0 {
-                                self.stat_interrupt = true;
-                            }
-                        } else {
-                            self.stat &= !0x04; // Clear coincidence flag
-                        }
+                        self.update_coincidence();
 
                         if self.ly == 144 {
                             // Enter VBlank
                             self.stat = (self.stat & 0xFC) | 1;
                             self.frame_ready = true;
+                            self.complete_frame();
+                            self.vblank_interrupt = true;
                             self.window_line = 0; // Reset window line counter at start of VBlank
-
-                            // VBlank STAT interrupt (STAT bit 4)
-                            if (self.stat & 0x10) != 0 {
-                                self.stat_interrupt = true;
-                            }
+                            self.window_y_triggered = false;
                         } else {
                             self.stat = (self.stat & 0xFC) | 2; // Back to OAM search
-
-                            // OAM interrupt (STAT bit 5)
-                            if (self.stat & 0x20) != 0 {
-                                self.stat_interrupt = true;
-                            }
                         }
                     }
                 }
@@ -198,37 +283,92 @@ impl Ppu {
                         self.dots -= 456;
                         self.ly += 1;
 
-                        // Check LY=LYC coincidence
-                        let lyc_match = self.ly == self.lyc;
-                        if lyc_match {
-                            self.stat |= 0x04; // Set coincidence flag
-                            // LYC interrupt (STAT bit 6)
-                            if (self.stat & 0x40) != 0 {
-                                self.stat_interrupt = true;
-                            }
-                        } else {
-                            self.stat &= !0x04; // Clear coincidence flag
-                        }
-
                         if self.ly > 153 {
                             self.ly = 0;
                             self.stat = (self.stat & 0xFC) | 2; // Back to OAM search
-
-                            // OAM interrupt (STAT bit 5)
-                            if (self.stat & 0x20) != 0 {
-                                self.stat_interrupt = true;
-                            }
                         }
+                        self.update_coincidence();
                     }
                 }
                 _ => {}
             }
+
+            self.update_stat_line();
+        }
+    }
+
+    fn update_coincidence(&mut self) {
+        if self.ly == self.lyc {
+            self.stat |= 0x04; // Set coincidence flag
+        } else {
+            self.stat &= !0x04; // Clear coincidence flag
+        }
+    }
+
+    // The STAT interrupt is requested on the rising edge of a single line
+    // that ORs together all enabled sources. While one source holds the line
+    // high, other sources becoming active don't trigger another interrupt.
+    fn update_stat_line(&mut self) {
+        let mode = self.stat & 0x03;
+        let line = self.lcd_enabled()
+            && ((mode == 0 && !self.first_line && (self.stat & 0x08) != 0)
+                || (mode == 1 && (self.stat & 0x10) != 0)
+                || (mode == 2 && (self.stat & 0x20) != 0)
+                || ((self.stat & 0x04) != 0 && (self.stat & 0x40) != 0));
+
+        if line && !self.stat_line {
+            self.stat_interrupt = true;
+        }
+        self.stat_line = line;
+    }
+
+    pub fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcdc = value;
+
+        if was_enabled && !self.lcd_enabled() {
+            // LCD off: LY is held at 0 in mode 0 and the screen goes blank
+            self.ly = 0;
+            self.dots = 0;
+            self.stat &= 0xFC;
+            self.stat_line = false;
+            self.window_line = 0;
+            self.window_y_triggered = false;
+            self.blank_screen();
+        } else if !was_enabled && self.lcd_enabled() {
+            // LCD on: line 0 starts in mode 0 without an OAM scan (and so no
+            // mode 2 STAT interrupt) and is 4 dots shorter than a normal line
+            self.ly = 0;
+            self.dots = 4;
+            self.stat &= 0xFC;
+            self.first_line = true;
+            self.update_coincidence();
+            self.update_stat_line();
+        }
+    }
+
+    pub fn write_stat(&mut self, value: u8) {
+        // DMG quirk: the write enables every STAT source for one cycle, so an
+        // interrupt fires if HBlank, VBlank or LY=LYC is active and the line was low
+        if !self.is_gbc {
+            self.stat |= 0x58; // LY=LYC (bit 6), VBlank (bit 4) and HBlank (bit 3), not OAM
+            self.update_stat_line();
+        }
+        self.stat = (value & 0xF8) | (self.stat & 0x07); // Only bits 3-6 writable
+        self.update_stat_line();
+    }
+
+    pub fn write_lyc(&mut self, value: u8) {
+        self.lyc = value;
+        if self.lcd_enabled() {
+            self.update_coincidence();
+            self.update_stat_line();
         }
     }
 
     fn render_scanline(&mut self) {
-        if (self.lcdc & 0x80) == 0 {
-            return; // LCD off
+        if (self.lcdc & 0x80) == 0 || self.skip_render {
+            return; // LCD off or frame skipped
         }
 
         let y = self.ly as usize;
@@ -236,8 +376,15 @@ impl Ppu {
             return;
         }
 
+        // WY is latched: the window only appears once LY has been equal to WY
+        // this frame, so later WY writes don't move or restart it
+        if self.ly == self.wy {
+            self.window_y_triggered = true;
+        }
+
         // Clear priority buffer for this scanline
         self.bg_priority = [0; SCREEN_WIDTH];
+        self.layers = [Layer::None; SCREEN_WIDTH];
 
         // Render background/window (unified)
         if (self.lcdc & 0x01) != 0 {
@@ -245,26 +392,51 @@ impl Ppu {
         }
 
         // Render sprites
-        if (self.lcdc & 0x02) != 0 {
-            self.render_sprites(y);
+        let overflow = (self.lcdc & 0x02) != 0 && self.render_sprites(y);
+
+        if self.layer_view {
+            self.tint_layers(y, overflow);
+        }
+    }
+
+    fn tint_layers(&mut self, y: usize, overflow: bool) {
+        let line = &mut self.framebuffer[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+        for (pixel, layer) in line.iter_mut().zip(self.layers) {
+            let tint = match layer {
+                Layer::None => continue,
+                Layer::Background => BACKGROUND_TINT,
+                Layer::Window => WINDOW_TINT,
+                Layer::Sprite => SPRITE_TINT,
+            };
+            // Average of the pixel and the tint
+            *pixel = ((*pixel >> 1) & 0x7F7F7F) + ((tint >> 1) & 0x7F7F7F);
+        }
+        if overflow {
+            line[SCREEN_WIDTH - OVERFLOW_MARKER_WIDTH..].fill(OVERFLOW_MARKER);
         }
     }
 
     fn render_bg_window(&mut self, y: usize) {
-        // Check if window is enabled and visible on this scanline
-        let window_enabled = (self.lcdc & 0x20) != 0 && self.wy <= self.ly;
-        let wx_offset = self.wx.saturating_sub(7); // Window X is offset by 7
+        // Check if window is enabled and visible on this scanline.
+        // WX=166 and above pushes the window completely off screen.
+        let window_enabled = (self.lcdc & 0x20) != 0 && self.window_y_triggered && self.wx < 166;
+        // Window X is offset by 7; WX=0..6 starts the window left of the screen
+        // edge, so its first columns are cut off instead of being delayed
+        let wx_offset = self.wx as i16 - 7;
 
         let mut window_rendered = false;
 
-        for x in 0..SCREEN_WIDTH {
+        // Pixels are drawn in spans that end at a tile edge or where the
+        // window starts, so each tile row is looked up once
+        let mut x = 0;
+        while x < SCREEN_WIDTH {
             // Determine if we're rendering window or background
-            let in_window = window_enabled && (x as u8) >= wx_offset;
+            let in_window = window_enabled && x as i16 >= wx_offset;
 
             let (pixel_x, pixel_y, tile_map_base) = if in_window {
                 window_rendered = true;
                 // Window rendering - use internal line counter
-                let win_x = (x as u8).wrapping_sub(wx_offset);
+                let win_x = (x as i16 - wx_offset) as u8;
                 let win_y = self.window_line;
                 let tile_map = if (self.lcdc & 0x40) != 0 { 0x1C00 } else { 0x1800 };
                 (win_x, win_y, tile_map)
@@ -277,75 +449,62 @@ impl Ppu {
             };
 
             // Calculate tile position
-            let tile_x = ((pixel_x as u16 / 8) & 31) as u16;
-            let tile_y = ((pixel_y as u16 / 8) & 31) as u16;
-            let pixel_x_in_tile = (pixel_x % 8) as u16;
-            let pixel_y_in_tile = (pixel_y % 8) as u16;
+            let tile_x = (pixel_x as usize / 8) & 31;
+            let tile_y = (pixel_y as usize / 8) & 31;
+            let pixel_x_in_tile = (pixel_x % 8) as usize;
+            let pixel_y_in_tile = (pixel_y % 8) as usize;
+
+            let mut span = (8 - pixel_x_in_tile).min(SCREEN_WIDTH - x);
+            if window_enabled && !in_window {
+                span = span.min((wx_offset - x as i16) as usize);
+            }
 
             // Get tile number from tile map
-            let tile_map_addr = tile_map_base + (tile_y * 32) + tile_x;
-            if tile_map_addr >= 0x2000 {
-                continue;
-            }
-            let tile_num = self.vram[0][tile_map_addr as usize];
+            let tile_map_addr = tile_map_base + tile_y * 32 + tile_x;
+            let tile_num = self.vram[0][tile_map_addr];
 
             // GBC: Read attributes from VRAM bank 1
-            let (palette_num, flip_x, flip_y, _bg_priority) = if self.is_gbc {
-                let attr = self.vram[1][tile_map_addr as usize];
+            let (palette_num, flip_x, flip_y, tile_vram_bank) = if self.cgb_rendering() {
+                let attr = self.vram[1][tile_map_addr];
                 let pal = attr & 0x07;
                 let flip_x = (attr & 0x20) != 0;
                 let flip_y = (attr & 0x40) != 0;
-                let priority = (attr & 0x80) != 0;
-                (pal, flip_x, flip_y, priority)
+                let bank = if (attr & 0x08) != 0 { 1 } else { 0 };
+                (pal, flip_x, flip_y, bank)
             } else {
-                (0, false, false, false)
+                (0, false, false, 0)
             };
 
-            // Tile data address (signed vs unsigned addressing)
-            // LCDC bit 4 = 1: unsigned mode, tiles at $8000-$8FFF (VRAM 0x0000-0x0FFF)
-            // LCDC bit 4 = 0: signed mode, tiles at $8800-$97FF, base at $9000 (VRAM 0x1000)
-            let tile_addr = if (self.lcdc & 0x10) != 0 {
-                // Unsigned mode: tile 0 at VRAM 0x0000
-                (tile_num as u16) * 16
+            // Tile index (signed vs unsigned addressing)
+            // LCDC bit 4 = 1: unsigned mode, tiles at $8000-$8FFF (tiles 0-255)
+            // LCDC bit 4 = 0: signed mode, tiles at $8800-$97FF, base at $9000 (tile 256)
+            let tile = if (self.lcdc & 0x10) != 0 {
+                tile_num as usize
             } else {
-                // Signed mode: tile 0 at VRAM 0x1000 ($9000)
-                let offset = (tile_num as i8 as i32) * 16;
-                (0x1000i32 + offset) as u16
+                (256 + tile_num as i8 as i32) as usize
             };
 
-            if (tile_addr + pixel_y_in_tile * 2 + 1) as usize >= 0x2000 {
-                continue;
-            }
-
-            // Read tile data (use correct VRAM bank for GBC)
-            let tile_vram_bank = if self.is_gbc && ((self.vram[1][tile_map_addr as usize] & 0x08) != 0) { 1 } else { 0 };
+            let line = if flip_y { 7 - pixel_y_in_tile } else { pixel_y_in_tile };
+            let row = self.tile_row(tile_vram_bank, tile, line, flip_x);
 
-            let mut line = pixel_y_in_tile;
-            if flip_y {
-                line = 7 - line;
-            }
+            // The tile's palette, resolved once for the span
+            let colors: [u32; 4] = std::array::from_fn(|color_num| {
+                if self.cgb_rendering() {
+                    self.get_gbc_bg_color(color_num as u8, palette_num)
+                } else {
+                    self.get_bg_color(color_num as u8)
+                }
+            });
 
-            let byte1 = self.vram[tile_vram_bank][(tile_addr + line * 2) as usize];
-            let byte2 = self.vram[tile_vram_bank][(tile_addr + line * 2 + 1) as usize];
+            for i in 0..span {
+                let color_num = row[pixel_x_in_tile + i];
 
-            let mut bit = 7 - pixel_x_in_tile;
-            if flip_x {
-                bit = pixel_x_in_tile;
+                // Store color number for sprite priority
+                self.bg_priority[x + i] = color_num;
+                self.layers[x + i] = if in_window { Layer::Window } else { Layer::Background };
+                self.framebuffer[y * SCREEN_WIDTH + x + i] = colors[color_num as usize];
             }
-
-            let color_bit_1 = (byte1 >> bit) & 1;
-            let color_bit_2 = (byte2 >> bit) & 1;
-            let color_num = (color_bit_2 << 1) | color_bit_1;
-
-            // Store color number for sprite priority
-            self.bg_priority[x] = color_num;
-
-            let color = if self.is_gbc {
-                self.get_gbc_bg_color(color_num, palette_num)
-            } else {
-                self.get_bg_color(color_num)
-            };
-            self.framebuffer[y * SCREEN_WIDTH + x] = color;
+            x += span;
         }
 
         // Increment window line counter if window was rendered on this scanline
@@ -354,48 +513,63 @@ impl Ppu {
         }
     }
 
-    fn render_sprites(&mut self, y: usize) {
+    // Color numbers (0-3) of one row of a tile, left to right as drawn,
+    // decoding the tile again if VRAM writes changed it
+    fn tile_row(&mut self, bank: usize, tile: usize, row: usize, flip_x: bool) -> [u8; 8] {
+        let index = bank * TILES_PER_BANK + tile;
+        if self.tile_dirty[index] {
+            self.tile_dirty[index] = false;
+            let data = &self.vram[bank][tile * 16..tile * 16 + 16];
+            for (line, pixels) in self.tile_cache[index].iter_mut().enumerate() {
+                let (low, high) = (data[line * 2] as usize, data[line * 2 + 1] as usize);
+                *pixels = (SPREAD_BITS[low] | SPREAD_BITS[high] << 1).to_be_bytes();
+            }
+        }
+        let mut pixels = self.tile_cache[index][row];
+        if flip_x {
+            pixels.reverse();
+        }
+        pixels
+    }
+
+    // Needed after writing `vram` directly instead of through write_vram()
+    pub fn invalidate_tile_cache(&mut self) {
+        self.tile_dirty = [true; TILES_PER_BANK * 2];
+    }
+
+    // Returns true if sprites were dropped because more than 10 overlap the line
+    fn render_sprites(&mut self, y: usize) -> bool {
         let sprite_height = if (self.lcdc & 0x04) != 0 { 16 } else { 8 };
 
-        // Collect visible sprites on this scanline
-        let mut visible_sprites = Vec::new();
+        // OAM scan: the first 10 sprites in OAM order that overlap this line
+        // are selected, whatever their X position (off-screen ones still count)
+        let mut visible_sprites: Vec<(usize, u8)> = Vec::with_capacity(10);
+        let mut overflow = false;
         for sprite_idx in 0..40 {
             let oam_addr = sprite_idx * 4;
-            let sprite_y_raw = self.oam[oam_addr];
+            let sprite_y = self.oam[oam_addr] as i16 - 16;
             let sprite_x_raw = self.oam[oam_addr + 1];
 
-            // Skip completely invalid/hidden sprites
-            // Games typically hide sprites at Y=0 or Y>=160
-            if sprite_y_raw == 0 {
-                continue;
-            }
-
-            // Convert to screen coordinates (Y - 16)
-            let sprite_y = sprite_y_raw as i16 - 16;
-
-            // Check if scanline intersects with this sprite
             let y_i16 = y as i16;
             if y_i16 >= sprite_y && y_i16 < sprite_y + sprite_height as i16 {
-                // Only add if X is potentially visible (0 is used to hide)
-                if sprite_x_raw > 0 && sprite_x_raw < 168 {
-                    visible_sprites.push((sprite_idx, sprite_x_raw)); // (index, x position)
+                if visible_sprites.len() == 10 {
+                    overflow = true;
+                    break;
                 }
+                visible_sprites.push((sprite_idx, sprite_x_raw)); // (index, x position)
             }
         }
 
-        // Limit to 10 sprites per scanline (hardware limitation)
-        visible_sprites.truncate(10);
-
-        // Sort sprites by X coordinate (descending), then by OAM index (ascending)
-        // This ensures sprites with lower X are drawn last (on top)
-        visible_sprites.sort_by(|a, b| {
-            match b.1.cmp(&a.1) {
-                std::cmp::Ordering::Equal => a.0.cmp(&b.0), // Same X: lower OAM index wins
-                other => other // Different X: higher X first (will be drawn first/behind)
-            }
-        });
+        // DMG (and CGB with OPRI bit 0 set) favours the lowest X, then the lowest
+        // OAM index. CGB priority is by OAM index only, which is the scan order.
+        if self.coordinate_priority() {
+            visible_sprites.sort_by_key(|&(sprite_idx, x)| (x, sprite_idx));
+        }
 
-        // Render sprites - those drawn later appear on top
+        // Sprites are rendered from highest to lowest priority. An opaque pixel
+        // claims its position even when it ends up hidden behind the background,
+        // so lower priority sprites never show through there.
+        let mut claimed = [false; SCREEN_WIDTH];
         for (sprite_idx, _) in visible_sprites.iter() {
             let oam_addr = sprite_idx * 4;
             let sprite_y_raw = self.oam[oam_addr];
@@ -403,13 +577,13 @@ impl Ppu {
             let tile_num = self.oam[oam_addr + 2];
             let attributes = self.oam[oam_addr + 3];
 
-            let palette = if (attributes & 0x10) != 0 { self.obp1 } else { self.obp0 };
+            let use_obp1 = (attributes & 0x10) != 0;
             let flip_y = (attributes & 0x40) != 0;
             let flip_x = (attributes & 0x20) != 0;
             let priority = (attributes & 0x80) != 0; // Priority flag: 1 = behind BG colors 1-3
 
             // GBC: Extract palette number and VRAM bank
-            let (gbc_palette, gbc_vram_bank) = if self.is_gbc {
+            let (gbc_palette, gbc_vram_bank) = if self.cgb_rendering() {
                 let pal = attributes & 0x07;
                 let bank = if (attributes & 0x08) != 0 { 1 } else { 0 };
                 (pal, bank)
@@ -441,14 +615,9 @@ impl Ppu {
                 (tile_num as u16 * 16) + (line * 2)
             };
 
-            if (tile_addr + 1) as usize >= 0x2000 {
-                continue;
-            }
-
-            let byte1 = self.vram[gbc_vram_bank][tile_addr as usize];
-            let byte2 = self.vram[gbc_vram_bank][(tile_addr + 1) as usize];
+            let row = self.tile_row(gbc_vram_bank, tile_addr as usize / 16, (tile_addr as usize % 16) / 2, flip_x);
 
-            for x in 0..8 {
+            for (x, &color_num) in row.iter().enumerate() {
                 let pixel_x = sprite_x + x as i16;
 
                 // Skip if off screen
@@ -456,15 +625,15 @@ impl Ppu {
                     continue;
                 }
 
-                let bit = if flip_x { x } else { 7 - x };
-                let color_bit_1 = (byte1 >> bit) & 1;
-                let color_bit_2 = (byte2 >> bit) & 1;
-                let color_num = (color_bit_2 << 1) | color_bit_1;
-
                 if color_num == 0 {
                     continue; // Transparent
                 }
 
+                if claimed[pixel_x as usize] {
+                    continue; // A higher priority sprite already owns this pixel
+                }
+                claimed[pixel_x as usize] = true;
+
                 // Check sprite-to-BG priority
                 let bg_color = self.bg_priority[pixel_x as usize];
 
@@ -476,40 +645,34 @@ impl Ppu {
                     continue; // Sprite is behind non-transparent background
                 }
 
-                let color = if self.is_gbc {
+                let color = if self.cgb_rendering() {
                     self.get_gbc_sprite_color(color_num, gbc_palette)
                 } else {
-                    self.get_sprite_color(color_num, palette)
+                    self.get_sprite_color(color_num, use_obp1)
                 };
                 self.framebuffer[y * SCREEN_WIDTH + pixel_x as usize] = color;
+                self.layers[pixel_x as usize] = Layer::Sprite;
             }
         }
+        overflow
     }
 
-    fn get_bg_color(&self, color_num: u8) -> u32 {
+    pub fn get_bg_color(&self, color_num: u8) -> u32 {
         let palette_color = (self.bgp >> (color_num * 2)) & 0x03;
-        // Classic Game Boy green palette (0RGB format)
-        match palette_color {
-            0 => 0x9BBC0F, // Lightest
-            1 => 0x8BAC0F, // Light
-            2 => 0x306230, // Dark
-            3 => 0x0F380F, // Darkest
-            _ => 0x9BBC0F,
-        }
+        self.dmg_palette.bg[palette_color as usize]
     }
 
-    fn get_sprite_color(&self, color_num: u8, palette: u8) -> u32 {
+    fn get_sprite_color(&self, color_num: u8, use_obp1: bool) -> u32 {
+        let (palette, colors) = if use_obp1 {
+            (self.obp1, &self.dmg_palette.obj1)
+        } else {
+            (self.obp0, &self.dmg_palette.obj0)
+        };
         let palette_color = (palette >> (color_num * 2)) & 0x03;
-        match palette_color {
-            0 => 0x9BBC0F,
-            1 => 0x8BAC0F,
-            2 => 0x306230,
-            3 => 0x0F380F,
-            _ => 0x9BBC0F,
-        }
+        colors[palette_color as usize]
     }
 
-    fn get_gbc_bg_color(&self, color_num: u8, palette_num: u8) -> u32 {
+    pub fn get_gbc_bg_color(&self, color_num: u8, palette_num: u8) -> u32 {
         // Each palette is 8 bytes (4 colors × 2 bytes per color)
         let palette_base = ((palette_num & 0x07) as usize) * 8;
         let color_offset = ((color_num & 0x03) as usize) * 2;
@@ -563,6 +726,26 @@ impl Ppu {
         (r8 << 16) | (g8 << 8) | b8
     }
 
+    // Whether sprite priority is decided by X coordinate (OPRI, 0xFF6C)
+    fn coordinate_priority(&self) -> bool {
+        !self.cgb_rendering() || (self.opri & 0x01) != 0
+    }
+
+    fn lcd_enabled(&self) -> bool {
+        (self.lcdc & 0x80) != 0
+    }
+
+    // The CPU can't access VRAM (or CGB palette data) while the PPU is
+    // drawing in mode 3
+    pub fn vram_accessible(&self) -> bool {
+        !self.lcd_enabled() || (self.stat & 0x03) != 3
+    }
+
+    // OAM is in use during OAM search (mode 2) and pixel transfer (mode 3)
+    pub fn oam_accessible(&self) -> bool {
+        !self.lcd_enabled() || (self.stat & 0x03) < 2
+    }
+
     pub fn read_vram(&self, addr: u16) -> u8 {
         let bank = if self.is_gbc { (self.vram_bank & 0x01) as usize } else { 0 };
         self.vram[bank][(addr - 0x8000) as usize]
@@ -570,7 +753,11 @@ impl Ppu {
 
     pub fn write_vram(&mut self, addr: u16, value: u8) {
         let bank = if self.is_gbc { (self.vram_bank & 0x01) as usize } else { 0 };
-        self.vram[bank][(addr - 0x8000) as usize] = value;
+        let offset = (addr - 0x8000) as usize;
+        self.vram[bank][offset] = value;
+        if offset < TILES_PER_BANK * 16 {
+            self.tile_dirty[bank * TILES_PER_BANK + offset / 16] = true;
+        }
     }
 
     pub fn read_oam(&self, addr: u16) -> u8 {
@@ -580,4 +767,75 @@ impl Ppu {
     pub fn write_oam(&mut self, addr: u16, value: u8) {
         self.oam[(addr - 0xFE00) as usize] = value;
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for bank in &self.vram {
+            w.bytes(bank);
+        }
+        w.bytes(&self.oam);
+        for &pixel in self.framebuffer.iter() {
+            w.u32(pixel);
+        }
+        let registers = [self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0, self.obp1];
+        w.bytes(&registers);
+        w.bytes(&[self.wy, self.wx, self.vram_bank, self.bcps, self.ocps, self.opri]);
+        w.bytes(&self.bcpd);
+        w.bytes(&self.ocpd);
+        w.u32(self.dots);
+        w.bool(self.frame_ready);
+        w.bool(self.stat_interrupt);
+        w.bool(self.stat_line);
+        w.bool(self.vblank_interrupt);
+        w.bool(self.hblank_started);
+        w.u8(self.window_line);
+        w.bool(self.window_y_triggered);
+        w.bool(self.first_line);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        for bank in &mut self.vram {
+            r.bytes(bank);
+        }
+        r.bytes(&mut self.oam);
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = r.u32();
+        }
+        for register in [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.vram_bank,
+            &mut self.bcps,
+            &mut self.ocps,
+            &mut self.opri,
+        ] {
+            *register = r.u8();
+        }
+        self.vram_bank &= 0x01;
+        // Palette indexes stay within the palette RAM, bit 6 isn't stored
+        self.bcps &= 0xBF;
+        self.ocps &= 0xBF;
+        r.bytes(&mut self.bcpd);
+        r.bytes(&mut self.ocpd);
+        self.dots = r.u32();
+        self.frame_ready = r.bool();
+        self.stat_interrupt = r.bool();
+        self.stat_line = r.bool();
+        self.vblank_interrupt = r.bool();
+        self.hblank_started = r.bool();
+        self.window_line = r.u8();
+        self.window_y_triggered = r.bool();
+        self.first_line = r.bool();
+        // All of VRAM changed
+        self.tile_dirty.fill(true);
+        self.complete_frame();
+    }
 }
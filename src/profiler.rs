@@ -0,0 +1,111 @@
+// Cycle profiler: attributes executed cycles to the instruction that took them
+// and to the functions on the call stack. With a sample interval above 1 only
+// every Nth instruction is recorded, scaled up, to keep the overhead down.
+
+use std::collections::HashMap;
+
+use crate::cpu::CallFrame;
+use crate::symbols::Symbols;
+
+// (ROM bank, address); the bank is 0 outside 0x4000-0x7FFF
+pub type Location = (u8, u16);
+
+pub struct Profiler {
+    sample_every: u32,
+    counter: u32,
+    pub total: u64,
+    self_cycles: HashMap<Location, u64>,      // Per instruction
+    function_self: HashMap<Location, u64>,    // Per innermost function
+    function_total: HashMap<Location, u64>,   // Per function anywhere on the call stack
+}
+
+// Location used for code executed outside any tracked call
+const TOP_LEVEL: Location = (0xFF, 0xFFFF);
+
+impl Profiler {
+    // sample_every = 1 records every instruction exactly
+    pub fn new(sample_every: u32) -> Self {
+        Profiler {
+            sample_every: sample_every.max(1),
+            counter: 0,
+            total: 0,
+            self_cycles: HashMap::new(),
+            function_self: HashMap::new(),
+            function_total: HashMap::new(),
+        }
+    }
+
+    // Records an instruction at `location` that took `cycles`
+    pub fn record(&mut self, location: Location, cycles: u32, call_stack: &[CallFrame]) {
+        self.counter += 1;
+        if self.counter < self.sample_every {
+            return;
+        }
+        self.counter = 0;
+
+        let cycles = cycles as u64 * self.sample_every as u64;
+        self.total += cycles;
+        *self.self_cycles.entry(location).or_insert(0) += cycles;
+
+        let function = call_stack.last().map_or(TOP_LEVEL, |frame| (frame.bank, frame.target));
+        *self.function_self.entry(function).or_insert(0) += cycles;
+
+        // Recursive functions are only counted once per instruction
+        let mut seen: Vec<Location> = Vec::with_capacity(call_stack.len());
+        for frame in call_stack {
+            let function = (frame.bank, frame.target);
+            if !seen.contains(&function) {
+                seen.push(function);
+                *self.function_total.entry(function).or_insert(0) += cycles;
+            }
+        }
+    }
+
+    // Prints the `count` hottest functions and instructions
+    pub fn report(&self, symbols: &Symbols, count: usize) {
+        if self.total == 0 {
+            println!("Profiler: nothing recorded");
+            return;
+        }
+
+        println!("Profile: {} cycles", self.total);
+        println!("Hottest functions (self / including calls):");
+        for (function, cycles) in hottest(&self.function_self, count) {
+            let total = self.function_total.get(&function).copied().unwrap_or(cycles);
+            println!(
+                "  {:5.1}% {:5.1}%  {}",
+                self.percent(cycles),
+                self.percent(total),
+                name(symbols, function)
+            );
+        }
+
+        println!("Hottest instructions:");
+        for (location, cycles) in hottest(&self.self_cycles, count) {
+            println!("  {:5.1}%  {}", self.percent(cycles), name(symbols, location));
+        }
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        cycles as f64 * 100.0 / self.total as f64
+    }
+}
+
+fn hottest(map: &HashMap<Location, u64>, count: usize) -> Vec<(Location, u64)> {
+    let mut entries: Vec<(Location, u64)> = map.iter().map(|(&l, &c)| (l, c)).collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    entries.truncate(count);
+    entries
+}
+
+fn name(symbols: &Symbols, (bank, address): Location) -> String {
+    if (bank, address) == TOP_LEVEL {
+        return "(outside any call)".to_string();
+    }
+    let location = symbols.format(bank, address);
+    if (0x4000..0x8000).contains(&address) {
+        format!("{:02X}:{}", bank, location)
+    } else {
+        location
+    }
+}
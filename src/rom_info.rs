@@ -0,0 +1,391 @@
+// Cartridge header inspection (--info <rom>): prints what the header at
+// 0x100-0x14F says about a ROM, as text or with --json as one JSON object,
+// and exits without running it. Works on ROMs the emulator would refuse
+// (bad logo, unsupported mapper), since that is when the header is of
+// interest.
+
+use std::path::Path;
+
+use gameboy_emulator::cartridge::{Cartridge, NINTENDO_LOGO};
+use gameboy_emulator::gamedb;
+
+// Prints the header of the ROM at `path` and returns whether it could be read
+pub fn run(path: &Path, json: bool) -> bool {
+    let rom = match Cartridge::read_rom_file(&path.to_string_lossy()) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            return false;
+        }
+    };
+    if rom.len() < 0x150 {
+        eprintln!("{}: {} bytes, too small for a cartridge header", path.display(), rom.len());
+        return false;
+    }
+
+    let fields = fields(&rom);
+    if json {
+        let entries: Vec<String> = fields
+            .iter()
+            .map(|(key, _, value)| format!("  \"{}\": {}", key, value.to_json()))
+            .collect();
+        println!("{{\n{}\n}}", entries.join(",\n"));
+    } else {
+        for (_, label, value) in &fields {
+            println!("{:<20} {}", format!("{}:", label), value);
+        }
+    }
+    true
+}
+
+enum Value {
+    Text(String),
+    Number(u64),
+    Bool(bool),
+    Null,
+}
+
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::Text(text) => {
+                let mut out = String::from("\"");
+                for c in text.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+                out
+            }
+            Value::Number(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Null => "null".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Text(text) => write!(f, "{}", text),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(true) => write!(f, "yes"),
+            Value::Bool(false) => write!(f, "no"),
+            Value::Null => write!(f, "-"),
+        }
+    }
+}
+
+// The header as (JSON key, text label, value), in header order
+fn fields(rom: &[u8]) -> Vec<(&'static str, &'static str, Value)> {
+    let text = Value::Text;
+    let hex = |n: u16, digits: usize| Value::Text(format!("0x{:0width$X}", n, width = digits));
+
+    let cgb = match rom[0x143] {
+        0xC0 => "CGB only",
+        0x80 => "CGB enhanced",
+        _ => "DMG",
+    };
+    let licensee = if rom[0x14B] == 0x33 {
+        let code = String::from_utf8_lossy(&rom[0x144..0x146]).to_string();
+        let name = new_licensee_name(&code).unwrap_or("unknown");
+        format!("{} ({})", code, name)
+    } else {
+        format!("{:02X} ({})", rom[0x14B], old_licensee_name(rom[0x14B]).unwrap_or("unknown"))
+    };
+    let rom_size = match rom[0x148] {
+        n @ 0x00..=0x08 => Value::Number(0x8000 << n),
+        0x52 => Value::Number(72 * 0x4000),
+        0x53 => Value::Number(80 * 0x4000),
+        0x54 => Value::Number(96 * 0x4000),
+        _ => Value::Null,
+    };
+    let ram_size = match rom[0x149] {
+        0x00 => Value::Number(0),
+        0x01 => Value::Number(0x800),
+        0x02 => Value::Number(0x2000),
+        0x03 => Value::Number(0x8000),
+        0x04 => Value::Number(0x20000),
+        0x05 => Value::Number(0x10000),
+        _ => Value::Null,
+    };
+    let region = match rom[0x14A] {
+        0x00 => "Japan",
+        0x01 => "Overseas",
+        _ => "unknown",
+    };
+    let header_checksum = rom[0x134..=0x14C].iter().fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+    let global_checksum = rom
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+    let stored_global = ((rom[0x14E] as u16) << 8) | rom[0x14F] as u16;
+    let quirks = gamedb::lookup(rom);
+    let quirks_value = if quirks == gamedb::Quirks::default() { Value::Null } else { text(format!("{:?}", quirks)) };
+
+    vec![
+        ("title", "Title", text(title(rom))),
+        ("cgb", "CGB", text(cgb.to_string())),
+        ("cgb_flag", "CGB flag", hex(rom[0x143] as u16, 2)),
+        ("sgb", "SGB", Value::Bool(rom[0x146] == 0x03)),
+        ("cartridge_type", "Cartridge type", hex(rom[0x147] as u16, 2)),
+        ("mapper", "Mapper", text(mapper_name(rom[0x147]).unwrap_or("unknown").to_string())),
+        ("rom_size", "ROM size", rom_size),
+        ("file_size", "File size", Value::Number(rom.len() as u64)),
+        ("ram_size", "RAM size", ram_size),
+        ("region", "Region", text(region.to_string())),
+        ("licensee", "Licensee", text(licensee)),
+        ("version", "Version", Value::Number(rom[0x14C] as u64)),
+        ("header_checksum", "Header checksum", hex(rom[0x14D] as u16, 2)),
+        ("header_checksum_ok", "Header checksum OK", Value::Bool(header_checksum == rom[0x14D])),
+        ("global_checksum", "Global checksum", hex(stored_global, 4)),
+        ("global_checksum_ok", "Global checksum OK", Value::Bool(global_checksum == stored_global)),
+        ("logo_ok", "Logo OK", Value::Bool(rom[0x104..0x134] == NINTENDO_LOGO)),
+        // The CGB boot ROM only checks the first half of the logo
+        ("logo_ok_cgb", "Logo OK on CGB", Value::Bool(rom[0x104..0x11C] == NINTENDO_LOGO[..24])),
+        ("game_database", "Game database", quirks_value),
+    ]
+}
+
+// Title up to the first byte that isn't printable ASCII, as Cartridge::title
+fn title(rom: &[u8]) -> String {
+    rom[0x134..0x144]
+        .iter()
+        .take_while(|&&b| b.is_ascii_graphic() || b == b' ')
+        .map(|&b| b as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+// Cartridge type byte (0x147), as Pan Docs names it
+fn mapper_name(byte: u8) -> Option<&'static str> {
+    Some(match byte {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => return None,
+    })
+}
+
+// Two-character licensee code at 0x144-0x145, used when 0x14B is 0x33
+fn new_licensee_name(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "00" => "none",
+        "01" => "Nintendo R&D1",
+        "08" => "Capcom",
+        "13" | "69" => "Electronic Arts",
+        "18" | "38" => "Hudson Soft",
+        "19" => "B-AI",
+        "20" => "KSS",
+        "22" => "Planning Office WADA",
+        "24" => "PCM Complete",
+        "25" => "San-X",
+        "28" => "Kemco",
+        "29" => "SETA",
+        "30" => "Viacom",
+        "31" => "Nintendo",
+        "32" => "Bandai",
+        "33" | "93" => "Ocean Software/Acclaim",
+        "34" | "54" => "Konami",
+        "35" => "HectorSoft",
+        "37" => "Taito",
+        "39" => "Banpresto",
+        "41" => "Ubi Soft",
+        "42" => "Atlus",
+        "44" => "Malibu Interactive",
+        "46" => "Angel",
+        "47" => "Bullet-Proof Software",
+        "49" => "Irem",
+        "50" => "Absolute",
+        "51" => "Acclaim",
+        "52" => "Activision",
+        "53" => "Sammy USA",
+        "55" => "Hi Tech Expressions",
+        "56" => "LJN",
+        "57" => "Matchbox",
+        "58" => "Mattel",
+        "59" => "Milton Bradley",
+        "60" => "Titus",
+        "61" => "Virgin Games",
+        "64" => "Lucasfilm Games",
+        "67" => "Ocean Software",
+        "70" => "Infogrames",
+        "71" => "Interplay",
+        "72" => "Broderbund",
+        "73" => "Sculptured Software",
+        "75" => "The Sales Curve",
+        "78" => "THQ",
+        "79" => "Accolade",
+        "80" => "Misawa Entertainment",
+        "83" => "LOZC",
+        "86" => "Tokuma Shoten",
+        "87" => "Tsukuda Original",
+        "91" => "Chunsoft",
+        "92" => "Video System",
+        "95" => "Varie",
+        "96" => "Yonezawa/S'pal",
+        "97" => "Kaneko",
+        "99" => "Pack-In-Video",
+        "9H" => "Bottom Up",
+        "A4" => "Konami (Yu-Gi-Oh!)",
+        "BL" => "MTO",
+        "DK" => "Kodansha",
+        _ => return None,
+    })
+}
+
+// Licensee byte at 0x14B of games from before the two-character codes
+fn old_licensee_name(code: u8) -> Option<&'static str> {
+    Some(match code {
+        0x00 => "none",
+        0x01 | 0x31 => "Nintendo",
+        0x08 | 0x38 => "Capcom",
+        0x09 => "HOT-B",
+        0x0A | 0xE0 => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C | 0x6E => "Elite Systems",
+        0x13 | 0x69 => "Electronic Arts",
+        0x18 => "Hudson Soft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F | 0x4A | 0x61 => "Virgin Games",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 | 0x7F | 0x97 | 0xC2 => "Kemco",
+        0x29 => "SETA",
+        0x30 | 0x70 => "Infogrames",
+        0x32 | 0xA2 | 0xB2 => "Bandai",
+        0x34 | 0xA4 => "Konami",
+        0x35 => "HectorSoft",
+        0x39 | 0x9D | 0xD9 => "Banpresto",
+        0x3C => "Entertainment Interactive",
+        0x3E => "Gremlin",
+        0x41 => "Ubi Soft",
+        0x42 | 0xEB => "Atlus",
+        0x44 | 0x4D => "Malibu Interactive",
+        0x46 | 0xCF => "Angel",
+        0x47 => "Spectrum HoloByte",
+        0x49 => "Irem",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 | 0xB0 => "Acclaim",
+        0x52 => "Activision",
+        0x53 => "Sammy USA",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 | 0xDB | 0xFF => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C | 0xD6 => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus",
+        0x67 => "Ocean Software",
+        0x6F => "Electro Brain",
+        0x71 => "Interplay",
+        0x72 | 0xAA => "Broderbund",
+        0x73 => "Sculptured Software",
+        0x75 => "The Sales Curve",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "MicroProse",
+        0x80 => "Misawa Entertainment",
+        0x83 => "LOZC",
+        0x86 | 0xC4 => "Tokuma Shoten",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai",
+        0x8E => "Ape",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft",
+        0x92 => "Video System",
+        0x93 => "Tsubaraya Productions",
+        0x95 | 0xE3 => "Varie",
+        0x96 => "Yonezawa/S'pal",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB1 => "ASCII/Nexsoft",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 | 0xCE => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy",
+        0xC0 | 0xD0 => "Taito",
+        0xC3 => "Square",
+        0xC5 => "Data East",
+        0xC6 => "Tonkin House",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra Games",
+        0xCB => "VAP",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xD1 => "SOFEL",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha",
+        0xD7 => "Copya System",
+        0xDA => "Tomy",
+        0xDD => "Nippon Computer Systems",
+        0xDE => "Human Entertainment",
+        0xDF => "Altron",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE5 => "Epoch",
+        0xE7 => "Athena",
+        0xE8 => "Asmik Ace",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        _ => return None,
+    })
+}
@@ -0,0 +1,228 @@
+// Savestates: the whole emulated machine in one file, to resume a game at the
+// exact point it was saved.
+//
+// File format: "GBSS", format version (u16 LE), flags byte (bit 0 = GBC
+// mode), ROM global checksum (u16 LE), ROM title (16 bytes, zero padded),
+// length-prefixed core revision (the emulator version that wrote it), since
+// version 2 the time of saving (u64 LE Unix seconds) and a thumbnail of the
+// screen (160x144 RGB, 3 bytes per pixel), then the state of each component
+// in a fixed order, as written by their save_state(). A new version can still
+// load older states: the components check StateReader::version for fields
+// that were added later.
+
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::emulator::Emulator;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use log::info;
+
+const MAGIC: &[u8; 4] = b"GBSS";
+pub const VERSION: u16 = 5;
+// Oldest format version that can still be loaded
+const MIN_VERSION: u16 = 1;
+const CORE_REVISION: &str = env!("CARGO_PKG_VERSION");
+
+// Appends little-endian values
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+// Reads values back in the order they were written. Reading past the end
+// returns zeros and marks the state as damaged, so components can load
+// without checking every read.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    damaged: bool,
+    pub version: u16, // Format version of the state being read
+}
+
+impl StateReader<'_> {
+    // For values that don't fit the running game; the state is refused
+    pub fn reject(&mut self) {
+        self.damaged = true;
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0; N];
+        self.bytes(&mut bytes);
+        bytes
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    pub fn i32(&mut self) -> i32 {
+        i32::from_le_bytes(self.take())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) {
+        match self.data.get(self.position..self.position + bytes.len()) {
+            Some(data) => {
+                bytes.copy_from_slice(data);
+                self.position += bytes.len();
+            }
+            None => {
+                bytes.fill(0);
+                self.damaged = true;
+            }
+        }
+    }
+}
+
+// Header of a state file
+pub struct StateInfo {
+    pub version: u16,
+    pub is_gbc: bool,
+    pub rom_checksum: u16,
+    pub rom_title: String,
+    pub core_revision: String,
+    pub timestamp: Option<u64>,      // Unix seconds, since version 2
+    pub thumbnail: Option<Vec<u32>>, // 160x144, 0xRRGGBB, since version 2
+}
+
+impl StateInfo {
+    pub fn parse(data: &[u8]) -> std::io::Result<(StateInfo, usize)> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        if data.len() < 26 || &data[0..4] != MAGIC {
+            return Err(invalid("not a savestate".to_string()));
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        let revision_len = data[25] as usize;
+        let revision = data.get(26..26 + revision_len).ok_or_else(|| invalid("savestate is truncated".to_string()))?;
+        let mut info = StateInfo {
+            version,
+            is_gbc: (data[6] & 0x01) != 0,
+            rom_checksum: u16::from_le_bytes([data[7], data[8]]),
+            rom_title: String::from_utf8_lossy(&data[9..25]).trim_end_matches('\0').to_string(),
+            core_revision: String::from_utf8_lossy(revision).to_string(),
+            timestamp: None,
+            thumbnail: None,
+        };
+        let mut header_len = 26 + revision_len;
+        if version >= 2 {
+            let thumbnail_len = SCREEN_WIDTH * SCREEN_HEIGHT * 3;
+            let preview = data
+                .get(header_len..header_len + 8 + thumbnail_len)
+                .ok_or_else(|| invalid("savestate is truncated".to_string()))?;
+            info.timestamp = Some(u64::from_le_bytes(preview[..8].try_into().unwrap()));
+            let pixels = preview[8..].chunks_exact(3);
+            info.thumbnail = Some(pixels.map(|p| u32::from_be_bytes([0, p[0], p[1], p[2]])).collect());
+            header_len += 8 + thumbnail_len;
+        }
+        Ok((info, header_len))
+    }
+}
+
+pub fn save(emu: &Emulator) -> Vec<u8> {
+    let mut writer = StateWriter::default();
+    writer.bytes(MAGIC);
+    writer.u16(VERSION);
+    writer.u8(emu.mmu.ppu.is_gbc as u8);
+    writer.u16(emu.mmu.cartridge.global_checksum());
+    let mut title = [0u8; 16];
+    let rom_title = emu.mmu.cartridge.title();
+    title[..rom_title.len()].copy_from_slice(rom_title.as_bytes());
+    writer.bytes(&title);
+    writer.u8(CORE_REVISION.len() as u8);
+    writer.bytes(CORE_REVISION.as_bytes());
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    writer.u64(timestamp);
+    for &pixel in emu.mmu.ppu.frame() {
+        writer.bytes(&pixel.to_be_bytes()[1..]);
+    }
+
+    emu.cpu.save_state(&mut writer);
+    emu.mmu.save_state(&mut writer);
+    writer.data
+}
+
+// Loads a state saved for the running game. States from another game, from
+// the other hardware mode or from a newer version are refused, and a state
+// that turns out to be damaged leaves the emulator as it was.
+pub fn load(emu: &mut Emulator, data: &[u8]) -> std::io::Result<()> {
+    let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+    let (info, header_len) = StateInfo::parse(data)?;
+    if info.version > VERSION {
+        return Err(invalid(format!("savestate version {} is from a newer emulator", info.version)));
+    }
+    if info.version < MIN_VERSION {
+        return Err(invalid(format!("savestate version {} is no longer supported", info.version)));
+    }
+    let cartridge = &emu.mmu.cartridge;
+    if info.rom_checksum != cartridge.global_checksum() || info.rom_title != cartridge.title() {
+        return Err(invalid(format!("savestate is for another game ({})", info.rom_title)));
+    }
+    if info.is_gbc != emu.mmu.ppu.is_gbc {
+        let mode = if info.is_gbc { "Game Boy Color" } else { "Game Boy (DMG)" };
+        return Err(invalid(format!("savestate was saved in {} mode", mode)));
+    }
+    if info.core_revision != CORE_REVISION {
+        info!("Loading a savestate from version {} of the emulator", info.core_revision);
+    }
+
+    let backup = save(emu);
+    let (_, backup_header_len) = StateInfo::parse(&backup)?;
+    if !load_components(emu, data, header_len, info.version) {
+        load_components(emu, &backup, backup_header_len, VERSION);
+        return Err(invalid("savestate is damaged".to_string()));
+    }
+    Ok(())
+}
+
+// Returns false if the data ran out or didn't fit
+fn load_components(emu: &mut Emulator, data: &[u8], position: usize, version: u16) -> bool {
+    let mut reader = StateReader { data, position, damaged: false, version };
+    emu.cpu.load_state(&mut reader);
+    emu.mmu.load_state(&mut reader);
+    !reader.damaged && reader.position == data.len()
+}
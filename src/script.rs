@@ -0,0 +1,216 @@
+// Lua scripting. The script runs once when loaded and can define callbacks:
+//
+//   on_frame_start()             before each emulated frame
+//   on_frame_end()               after each frame, where overlays are drawn
+//   emu.on_read(address, fn)     fn(address, value) after a read of address
+//   emu.on_write(address, fn)    fn(address, value) after a write to address
+//
+// Everything else goes through the `emu` table:
+//
+//   emu.read(address), emu.read16(address), emu.write(address, value)
+//   emu.press(button, ...)       hold buttons for the next frame: "a", "b",
+//                                "select", "start", "right", "left", "up", "down"
+//   emu.frame()                  frames run since the script was loaded
+//   emu.text(x, y, text[, color]), emu.pixel(x, y, color)
+//   emu.rect(x, y, w, h, color), emu.box(x, y, w, h, color) (filled)
+//
+// Drawing goes straight into the completed frame, so it shows up in
+// screenshots and recordings too. Colors are 0xRRGGBB.
+
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+
+use mlua::{Function, Lua, Table, Variadic};
+
+use crate::emulator::Emulator;
+use crate::font;
+use crate::joypad::JoypadState;
+use crate::mmu::MemoryAccess;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use log::{error, info};
+
+const BUTTONS: [&str; 8] = ["right", "left", "up", "down", "a", "b", "select", "start"];
+
+pub struct Script {
+    lua: Lua,
+    buttons: u8, // Pressed by the script for the next frame
+    frame: u64,
+    failed: bool, // Stopped after an error
+}
+
+impl Script {
+    pub fn load(path: &Path, emu: &mut Emulator) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let lua = Lua::new();
+        let setup = || -> mlua::Result<()> {
+            lua.globals().set("emu", lua.create_table()?)?;
+            lua.set_named_registry_value("read_hooks", lua.create_table()?)?;
+            lua.set_named_registry_value("write_hooks", lua.create_table()?)
+        };
+        setup().map_err(|e| e.to_string())?;
+
+        let mut script = Script { lua, buttons: 0, frame: 0, failed: false };
+        let name = path.display().to_string();
+        script.call(emu, |lua| lua.load(source.as_str()).set_name(name.as_str()).exec());
+        if script.failed {
+            return Err("script failed to run".to_string());
+        }
+        info!("Loaded script: {}", path.display());
+        Ok(script)
+    }
+
+    // Buttons pressed by the script since the last call
+    pub fn take_buttons(&mut self) -> JoypadState {
+        JoypadState::from_bits(std::mem::take(&mut self.buttons))
+    }
+
+    pub fn frame_start(&mut self, emu: &mut Emulator) {
+        self.call_global(emu, "on_frame_start");
+    }
+
+    pub fn frame_end(&mut self, emu: &mut Emulator) {
+        self.frame += 1;
+        self.call_global(emu, "on_frame_end");
+    }
+
+    // Runs the hook registered for a memory access
+    pub fn memory_access(&mut self, emu: &mut Emulator, access: MemoryAccess) {
+        let hooks = if access.write { "write_hooks" } else { "read_hooks" };
+        self.call(emu, |lua| {
+            let hooks: Table = lua.named_registry_value(hooks)?;
+            match hooks.get::<_, Option<Function>>(access.address)? {
+                Some(hook) => hook.call((access.address, access.value)),
+                None => Ok(()),
+            }
+        });
+    }
+
+    fn call_global(&mut self, emu: &mut Emulator, name: &str) {
+        self.call(emu, |lua| match lua.globals().get::<_, Option<Function>>(name)? {
+            Some(callback) => callback.call(()),
+            None => Ok(()),
+        });
+    }
+
+    // Runs `f` with the emulator API bound to `emu`. The API functions only
+    // live for the duration of the call.
+    fn call(&mut self, emu: &mut Emulator, f: impl FnOnce(&Lua) -> mlua::Result<()>) {
+        if self.failed {
+            return;
+        }
+
+        let emu = RefCell::new(emu);
+        let buttons = Cell::new(self.buttons);
+        let frame = self.frame;
+        let lua = &self.lua;
+
+        let result = lua.scope(|scope| {
+            let api: Table = lua.globals().get("emu")?;
+            api.set("read", scope.create_function(|_, address: u16| Ok(emu.borrow().mmu.read_byte(address)))?)?;
+            api.set(
+                "read16",
+                scope.create_function(|_, address: u16| {
+                    let emu = emu.borrow();
+                    Ok(emu.mmu.read_byte(address) as u16 | (emu.mmu.read_byte(address.wrapping_add(1)) as u16) << 8)
+                })?,
+            )?;
+            api.set(
+                "write",
+                scope.create_function(|_, (address, value): (u16, u8)| {
+                    emu.borrow_mut().mmu.write_byte(address, value);
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "press",
+                scope.create_function(|_, names: Variadic<String>| {
+                    for name in names.iter() {
+                        match BUTTONS.iter().position(|b| b.eq_ignore_ascii_case(name)) {
+                            Some(bit) => buttons.set(buttons.get() | 1 << bit),
+                            None => return Err(mlua::Error::RuntimeError(format!("unknown button '{}'", name))),
+                        }
+                    }
+                    Ok(())
+                })?,
+            )?;
+            api.set("frame", scope.create_function(move |_, ()| Ok(frame))?)?;
+            api.set(
+                "on_read",
+                scope.create_function(|lua, (address, hook): (u16, Function)| {
+                    lua.named_registry_value::<Table>("read_hooks")?.set(address, hook)?;
+                    emu.borrow_mut().mmu.add_hook(address, false);
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "on_write",
+                scope.create_function(|lua, (address, hook): (u16, Function)| {
+                    lua.named_registry_value::<Table>("write_hooks")?.set(address, hook)?;
+                    emu.borrow_mut().mmu.add_hook(address, true);
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "text",
+                scope.create_function(|_, (x, y, text, color): (i32, i32, String, Option<u32>)| {
+                    let mut emu = emu.borrow_mut();
+                    let framebuffer = emu.mmu.ppu.frame_mut();
+                    let color = color.unwrap_or(0xFFFFFF);
+                    font::draw_text(framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT, x, y, &text, color);
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "pixel",
+                scope.create_function(|_, (x, y, color): (i32, i32, u32)| {
+                    let mut emu = emu.borrow_mut();
+                    let framebuffer = emu.mmu.ppu.frame_mut();
+                    font::put_pixel(framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT, x, y, color);
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "rect",
+                scope.create_function(|_, (x, y, w, h, color): (i32, i32, i32, i32, u32)| {
+                    let mut emu = emu.borrow_mut();
+                    let framebuffer = emu.mmu.ppu.frame_mut();
+                    for py in y..y + h {
+                        for px in x..x + w {
+                            if py == y || py == y + h - 1 || px == x || px == x + w - 1 {
+                                font::put_pixel(framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT, px, py, color);
+                            }
+                        }
+                    }
+                    Ok(())
+                })?,
+            )?;
+            api.set(
+                "box",
+                scope.create_function(|_, (x, y, w, h, color): (i32, i32, i32, i32, u32)| {
+                    let mut emu = emu.borrow_mut();
+                    let framebuffer = emu.mmu.ppu.frame_mut();
+                    for py in y..y + h {
+                        for px in x..x + w {
+                            font::put_pixel(framebuffer, SCREEN_WIDTH, SCREEN_HEIGHT, px, py, color);
+                        }
+                    }
+                    Ok(())
+                })?,
+            )?;
+
+            f(lua)
+        });
+
+        self.buttons = buttons.get();
+
+        // Accesses made by the script itself don't trigger hooks or watchpoints
+        let emu = emu.into_inner();
+        emu.mmu.take_hook_hits();
+        emu.mmu.take_io_hit();
+
+        if let Err(e) = result {
+            error!("Script error, script stopped: {}", e);
+            self.failed = true;
+        }
+    }
+}
@@ -0,0 +1,296 @@
+// SDL2 frontend (cargo feature "sdl", selected with --sdl): GPU scaling with
+// aspect-correct letterboxing, desktop fullscreen, audio through an SDL queue
+// and game controller input. The debugging windows and recording hotkeys are
+// only available in the default minifb frontend.
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::{FullscreenType, WindowContext};
+use sdl2::{EventPump, GameControllerSubsystem};
+
+use crate::apu;
+use crate::cpu::Lockup;
+use crate::emulator::Emulator;
+use crate::frontend::{self, Frontend};
+use crate::joypad::JoypadState;
+use crate::mixer::{AudioFilter, Mixer};
+use crate::movie::MovieSession;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::video::{FrameBlender, Scaler};
+use log::{error, info, warn};
+
+const SCALE: u32 = 3;
+const STICK_THRESHOLD: i16 = 16384; // Half-way stick deflection presses the D-pad
+
+// Controller buttons and keys for each JoypadState::bits() bit
+const CONTROLLER_BUTTONS: [Button; 8] = [
+    Button::DPadRight,
+    Button::DPadLeft,
+    Button::DPadUp,
+    Button::DPadDown,
+    Button::A,
+    Button::B,
+    Button::Back,
+    Button::Start,
+];
+const KEYS: [&[Scancode]; 8] = [
+    &[Scancode::Right],
+    &[Scancode::Left],
+    &[Scancode::Up],
+    &[Scancode::Down],
+    &[Scancode::Z],
+    &[Scancode::X],
+    &[Scancode::LShift, Scancode::RShift],
+    &[Scancode::Return],
+];
+
+pub fn run(
+    emu: &mut Emulator,
+    title: &str,
+    fullscreen: bool,
+    blender: Option<FrameBlender>,
+    scaler: Scaler,
+    audio_filter: AudioFilter,
+    movie: Option<&mut MovieSession>,
+) -> Result<(), String> {
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+    let controller_subsystem = sdl.game_controller()?;
+    let events = sdl.event_pump()?;
+
+    // Nearest-neighbour scaling; smoothing comes from --filter if wanted
+    sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", "0");
+
+    let mut window = video
+        .window(title, SCREEN_WIDTH as u32 * SCALE, SCREEN_HEIGHT as u32 * SCALE)
+        .position_centered()
+        .resizable()
+        .build()
+        .map_err(|e| e.to_string())?;
+    if fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop)?;
+    }
+    let mut canvas = window.into_canvas().accelerated().present_vsync().build().map_err(|e| e.to_string())?;
+    canvas
+        .set_logical_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .map_err(|e| e.to_string())?;
+
+    // Without an audio device the emulator still runs, paced by the clock
+    let audio_queue: Option<AudioQueue<f32>> = sdl
+        .audio()
+        .and_then(|audio| {
+            let spec = AudioSpecDesired {
+                freq: Some(apu::SAMPLE_RATE as i32),
+                channels: Some(1),
+                samples: Some(512),
+            };
+            audio.open_queue::<f32, _>(None, &spec)
+        })
+        .map_err(|e| warn!("SDL audio unavailable: {}", e))
+        .ok();
+    if let Some(ref queue) = audio_queue {
+        queue.resume();
+    }
+    // SDL may pick another rate than the one asked for
+    let audio_rate = audio_queue.as_ref().map_or(apu::SAMPLE_RATE, |queue| queue.spec().freq as u32);
+
+    let texture_creator = canvas.texture_creator();
+    let mut sdl_frontend = SdlFrontend {
+        canvas,
+        texture_creator: &texture_creator,
+        texture: None,
+        texture_size: (0, 0),
+        events,
+        controller_subsystem,
+        controllers: Vec::new(),
+        audio_queue,
+        audio_rate,
+        mixer: Mixer::new(audio_rate, audio_filter),
+        mixed: Vec::new(),
+        blender,
+        scaler,
+        paused: false,
+        open: true,
+        error: None,
+    };
+
+    println!("SDL frontend: Alt+Enter/F11 toggles fullscreen, P pauses, ESC exits");
+    frontend::run(emu, &mut sdl_frontend, movie);
+    sdl_frontend.error.map_or(Ok(()), Err)
+}
+
+struct SdlFrontend<'a> {
+    canvas: WindowCanvas,
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Option<Texture<'a>>,
+    texture_size: (usize, usize),
+    events: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    audio_queue: Option<AudioQueue<f32>>,
+    audio_rate: u32,
+    mixer: Mixer,
+    mixed: Vec<f32>, // Mono samples for the queue, reused between frames
+    blender: Option<FrameBlender>,
+    scaler: Scaler,
+    paused: bool,
+    open: bool,
+    error: Option<String>, // Ends the session
+}
+
+impl SdlFrontend<'_> {
+    fn fail(&mut self, error: String) {
+        self.error.get_or_insert(error);
+        self.open = false;
+    }
+
+    fn toggle_fullscreen(&mut self) -> Result<(), String> {
+        let window = self.canvas.window_mut();
+        let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(state)
+    }
+
+    // Draws the frame, re-creating the texture when the filter's output size changes
+    fn draw(&mut self, fb: &[u32]) -> Result<(), String> {
+        let frame = match self.blender.as_mut() {
+            Some(blender) if self.paused => blender.output(),
+            Some(blender) => blender.apply(fb),
+            None => fb,
+        };
+        let (pixels, width, height) = self.scaler.apply(frame);
+        if self.texture.is_none() || self.texture_size != (width, height) {
+            self.texture = Some(
+                self.texture_creator
+                    .create_texture_streaming(PixelFormatEnum::RGB888, width as u32, height as u32)
+                    .map_err(|e| e.to_string())?,
+            );
+            self.texture_size = (width, height);
+        }
+        let texture = self.texture.as_mut().unwrap();
+        texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for (y, row) in pixels.chunks(width).enumerate() {
+                for (x, &pixel) in row.iter().enumerate() {
+                    let offset = y * pitch + x * 4;
+                    buffer[offset..offset + 4].copy_from_slice(&pixel.to_ne_bytes());
+                }
+            }
+        })?;
+        self.canvas.clear();
+        self.canvas.copy(texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+impl Frontend for SdlFrontend<'_> {
+    fn present(&mut self, fb: &[u32]) {
+        if let Err(e) = self.draw(fb) {
+            self.fail(e);
+        }
+    }
+
+    fn poll_input(&mut self) -> JoypadState {
+        let events: Vec<Event> = self.events.poll_iter().collect();
+        for event in events {
+            match event {
+                Event::Quit { .. } => self.open = false,
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => {
+                    let alt = keymod.intersects(Mod::LALTMOD | Mod::RALTMOD);
+                    if key == Keycode::Escape {
+                        self.open = false;
+                    } else if key == Keycode::F11 || (alt && key == Keycode::Return) {
+                        if let Err(e) = self.toggle_fullscreen() {
+                            self.fail(e);
+                        }
+                    } else if key == Keycode::P {
+                        self.paused = !self.paused;
+                        info!("{}", if self.paused { "Paused" } else { "Resumed" });
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => match self.controller_subsystem.open(which) {
+                    Ok(controller) => {
+                        info!("Controller connected: {}", controller.name());
+                        self.controllers.push(controller);
+                    }
+                    Err(e) => error!("Failed to open controller: {}", e),
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|c| c.instance_id() != which);
+                }
+                _ => {}
+            }
+        }
+        JoypadState::from_bits(read_buttons(&self.events.keyboard_state(), &self.controllers))
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        if let Some(ref queue) = self.audio_queue {
+            self.mixed.clear();
+            self.mixer.process(samples, &mut self.mixed);
+            if let Err(e) = queue.queue_audio(&self.mixed) {
+                self.fail(e);
+            }
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Without an audio device run() paces on the clock
+    fn audio_queued(&self) -> Option<usize> {
+        // Counted at apu::SAMPLE_RATE
+        let rate_ratio = apu::SAMPLE_RATE as f64 / self.audio_rate as f64;
+        self.audio_queue.as_ref().map(|queue| (queue.size() as f64 / 4.0 * rate_ratio) as usize)
+    }
+
+    fn crashed(&mut self, lockup: Lockup) {
+        error!("{}", lockup);
+        let title = format!("{} [crashed]", self.canvas.window().title());
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+}
+
+fn read_buttons(keyboard: &sdl2::keyboard::KeyboardState, controllers: &[GameController]) -> u8 {
+    let mut mask = 0;
+    for (bit, keys) in KEYS.iter().enumerate() {
+        if keys.iter().any(|&key| keyboard.is_scancode_pressed(key)) {
+            mask |= 1 << bit;
+        }
+    }
+    for controller in controllers {
+        for (bit, &button) in CONTROLLER_BUTTONS.iter().enumerate() {
+            if controller.button(button) {
+                mask |= 1 << bit;
+            }
+        }
+
+        // Left stick as the D-pad
+        let x = controller.axis(Axis::LeftX);
+        let y = controller.axis(Axis::LeftY);
+        if x > STICK_THRESHOLD {
+            mask |= 0x01;
+        }
+        if x < -STICK_THRESHOLD {
+            mask |= 0x02;
+        }
+        if y < -STICK_THRESHOLD {
+            mask |= 0x04;
+        }
+        if y > STICK_THRESHOLD {
+            mask |= 0x08;
+        }
+    }
+    mask
+}
@@ -0,0 +1,117 @@
+// Serial port: SB (0xFF01) and SC (0xFF02). A transfer on the internal clock
+// shifts SB out one bit per serial clock, shifting 1s in when nothing is
+// connected, so after 8 clocks SB reads 0xFF and the interrupt fires. With a
+// link cable (link.rs) the bytes are exchanged with the other emulator instead.
+
+use crate::savestate::{StateReader, StateWriter};
+
+// The serial clock is the falling edge of a divider bit: bit 8 (8192 Hz) or,
+// with the CGB fast clock (SC bit 1), bit 3 (262144 Hz). It doubles with the
+// CPU in double speed mode since the divider does.
+const CLOCK_SHIFT: u32 = 9; // One clock every 2^9 divider counts
+const FAST_CLOCK_SHIFT: u32 = 4;
+
+pub struct Serial {
+    sb: u8,
+    sc: u8, // Bit 7: transfer in progress, bit 1: fast clock (CGB), bit 0: internal clock
+    is_gbc: bool,
+    bits_left: u8, // Of the internally clocked transfer in progress
+    sending: u8,   // SB when that transfer started
+    pub connected: bool, // Finished transfers wait for the link to exchange the bytes
+    finished: bool, // An internally clocked transfer is waiting for the exchange
+}
+
+impl Serial {
+    pub fn new(is_gbc: bool) -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            is_gbc,
+            bits_left: 0,
+            sending: 0,
+            connected: false,
+            finished: false,
+        }
+    }
+
+    pub fn read_sb(&self) -> u8 {
+        self.sb
+    }
+
+    pub fn write_sb(&mut self, value: u8) {
+        self.sb = value;
+    }
+
+    pub fn read_sc(&self) -> u8 {
+        // Unused bits read as 1; bit 1 only exists on the CGB
+        if self.is_gbc { self.sc | 0x7C } else { self.sc | 0x7E }
+    }
+
+    pub fn write_sc(&mut self, value: u8) {
+        self.sc = value & if self.is_gbc { 0x83 } else { 0x81 };
+        self.finished = false;
+        self.bits_left = if (self.sc & 0x81) == 0x81 { 8 } else { 0 };
+        self.sending = self.sb;
+    }
+
+    // Shifts one bit per serial clock while the divider advances `cycles`
+    // from `div`. Returns true when a transfer completes on its own, without
+    // a link, and the serial interrupt fires.
+    pub fn step(&mut self, div: u16, cycles: u32) -> bool {
+        if self.bits_left == 0 {
+            return false;
+        }
+        let shift = if (self.sc & 0x02) != 0 { FAST_CLOCK_SHIFT } else { CLOCK_SHIFT };
+        let clocks = ((div as u32 + cycles) >> shift) - (div as u32 >> shift);
+        for _ in 0..clocks.min(self.bits_left as u32) {
+            self.sb = (self.sb << 1) | 1;
+            self.bits_left -= 1;
+        }
+        if self.bits_left > 0 {
+            return false;
+        }
+
+        if self.connected {
+            self.finished = true;
+            false
+        } else {
+            self.sc &= 0x7F;
+            true
+        }
+    }
+
+    // The byte sent by an internally clocked transfer that just finished
+    pub fn take_sent(&mut self) -> Option<u8> {
+        std::mem::take(&mut self.finished).then_some(self.sending)
+    }
+
+    // Ends the transfer with the byte shifted in. The caller raises the interrupt.
+    pub fn complete(&mut self, received: u8) {
+        self.sb = received;
+        self.sc &= 0x7F;
+    }
+
+    // A transfer clocked by the other side of the link. Returns the byte sent
+    // back, or None if no transfer on the external clock is waiting.
+    pub fn external_transfer(&mut self, received: u8) -> Option<u8> {
+        if (self.sc & 0x81) != 0x80 {
+            return None;
+        }
+        let sent = self.sb;
+        self.complete(received);
+        Some(sent)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.sb, self.sc, self.bits_left, self.sending]);
+        w.bool(self.finished);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.sb = r.u8();
+        self.sc = r.u8();
+        self.bits_left = r.u8();
+        self.sending = r.u8();
+        self.finished = r.bool();
+    }
+}
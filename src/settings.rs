@@ -0,0 +1,187 @@
+// Settings kept between sessions in settings.txt in the data directory: the
+// recently played ROMs, newest first, and per-game settings in a section
+// named after the header title and global checksum. Per-game settings are
+// applied when the game starts, unless overridden on the command line.
+//
+//   recent = /home/me/roms/tetris.gb
+//   audio_device = USB Headset     (as --audio-device)
+//
+//   [POKEMON RED 91E6]
+//   palette = gbc                  (as --palette)
+//   mode = dmg                     (or cgb)
+//   controller = wasd              (keyboard layout: arrows or wasd)
+//   cheat = 01FF16D0 Max money     (one per line, as in a cheat file)
+
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::Cartridge;
+use crate::palette::PalettePreset;
+use crate::paths;
+use crate::window_frontend::KeyLayout;
+use log::{error, warn};
+
+const MAX_RECENT: usize = 10;
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct GameSettings {
+    pub palette: Option<PalettePreset>,
+    pub gbc: Option<bool>, // Forced GBC (true) or DMG mode
+    pub controller: Option<KeyLayout>,
+    pub cheats: Vec<String>, // Lines in the cheat file format
+}
+
+pub struct Settings {
+    path: PathBuf,
+    pub recent: Vec<PathBuf>,
+    pub audio_device: Option<String>,
+    games: Vec<(String, GameSettings)>,
+    pub defaults: GameSettings, // From the config file, for what a game doesn't set
+}
+
+// Section name for a game
+pub fn game_key(cartridge: &Cartridge) -> String {
+    format!("{} {:04X}", cartridge.title(), cartridge.global_checksum())
+}
+
+impl Settings {
+    // Loads the settings file, starting empty if there is none. Invalid lines
+    // are reported and dropped.
+    pub fn load() -> Self {
+        let path = paths::data_dir().join("settings.txt");
+        let mut settings = Settings {
+            path,
+            recent: Vec::new(),
+            audio_device: None,
+            games: Vec::new(),
+            defaults: GameSettings::default(),
+        };
+        let text = match std::fs::read_to_string(&settings.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return settings,
+            Err(e) => {
+                error!("Failed to read {}: {}", settings.path.display(), e);
+                return settings;
+            }
+        };
+
+        for (number, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(key) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                settings.games.push((key.to_string(), GameSettings::default()));
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=').map(|(n, v)| (n.trim(), v.trim())) else {
+                warn!("settings.txt line {}: expected 'name = value'", number + 1);
+                continue;
+            };
+            let valid = match settings.games.last_mut() {
+                None if name == "recent" => {
+                    settings.recent.push(PathBuf::from(value));
+                    true
+                }
+                None if name == "audio_device" => {
+                    settings.audio_device = Some(value.to_string());
+                    true
+                }
+                None => false,
+                Some((_, game)) => game.set(name, value),
+            };
+            if !valid {
+                warn!("settings.txt line {}: ignoring '{}'", number + 1, line);
+            }
+        }
+        settings
+    }
+
+    pub fn save(&self) {
+        let mut text = String::from("# Recently played ROMs, newest first\n");
+        for rom in &self.recent {
+            text += &format!("recent = {}\n", rom.display());
+        }
+        if let Some(ref device) = self.audio_device {
+            text += &format!("audio_device = {}\n", device);
+        }
+        for (key, game) in self.games.iter().filter(|(_, game)| *game != GameSettings::default()) {
+            text += &format!("\n[{}]\n", key);
+            if let Some(palette) = game.palette {
+                text += &format!("palette = {}\n", palette.name());
+            }
+            if let Some(gbc) = game.gbc {
+                text += &format!("mode = {}\n", if gbc { "cgb" } else { "dmg" });
+            }
+            if let Some(controller) = game.controller {
+                text += &format!("controller = {}\n", controller.name());
+            }
+            for cheat in &game.cheats {
+                text += &format!("cheat = {}\n", cheat);
+            }
+        }
+
+        let result = std::fs::create_dir_all(paths::data_dir()).and_then(|_| std::fs::write(&self.path, text));
+        if let Err(e) = result {
+            error!("Failed to save {}: {}", self.path.display(), e);
+        }
+    }
+
+    // Moves a ROM to the top of the recent list
+    pub fn add_recent(&mut self, rom: &Path) {
+        let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+        self.recent.retain(|path| *path != rom);
+        self.recent.insert(0, rom);
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    // The settings of a game, completed with the defaults
+    pub fn game(&self, key: &str) -> GameSettings {
+        let game = self.games.iter().find(|(k, _)| k == key).map(|(_, game)| game.clone()).unwrap_or_default();
+        GameSettings {
+            palette: game.palette.or(self.defaults.palette),
+            gbc: game.gbc.or(self.defaults.gbc),
+            controller: game.controller.or(self.defaults.controller),
+            cheats: game.cheats,
+        }
+    }
+
+    pub fn game_mut(&mut self, key: &str) -> &mut GameSettings {
+        let index = match self.games.iter().position(|(k, _)| k == key) {
+            Some(index) => index,
+            None => {
+                self.games.push((key.to_string(), GameSettings::default()));
+                self.games.len() - 1
+            }
+        };
+        &mut self.games[index].1
+    }
+}
+
+impl GameSettings {
+    // Returns false for unknown settings and invalid values
+    pub fn set(&mut self, name: &str, value: &str) -> bool {
+        match name {
+            "palette" => {
+                self.palette = PalettePreset::from_name(value);
+                self.palette.is_some()
+            }
+            "mode" => {
+                self.gbc = match value.to_lowercase().as_str() {
+                    "dmg" => Some(false),
+                    "cgb" | "gbc" => Some(true),
+                    _ => None,
+                };
+                self.gbc.is_some()
+            }
+            "controller" => {
+                self.controller = KeyLayout::from_name(value);
+                self.controller.is_some()
+            }
+            "cheat" => {
+                self.cheats.push(value.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+// Savestate slot browser: while Tab is held, the screen shows a 3x3 grid of
+// the game's savestate slots with a thumbnail of each state and how long ago
+// it was saved. The arrows or the keys 1-9 pick the slot that Ctrl+S and
+// Ctrl+L use. Slot 1 is <rom>.state, the file of the single savestate of
+// earlier versions; the others are <rom>.<slot>.state.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use minifb::{Key, KeyRepeat, Window};
+
+use crate::font;
+use crate::paths;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use gameboy_emulator::savestate::StateInfo;
+
+pub const SLOTS: usize = 9;
+const COLUMNS: usize = 3;
+const CELL_WIDTH: usize = SCREEN_WIDTH / COLUMNS;
+const CELL_HEIGHT: usize = SCREEN_HEIGHT / (SLOTS / COLUMNS);
+// Thumbnails are the screen at a quarter of its size
+const THUMB_SCALE: usize = 4;
+const THUMB_WIDTH: usize = SCREEN_WIDTH / THUMB_SCALE;
+const THUMB_HEIGHT: usize = SCREEN_HEIGHT / THUMB_SCALE;
+
+const TEXT_COLOR: u32 = 0xFFFFFF;
+const EMPTY_COLOR: u32 = 0x303030;
+const BORDER_COLOR: u32 = 0x606060;
+const SELECTED_COLOR: u32 = 0xFFD000;
+
+const NUMBER_KEYS: [Key; SLOTS] =
+    [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9];
+
+// Savestate file of a slot (1 to SLOTS)
+pub fn slot_path(rom_name: &str, slot: usize) -> PathBuf {
+    let name = if slot == 1 { format!("{}.state", rom_name) } else { format!("{}.{}.state", rom_name, slot) };
+    paths::state_dir().join(name)
+}
+
+// What the browser shows of a saved state
+enum Slot {
+    Empty,
+    Saved { thumbnail: Option<Vec<u32>>, timestamp: Option<u64> }, // Both missing in states before version 2
+    Unreadable,
+}
+
+pub struct SlotBrowser {
+    pub selected: usize, // 1 to SLOTS
+    slots: Vec<Slot>,
+}
+
+impl SlotBrowser {
+    // Reads the headers of the game's savestates
+    pub fn open(rom_name: &str, selected: usize) -> Self {
+        let slots = (1..=SLOTS)
+            .map(|slot| match std::fs::read(slot_path(rom_name, slot)) {
+                Ok(data) => match StateInfo::parse(&data) {
+                    Ok((info, _)) => {
+                        Slot::Saved { thumbnail: info.thumbnail.map(|t| shrink(&t)), timestamp: info.timestamp }
+                    }
+                    Err(_) => Slot::Unreadable,
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Slot::Empty,
+                Err(_) => Slot::Unreadable,
+            })
+            .collect();
+        SlotBrowser { selected, slots }
+    }
+
+    // Arrows move through the grid, 1-9 pick a slot directly
+    pub fn handle_keys(&mut self, window: &Window) {
+        let index = self.selected - 1;
+        let pressed = |key| window.is_key_pressed(key, KeyRepeat::Yes);
+        let index = if pressed(Key::Left) {
+            (index + SLOTS - 1) % SLOTS
+        } else if pressed(Key::Right) {
+            (index + 1) % SLOTS
+        } else if pressed(Key::Up) {
+            (index + SLOTS - COLUMNS) % SLOTS
+        } else if pressed(Key::Down) {
+            (index + COLUMNS) % SLOTS
+        } else {
+            NUMBER_KEYS.iter().position(|&key| window.is_key_pressed(key, KeyRepeat::No)).unwrap_or(index)
+        };
+        self.selected = index + 1;
+    }
+
+    // Draws the grid over a dimmed 160x144 frame
+    pub fn draw(&self, frame: &mut [u32], width: usize, height: usize) {
+        for pixel in frame.iter_mut() {
+            *pixel = (*pixel >> 2) & 0x3F3F3F;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        for (i, slot) in self.slots.iter().enumerate() {
+            let left = (i % COLUMNS * CELL_WIDTH + (CELL_WIDTH - THUMB_WIDTH) / 2) as i32;
+            let top = (i / COLUMNS * CELL_HEIGHT + 1) as i32;
+
+            // Thumbnail with a border, the selected slot highlighted
+            let border = if i + 1 == self.selected { SELECTED_COLOR } else { BORDER_COLOR };
+            for x in left - 1..=left + THUMB_WIDTH as i32 {
+                font::put_pixel(frame, width, height, x, top, border);
+                font::put_pixel(frame, width, height, x, top + THUMB_HEIGHT as i32 + 1, border);
+            }
+            for y in top..=top + THUMB_HEIGHT as i32 {
+                font::put_pixel(frame, width, height, left - 1, y, border);
+                font::put_pixel(frame, width, height, left + THUMB_WIDTH as i32, y, border);
+            }
+            for y in 0..THUMB_HEIGHT {
+                for x in 0..THUMB_WIDTH {
+                    let color = match slot {
+                        Slot::Saved { thumbnail: Some(thumbnail), .. } => thumbnail[y * THUMB_WIDTH + x],
+                        _ => EMPTY_COLOR,
+                    };
+                    font::put_pixel(frame, width, height, left + x as i32, top + 1 + y as i32, color);
+                }
+            }
+
+            let label = match slot {
+                Slot::Empty => format!("{} EMPTY", i + 1),
+                Slot::Saved { timestamp: Some(timestamp), .. } => format!("{} {}", i + 1, age(now, *timestamp)),
+                Slot::Saved { timestamp: None, .. } => format!("{} SAVED", i + 1),
+                Slot::Unreadable => format!("{} ERROR", i + 1),
+            };
+            let label_width = label.chars().count() as i32 * font::ADVANCE - 1;
+            let x = (i % COLUMNS * CELL_WIDTH) as i32 + (CELL_WIDTH as i32 - label_width) / 2;
+            font::draw_text(frame, width, height, x, top + THUMB_HEIGHT as i32 + 4, &label, TEXT_COLOR);
+        }
+    }
+}
+
+// Averages each 4x4 block of a 160x144 frame
+fn shrink(frame: &[u32]) -> Vec<u32> {
+    let mut thumbnail = Vec::with_capacity(THUMB_WIDTH * THUMB_HEIGHT);
+    for y in 0..THUMB_HEIGHT {
+        for x in 0..THUMB_WIDTH {
+            let mut sums = [0u32; 3];
+            for dy in 0..THUMB_SCALE {
+                for dx in 0..THUMB_SCALE {
+                    let pixel = frame[(y * THUMB_SCALE + dy) * SCREEN_WIDTH + x * THUMB_SCALE + dx];
+                    for (channel, sum) in sums.iter_mut().enumerate() {
+                        *sum += (pixel >> (16 - channel * 8)) & 0xFF;
+                    }
+                }
+            }
+            let count = (THUMB_SCALE * THUMB_SCALE) as u32;
+            thumbnail.push(sums.iter().fold(0, |color, sum| (color << 8) | (sum / count)));
+        }
+    }
+    thumbnail
+}
+
+// How long ago a state was saved, e.g. "5M AGO"
+fn age(now: u64, timestamp: u64) -> String {
+    let seconds = now.saturating_sub(timestamp);
+    match seconds {
+        0..=59 => "NOW".to_string(),
+        60..=3599 => format!("{}M AGO", seconds / 60),
+        3600..=86399 => format!("{}H AGO", seconds / 3600),
+        _ => format!("{}D AGO", seconds / 86400),
+    }
+}
@@ -0,0 +1,345 @@
+// Runner for the SM83 single-step tests (github.com/SingleStepTests/sm83):
+// one JSON file per opcode, each test an initial CPU and memory state, the
+// state after executing one instruction and the bus activity of every
+// M-cycle. Tests run against flat RAM (Mmu::use_flat_ram) and check the
+// registers, flags, IME, memory, the M-cycle count and the order of writes.
+
+use std::path::{Path, PathBuf};
+
+use gameboy_emulator::cpu::Cpu;
+use gameboy_emulator::emulator::Emulator;
+
+// Differences printed for the first failing test of a file
+const MAX_REPORTED: usize = 8;
+
+// Runs every .json file in `path` (or `path` itself) and returns whether all
+// tests passed
+pub fn run(path: &Path) -> bool {
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        match std::fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|e| e == "json"))
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", path.display(), e);
+                return false;
+            }
+        }
+    } else {
+        vec![path.to_path_buf()]
+    };
+    files.sort();
+
+    let mut emu = Emulator::with_program(&[], None);
+    emu.mmu.use_flat_ram();
+    for address in 0..=0xFFFF {
+        emu.mmu.add_hook(address, false);
+        emu.mmu.add_hook(address, true);
+    }
+
+    let (mut passed, mut total, mut bad_files) = (0, 0, 0);
+    for file in &files {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let tests = match std::fs::read_to_string(file).map_err(|e| e.to_string()).and_then(|t| Json::parse(&t)) {
+            Ok(Json::Array(tests)) => tests,
+            Ok(_) => {
+                eprintln!("{}: expected an array of tests", name);
+                bad_files += 1;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                bad_files += 1;
+                continue;
+            }
+        };
+
+        let mut file_passed = 0;
+        let mut reported = false;
+        for test in &tests {
+            let errors = run_test(&mut emu, test);
+            if errors.is_empty() {
+                file_passed += 1;
+            } else if !reported {
+                println!("{}: test '{}' failed:", name, test.get("name").and_then(Json::as_str).unwrap_or("?"));
+                for error in errors.iter().take(MAX_REPORTED) {
+                    println!("    {}", error);
+                }
+                reported = true;
+            }
+        }
+        println!("{}: {}/{} passed", name, file_passed, tests.len());
+        passed += file_passed;
+        total += tests.len();
+    }
+
+    println!("\n{} of {} tests passed", passed, total);
+    if bad_files > 0 {
+        println!("{} file(s) could not be read", bad_files);
+    }
+    if total == 0 {
+        eprintln!("No tests found in {}", path.display());
+    }
+    // Unreadable files and an empty or wrong folder fail the run too
+    passed == total && bad_files == 0 && total > 0
+}
+
+// Runs one test and returns what differed from the expected final state
+fn run_test(emu: &mut Emulator, test: &Json) -> Vec<String> {
+    let (Some(initial), Some(expected)) = (test.get("initial"), test.get("final")) else {
+        return vec!["missing initial or final state".to_string()];
+    };
+
+    emu.cpu = Cpu::new();
+    let regs = &mut emu.cpu.registers;
+    for (name, register) in [
+        ("a", &mut regs.a),
+        ("b", &mut regs.b),
+        ("c", &mut regs.c),
+        ("d", &mut regs.d),
+        ("e", &mut regs.e),
+        ("f", &mut regs.f),
+        ("h", &mut regs.h),
+        ("l", &mut regs.l),
+    ] {
+        *register = initial.number(name) as u8;
+    }
+    regs.sp = initial.number("sp") as u16;
+    regs.pc = initial.number("pc") as u16;
+    emu.cpu.ime = initial.number("ime") != 0;
+    for (address, value) in memory(initial) {
+        emu.mmu.write_byte(address, value);
+    }
+    emu.mmu.take_hook_hits();
+
+    let cycles = emu.cpu.step(&mut emu.mmu);
+    let accesses = emu.mmu.take_hook_hits();
+
+    let mut errors = Vec::new();
+    let regs = &emu.cpu.registers;
+    for (name, value) in [
+        ("a", regs.a as u64),
+        ("b", regs.b as u64),
+        ("c", regs.c as u64),
+        ("d", regs.d as u64),
+        ("e", regs.e as u64),
+        ("f", regs.f as u64),
+        ("h", regs.h as u64),
+        ("l", regs.l as u64),
+        ("sp", regs.sp as u64),
+        ("pc", regs.pc as u64),
+        ("ime", emu.cpu.ime as u64),
+    ] {
+        let wanted = expected.number(name);
+        if value != wanted {
+            errors.push(format!("{}: expected ${:02X}, got ${:02X}", name, wanted, value));
+        }
+    }
+    for (address, wanted) in memory(expected) {
+        let value = emu.mmu.read_byte(address);
+        if value != wanted {
+            errors.push(format!("[${:04X}]: expected ${:02X}, got ${:02X}", address, wanted, value));
+        }
+    }
+
+    // Bus activity: one entry per M-cycle, null or [address, value, "r-m"/"-wm"/"---"]
+    let bus = test.get("cycles").and_then(Json::as_array).unwrap_or(&[]);
+    if cycles / 4 != bus.len() as u32 {
+        errors.push(format!("expected {} M-cycles, took {}", bus.len(), cycles / 4));
+    }
+    let wanted_writes: Vec<(u16, u8)> = bus
+        .iter()
+        .filter_map(Json::as_array)
+        .filter(|cycle| cycle.get(2).and_then(Json::as_str).is_some_and(|kind| kind.contains('w')))
+        .map(|cycle| (cycle[0].as_number() as u16, cycle[1].as_number() as u8))
+        .collect();
+    let writes: Vec<(u16, u8)> = accesses.iter().filter(|a| a.write).map(|a| (a.address, a.value)).collect();
+    if writes != wanted_writes {
+        errors.push(format!("expected writes {}, got {}", format_writes(&wanted_writes), format_writes(&writes)));
+    }
+
+    // Leave the RAM clean for the next test
+    for (address, _) in memory(initial).chain(memory(expected)) {
+        emu.mmu.write_byte(address, 0);
+    }
+    emu.mmu.take_hook_hits();
+    errors
+}
+
+// The "ram" list of a state: [address, value] pairs
+fn memory(state: &Json) -> impl Iterator<Item = (u16, u8)> + '_ {
+    state
+        .get("ram")
+        .and_then(Json::as_array)
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(Json::as_array)
+        .filter(|pair| pair.len() == 2)
+        .map(|pair| (pair[0].as_number() as u16, pair[1].as_number() as u8))
+}
+
+fn format_writes(writes: &[(u16, u8)]) -> String {
+    let list: Vec<String> = writes.iter().map(|(address, value)| format!("${:02X}->${:04X}", value, address)).collect();
+    format!("[{}]", list.join(", "))
+}
+
+// Just enough JSON for the test files
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes: text.as_bytes(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn as_number(&self) -> u64 {
+        match self {
+            Json::Number(number) => *number as u64,
+            Json::Bool(value) => *value as u64,
+            _ => 0,
+        }
+    }
+
+    // Field of an object as a number, 0 if missing
+    fn number(&self, key: &str) -> u64 {
+        self.get(key).map_or(0, Json::as_number)
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("invalid JSON at byte {}: {}", self.position, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.bytes.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    // Skips whitespace and consumes `byte` if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.bytes.get(self.position) == Some(&byte);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let rest = &self.bytes[self.position..];
+        for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+            if rest.starts_with(word.as_bytes()) {
+                self.position += word.len();
+                return Ok(value);
+            }
+        }
+        match rest.first() {
+            Some(b'{') => self.list(b'}', |parser| {
+                let key = parser.string()?;
+                if !parser.eat(b':') {
+                    return Err(parser.error("expected ':'"));
+                }
+                Ok((key, parser.value()?))
+            })
+            .map(Json::Object),
+            Some(b'[') => self.list(b']', Parser::value).map(Json::Array),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                while self.bytes.get(self.position).is_some_and(|b| b"+-.eE0123456789".contains(b)) {
+                    self.position += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.position]).unwrap_or_default();
+                text.parse().map(Json::Number).map_err(|_| self.error("bad number"))
+            }
+            _ => Err(self.error("unexpected character")),
+        }
+    }
+
+    // Comma-separated items up to `end`, after the opening bracket
+    fn list<T>(&mut self, end: u8, mut item: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        self.position += 1;
+        let mut items = Vec::new();
+        if self.eat(end) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.eat(end) {
+                return Ok(items);
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected ','"));
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if !self.eat(b'"') {
+            return Err(self.error("expected a string"));
+        }
+        let mut text = Vec::new();
+        loop {
+            match self.bytes.get(self.position) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    let escaped = match self.bytes.get(self.position) {
+                        Some(b'n') => b'\n',
+                        Some(b't') => b'\t',
+                        Some(&byte) => byte,
+                        None => return Err(self.error("unterminated string")),
+                    };
+                    text.push(escaped);
+                }
+                Some(&byte) => text.push(byte),
+                None => return Err(self.error("unterminated string")),
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+}
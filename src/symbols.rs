@@ -0,0 +1,79 @@
+// RGBDS-style symbol files (.sym): "BB:AAAA Label" per line, ';' comments
+
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct Symbols {
+    by_name: HashMap<String, (u8, u16)>,
+    sorted: Vec<(u8, u16, String)>, // Sorted by bank, then address
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols { by_name: HashMap::new(), sorted: Vec::new() }
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let mut symbols = Symbols::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let Some((location, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let Some((bank, address)) = location.split_once(':') else {
+                continue;
+            };
+            if let (Ok(bank), Ok(address)) = (u8::from_str_radix(bank, 16), u16::from_str_radix(address, 16)) {
+                let name = name.trim().to_string();
+                symbols.by_name.insert(name.clone(), (bank, address));
+                symbols.sorted.push((bank, address, name));
+            }
+        }
+
+        symbols.sorted.sort();
+        Ok(symbols)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sorted.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sorted.is_empty()
+    }
+
+    // Bank and address of a label
+    pub fn lookup(&self, name: &str) -> Option<(u8, u16)> {
+        self.by_name.get(name).copied()
+    }
+
+    // Closest label at or before `address` in `bank`, as "Label" or "Label+$12".
+    // Labels never extend across the ROM0/ROMX or ROM/RAM boundaries.
+    pub fn label(&self, bank: u8, address: u16) -> Option<String> {
+        let region_start = match address {
+            0x0000..=0x3FFF => 0x0000,
+            0x4000..=0x7FFF => 0x4000,
+            _ => address & 0xF000,
+        };
+        let index = self.sorted.partition_point(|(b, a, _)| (*b, *a) <= (bank, address));
+        let (b, a, name) = self.sorted[..index].last()?;
+        if *b != bank || *a < region_start {
+            return None;
+        }
+        Some(match address - a {
+            0 => name.clone(),
+            offset => format!("{}+${:X}", name, offset),
+        })
+    }
+
+    // "$4123 (Label+$23)", or just the address without a matching label
+    pub fn format(&self, bank: u8, address: u16) -> String {
+        match self.label(bank, address) {
+            Some(label) => format!("${:04X} ({})", address, label),
+            None => format!("${:04X}", address),
+        }
+    }
+}
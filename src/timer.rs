@@ -1,11 +1,19 @@
+use crate::savestate::{StateReader, StateWriter};
+
 pub struct Timer {
-    pub div: u16,  // Internal divider counter (16-bit, but only upper 8 bits exposed)
+    pub div: u16,  // Internal divider counter (16-bit, upper 8 bits exposed as DIV)
     pub tima: u8,  // Timer counter (0xFF05)
     pub tma: u8,   // Timer modulo (0xFF06)
     pub tac: u8,   // Timer control (0xFF07)
 
-    div_cycles: u32,
-    tima_cycles: u32,
+    overflow_pending: bool, // TIMA overflowed and reads 0 until the reload next M-cycle
+    reloading: bool,        // TIMA is being reloaded from TMA during this M-cycle
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Timer {
@@ -15,46 +23,56 @@ impl Timer {
             tima: 0,
             tma: 0,
             tac: 0,
-            div_cycles: 0,
-            tima_cycles: 0,
+            overflow_pending: false,
+            reloading: false,
         }
     }
 
-    pub fn step(&mut self, cycles: u32) -> bool {
-        // Update DIV register (increments at 16384 Hz = every 256 cycles)
-        self.div_cycles += cycles;
-        while self.div_cycles >= 256 {
-            self.div = self.div.wrapping_add(1);
-            self.div_cycles -= 256;
+    // TIMA is clocked by the falling edge of this signal: the DIV bit selected
+    // by TAC, ANDed with the timer enable bit
+    fn timer_input(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0 => 9, // 4096 Hz
+            1 => 3, // 262144 Hz
+            2 => 5, // 65536 Hz
+            _ => 7, // 16384 Hz
+        };
+        (self.tac & 0x04) != 0 && (self.div >> bit) & 1 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            // The reload from TMA and the interrupt happen one M-cycle later
+            self.overflow_pending = true;
         }
+    }
 
-        // Check if timer is enabled
-        if (self.tac & 0x04) == 0 {
-            return false;
+    pub fn step(&mut self, cycles: u32) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            interrupt |= self.tick();
         }
+        interrupt
+    }
 
-        // Update TIMA based on frequency
-        let frequency = match self.tac & 0x03 {
-            0 => 1024,  // 4096 Hz
-            1 => 16,    // 262144 Hz
-            2 => 64,    // 65536 Hz
-            3 => 256,   // 16384 Hz
-            _ => 1024,
-        };
+    // Advances the timer by one M-cycle, returning true when the interrupt fires
+    fn tick(&mut self) -> bool {
+        self.reloading = false;
 
-        self.tima_cycles += cycles;
         let mut interrupt = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            interrupt = true;
+        }
 
-        while self.tima_cycles >= frequency {
-            self.tima_cycles -= frequency;
-
-            if self.tima == 0xFF {
-                // Timer overflow - trigger interrupt
-                self.tima = self.tma;
-                interrupt = true;
-            } else {
-                self.tima = self.tima.wrapping_add(1);
-            }
+        let old_input = self.timer_input();
+        self.div = self.div.wrapping_add(4);
+        if old_input && !self.timer_input() {
+            self.increment_tima();
         }
 
         interrupt
@@ -65,8 +83,12 @@ impl Timer {
     }
 
     pub fn write_div(&mut self) {
+        // Resetting the divider can cause a falling edge on the selected bit
+        let old_input = self.timer_input();
         self.div = 0;
-        self.div_cycles = 0;
+        if old_input {
+            self.increment_tima();
+        }
     }
 
     pub fn read_tima(&self) -> u8 {
@@ -74,6 +96,12 @@ impl Timer {
     }
 
     pub fn write_tima(&mut self, value: u8) {
+        // Writes during the reload cycle are overwritten by TMA
+        if self.reloading {
+            return;
+        }
+        // Writing while TIMA reads 0 after an overflow cancels the reload and interrupt
+        self.overflow_pending = false;
         self.tima = value;
     }
 
@@ -83,6 +111,10 @@ impl Timer {
 
     pub fn write_tma(&mut self, value: u8) {
         self.tma = value;
+        // During the reload cycle the new TMA value is copied to TIMA as well
+        if self.reloading {
+            self.tima = value;
+        }
     }
 
     pub fn read_tac(&self) -> u8 {
@@ -90,6 +122,27 @@ impl Timer {
     }
 
     pub fn write_tac(&mut self, value: u8) {
+        // Disabling the timer or changing the selected bit can cause a falling edge
+        let old_input = self.timer_input();
         self.tac = value & 0x07;
+        if old_input && !self.timer_input() {
+            self.increment_tima();
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.div);
+        w.bytes(&[self.tima, self.tma, self.tac]);
+        w.bool(self.overflow_pending);
+        w.bool(self.reloading);
     }
-}
\ No newline at end of file
+
+    pub fn load_state(&mut self, r: &mut StateReader) {
+        self.div = r.u16();
+        self.tima = r.u8();
+        self.tma = r.u8();
+        self.tac = r.u8();
+        self.overflow_pending = r.bool();
+        self.reloading = r.bool();
+    }
+}
@@ -0,0 +1,56 @@
+// Hands frames from one thread to another without either waiting for the
+// other: the writer always has a buffer to draw into and the reader always
+// gets the newest complete frame. Frames the reader didn't get to are dropped.
+
+use std::sync::{Arc, Mutex};
+
+// The middle buffer, and whether it holds a frame the reader hasn't seen
+type Shared<T> = Arc<Mutex<(T, bool)>>;
+
+pub struct Writer<T> {
+    back: T,
+    shared: Shared<T>,
+}
+
+pub struct Reader<T> {
+    front: T,
+    shared: Shared<T>,
+}
+
+pub fn new<T: Clone>(initial: T) -> (Writer<T>, Reader<T>) {
+    let shared = Arc::new(Mutex::new((initial.clone(), false)));
+    let writer = Writer { back: initial.clone(), shared: Arc::clone(&shared) };
+    (writer, Reader { front: initial, shared })
+}
+
+impl<T> Writer<T> {
+    // Buffer for the next frame; its old contents are an earlier frame
+    pub fn back(&mut self) -> &mut T {
+        &mut self.back
+    }
+
+    // Makes the back buffer the newest frame
+    pub fn publish(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        std::mem::swap(&mut self.back, &mut shared.0);
+        shared.1 = true;
+    }
+}
+
+impl<T> Reader<T> {
+    // Takes the newest frame if one was published since the last call.
+    // Returns false if the front buffer still holds the same frame.
+    pub fn update(&mut self) -> bool {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.1 {
+            return false;
+        }
+        std::mem::swap(&mut self.front, &mut shared.0);
+        shared.1 = false;
+        true
+    }
+
+    pub fn front(&self) -> &T {
+        &self.front
+    }
+}
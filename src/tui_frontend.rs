@@ -0,0 +1,255 @@
+// Terminal frontend (--tui): draws the screen with Unicode half blocks, two
+// pixels per character cell, in truecolor or 256 colors, and reads the keys
+// from the terminal. Runs without a display or audio device, e.g. over SSH.
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+    PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Colors, Print, ResetColor, SetColors};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+
+use crate::cpu::Lockup;
+use crate::emulator::Emulator;
+use crate::frontend::{self, Frontend};
+use crate::joypad::JoypadState;
+use crate::movie::MovieSession;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Most terminals only report key presses and auto-repeat, so a press holds
+// the button for this many frames unless release events are available
+const HOLD_FRAMES: u64 = 10;
+
+// Keys for each JoypadState::bits() bit
+const KEYS: [&[KeyCode]; 8] = [
+    &[KeyCode::Right],
+    &[KeyCode::Left],
+    &[KeyCode::Up],
+    &[KeyCode::Down],
+    &[KeyCode::Char('z')],
+    &[KeyCode::Char('x')],
+    &[KeyCode::Char(' '), KeyCode::Backspace],
+    &[KeyCode::Enter],
+];
+
+pub fn run(emu: &mut Emulator, movie: Option<&mut MovieSession>) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, EnterAlternateScreen, cursor::Hide, Clear(ClearType::All))?;
+
+    // Key releases are only reported by terminals supporting the kitty protocol
+    let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if releases {
+        execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+    }
+
+    let truecolor = std::env::var("COLORTERM").is_ok_and(|v| v.contains("truecolor") || v.contains("24bit"));
+    let mut tui = TuiFrontend {
+        stdout: io::stdout(),
+        screen: Screen::new(truecolor),
+        releases,
+        held: [0; 8],
+        frame: 0,
+        paused: false,
+        lockup: None,
+        open: true,
+        error: None,
+    };
+    frontend::run(emu, &mut tui, movie);
+
+    // Always give the terminal back in a usable state
+    if releases {
+        let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+    }
+    let _ = execute!(stdout, ResetColor, cursor::Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    tui.error.map_or(Ok(()), Err)
+}
+
+struct TuiFrontend {
+    stdout: io::Stdout,
+    screen: Screen,
+    releases: bool,  // Terminal reports key releases
+    held: [u64; 8], // Frame until which each button is held
+    frame: u64,
+    paused: bool,
+    lockup: Option<Lockup>, // Shown on the status line, since logging would garble the screen
+    open: bool,
+    error: Option<io::Error>, // Ends the session
+}
+
+impl TuiFrontend {
+    fn fail(&mut self, error: io::Error) {
+        self.error.get_or_insert(error);
+        self.open = false;
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Key(key) => {
+                let pressed = key.kind != KeyEventKind::Release;
+                match key.code {
+                    KeyCode::Esc | KeyCode::Char('q') if pressed => self.open = false,
+                    KeyCode::Char('p') if key.kind == KeyEventKind::Press => self.paused = !self.paused,
+                    code => {
+                        if let Some(bit) = KEYS.iter().position(|keys| keys.contains(&code)) {
+                            self.held[bit] = match (pressed, self.releases) {
+                                (false, _) => 0,
+                                (true, true) => u64::MAX,
+                                (true, false) => self.frame + HOLD_FRAMES,
+                            };
+                        }
+                    }
+                }
+            }
+            Event::Resize(..) => self.screen.invalidate(),
+            _ => {}
+        }
+    }
+}
+
+impl Frontend for TuiFrontend {
+    fn present(&mut self, fb: &[u32]) {
+        let status = match (self.lockup, self.paused) {
+            (Some(lockup), _) => format!("{} - Q quit", lockup),
+            (None, true) => "PAUSED - P resume, Q quit".to_string(),
+            (None, false) => "P pause, Q quit".to_string(),
+        };
+        if let Err(e) = self.screen.draw(&mut self.stdout, fb, &status) {
+            self.fail(e);
+        }
+    }
+
+    fn poll_input(&mut self) -> JoypadState {
+        loop {
+            let event = match event::poll(Duration::ZERO) {
+                Ok(true) => event::read(),
+                Ok(false) => break,
+                Err(e) => Err(e),
+            };
+            match event {
+                Ok(event) => self.handle_event(event),
+                Err(e) => {
+                    self.fail(e);
+                    break;
+                }
+            }
+        }
+
+        if !self.paused {
+            self.frame += 1;
+        }
+        let frame = self.frame;
+        let held = self
+            .held
+            .iter()
+            .enumerate()
+            .fold(0, |mask, (bit, &until)| mask | (((until > frame) as u8) << bit));
+        JoypadState::from_bits(held)
+    }
+
+    // No sound in the terminal
+    fn push_audio(&mut self, _samples: &[f32]) {}
+
+    fn is_open(&self) -> bool {
+        self.open
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn crashed(&mut self, lockup: Lockup) {
+        self.lockup = Some(lockup);
+    }
+}
+
+// Terminal contents, so only the cells that changed are redrawn
+struct Screen {
+    truecolor: bool,
+    cells: Vec<Option<(u32, u32)>>, // Top and bottom pixel of each cell
+    width: usize,                   // In cells
+    height: usize,
+}
+
+impl Screen {
+    fn new(truecolor: bool) -> Self {
+        Screen { truecolor, cells: Vec::new(), width: 0, height: 0 }
+    }
+
+    fn invalidate(&mut self) {
+        self.cells.clear();
+    }
+
+    fn draw(&mut self, out: &mut impl Write, framebuffer: &[u32], status: &str) -> io::Result<()> {
+        // Fit the screen into the terminal, never scaling up, leaving the
+        // last row for the status line
+        let (cols, rows) = terminal::size()?;
+        let max_rows = (rows as usize).saturating_sub(1) * 2;
+        let scale = (cols as f64 / SCREEN_WIDTH as f64).min(max_rows as f64 / SCREEN_HEIGHT as f64).min(1.0);
+        let width = ((SCREEN_WIDTH as f64 * scale) as usize).max(1);
+        let pixel_rows = ((SCREEN_HEIGHT as f64 * scale) as usize).max(2);
+        let height = pixel_rows / 2;
+
+        if self.cells.len() != width * height || self.width != width {
+            self.cells = vec![None; width * height];
+            self.width = width;
+            self.height = height;
+            queue!(out, ResetColor, Clear(ClearType::All))?;
+        }
+
+        let sample = |x: usize, y: usize| framebuffer[(y * SCREEN_HEIGHT / pixel_rows) * SCREEN_WIDTH + x * SCREEN_WIDTH / width];
+        let mut cursor_at = None;
+        let mut colors = None;
+        for cy in 0..height {
+            for cx in 0..width {
+                let cell = (sample(cx, cy * 2), sample(cx, cy * 2 + 1));
+                let index = cy * width + cx;
+                if self.cells[index] == Some(cell) {
+                    continue;
+                }
+                self.cells[index] = Some(cell);
+
+                if cursor_at != Some((cx, cy)) {
+                    queue!(out, cursor::MoveTo(cx as u16, cy as u16))?;
+                }
+                if colors != Some(cell) {
+                    queue!(out, SetColors(Colors::new(self.color(cell.0), self.color(cell.1))))?;
+                    colors = Some(cell);
+                }
+                queue!(out, Print('▀'))?;
+                cursor_at = Some((cx + 1, cy));
+            }
+        }
+
+        queue!(out, ResetColor, cursor::MoveTo(0, height as u16), Clear(ClearType::CurrentLine), Print(status))?;
+        out.flush()
+    }
+
+    fn color(&self, pixel: u32) -> Color {
+        let (r, g, b) = ((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8);
+        if self.truecolor {
+            Color::Rgb { r, g, b }
+        } else {
+            Color::AnsiValue(ansi256(r, g, b))
+        }
+    }
+}
+
+// Nearest entry of the xterm 256-color palette: the 24-step gray ramp for
+// grays, the 6x6x6 color cube otherwise
+fn ansi256(r: u8, g: u8, b: u8) -> u8 {
+    if r == g && g == b {
+        return match r {
+            0..=7 => 16,
+            249..=255 => 231,
+            _ => 232 + ((r as u16 - 8) * 24 / 241) as u8,
+        };
+    }
+    let level = |c: u8| ((c as u16 * 5 + 127) / 255) as u8;
+    16 + 36 * level(r) + 6 * level(g) + level(b)
+}
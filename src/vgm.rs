@@ -0,0 +1,98 @@
+// VGM 1.71 writer for sound logging: every APU register write, with the time
+// between writes as waits in 44100 Hz samples. Players such as VGMPlay replay
+// it on their own Game Boy APU emulation, so the file stays small and exact.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+const VGM_RATE: u64 = 44100;
+const CLOCK_RATE: u64 = 4194304;
+const HEADER_SIZE: u32 = 0x100;
+
+pub struct VgmWriter {
+    file: BufWriter<File>,
+    cycles: u64,  // Emulated since the log started
+    samples: u64, // Waits written so far
+    game: String,
+    system: String,
+}
+
+impl VgmWriter {
+    pub fn create(path: &str, game: &str, system: &str) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // Header - offsets and sample count are patched in finish()
+        let mut header = [0u8; HEADER_SIZE as usize];
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        header[0x08..0x0C].copy_from_slice(&0x171u32.to_le_bytes());
+        header[0x34..0x38].copy_from_slice(&(HEADER_SIZE - 0x34).to_le_bytes()); // Data offset
+        header[0x80..0x84].copy_from_slice(&(CLOCK_RATE as u32).to_le_bytes()); // GB DMG clock
+        file.write_all(&header)?;
+
+        Ok(VgmWriter {
+            file,
+            cycles: 0,
+            samples: 0,
+            game: game.to_string(),
+            system: system.to_string(),
+        })
+    }
+
+    // Emulated time passing, in normal-speed cycles
+    pub fn advance(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+    }
+
+    // Logs a write to 0xFF10-0xFF3F at the current time
+    pub fn write_register(&mut self, address: u16, value: u8) -> std::io::Result<()> {
+        self.write_wait()?;
+        self.file.write_all(&[0xB3, (address - 0xFF10) as u8, value])
+    }
+
+    // Waits until the current time, in the shortest commands
+    fn write_wait(&mut self) -> std::io::Result<()> {
+        let target = self.cycles * VGM_RATE / CLOCK_RATE;
+        while self.samples < target {
+            let wait = (target - self.samples).min(0xFFFF);
+            match wait {
+                735 => self.file.write_all(&[0x62])?, // 1/60 s
+                882 => self.file.write_all(&[0x63])?, // 1/50 s
+                1..=16 => self.file.write_all(&[0x70 + (wait - 1) as u8])?,
+                _ => {
+                    self.file.write_all(&[0x61])?;
+                    self.file.write_all(&(wait as u16).to_le_bytes())?;
+                }
+            }
+            self.samples += wait;
+        }
+        Ok(())
+    }
+
+    // Ends the data, adds the GD3 tag and patches the header
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.write_wait()?;
+        self.file.write_all(&[0x66])?;
+
+        // GD3 tag: English and Japanese track, game, system and author names,
+        // then date, converter and notes, as null-terminated UTF-16
+        let gd3_offset = self.file.stream_position()? as u32;
+        let fields = ["", "", &self.game, "", &self.system, "", "", "", "", "gameboy_emulator", ""];
+        let mut text = Vec::new();
+        for field in fields {
+            for unit in field.encode_utf16().chain([0]) {
+                text.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        self.file.write_all(b"Gd3 ")?;
+        self.file.write_all(&0x100u32.to_le_bytes())?;
+        self.file.write_all(&(text.len() as u32).to_le_bytes())?;
+        self.file.write_all(&text)?;
+        let end = self.file.stream_position()? as u32;
+
+        self.file.seek(SeekFrom::Start(0x04))?;
+        self.file.write_all(&(end - 0x04).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(0x14))?;
+        self.file.write_all(&(gd3_offset - 0x14).to_le_bytes())?;
+        self.file.write_all(&(self.samples as u32).to_le_bytes())?;
+        self.file.flush()
+    }
+}
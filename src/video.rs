@@ -0,0 +1,275 @@
+// Post-processing applied to the PPU framebuffer before it is shown
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Emulates the slow response of the DMG LCD by blending each frame with the
+// previously displayed one. Games relying on this flicker sprites on
+// alternate frames to fake transparency.
+pub struct FrameBlender {
+    pub persistence: f32, // Weight of the previous frame, 0.0 (off) to 0.9
+    output: Vec<u32>,
+    primed: bool,
+}
+
+impl FrameBlender {
+    pub fn new(persistence: f32) -> Self {
+        FrameBlender {
+            persistence: persistence.clamp(0.0, 0.9),
+            output: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            primed: false,
+        }
+    }
+
+    // The last blended frame
+    pub fn output(&self) -> &[u32] {
+        &self.output
+    }
+
+    pub fn apply(&mut self, frame: &[u32]) -> &[u32] {
+        // The first frame has nothing to blend with
+        if !self.primed || self.persistence <= 0.0 {
+            self.output.copy_from_slice(frame);
+            self.primed = true;
+            return &self.output;
+        }
+
+        let old = (self.persistence * 256.0) as u32;
+        let new = 256 - old;
+        for (out, &pixel) in self.output.iter_mut().zip(frame) {
+            let mut blended = 0;
+            for shift in [0, 8, 16] {
+                let a = (pixel >> shift) & 0xFF;
+                let b = (*out >> shift) & 0xFF;
+                // Round towards the new value so pixels always settle on it
+                let round = if a > b { 255 } else { 0 };
+                blended |= ((a * new + b * old + round) >> 8) << shift;
+            }
+            *out = blended;
+        }
+        &self.output
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScaleFilter {
+    None,      // Native resolution, scaled by the window
+    Nearest,   // Integer nearest-neighbour
+    Scale2x,   // EPX/Scale2x edge smoothing
+    Scale3x,   // AdvMAME3x edge smoothing
+    Scanlines, // Nearest with darkened horizontal lines
+    LcdGrid,   // Nearest with a dark grid between pixels
+}
+
+impl ScaleFilter {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Some(ScaleFilter::None),
+            "nearest" => Some(ScaleFilter::Nearest),
+            "scale2x" => Some(ScaleFilter::Scale2x),
+            "scale3x" => Some(ScaleFilter::Scale3x),
+            "scanlines" => Some(ScaleFilter::Scanlines),
+            "lcd" => Some(ScaleFilter::LcdGrid),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScaleFilter::None => "none",
+            ScaleFilter::Nearest => "nearest",
+            ScaleFilter::Scale2x => "scale2x",
+            ScaleFilter::Scale3x => "scale3x",
+            ScaleFilter::Scanlines => "scanlines",
+            ScaleFilter::LcdGrid => "lcd",
+        }
+    }
+
+    // Next filter for the runtime hotkey
+    pub fn next(&self) -> Self {
+        match self {
+            ScaleFilter::None => ScaleFilter::Nearest,
+            ScaleFilter::Nearest => ScaleFilter::Scale2x,
+            ScaleFilter::Scale2x => ScaleFilter::Scale3x,
+            ScaleFilter::Scale3x => ScaleFilter::Scanlines,
+            ScaleFilter::Scanlines => ScaleFilter::LcdGrid,
+            ScaleFilter::LcdGrid => ScaleFilter::None,
+        }
+    }
+
+    fn factor(&self) -> usize {
+        match self {
+            ScaleFilter::None => 1,
+            ScaleFilter::Scale2x => 2,
+            _ => 3,
+        }
+    }
+}
+
+// Software scaler run on the frame before it is handed to the window
+pub struct Scaler {
+    pub filter: ScaleFilter,
+    output: Vec<u32>,
+}
+
+impl Scaler {
+    pub fn new(filter: ScaleFilter) -> Self {
+        Scaler { filter, output: Vec::new() }
+    }
+
+    // Returns the scaled frame with its width and height
+    pub fn apply<'a>(&'a mut self, frame: &'a [u32]) -> (&'a [u32], usize, usize) {
+        let factor = self.filter.factor();
+        let (width, height) = (SCREEN_WIDTH * factor, SCREEN_HEIGHT * factor);
+        if self.filter == ScaleFilter::None {
+            return (frame, SCREEN_WIDTH, SCREEN_HEIGHT);
+        }
+
+        self.output.resize(width * height, 0);
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let block = match self.filter {
+                    ScaleFilter::Scale2x => scale2x(frame, x, y),
+                    ScaleFilter::Scale3x => scale3x(frame, x, y),
+                    ScaleFilter::Scanlines => {
+                        let p = frame[y * SCREEN_WIDTH + x];
+                        let dark = darken(p);
+                        [p, p, p, p, p, p, dark, dark, dark]
+                    }
+                    ScaleFilter::LcdGrid => {
+                        let p = frame[y * SCREEN_WIDTH + x];
+                        let dark = darken(p);
+                        [p, p, dark, p, p, dark, dark, dark, dark]
+                    }
+                    _ => [frame[y * SCREEN_WIDTH + x]; 9],
+                };
+
+                for dy in 0..factor {
+                    let row = (y * factor + dy) * width + x * factor;
+                    self.output[row..row + factor]
+                        .copy_from_slice(&block[dy * factor..dy * factor + factor]);
+                }
+            }
+        }
+        (&self.output, width, height)
+    }
+}
+
+// Pixel at (x + dx, y + dy), clamped to the screen edges
+fn neighbor(frame: &[u32], x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+    let nx = (x as isize + dx).clamp(0, SCREEN_WIDTH as isize - 1) as usize;
+    let ny = (y as isize + dy).clamp(0, SCREEN_HEIGHT as isize - 1) as usize;
+    frame[ny * SCREEN_WIDTH + nx]
+}
+
+// Scales one pixel to 2x2; only the first 4 entries are used
+fn scale2x(frame: &[u32], x: usize, y: usize) -> [u32; 9] {
+    let p = neighbor(frame, x, y, 0, 0);
+    let a = neighbor(frame, x, y, 0, -1);
+    let b = neighbor(frame, x, y, 1, 0);
+    let c = neighbor(frame, x, y, -1, 0);
+    let d = neighbor(frame, x, y, 0, 1);
+
+    let mut out = [p; 9];
+    if c == a && c != d && a != b { out[0] = a; }
+    if a == b && a != c && b != d { out[1] = b; }
+    if d == c && d != b && c != a { out[2] = c; }
+    if b == d && b != a && d != c { out[3] = d; }
+    out
+}
+
+// Scales one pixel to 3x3
+fn scale3x(frame: &[u32], x: usize, y: usize) -> [u32; 9] {
+    let a = neighbor(frame, x, y, -1, -1);
+    let b = neighbor(frame, x, y, 0, -1);
+    let c = neighbor(frame, x, y, 1, -1);
+    let d = neighbor(frame, x, y, -1, 0);
+    let e = neighbor(frame, x, y, 0, 0);
+    let f = neighbor(frame, x, y, 1, 0);
+    let g = neighbor(frame, x, y, -1, 1);
+    let h = neighbor(frame, x, y, 0, 1);
+    let i = neighbor(frame, x, y, 1, 1);
+
+    let mut out = [e; 9];
+    if b != h && d != f {
+        if d == b { out[0] = d; }
+        if (d == b && e != c) || (b == f && e != a) { out[1] = b; }
+        if b == f { out[2] = f; }
+        if (d == b && e != g) || (d == h && e != a) { out[3] = d; }
+        if (b == f && e != i) || (h == f && e != c) { out[5] = f; }
+        if d == h { out[6] = d; }
+        if (d == h && e != i) || (h == f && e != g) { out[7] = h; }
+        if h == f { out[8] = f; }
+    }
+    out
+}
+
+// Color at 5/8 brightness, used for scanlines and the LCD grid
+fn darken(color: u32) -> u32 {
+    let mut out = 0;
+    for shift in [0, 8, 16] {
+        let c = (color >> shift) & 0xFF;
+        out |= (c * 5 / 8) << shift;
+    }
+    out
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FitMode {
+    Integer, // Largest whole-number scale that fits
+    Aspect,  // Largest scale that keeps the aspect ratio
+}
+
+impl FitMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "integer" => Some(FitMode::Integer),
+            "aspect" => Some(FitMode::Aspect),
+            _ => None,
+        }
+    }
+}
+
+// Fits the frame into a window of any size, centered with black bars
+pub struct Letterbox {
+    pub mode: FitMode,
+    output: Vec<u32>,
+}
+
+impl Letterbox {
+    pub fn new(mode: FitMode) -> Self {
+        Letterbox { mode, output: Vec::new() }
+    }
+
+    // The last apply() result
+    pub fn output(&self) -> &[u32] {
+        &self.output
+    }
+
+    pub fn apply(&mut self, frame: &[u32], width: usize, height: usize, win_width: usize, win_height: usize) -> &[u32] {
+        self.output.clear();
+        self.output.resize(win_width * win_height, 0);
+
+        let scale = (win_width as f32 / width as f32).min(win_height as f32 / height as f32);
+        let scale = match self.mode {
+            // Frames larger than the window are still shrunk to fit
+            FitMode::Integer if scale >= 1.0 => scale.floor(),
+            _ => scale,
+        };
+        let out_width = ((width as f32 * scale) as usize).min(win_width);
+        let out_height = ((height as f32 * scale) as usize).min(win_height);
+        if out_width == 0 || out_height == 0 {
+            return &self.output;
+        }
+
+        let left = (win_width - out_width) / 2;
+        let top = (win_height - out_height) / 2;
+        for y in 0..out_height {
+            let src_row = (y * height / out_height) * width;
+            let dst_row = (top + y) * win_width + left;
+            for x in 0..out_width {
+                self.output[dst_row + x] = frame[src_row + x * width / out_width];
+            }
+        }
+        &self.output
+    }
+}
@@ -0,0 +1,150 @@
+// VRAM debug view: the tile data of both banks and the two 32x32 tilemaps,
+// with the BG and window viewports outlined and optional CGB attribute overlay
+
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use crate::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const GAP: usize = 8;
+const TILES_WIDTH: usize = 16 * 8; // 16 tiles per row, 24 rows (384 tiles) per bank
+const MAP_SIZE: usize = 256;
+pub const WIDTH: usize = TILES_WIDTH * 2 + GAP + (MAP_SIZE + GAP) * 2 - GAP;
+pub const HEIGHT: usize = MAP_SIZE;
+
+const BACKGROUND: u32 = 0x202020;
+const BG_OUTLINE: u32 = 0xFF0000;
+const WINDOW_OUTLINE: u32 = 0x00FF00;
+const PRIORITY_MARK: u32 = 0xFFFFFF;
+// Tint per CGB palette number for the attribute overlay
+const PALETTE_TINTS: [u32; 8] = [0xFF0000, 0xFF8000, 0xFFFF00, 0x00FF00, 0x00FFFF, 0x0000FF, 0x8000FF, 0xFF00FF];
+
+pub struct VramViewer {
+    window: Window,
+    buffer: Vec<u32>,
+    pub overlay: bool, // Tint map tiles by CGB palette and mark BG-priority tiles
+}
+
+impl VramViewer {
+    pub fn new() -> Result<Self, String> {
+        let options = WindowOptions { scale: Scale::X2, ..WindowOptions::default() };
+        let mut window = Window::new("VRAM Viewer (A: toggle attribute overlay)", WIDTH, HEIGHT, options)
+            .map_err(|e| e.to_string())?;
+        window.limit_update_rate(None);
+        Ok(VramViewer { window, buffer: vec![BACKGROUND; WIDTH * HEIGHT], overlay: false })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    // Redraws the view from the current VRAM contents
+    pub fn update(&mut self, ppu: &Ppu) {
+        if self.window.is_key_pressed(Key::A, KeyRepeat::No) {
+            self.overlay = !self.overlay;
+        }
+        render(ppu, self.overlay, &mut self.buffer);
+        let _ = self.window.update_with_buffer(&self.buffer, WIDTH, HEIGHT);
+    }
+}
+
+// Draws the whole view into a WIDTH x HEIGHT buffer
+pub fn render(ppu: &Ppu, overlay: bool, buffer: &mut [u32]) {
+    buffer.fill(BACKGROUND);
+
+    // Tile data, one block per bank, using BG palette 0
+    let banks = if ppu.is_gbc { 2 } else { 1 };
+    for bank in 0..banks {
+        for tile in 0..384 {
+            let x = bank * TILES_WIDTH + (tile % 16) * 8;
+            let y = (tile / 16) * 8;
+            draw_tile(ppu, buffer, x, y, bank, tile * 16, 0, false, false);
+        }
+    }
+
+    // Tilemaps at 0x9800 and 0x9C00
+    for (i, map_base) in [0x1800, 0x1C00].into_iter().enumerate() {
+        let left = TILES_WIDTH * 2 + GAP + i * (MAP_SIZE + GAP);
+        for row in 0..32 {
+            for col in 0..32 {
+                let map_addr = map_base + row * 32 + col;
+                let tile_num = ppu.vram[0][map_addr];
+                let attr = if ppu.is_gbc { ppu.vram[1][map_addr] } else { 0 };
+
+                // LCDC bit 4 selects unsigned (0x8000) or signed (0x9000) tile numbers
+                let tile_addr = if (ppu.lcdc & 0x10) != 0 {
+                    tile_num as usize * 16
+                } else {
+                    (0x1000 + (tile_num as i8 as isize) * 16) as usize
+                };
+                let (x, y) = (left + col * 8, row * 8);
+                draw_tile(ppu, buffer, x, y, ((attr >> 3) & 1) as usize, tile_addr, attr & 0x07, attr & 0x20 != 0, attr & 0x40 != 0);
+
+                if overlay && ppu.is_gbc {
+                    tint_tile(buffer, x, y, PALETTE_TINTS[(attr & 0x07) as usize]);
+                    if attr & 0x80 != 0 {
+                        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                            buffer[(y + dy) * WIDTH + x + dx] = PRIORITY_MARK;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // BG viewport on the map selected by LCDC bit 3, wrapping around the edges
+    let bg_map = if (ppu.lcdc & 0x08) != 0 { 1 } else { 0 };
+    outline(buffer, bg_map, ppu.scx as usize, ppu.scy as usize, SCREEN_WIDTH, SCREEN_HEIGHT, BG_OUTLINE);
+
+    // Visible part of the window on the map selected by LCDC bit 6
+    if (ppu.lcdc & 0x20) != 0 && ppu.wx < 167 && (ppu.wy as usize) < SCREEN_HEIGHT {
+        let window_map = if (ppu.lcdc & 0x40) != 0 { 1 } else { 0 };
+        let width = SCREEN_WIDTH - (ppu.wx as usize).saturating_sub(7);
+        let height = SCREEN_HEIGHT - ppu.wy as usize;
+        outline(buffer, window_map, 0, 0, width, height, WINDOW_OUTLINE);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_tile(ppu: &Ppu, buffer: &mut [u32], x: usize, y: usize, bank: usize, tile_addr: usize, palette: u8, x_flip: bool, y_flip: bool) {
+    for line in 0..8 {
+        let src_line = if y_flip { 7 - line } else { line };
+        let low = ppu.vram[bank][tile_addr + src_line * 2];
+        let high = ppu.vram[bank][tile_addr + src_line * 2 + 1];
+        for px in 0..8 {
+            let bit = if x_flip { px } else { 7 - px };
+            let color_num = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            let color = if ppu.is_gbc {
+                ppu.get_gbc_bg_color(color_num, palette)
+            } else {
+                ppu.get_bg_color(color_num)
+            };
+            buffer[(y + line) * WIDTH + x + px] = color;
+        }
+    }
+}
+
+// Blends an 8x8 tile 50/50 with a color
+fn tint_tile(buffer: &mut [u32], x: usize, y: usize, tint: u32) {
+    for line in 0..8 {
+        for px in 0..8 {
+            let pixel = &mut buffer[(y + line) * WIDTH + x + px];
+            *pixel = ((*pixel >> 1) & 0x7F7F7F) + ((tint >> 1) & 0x7F7F7F);
+        }
+    }
+}
+
+// Draws a rectangle on tilemap `map`, wrapping at the 256x256 map edges
+fn outline(buffer: &mut [u32], map: usize, x: usize, y: usize, width: usize, height: usize, color: u32) {
+    let left = TILES_WIDTH * 2 + GAP + map * (MAP_SIZE + GAP);
+    let mut plot = |px: usize, py: usize| {
+        buffer[(py % MAP_SIZE) * WIDTH + left + px % MAP_SIZE] = color;
+    };
+    for dx in 0..width {
+        plot(x + dx, y);
+        plot(x + dx, y + height - 1);
+    }
+    for dy in 0..height {
+        plot(x, y + dy);
+        plot(x + width - 1, y + dy);
+    }
+}
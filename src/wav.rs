@@ -0,0 +1,63 @@
+// Minimal WAV (RIFF, 16-bit PCM) writer used for audio recording
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    data_bytes: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32, channels: u16) -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let block_align = channels * 2;
+        let byte_rate = sample_rate * block_align as u32;
+
+        // RIFF header - sizes are patched in finish()
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+
+        // Format chunk
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&byte_rate.to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+
+        // Data chunk
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            file,
+            channels,
+            data_bytes: 0,
+        })
+    }
+
+    // Writes one frame (one sample per channel) in the -1.0..1.0 range
+    pub fn write_frame(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for &sample in samples.iter().take(self.channels as usize) {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_bytes += self.channels as u32 * 2;
+        Ok(())
+    }
+
+    // Patches the chunk sizes and flushes the file
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(36 + self.data_bytes).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
@@ -0,0 +1,246 @@
+// Default desktop frontend: a minifb window with software filters and
+// letterboxing, and audio through cpal. main.rs runs it with the debugger,
+// scripts, recording and the other hotkeys around it.
+
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
+
+use crate::audio_output::{AudioConfig, AudioDevice, AudioOutput};
+use crate::cpu::Lockup;
+use crate::frontend::Frontend;
+use crate::joypad::JoypadState;
+use crate::osd::Osd;
+use crate::ppu;
+use crate::video::{FrameBlender, Letterbox, Scaler};
+use log::{error, info};
+
+const SCALE: usize = 3;
+
+// Keyboard layouts, chosen per game in the settings file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyLayout {
+    Arrows, // Arrows, Z = A, X = B
+    Wasd,   // WASD, K = A, J = B
+}
+
+impl KeyLayout {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "arrows" => Some(KeyLayout::Arrows),
+            "wasd" => Some(KeyLayout::Wasd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyLayout::Arrows => "arrows",
+            KeyLayout::Wasd => "wasd",
+        }
+    }
+
+    fn keys(&self) -> &'static [&'static [Key]; 8] {
+        match self {
+            KeyLayout::Arrows => &ARROW_KEYS,
+            KeyLayout::Wasd => &WASD_KEYS,
+        }
+    }
+}
+
+// Keys for each JoypadState::bits() bit
+const ARROW_KEYS: [&[Key]; 8] = [
+    &[Key::Right],
+    &[Key::Left],
+    &[Key::Up],
+    &[Key::Down],
+    &[Key::Z],
+    &[Key::X],
+    &[Key::LeftShift, Key::RightShift],
+    &[Key::Enter],
+];
+const WASD_KEYS: [&[Key]; 8] = [
+    &[Key::D],
+    &[Key::A],
+    &[Key::W],
+    &[Key::S],
+    &[Key::K],
+    &[Key::J],
+    &[Key::LeftShift, Key::RightShift],
+    &[Key::Enter],
+];
+
+pub struct WindowFrontend {
+    pub window: Window,
+    title: String,
+    fullscreen: bool,
+    letterbox: Letterbox,
+    pub blender: Option<FrameBlender>,
+    pub scaler: Scaler,
+    pub layout: KeyLayout,
+    pub osd: Osd,
+    osd_frame: Vec<u32>, // Frame with the OSD drawn over it
+    pub paused: bool,                   // Set by main.rs when there is no new frame to show
+    shown_size: Option<(usize, usize)>, // Window size of the last presented frame
+    audio: AudioOutput,
+    audio_device: AudioDevice,
+}
+
+impl WindowFrontend {
+    pub fn new(
+        title: &str,
+        fullscreen: bool,
+        letterbox: Letterbox,
+        blender: Option<FrameBlender>,
+        scaler: Scaler,
+        audio: AudioConfig,
+    ) -> Self {
+        let (audio, audio_device) = AudioOutput::open(audio);
+        WindowFrontend {
+            window: create_window(title, fullscreen),
+            title: title.to_string(),
+            fullscreen,
+            letterbox,
+            blender,
+            scaler,
+            layout: KeyLayout::Arrows,
+            osd: Osd::new(),
+            osd_frame: Vec::new(),
+            paused: false,
+            shown_size: None,
+            audio,
+            audio_device,
+        }
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+        self.window.set_title(title);
+    }
+
+    // Shows a message on screen and logs it
+    pub fn notify(&mut self, message: String) {
+        info!("{}", message);
+        self.osd.show(message);
+    }
+
+    pub fn audio_output(&self) -> AudioOutput {
+        self.audio.clone()
+    }
+
+    // The last presented frame as shown in the window, with filters and
+    // letterboxing. None while minimized.
+    pub fn scaled_frame(&self) -> Option<(&[u32], usize, usize)> {
+        self.shown_size.map(|(width, height)| (self.letterbox.output(), width, height))
+    }
+}
+
+impl Frontend for WindowFrontend {
+    fn present(&mut self, fb: &[u32]) {
+        let frame = match self.blender.as_mut() {
+            Some(blender) if self.paused => blender.output(),
+            Some(blender) => blender.apply(fb),
+            None => fb,
+        };
+        let frame = if self.osd.is_active() {
+            self.osd_frame.clear();
+            self.osd_frame.extend_from_slice(frame);
+            self.osd.draw(&mut self.osd_frame, ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT);
+            &self.osd_frame
+        } else {
+            frame
+        };
+        let (frame, width, height) = self.scaler.apply(frame);
+        let (win_width, win_height) = self.window.get_size();
+        if win_width > 0 && win_height > 0 {
+            let frame = self.letterbox.apply(frame, width, height, win_width, win_height);
+            self.window.update_with_buffer(frame, win_width, win_height).unwrap();
+            self.shown_size = Some((win_width, win_height));
+        } else {
+            // Minimized
+            self.window.update();
+            self.shown_size = None;
+        }
+    }
+
+    // Also handles the window's own hotkeys: fullscreen and the scaling
+    // filter, and follows audio device changes
+    fn poll_input(&mut self) -> JoypadState {
+        self.audio_device.check();
+
+        // Toggle fullscreen by recreating the window
+        let alt = self.window.is_key_down(Key::LeftAlt) || self.window.is_key_down(Key::RightAlt);
+        if alt && self.window.is_key_pressed(Key::Enter, KeyRepeat::No) {
+            self.fullscreen = !self.fullscreen;
+            self.window = create_window(&self.title, self.fullscreen);
+        }
+
+        // Cycle scaling filter
+        if self.window.is_key_pressed(Key::F3, KeyRepeat::No) {
+            self.scaler.filter = self.scaler.filter.next();
+            self.notify(format!("Filter: {}", self.scaler.filter.name()));
+        }
+
+        // Enter is left out while Alt is held, so going fullscreen doesn't press Start
+        let down = |key: Key| self.window.is_key_down(key) && !(alt && key == Key::Enter);
+        let held = self.layout.keys().iter().enumerate().fold(0, |mask, (bit, keys)| {
+            mask | ((keys.iter().any(|&key| down(key)) as u8) << bit)
+        });
+        JoypadState::from_bits(held)
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        self.audio.push(samples);
+    }
+
+    fn is_open(&self) -> bool {
+        self.window.is_open() && !self.window.is_key_down(Key::Escape)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn audio_queued(&self) -> Option<usize> {
+        Some(self.audio.queued())
+    }
+
+    // Kept in the title, which survives toggling fullscreen
+    fn crashed(&mut self, lockup: Lockup) {
+        error!("{}", lockup);
+        self.osd.show("CPU locked up");
+        self.title.push_str(" [crashed]");
+        self.window.set_title(&self.title);
+    }
+}
+
+// Creates the emulator window. Fullscreen is a borderless window sized to
+// fit the screen, since minifb has no exclusive fullscreen mode.
+fn create_window(title: &str, fullscreen: bool) -> Window {
+    let options = if fullscreen {
+        WindowOptions {
+            borderless: true,
+            title: false,
+            resize: true,
+            scale: Scale::FitScreen,
+            topmost: true,
+            ..WindowOptions::default()
+        }
+    } else {
+        WindowOptions { resize: true, ..WindowOptions::default() }
+    };
+    let (width, height) = if fullscreen {
+        (ppu::SCREEN_WIDTH, ppu::SCREEN_HEIGHT)
+    } else {
+        (ppu::SCREEN_WIDTH * SCALE, ppu::SCREEN_HEIGHT * SCALE)
+    };
+
+    let mut window = Window::new(title, width, height, options).unwrap_or_else(|e| {
+        panic!("Failed to create window: {}", e);
+    });
+    if fullscreen {
+        window.set_position(0, 0);
+    }
+
+    // Pacing is done by FramePacer, not by minifb's rate limiter
+    window.limit_update_rate(None);
+    window
+}
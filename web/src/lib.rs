@@ -0,0 +1,180 @@
+// Browser frontend: runs the emulator core compiled to wasm, draws frames to a
+// canvas and plays audio through WebAudio. index.html drives it: it passes the
+// ROM bytes in, calls frame() about 60 times a second and forwards key events.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{AudioContext, AudioContextOptions, CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+use gameboy_emulator::apu::SAMPLE_RATE;
+use gameboy_emulator::cartridge::Cartridge;
+use gameboy_emulator::cpu::Lockup;
+use gameboy_emulator::emulator::Emulator;
+use gameboy_emulator::frontend::{self, Frontend};
+use gameboy_emulator::joypad::JoypadState;
+use gameboy_emulator::mixer::{AudioFilter, Mixer};
+use gameboy_emulator::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Audio is scheduled this far ahead of the AudioContext clock
+const AUDIO_LATENCY: f64 = 0.05;
+// Chunks are dropped when the queue gets longer than this (tab was hidden)
+const MAX_AUDIO_QUEUE: f64 = 0.25;
+
+// KeyboardEvent.code for each JoypadState::bits() bit, same keys as the
+// desktop frontend
+const KEYS: [&[&str]; 8] = [
+    &["ArrowRight"],
+    &["ArrowLeft"],
+    &["ArrowUp"],
+    &["ArrowDown"],
+    &["KeyZ"],
+    &["KeyX"],
+    &["ShiftLeft", "ShiftRight"],
+    &["Enter"],
+];
+
+#[wasm_bindgen]
+pub struct WebEmulator {
+    emu: Emulator,
+    canvas: CanvasFrontend,
+}
+
+#[wasm_bindgen]
+impl WebEmulator {
+    // Loads a ROM image and draws into the canvas with the given id. Should be
+    // called from a user gesture (e.g. picking the ROM) so audio may start.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: Vec<u8>, canvas_id: &str) -> Result<WebEmulator, JsValue> {
+        let cartridge = Cartridge::from_rom(rom).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let is_gbc = cartridge.supports_cgb();
+
+        let document = web_sys::window().and_then(|w| w.document()).ok_or("no document")?;
+        let canvas: HtmlCanvasElement = document
+            .get_element_by_id(canvas_id)
+            .ok_or("canvas not found")?
+            .dyn_into()?;
+        canvas.set_width(SCREEN_WIDTH as u32);
+        canvas.set_height(SCREEN_HEIGHT as u32);
+        let context: CanvasRenderingContext2d = canvas.get_context("2d")?.ok_or("no 2d context")?.dyn_into()?;
+
+        // Runs without sound if WebAudio isn't available
+        let options = AudioContextOptions::new();
+        options.set_sample_rate(SAMPLE_RATE as f32);
+        let audio = AudioContext::new_with_context_options(&options).ok();
+
+        Ok(WebEmulator {
+            emu: Emulator::new(cartridge, is_gbc),
+            canvas: CanvasFrontend {
+                context,
+                audio,
+                audio_time: 0.0,
+                mixer: Mixer::new(SAMPLE_RATE, AudioFilter::Smooth),
+                rgba: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+                buttons: 0,
+                error: None,
+            },
+        })
+    }
+
+    // Runs one frame, draws it and queues its audio. The page paces the calls.
+    pub fn frame(&mut self) -> Result<(), JsValue> {
+        frontend::step(&mut self.emu, &mut self.canvas, None);
+        self.canvas.error.take().map_or(Ok(()), Err)
+    }
+
+    // Returns true if the key is mapped, so the page can prevent scrolling
+    pub fn key_down(&mut self, code: &str) -> bool {
+        self.canvas.set_key(code, true)
+    }
+
+    pub fn key_up(&mut self, code: &str) -> bool {
+        self.canvas.set_key(code, false)
+    }
+}
+
+struct CanvasFrontend {
+    context: CanvasRenderingContext2d,
+    audio: Option<AudioContext>,
+    audio_time: f64, // When the next audio chunk starts playing
+    mixer: Mixer,
+    rgba: Vec<u8>,
+    buttons: u8,
+    error: Option<JsValue>, // Returned by the current frame() call
+}
+
+impl CanvasFrontend {
+    fn set_key(&mut self, code: &str, pressed: bool) -> bool {
+        let Some(bit) = KEYS.iter().position(|codes| codes.contains(&code)) else {
+            return false;
+        };
+        if pressed {
+            self.buttons |= 1 << bit;
+        } else {
+            self.buttons &= !(1 << bit);
+        }
+        true
+    }
+
+    fn draw(&mut self, fb: &[u32]) -> Result<(), JsValue> {
+        for (&pixel, rgba) in fb.iter().zip(self.rgba.chunks_exact_mut(4)) {
+            rgba[0] = (pixel >> 16) as u8;
+            rgba[1] = (pixel >> 8) as u8;
+            rgba[2] = pixel as u8;
+            rgba[3] = 0xFF;
+        }
+        let image =
+            ImageData::new_with_u8_clamped_array_and_sh(Clamped(&self.rgba), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)?;
+        self.context.put_image_data(&image, 0.0, 0.0)
+    }
+
+    // Plays the frame's samples right after the previously queued ones
+    fn queue_audio(&mut self, samples: &[f32]) -> Result<(), JsValue> {
+        let Some(ref audio) = self.audio else {
+            return Ok(());
+        };
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let now = audio.current_time();
+        if self.audio_time < now {
+            self.audio_time = now + AUDIO_LATENCY;
+        } else if self.audio_time - now > MAX_AUDIO_QUEUE {
+            return Ok(());
+        }
+
+        let buffer = audio.create_buffer(1, samples.len() as u32, SAMPLE_RATE as f32)?;
+        buffer.copy_to_channel(samples, 0)?;
+        let source = audio.create_buffer_source()?;
+        source.set_buffer(Some(&buffer));
+        source.connect_with_audio_node(&audio.destination())?;
+        source.start_with_when(self.audio_time)?;
+        self.audio_time += samples.len() as f64 / SAMPLE_RATE as f64;
+        Ok(())
+    }
+}
+
+impl Frontend for CanvasFrontend {
+    fn present(&mut self, fb: &[u32]) {
+        if let Err(e) = self.draw(fb) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    fn poll_input(&mut self) -> JoypadState {
+        JoypadState::from_bits(self.buttons)
+    }
+
+    fn push_audio(&mut self, samples: &[f32]) {
+        let mut mixed = Vec::new();
+        self.mixer.process(samples, &mut mixed);
+        if let Err(e) = self.queue_audio(&mixed) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    // Reported to the page through frame()
+    fn crashed(&mut self, lockup: Lockup) {
+        self.error.get_or_insert(JsValue::from_str(&lockup.to_string()));
+    }
+}